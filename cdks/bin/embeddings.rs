@@ -1,58 +1,86 @@
-use embeddings::{embeddings::DEFAULT_MODEL_EMBEDDING_SIZE, service::EmbeddingsService};
+use embeddings::{
+    client, config::EmbeddingsConfig, embeddings::Embeddings, id::ChunkId,
+    service::EmbeddingsService,
+};
 use log::info;
 
+const USAGE: &str = "Usage: embeddings [export <file> | import <file>]
+
+Without a command, runs a demo of the embeddings service. `export` writes the chunks of the
+configured store to a .npy, .npz, .arrow or .parquet file, and `import` stores the chunks of
+such a file, which must have been produced by the configured model. The embeddings service
+must not be running on the same store meanwhile.";
+
 #[tokio::main]
 async fn main() {
     env_logger::init();
 
-    let (chunk_sender, chunk_receiver) = std::sync::mpsc::channel::<String>();
-    let (embeddings_sender, embeddings_receiver) =
-        std::sync::mpsc::channel::<[f32; DEFAULT_MODEL_EMBEDDING_SIZE]>();
-    let (embedding_index_sender, _embedding_index_receiver) = std::sync::mpsc::channel::<u32>();
-    let _join_handle =
-        EmbeddingsService::spawn(chunk_receiver, embeddings_sender, embedding_index_sender);
+    let config = EmbeddingsConfig::from_env().expect("Failed to load embeddings config");
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        [] => demo(config).await,
+        ["export", path] => {
+            let embeddings = Embeddings::from_config(&config).expect("Failed to load embeddings");
+            let count = embeddings
+                .export(path)
+                .expect("Failed to export embeddings");
+            info!("Exported {count} chunks to {path}");
+        }
+        ["import", path] => {
+            let mut embeddings =
+                Embeddings::from_config(&config).expect("Failed to load embeddings");
+            let count = embeddings
+                .import(path)
+                .expect("Failed to import embeddings");
+            info!("Imported {count} chunks from {path}");
+        }
+        _ => {
+            eprintln!("{USAGE}");
+            std::process::exit(2);
+        }
+    }
+}
+
+async fn demo(config: EmbeddingsConfig) {
+    let (client, request_receiver) = client::channel(config.service.queue_capacity);
+    let _join_handle = EmbeddingsService::spawn_with_config(config, request_receiver);
     // _join_handle.join().expect("Failed to execute JoinHandle");
 
     info!("Sending text chunks");
 
-    chunk_sender
-        .send(r#"{"chunk_text":"Hello world !"}"#.to_string())
-        .expect("Failed to send message");
-    chunk_sender
-        .send(r#"{"chunk_text":"Knowledge graphs are great !"}"#.to_string())
-        .expect("Failed to send message");
-    chunk_sender
-        .send(r#"{"chunk_text":"LLMs are amazing, as well !"}"#.to_string())
-        .expect("Failed to send message");
-    chunk_sender
-        .send(r#"{"chunk_text":"Integrated circuits complexity is bound by physical constraints. Namely, the number of transistor that can be integrated in current chips."}"#.to_string())
-        .expect("Failed to send message");
-
-    chunk_sender
-        .send(String::from(r#"{"process_chunk":"Integrated circuits complexity is bound by physical constraints. Namely, the number of transistor that can be integrated in current chips."}"#))
-        .expect("Failed to send message");
-
-    let embedding = embeddings_receiver.recv().expect("Failed to get embedding");
-    chunk_sender
-        .send(format!(r#"{{"send":[1,{:?}]}}"#, embedding))
-        .expect("Failed to send message");
-    let closer_embedding = embeddings_receiver.recv().expect("Failed to get embedding");
-
-    chunk_sender
-        .send(r#""reset""#.to_string())
-        .expect("Failed to send message");
-    let mut all_stored_embeddings = Vec::with_capacity(3);
-
-    (0..4).for_each(|_| {
-        let embedding = embeddings_receiver
-            .recv()
-            .expect("Failed to received new message");
-        info!("Received new embedding");
-        all_stored_embeddings.push(embedding);
-    });
+    let chunks = [
+        "Hello world !",
+        "Knowledge graphs are great !",
+        "LLMs are amazing, as well !",
+        "Integrated circuits complexity is bound by physical constraints. Namely, the number of transistor that can be integrated in current chips.",
+    ];
+    for (id, chunk) in chunks.iter().enumerate() {
+        client
+            .insert(ChunkId::from(id as u32), chunk.to_string())
+            .await
+            .expect("Failed to store chunk");
+    }
+
+    let embedding = client
+        .embed(chunks[3].to_string())
+        .await
+        .expect("Failed to get embedding");
+    let closest = client
+        .search_embedding(embedding.clone(), 1)
+        .await
+        .expect("Failed to search embeddings");
+    assert_eq!(closest[0].id, ChunkId::from(3));
+
+    let all_stored_embeddings = client.reset().await.expect("Failed to reset embeddings");
+    info!("Removed {} embeddings", all_stored_embeddings.len());
 
     assert_eq!(
         all_stored_embeddings[all_stored_embeddings.len() - 1],
-        closer_embedding
+        embedding
     );
+
+    client
+        .stop()
+        .await
+        .expect("Failed to stop embeddings service");
 }
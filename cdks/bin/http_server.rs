@@ -1,10 +1,7 @@
-use std::{
-    env,
-    sync::{mpsc, Arc},
-};
+use std::{env, sync::Arc};
 
 use dotenv::dotenv;
-use embeddings::{embeddings::DEFAULT_MODEL_EMBEDDING_SIZE, service::EmbeddingsService};
+use embeddings::{client, config::EmbeddingsConfig, service::EmbeddingsService};
 use http_server::{client::OpenAiClient, config::Config, service::run_service};
 use neo4j::{neo4j::Neo4jConnection, neo4j_service::Neo4jService, ConfigBuilder};
 use tokio::sync::RwLock;
@@ -15,12 +12,10 @@ async fn main() -> Result<(), anyhow::Error> {
     dotenv().ok();
 
     let (tx_neo4j, rx_neo4j) = tokio::sync::mpsc::channel(100);
-    let (tx_neo4j_relations, rx_neo4j_relations) = tokio::sync::mpsc::channel(100);
 
-    let (embeddings_sender, embeddings_receiver) =
-        mpsc::channel::<[f32; DEFAULT_MODEL_EMBEDDING_SIZE]>();
-    let (embeddings_text_sender, embeddings_text_receiver) = mpsc::channel::<String>();
-    let (embeddings_index_sender, embeddings_index_receiver) = mpsc::channel::<u32>();
+    let embeddings_config = EmbeddingsConfig::from_env()?;
+    let (embeddings_client, embeddings_request_receiver) =
+        client::channel(embeddings_config.service.queue_capacity);
 
     // Start Neo4j service
     let config = ConfigBuilder::new()
@@ -30,35 +25,18 @@ async fn main() -> Result<(), anyhow::Error> {
         .build()
         .expect("Failed to generate Neo4j Config");
     let connection = Neo4jConnection::new(config).await.unwrap();
-    let _neo4j_join_handle = Neo4jService::spawn(
-        rx_neo4j,
-        tx_neo4j_relations,
-        Arc::new(RwLock::new(connection)),
-    )
-    .await;
+    let _neo4j_join_handle = Neo4jService::spawn(rx_neo4j, Arc::new(RwLock::new(connection))).await;
 
     // Start Embeddings service
-    let _embeddings_join_handle = EmbeddingsService::spawn(
-        embeddings_text_receiver,
-        embeddings_sender,
-        embeddings_index_sender,
-    );
+    let _embeddings_join_handle =
+        EmbeddingsService::spawn_with_config(embeddings_config, embeddings_request_receiver);
 
     let endpoint = env::var("OPENAI_API_ENDPOINT").expect("Failed to load OPENAI_API_ENDPOINT");
 
     let client = OpenAiClient::new(endpoint);
     let config = Config::default();
 
-    run_service(
-        tx_neo4j,
-        rx_neo4j_relations,
-        client,
-        embeddings_receiver,
-        embeddings_text_sender,
-        embeddings_index_receiver,
-        config,
-    )
-    .await?;
+    run_service(tx_neo4j, client, embeddings_client, config).await?;
 
     Ok(())
 }
@@ -1,13 +1,14 @@
-use neo4j::{neo4j::Neo4jConnection, neo4j_service::Neo4jService};
-use serde_json::Value;
+use neo4j::{
+    neo4j::Neo4jConnection,
+    neo4j_service::{Neo4jRequest, Neo4jService},
+};
 
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
 #[tokio::main]
 async fn main() {
-    let (tx, rx) = tokio::sync::mpsc::channel::<Value>(100);
-    let (tx_relations, _) = tokio::sync::mpsc::channel::<Value>(100);
+    let (tx, rx) = tokio::sync::mpsc::channel::<Neo4jRequest>(100);
     let config = neo4rs::ConfigBuilder::new()
         .uri("neo4j")
         .user("neo4j")
@@ -15,8 +16,7 @@ async fn main() {
         .build()
         .expect("Failed to generate Neo4j Config");
     let connection = Neo4jConnection::new(config).await.unwrap();
-    let _join_handle =
-        Neo4jService::spawn(rx, tx_relations, Arc::new(RwLock::new(connection))).await;
+    let _join_handle = Neo4jService::spawn(rx, Arc::new(RwLock::new(connection))).await;
 
     for _ in 0..10 {
         let tx_clone = tx.clone();
@@ -26,7 +26,7 @@ async fn main() {
             )
             .unwrap();
             tx_clone
-                .send(query_value)
+                .send(Neo4jRequest::new(query_value))
                 .await
                 .expect("Failed to send value");
         });
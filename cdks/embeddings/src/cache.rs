@@ -0,0 +1,645 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{File, OpenOptions},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use anyhow::Result;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    dedup::{normalize, ContentHash},
+    embedder::Embedder,
+    error::EmbeddingsError,
+    store::storage_error,
+    vectors::{read_exact_at, write_all_at},
+};
+
+const CACHE_MAGIC: &[u8; 4] = b"CDKC";
+const CACHE_VERSION: u32 = 1;
+
+/// Where, and how many, embeddings are cached.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// Most embeddings held in memory. `0` disables the cache.
+    pub capacity: usize,
+    /// File of the on-disk layer, which outlives restarts. If not set, embeddings are only
+    /// cached in memory.
+    pub path: Option<PathBuf>,
+    /// Most embeddings kept in the file.
+    pub disk_capacity: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 1_024,
+            path: None,
+            disk_capacity: 100_000,
+        }
+    }
+}
+
+/// Counters of an [`EmbeddingCache`], since it was created.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CacheStats {
+    /// Lookups answered from memory.
+    pub hits: u64,
+    /// Lookups answered from the on-disk layer.
+    pub disk_hits: u64,
+    pub misses: u64,
+    /// Embeddings dropped to make room, from memory or from the disk.
+    pub evictions: u64,
+    /// Times the cached embeddings were dropped, because they came from another model.
+    pub invalidations: u64,
+    /// Embeddings currently held in memory, and on disk.
+    pub entries: usize,
+    pub disk_entries: usize,
+}
+
+/// Least recently used entries, by key.
+struct Lru<V> {
+    entries: HashMap<ContentHash, (V, u64)>,
+    // Keys by last use.
+    order: BTreeMap<u64, ContentHash>,
+    tick: u64,
+}
+
+impl<V> Default for Lru<V> {
+    fn default() -> Self {
+        Self {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+}
+
+impl<V> Lru<V> {
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn contains(&self, key: &ContentHash) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    /// Value of `key`, which becomes the most recently used.
+    fn get(&mut self, key: &ContentHash) -> Option<&V> {
+        self.tick += 1;
+        let (value, last_use) = self.entries.get_mut(key)?;
+        self.order.remove(last_use);
+        self.order.insert(self.tick, *key);
+        *last_use = self.tick;
+        Some(value)
+    }
+
+    fn insert(&mut self, key: ContentHash, value: V) {
+        self.tick += 1;
+        if let Some((_, last_use)) = self.entries.insert(key, (value, self.tick)) {
+            self.order.remove(&last_use);
+        }
+        self.order.insert(self.tick, key);
+    }
+
+    fn remove(&mut self, key: &ContentHash) -> Option<V> {
+        let (value, last_use) = self.entries.remove(key)?;
+        self.order.remove(&last_use);
+        Some(value)
+    }
+
+    fn pop_least_recent(&mut self) -> Option<(ContentHash, V)> {
+        let (_, key) = self.order.pop_first()?;
+        let (value, _) = self.entries.remove(&key)?;
+        Some((key, value))
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+}
+
+/// Cached embeddings of a single model in a file, as fixed size records: key, vector, and
+/// checksum. Records evicted make room for the next ones in place.
+///
+/// The file starts with the model id and dimension, and is emptied when opened for another
+/// model. It is never synced: records torn by a crash fail their checksum, and are dropped.
+struct DiskLayer {
+    path: PathBuf,
+    file: File,
+    header_len: u64,
+    dimension: usize,
+    capacity: usize,
+    // Record slot of every key.
+    slots: Lru<usize>,
+    free: Vec<usize>,
+    // Slots in the file, taken or free.
+    len: usize,
+}
+
+impl DiskLayer {
+    /// Opens the layer at `path` for `model_id`, returning whether it held the embeddings of
+    /// another model, which are dropped.
+    fn open(
+        path: &Path,
+        model_id: &str,
+        dimension: usize,
+        capacity: usize,
+    ) -> Result<(Self, bool), EmbeddingsError> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir).map_err(|e| storage_error(dir, e))?;
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(|e| storage_error(path, e))?;
+        let mut header = CACHE_MAGIC.to_vec();
+        header.extend_from_slice(&CACHE_VERSION.to_le_bytes());
+        header.extend_from_slice(&(dimension as u32).to_le_bytes());
+        header.extend_from_slice(&(model_id.len() as u32).to_le_bytes());
+        header.extend_from_slice(model_id.as_bytes());
+
+        let mut layer = Self {
+            path: path.to_path_buf(),
+            file,
+            header_len: header.len() as u64,
+            dimension,
+            capacity,
+            slots: Lru::default(),
+            free: vec![],
+            len: 0,
+        };
+        let file_len = layer
+            .file
+            .metadata()
+            .map_err(|e| storage_error(path, e))?
+            .len();
+        let mut stored_header = vec![0; header.len()];
+        let same_model = file_len >= layer.header_len
+            && read_exact_at(&layer.file, &mut stored_header, 0).is_ok()
+            && stored_header == header;
+        if !same_model {
+            layer
+                .file
+                .set_len(0)
+                .and_then(|_| write_all_at(&layer.file, &header, 0))
+                .map_err(|e| storage_error(path, e))?;
+            return Ok((layer, file_len > 0));
+        }
+
+        let records = ((file_len - layer.header_len) / layer.record_len() as u64) as usize;
+        layer.len = records.min(capacity);
+        for slot in 0..layer.len {
+            match layer.read(slot)? {
+                Some((key, _)) => layer.slots.insert(key, slot),
+                None => layer.free.push(slot),
+            }
+        }
+        layer
+            .file
+            .set_len(layer.offset(layer.len))
+            .map_err(|e| storage_error(path, e))?;
+        Ok((layer, false))
+    }
+
+    fn record_len(&self) -> usize {
+        32 + 4 * self.dimension + 4
+    }
+
+    fn offset(&self, slot: usize) -> u64 {
+        self.header_len + (slot * self.record_len()) as u64
+    }
+
+    /// Key and vector of the record at `slot`, unless it fails its checksum.
+    fn read(&self, slot: usize) -> Result<Option<(ContentHash, Vec<f32>)>, EmbeddingsError> {
+        let mut bytes = vec![0; self.record_len()];
+        read_exact_at(&self.file, &mut bytes, self.offset(slot))
+            .map_err(|e| storage_error(&self.path, e))?;
+        let (payload, checksum) = bytes.split_at(bytes.len() - 4);
+        if crc32fast::hash(payload).to_le_bytes() != checksum {
+            return Ok(None);
+        }
+        let mut key = [0; 32];
+        key.copy_from_slice(&payload[..32]);
+        let vector = payload[32..]
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        Ok(Some((ContentHash(key), vector)))
+    }
+
+    fn get(&mut self, key: &ContentHash) -> Result<Option<Vec<f32>>, EmbeddingsError> {
+        let Some(&slot) = self.slots.get(key) else {
+            return Ok(None);
+        };
+        match self.read(slot)? {
+            Some((stored, vector)) if stored == *key => Ok(Some(vector)),
+            _ => {
+                self.slots.remove(key);
+                self.free.push(slot);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Writes `vector` under `key`, returning whether another record was evicted for it.
+    fn insert(&mut self, key: ContentHash, vector: &[f32]) -> Result<bool, EmbeddingsError> {
+        if self.slots.contains(&key) || vector.len() != self.dimension {
+            return Ok(false);
+        }
+        let (slot, evicted) = match self.free.pop() {
+            Some(slot) => (slot, false),
+            None if self.len < self.capacity => {
+                self.len += 1;
+                (self.len - 1, false)
+            }
+            None => match self.slots.pop_least_recent() {
+                Some((_, slot)) => (slot, true),
+                None => return Ok(false),
+            },
+        };
+        let mut bytes = Vec::with_capacity(self.record_len());
+        bytes.extend_from_slice(&key.0);
+        vector
+            .iter()
+            .for_each(|x| bytes.extend_from_slice(&x.to_le_bytes()));
+        bytes.extend_from_slice(&crc32fast::hash(&bytes).to_le_bytes());
+        write_all_at(&self.file, &bytes, self.offset(slot))
+            .map_err(|e| storage_error(&self.path, e))?;
+        self.slots.insert(key, slot);
+        Ok(evicted)
+    }
+}
+
+struct CacheState {
+    // Model the cached embeddings were encoded by, and its dimension.
+    model: Option<(String, usize)>,
+    memory: Lru<Vec<f32>>,
+    disk: Option<DiskLayer>,
+    stats: CacheStats,
+}
+
+/// Embeddings of recently encoded texts, keyed by the model id and the BLAKE3 hash of the
+/// [`normalize`]d text, in memory and optionally on disk, both least recently used first out.
+///
+/// The cache holds the embeddings of a single model: binding it to another one with
+/// [`Self::use_model`] drops them. It is shared by the workers of a pool.
+pub struct EmbeddingCache {
+    config: CacheConfig,
+    state: Mutex<CacheState>,
+}
+
+impl EmbeddingCache {
+    pub fn new(config: CacheConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(CacheState {
+                model: None,
+                memory: Lru::default(),
+                disk: None,
+                stats: CacheStats::default(),
+            }),
+        }
+    }
+
+    pub fn config(&self) -> &CacheConfig {
+        &self.config
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.capacity > 0
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, CacheState> {
+        // The state is consistent after every statement, so it outlives a panicking holder.
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Binds the cache to the embeddings of `model_id`, opening the on-disk layer. Cached
+    /// embeddings of any other model are dropped.
+    pub fn use_model(&self, model_id: &str, dimension: usize) -> Result<(), EmbeddingsError> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        let mut state = self.state();
+        if state
+            .model
+            .as_ref()
+            .is_some_and(|(id, d)| id == model_id && *d == dimension)
+        {
+            return Ok(());
+        }
+        let mut invalidated = state.model.is_some();
+        state.memory.clear();
+        state.disk = None;
+        if let Some(path) = &self.config.path {
+            let (disk, stale) =
+                DiskLayer::open(path, model_id, dimension, self.config.disk_capacity)?;
+            invalidated |= stale;
+            state.disk = Some(disk);
+        }
+        if invalidated {
+            info!("Dropped the cached embeddings of other models than {model_id}");
+            state.stats.invalidations += 1;
+        }
+        state.model = Some((model_id.to_string(), dimension));
+        Ok(())
+    }
+
+    /// Cached embedding of `text` by `model_id`, if any. Models are identified by their
+    /// [`ModelTag`](crate::embedder::ModelTag), as `id@version`.
+    pub fn get(&self, model_id: &str, text: &str) -> Option<Vec<f32>> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let key = cache_key(model_id, text);
+        let mut state = self.state();
+        let state = &mut *state;
+        if state.model.as_ref().is_some_and(|(id, _)| id == model_id) {
+            if let Some(vector) = state.memory.get(&key) {
+                state.stats.hits += 1;
+                return Some(vector.clone());
+            }
+            let found = state.disk.as_mut().and_then(|disk| {
+                disk.get(&key).unwrap_or_else(|e| {
+                    warn!("Failed to read the embeddings cache: {e}");
+                    None
+                })
+            });
+            if let Some(vector) = found {
+                state.stats.disk_hits += 1;
+                self.insert_in_memory(state, key, vector.clone());
+                return Some(vector);
+            }
+        }
+        state.stats.misses += 1;
+        None
+    }
+
+    /// Caches the embedding `vector` of `text` by `model_id`, unless the cache is bound to
+    /// another model.
+    pub fn insert(&self, model_id: &str, text: &str, vector: &[f32]) {
+        if !self.is_enabled() {
+            return;
+        }
+        let key = cache_key(model_id, text);
+        let mut state = self.state();
+        let state = &mut *state;
+        if state.model.as_ref().is_none_or(|(id, _)| id != model_id) {
+            return;
+        }
+        self.insert_in_memory(state, key, vector.to_vec());
+        if let Some(disk) = state.disk.as_mut() {
+            match disk.insert(key, vector) {
+                Ok(evicted) => state.stats.evictions += evicted as u64,
+                Err(e) => warn!("Failed to write the embeddings cache: {e}"),
+            }
+        }
+    }
+
+    fn insert_in_memory(&self, state: &mut CacheState, key: ContentHash, vector: Vec<f32>) {
+        if !state.memory.contains(&key) && state.memory.len() >= self.config.capacity {
+            state.memory.pop_least_recent();
+            state.stats.evictions += 1;
+        }
+        state.memory.insert(key, vector);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let state = self.state();
+        CacheStats {
+            entries: state.memory.len(),
+            disk_entries: state.disk.as_ref().map_or(0, |disk| disk.slots.len()),
+            ..state.stats
+        }
+    }
+}
+
+fn cache_key(model_id: &str, text: &str) -> ContentHash {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&(model_id.len() as u64).to_le_bytes());
+    hasher.update(model_id.as_bytes());
+    hasher.update(normalize(text).as_bytes());
+    ContentHash(*hasher.finalize().as_bytes())
+}
+
+/// Model looking its embeddings up in an [`EmbeddingCache`] before encoding them, and caching
+/// those it encodes.
+pub struct CachedEmbedder<E: Embedder> {
+    model: E,
+    cache: Arc<EmbeddingCache>,
+    // Model id and version the embeddings are cached under.
+    cache_id: String,
+}
+
+impl<E: Embedder> CachedEmbedder<E> {
+    /// Wraps `model`, binding `cache` to it.
+    pub fn new(model: E, cache: Arc<EmbeddingCache>) -> Result<Self, EmbeddingsError> {
+        let cache_id = model.model_tag().to_string();
+        cache.use_model(&cache_id, model.dimension())?;
+        Ok(Self {
+            model,
+            cache,
+            cache_id,
+        })
+    }
+
+    pub fn model(&self) -> &E {
+        &self.model
+    }
+
+    pub fn into_inner(self) -> E {
+        self.model
+    }
+}
+
+impl<E: Embedder> Embedder for CachedEmbedder<E> {
+    fn encode(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+        let model_id = &self.cache_id;
+        let mut embeddings: Vec<_> = texts
+            .iter()
+            .map(|text| self.cache.get(model_id, text))
+            .collect();
+        let missing: Vec<&str> = texts
+            .iter()
+            .zip(&embeddings)
+            .filter(|(_, embedding)| embedding.is_none())
+            .map(|(text, _)| *text)
+            .collect();
+        if missing.is_empty() {
+            return Ok(embeddings.into_iter().flatten().collect());
+        }
+        let mut encoded = self.model.encode(&missing)?.into_iter();
+        for (text, embedding) in texts.iter().zip(embeddings.iter_mut()) {
+            if embedding.is_none() {
+                let Some(vector) = encoded.next() else {
+                    break;
+                };
+                self.cache.insert(model_id, text, &vector);
+                *embedding = Some(vector);
+            }
+        }
+        Ok(embeddings.into_iter().flatten().collect())
+    }
+
+    fn dimension(&self) -> usize {
+        self.model.dimension()
+    }
+
+    fn model_id(&self) -> &str {
+        self.model.model_id()
+    }
+
+    fn model_version(&self) -> &str {
+        self.model.model_version()
+    }
+
+    fn count_tokens(&self, text: &str) -> usize {
+        self.model.count_tokens(text)
+    }
+
+    fn cache(&self) -> Option<&EmbeddingCache> {
+        Some(&self.cache)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::embedder::HashingEmbedder;
+
+    /// Counts the texts actually encoded.
+    struct CountingEmbedder {
+        inner: HashingEmbedder,
+        encoded: Cell<usize>,
+    }
+
+    impl CountingEmbedder {
+        fn new(dimension: usize) -> Self {
+            Self {
+                inner: HashingEmbedder::new(dimension).unwrap(),
+                encoded: Cell::new(0),
+            }
+        }
+    }
+
+    impl Embedder for CountingEmbedder {
+        fn encode(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+            self.encoded.set(self.encoded.get() + texts.len());
+            self.inner.encode(texts)
+        }
+
+        fn dimension(&self) -> usize {
+            self.inner.dimension()
+        }
+
+        fn model_id(&self) -> &str {
+            self.inner.model_id()
+        }
+    }
+
+    #[test]
+    fn test_cache_hits_and_evictions() {
+        let cache = Arc::new(EmbeddingCache::new(CacheConfig {
+            capacity: 2,
+            ..Default::default()
+        }));
+        let model = CachedEmbedder::new(CountingEmbedder::new(16), cache.clone()).unwrap();
+        let expected = HashingEmbedder::new(16)
+            .unwrap()
+            .encode(&["a b", "c d"])
+            .unwrap();
+
+        assert_eq!(model.encode(&["a b", "c d"]).unwrap(), expected);
+        // Whitespace aside, the same text is not encoded again.
+        assert_eq!(model.encode(&[" a  b ", "c d"]).unwrap(), expected);
+        assert_eq!(model.model().encoded.get(), 2);
+
+        model.encode(&["e f"]).unwrap();
+        assert_eq!(
+            model.encode(&["c d", "a b"]).unwrap(),
+            [expected[1].clone(), expected[0].clone()]
+        );
+        // "a b" was the least recently used when "e f" came in.
+        assert_eq!(model.model().encoded.get(), 4);
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 3,
+                disk_hits: 0,
+                misses: 4,
+                evictions: 2,
+                invalidations: 0,
+                entries: 2,
+                disk_entries: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_disk_layer_and_invalidation() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = CacheConfig {
+            capacity: 1,
+            path: Some(dir.path().join("cache").join("embeddings.bin")),
+            disk_capacity: 2,
+        };
+        {
+            let cache = Arc::new(EmbeddingCache::new(config.clone()));
+            let model = CachedEmbedder::new(CountingEmbedder::new(16), cache.clone()).unwrap();
+            model.encode(&["a", "b", "c"]).unwrap();
+            assert_eq!(cache.stats().entries, 1);
+            assert_eq!(cache.stats().disk_entries, 2);
+        }
+
+        // The disk layer outlives the cache, up to its capacity.
+        let cache = Arc::new(EmbeddingCache::new(config.clone()));
+        let model = CachedEmbedder::new(CountingEmbedder::new(16), cache.clone()).unwrap();
+        assert_eq!(
+            model.encode(&["b", "c"]).unwrap(),
+            HashingEmbedder::new(16)
+                .unwrap()
+                .encode(&["b", "c"])
+                .unwrap()
+        );
+        assert_eq!(model.model().encoded.get(), 0);
+        assert_eq!(cache.stats().disk_hits, 2);
+
+        // Another model drops the cached embeddings, from memory and from disk.
+        let other = CachedEmbedder::new(CountingEmbedder::new(8), cache.clone()).unwrap();
+        other.encode(&["b"]).unwrap();
+        assert_eq!(other.model().encoded.get(), 1);
+        let stats = cache.stats();
+        assert_eq!(
+            (stats.invalidations, stats.entries, stats.disk_entries),
+            (1, 1, 1)
+        );
+
+        let reopened = EmbeddingCache::new(config);
+        reopened.use_model("hashing-bow-16", 16).unwrap();
+        assert_eq!(reopened.stats().invalidations, 1);
+        assert!(reopened.get("hashing-bow-16", "b").is_none());
+    }
+
+    #[test]
+    fn test_disabled_cache() {
+        let cache = Arc::new(EmbeddingCache::new(CacheConfig {
+            capacity: 0,
+            ..Default::default()
+        }));
+        let model = CachedEmbedder::new(CountingEmbedder::new(16), cache.clone()).unwrap();
+        model.encode(&["a"]).unwrap();
+        model.encode(&["a"]).unwrap();
+        assert_eq!(model.model().encoded.get(), 2);
+        assert_eq!(cache.stats(), CacheStats::default());
+    }
+}
@@ -0,0 +1,504 @@
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+
+use crate::embedder::Embedder;
+
+/// Token budgets of the chunks a document is split into.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ChunkerConfig {
+    /// Maximum number of embedding model tokens of a chunk, past which the model truncates it.
+    pub max_tokens: usize,
+    /// Maximum number of LLM tokens of a chunk, so that its extraction prompt fits the context.
+    pub max_llm_tokens: usize,
+    /// Number of embedding model tokens repeated from the end of a chunk at the start of the
+    /// next one, rounded down to whole paragraphs or sentences.
+    pub overlap_tokens: usize,
+}
+
+impl Default for ChunkerConfig {
+    fn default() -> Self {
+        Self {
+            max_tokens: 256,
+            max_llm_tokens: 2_048,
+            overlap_tokens: 32,
+        }
+    }
+}
+
+/// A section of a document, starting at a Markdown heading, or at the document start.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Section {
+    /// Position of the section among the sections of the document.
+    pub index: usize,
+    /// Text of the heading of the section, if any.
+    pub title: Option<String>,
+    /// Byte offset of the section start in the document.
+    pub start: usize,
+    /// Byte offset of the section end in the document, exclusive.
+    pub end: usize,
+}
+
+/// A chunk of a document, along with its position in it.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct DocumentChunk {
+    /// Position of the chunk among the chunks of the document.
+    pub index: usize,
+    /// Section the chunk belongs to. Chunks do not cross sections.
+    pub section: usize,
+    pub text: String,
+    /// Byte offset of the chunk start in the document.
+    pub start: usize,
+    /// Byte offset of the chunk end in the document, exclusive.
+    pub end: usize,
+    /// Number of embedding model tokens of the chunk.
+    pub tokens: usize,
+}
+
+/// Sections and chunks of a document, in document order.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct DocumentSplit {
+    pub sections: Vec<Section>,
+    pub chunks: Vec<DocumentChunk>,
+}
+
+/// Counts the LLM tokens of a text, e.g. with the tokenizer of the LLM extracting knowledge
+/// graphs.
+pub type LlmTokenCounter = dyn Fn(&str) -> usize + Send + Sync;
+
+/// Approximate number of LLM tokens of `text`, as a fallback for LLMs whose tokenizer is not
+/// at hand: BPE tokenizers average about four characters of English text per token.
+pub fn approximate_llm_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+/// Splits `document` into sections, and chunks within the token budgets of `model` and of the
+/// LLM, the latter as counted by `llm_tokens`.
+pub fn split_document<E, L>(
+    model: &E,
+    document: &str,
+    config: ChunkerConfig,
+    llm_tokens: L,
+) -> DocumentSplit
+where
+    E: Embedder,
+    L: Fn(&str) -> usize,
+{
+    Chunker::new(config, |text: &str| model.count_tokens(text), llm_tokens).split(document)
+}
+
+/// Splits documents into chunks fitting the token budgets of both the embedding model and
+/// the LLM, as measured by `tokens` and `llm_tokens`.
+///
+/// Documents are split into sections at their Markdown headings, then at paragraph
+/// boundaries, sentence boundaries, and only then between words, and the resulting pieces of
+/// every section are packed into chunks as large as the budgets allow. Chunks are slices of
+/// the document, so that their offsets point back into it.
+pub struct Chunker<T, L> {
+    config: ChunkerConfig,
+    tokens: T,
+    llm_tokens: L,
+}
+
+// A piece of the document that no chunk boundary falls into, and its token counts.
+struct Piece {
+    range: Range<usize>,
+    tokens: usize,
+    llm_tokens: usize,
+}
+
+impl<T, L> Chunker<T, L>
+where
+    T: Fn(&str) -> usize,
+    L: Fn(&str) -> usize,
+{
+    pub fn new(config: ChunkerConfig, tokens: T, llm_tokens: L) -> Self {
+        Self {
+            config,
+            tokens,
+            llm_tokens,
+        }
+    }
+
+    pub fn split(&self, document: &str) -> DocumentSplit {
+        let sections = split_sections(document);
+        let mut chunks = vec![];
+        for section in &sections {
+            let pieces = self.pieces(document, section.start..section.end);
+            self.pack(document, section.index, &pieces, &mut chunks);
+        }
+        DocumentSplit { sections, chunks }
+    }
+
+    /// Packs the `pieces` of a section into chunks, appended to `chunks`.
+    fn pack(
+        &self,
+        document: &str,
+        section: usize,
+        pieces: &[Piece],
+        chunks: &mut Vec<DocumentChunk>,
+    ) {
+        let mut first = 0;
+        while first < pieces.len() {
+            // A chunk holds at least one piece, even an oversized one.
+            let mut last = first + 1;
+            while last < pieces.len() && self.fits_all(&pieces[first..=last]) {
+                last += 1;
+            }
+
+            let range = pieces[first].range.start..pieces[last - 1].range.end;
+            chunks.push(DocumentChunk {
+                index: chunks.len(),
+                section,
+                text: document[range.clone()].to_string(),
+                start: range.start,
+                end: range.end,
+                tokens: (self.tokens)(&document[range]),
+            });
+            if last == pieces.len() {
+                break;
+            }
+
+            // The next chunk repeats the last pieces of this one within the overlap budget,
+            // but for the first, so that chunks move forward, and as long as the next piece
+            // still fits after them.
+            let mut next = last;
+            while next > first + 1 && tokens(&pieces[next - 1..last]) <= self.config.overlap_tokens
+            {
+                next -= 1;
+            }
+            while next < last && !self.fits_all(&pieces[next..=last]) {
+                next += 1;
+            }
+            first = next;
+        }
+    }
+
+    /// Splits `range` into paragraphs, the paragraphs over budget into sentences, and the
+    /// sentences still over budget into runs of words.
+    fn pieces(&self, document: &str, range: Range<usize>) -> Vec<Piece> {
+        let mut pieces = vec![];
+        for paragraph in split_paragraphs(document, range) {
+            let piece = self.piece(document, paragraph);
+            if self.fits(&piece) {
+                pieces.push(piece);
+                continue;
+            }
+            for sentence in split_sentences(document, piece.range) {
+                let piece = self.piece(document, sentence);
+                if self.fits(&piece) {
+                    pieces.push(piece);
+                } else {
+                    self.split_words(document, piece.range, &mut pieces);
+                }
+            }
+        }
+        pieces
+    }
+
+    /// Packs the words of `range` into pieces within budget. Words over budget on their own
+    /// are kept whole.
+    fn split_words(&self, document: &str, range: Range<usize>, pieces: &mut Vec<Piece>) {
+        let mut current: Option<Piece> = None;
+        for word in split_words(document, range) {
+            let extended = match &current {
+                Some(piece) => self.piece(document, piece.range.start..word.end),
+                None => self.piece(document, word.clone()),
+            };
+            if self.fits(&extended) || current.is_none() {
+                current = Some(extended);
+            } else {
+                pieces.extend(current.replace(self.piece(document, word)));
+            }
+        }
+        pieces.extend(current);
+    }
+
+    fn piece(&self, document: &str, range: Range<usize>) -> Piece {
+        let text = &document[range.clone()];
+        Piece {
+            tokens: (self.tokens)(text),
+            llm_tokens: (self.llm_tokens)(text),
+            range,
+        }
+    }
+
+    fn fits(&self, piece: &Piece) -> bool {
+        piece.tokens <= self.config.max_tokens && piece.llm_tokens <= self.config.max_llm_tokens
+    }
+
+    // Token counts of pieces add up: the whitespace between them is left to the tokenizers'
+    // rounding.
+    fn fits_all(&self, pieces: &[Piece]) -> bool {
+        let llm_tokens: usize = pieces.iter().map(|piece| piece.llm_tokens).sum();
+        tokens(pieces) <= self.config.max_tokens && llm_tokens <= self.config.max_llm_tokens
+    }
+}
+
+fn tokens(pieces: &[Piece]) -> usize {
+    pieces.iter().map(|piece| piece.tokens).sum()
+}
+
+/// Sections of `text`, each starting at a Markdown heading line, i.e. one to six `#` followed
+/// by a space. Lines of fenced code blocks are not headings. Sections holding only whitespace
+/// are left out.
+fn split_sections(text: &str) -> Vec<Section> {
+    let mut sections: Vec<Section> = vec![];
+    let mut push = |title: Option<String>, range: Range<usize>| {
+        if let Some(range) = trimmed(text, range) {
+            sections.push(Section {
+                index: sections.len(),
+                title,
+                start: range.start,
+                end: range.end,
+            });
+        }
+    };
+    let (mut title, mut start, mut offset) = (None, 0, 0);
+    let mut fence: Option<&str> = None;
+    for line in text.split_inclusive('\n') {
+        match (fence, code_fence(line)) {
+            (None, Some(opening)) => fence = Some(opening),
+            (Some(opening), Some(closing))
+                if closing.starts_with(opening) && line.trim() == closing =>
+            {
+                fence = None
+            }
+            (None, None) => {
+                if let Some(heading) = heading(line) {
+                    push(title.take(), start..offset);
+                    title = Some(heading.to_string());
+                    start = offset;
+                }
+            }
+            _ => {}
+        }
+        offset += line.len();
+    }
+    push(title, start..text.len());
+    sections
+}
+
+/// `line` without its leading spaces, unless they make it indented code.
+fn block_start(line: &str) -> Option<&str> {
+    let text = line.trim_start_matches(' ');
+    (line.len() - text.len() <= 3 && !text.starts_with('\t')).then_some(text)
+}
+
+/// Marker of `line` if it opens or closes a fenced code block, i.e. three or more backticks
+/// or tildes.
+fn code_fence(line: &str) -> Option<&str> {
+    let text = block_start(line)?;
+    let marker = text.chars().next().filter(|c| matches!(c, '`' | '~'))?;
+    let fence = &text[..text.len() - text.trim_start_matches(marker).len()];
+    (fence.len() >= 3).then_some(fence)
+}
+
+/// Text of `line` if it is a Markdown heading, without its closing `#` sequence, if any.
+fn heading(line: &str) -> Option<&str> {
+    let line = block_start(line)?.trim_end();
+    let level = line.len() - line.trim_start_matches('#').len();
+    let text = line[level..].strip_prefix(' ')?.trim();
+    let unclosed = text.trim_end_matches('#');
+    let text = match unclosed.is_empty() || unclosed.ends_with(' ') {
+        true => unclosed.trim_end(),
+        false => text,
+    };
+    (1..=6).contains(&level).then_some(text)
+}
+
+/// Byte ranges of the paragraphs of `range`, separated by blank lines, trimmed.
+fn split_paragraphs(text: &str, range: Range<usize>) -> Vec<Range<usize>> {
+    let mut paragraphs = vec![];
+    let mut start = range.start;
+    let mut offset = range.start;
+    for line in text[range.clone()].split_inclusive('\n') {
+        if line.trim().is_empty() {
+            paragraphs.extend(trimmed(text, start..offset));
+            start = offset + line.len();
+        }
+        offset += line.len();
+    }
+    paragraphs.extend(trimmed(text, start..range.end));
+    paragraphs
+}
+
+/// Byte ranges of the sentences of `range`, ending with `.`, `!` or `?` followed by
+/// whitespace, trimmed.
+fn split_sentences(text: &str, range: Range<usize>) -> Vec<Range<usize>> {
+    let mut sentences = vec![];
+    let mut start = range.start;
+    let mut chars = text[range.clone()].char_indices().peekable();
+    while let Some((offset, c)) = chars.next() {
+        let ends_sentence = matches!(c, '.' | '!' | '?')
+            && chars.peek().is_some_and(|(_, next)| next.is_whitespace());
+        if ends_sentence {
+            let end = range.start + offset + c.len_utf8();
+            sentences.extend(trimmed(text, start..end));
+            start = end;
+        }
+    }
+    sentences.extend(trimmed(text, start..range.end));
+    sentences
+}
+
+/// Byte ranges of the whitespace separated words of `range`.
+fn split_words(text: &str, range: Range<usize>) -> Vec<Range<usize>> {
+    let mut words = vec![];
+    let mut start = None;
+    for (offset, c) in text[range.clone()].char_indices() {
+        let offset = range.start + offset;
+        match (c.is_whitespace(), start) {
+            (true, Some(word_start)) => {
+                words.push(word_start..offset);
+                start = None;
+            }
+            (false, None) => start = Some(offset),
+            _ => {}
+        }
+    }
+    words.extend(start.map(|word_start| word_start..range.end));
+    words
+}
+
+/// `range` without its leading and trailing whitespace, if anything is left.
+fn trimmed(text: &str, range: Range<usize>) -> Option<Range<usize>> {
+    let slice = &text[range.clone()];
+    let start = range.start + (slice.len() - slice.trim_start().len());
+    let end = range.end - (slice.len() - slice.trim_end().len());
+    (start < end).then_some(start..end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type TokenCount = fn(&str) -> usize;
+
+    fn words(text: &str) -> usize {
+        text.split_whitespace().count()
+    }
+
+    fn chunker(max_tokens: usize, overlap_tokens: usize) -> Chunker<TokenCount, TokenCount> {
+        let config = ChunkerConfig {
+            max_tokens,
+            overlap_tokens,
+            ..Default::default()
+        };
+        Chunker::new(config, words, approximate_llm_tokens)
+    }
+
+    #[test]
+    fn test_split_paragraphs_and_sentences() {
+        let document = "First paragraph. It is short.\n\n  Second paragraph has one sentence.\n";
+        let chunks = chunker(8, 0).split(document).chunks;
+        let texts: Vec<_> = chunks.iter().map(|chunk| chunk.text.as_str()).collect();
+        assert_eq!(
+            texts,
+            vec![
+                "First paragraph. It is short.",
+                "Second paragraph has one sentence."
+            ]
+        );
+        for chunk in &chunks {
+            assert_eq!(&document[chunk.start..chunk.end], chunk.text);
+        }
+        assert_eq!(chunks[1].index, 1);
+        assert_eq!(chunks[1].tokens, 5);
+
+        // The first paragraph no longer fits, and is split into its sentences, and the second
+        // one into words.
+        let chunks = chunker(4, 0).split(document).chunks;
+        let texts: Vec<_> = chunks.iter().map(|chunk| chunk.text.as_str()).collect();
+        assert_eq!(
+            texts,
+            vec![
+                "First paragraph.",
+                "It is short.",
+                "Second paragraph has one",
+                "sentence."
+            ]
+        );
+        assert!(chunks.iter().all(|chunk| chunk.tokens <= 4));
+    }
+
+    #[test]
+    fn test_overlap_and_budgets() {
+        let document = "One two. Three four. Five six. Seven eight. Nine ten.";
+        let chunks = chunker(6, 2).split(document).chunks;
+        let texts: Vec<_> = chunks.iter().map(|chunk| chunk.text.as_str()).collect();
+        assert_eq!(
+            texts,
+            vec![
+                "One two. Three four. Five six.",
+                "Five six. Seven eight. Nine ten."
+            ]
+        );
+
+        // An overlap as large as the budget cannot stall the chunker.
+        let chunks = chunker(4, 4).split(document).chunks;
+        assert!(chunks.len() >= 3);
+        assert_eq!(chunks.last().unwrap().end, document.len());
+
+        // The LLM budget applies as well.
+        let config = ChunkerConfig {
+            max_tokens: 100,
+            max_llm_tokens: 4,
+            overlap_tokens: 0,
+        };
+        let chunks = Chunker::new(config, approximate_llm_tokens, words)
+            .split(document)
+            .chunks;
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks.iter().all(|chunk| words(&chunk.text) <= 4));
+        assert_eq!(chunker(4, 0).split(" \n\n "), DocumentSplit::default());
+    }
+
+    #[test]
+    fn test_split_sections() {
+        let document =
+            "Preamble.\n\n# Title\n\nIntro text.\n\n## Details ##\nOne. Two. Three.\n#hashtag\n";
+        let split = chunker(2, 0).split(document);
+        let titles: Vec<_> = split
+            .sections
+            .iter()
+            .map(|section| section.title.as_deref())
+            .collect();
+        assert_eq!(titles, vec![None, Some("Title"), Some("Details")]);
+        assert_eq!(
+            &document[split.sections[1].start..split.sections[1].end],
+            "# Title\n\nIntro text."
+        );
+
+        // Chunks stay within their section.
+        let chunks: Vec<_> = split
+            .chunks
+            .iter()
+            .map(|chunk| (chunk.section, chunk.text.as_str()))
+            .collect();
+        assert_eq!(
+            chunks,
+            vec![
+                (0, "Preamble."),
+                (1, "# Title"),
+                (1, "Intro text."),
+                (2, "## Details"),
+                (2, "##\nOne."),
+                (2, "Two. Three."),
+                (2, "#hashtag"),
+            ]
+        );
+
+        // Neither code, fenced or indented, nor `#` within a title end sections.
+        let document = "# C#\n\n```sh\n# Install\n```\n\n    # Not a heading\n\n#  Usage  #\n~~~\n# Run\n~~~~\n";
+        let titles: Vec<_> = split_sections(document)
+            .into_iter()
+            .map(|section| section.title)
+            .collect();
+        assert_eq!(
+            titles,
+            vec![Some("C#".to_string()), Some("Usage".to_string())]
+        );
+    }
+}
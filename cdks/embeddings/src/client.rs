@@ -0,0 +1,457 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::sync::{
+    mpsc::{self, Receiver, Sender},
+    oneshot,
+};
+
+use crate::{
+    cache::CacheStats,
+    chunker::{ChunkerConfig, DocumentSplit},
+    dedup::{ContentHash, DedupConfig, DuplicateMatch},
+    embeddings::Embedding,
+    error::EmbeddingsError,
+    id::ChunkId,
+    index::{IngestionClaim, MemoryFootprint, RecallReport, StoredChunk, StoredEmbedding},
+    metadata::{ChunkMetadata, Ingestion},
+    migration::{MigrationProgress, ModelSpec},
+    search::{DocumentResults, SearchParams, SearchResult},
+    service::{Message, Reply, Request, ServiceStats},
+};
+
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Creates a client and the matching request receiver, to be handed to an `EmbeddingsService`.
+/// At most `capacity` requests wait for a worker: further callers are held back until the
+/// service catches up.
+pub fn channel(capacity: usize) -> (EmbeddingsClient, Receiver<Request>) {
+    let (request_sender, request_receiver) = mpsc::channel(capacity.max(1));
+    (EmbeddingsClient::new(request_sender), request_receiver)
+}
+
+/// Shared flag telling the service to skip a request that nobody waits for anymore.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Reply to a single request. Dropping it before the reply arrives cancels the request.
+pub struct PendingReply {
+    reply_receiver: oneshot::Receiver<Reply>,
+    cancellation: CancellationToken,
+}
+
+impl PendingReply {
+    pub(crate) fn new(
+        reply_receiver: oneshot::Receiver<Reply>,
+        cancellation: CancellationToken,
+    ) -> Self {
+        Self {
+            reply_receiver,
+            cancellation,
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancellation.cancel();
+    }
+
+    pub fn cancellation(&self) -> &CancellationToken {
+        &self.cancellation
+    }
+
+    /// Waits until the service replies.
+    pub async fn wait(mut self) -> Result<Reply, EmbeddingsError> {
+        if self.cancellation.is_cancelled() {
+            return Err(EmbeddingsError::Cancelled);
+        }
+        (&mut self.reply_receiver)
+            .await
+            .map_err(|_| EmbeddingsError::NoReply)
+    }
+
+    /// Waits until the service replies, cancelling the request if `timeout` elapses first.
+    pub async fn wait_timeout(self, timeout: Duration) -> Result<Reply, EmbeddingsError> {
+        tokio::time::timeout(timeout, self.wait())
+            .await
+            .map_err(|_| EmbeddingsError::Timeout(timeout))?
+    }
+}
+
+impl Drop for PendingReply {
+    fn drop(&mut self) {
+        self.cancellation.cancel();
+    }
+}
+
+/// Cloneable handle to an `EmbeddingsService`. Every request gets its own reply channel, so
+/// concurrent callers never receive each other's results.
+#[derive(Clone, Debug)]
+pub struct EmbeddingsClient {
+    request_sender: Sender<Request>,
+    timeout: Duration,
+}
+
+impl EmbeddingsClient {
+    pub fn new(request_sender: Sender<Request>) -> Self {
+        Self {
+            request_sender,
+            timeout: DEFAULT_REQUEST_TIMEOUT,
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Queues `message` without waiting for its reply, waiting for room in the queue if the
+    /// service is busy.
+    pub async fn send(&self, message: Message) -> Result<PendingReply, EmbeddingsError> {
+        let (request, pending_reply) = Request::new(message);
+        self.request_sender
+            .send(request)
+            .await
+            .map_err(|_| EmbeddingsError::ServiceStopped)?;
+        Ok(pending_reply)
+    }
+
+    /// Embeds `chunk` and stores it under `id`.
+    pub async fn insert(&self, id: ChunkId, chunk: String) -> Result<(), EmbeddingsError> {
+        match self.call(Message::ChunkText((id, chunk))).await? {
+            Reply::Stored => Ok(()),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    /// Embeds `chunk` and stores it under `id`, along with its `metadata`.
+    pub async fn insert_with_metadata(
+        &self,
+        id: ChunkId,
+        chunk: String,
+        metadata: ChunkMetadata,
+    ) -> Result<(), EmbeddingsError> {
+        match self
+            .call(Message::ChunkTextWithMetadata((id, chunk, metadata)))
+            .await?
+        {
+            Reply::Stored => Ok(()),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    /// Embeds `chunk` and stores it under `id`, unless it duplicates a stored chunk, as set by
+    /// `dedup`. Returns the stored chunk it duplicates, if any.
+    pub async fn ingest(
+        &self,
+        id: ChunkId,
+        chunk: String,
+        metadata: ChunkMetadata,
+        dedup: DedupConfig,
+    ) -> Result<Option<DuplicateMatch>, EmbeddingsError> {
+        match self
+            .call(Message::Ingest((id, chunk, metadata, dedup)))
+            .await?
+        {
+            Reply::Ingested(duplicate) => Ok(duplicate),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    /// Embeds `chunks` in a single model call and stores them in bulk, returning one result
+    /// per chunk.
+    pub async fn insert_batch(
+        &self,
+        chunks: Vec<(ChunkId, String)>,
+    ) -> Result<Vec<Result<(), EmbeddingsError>>, EmbeddingsError> {
+        match self.call(Message::ChunkTextBatch(chunks)).await? {
+            Reply::BatchStored(results) => Ok(results),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    /// Embeds `chunk` and stores it under `id`, replacing the embedding already stored there.
+    /// Returns whether an embedding was replaced.
+    pub async fn upsert(&self, id: ChunkId, chunk: String) -> Result<bool, EmbeddingsError> {
+        match self.call(Message::Upsert((id, chunk))).await? {
+            Reply::Upserted(replaced) => Ok(replaced),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    /// Deletes the embeddings of `ids`, returning one result per id: `NotFound` for ids that
+    /// were not stored.
+    pub async fn delete(
+        &self,
+        ids: Vec<ChunkId>,
+    ) -> Result<Vec<Result<(), EmbeddingsError>>, EmbeddingsError> {
+        match self.call(Message::Delete(ids)).await? {
+            Reply::Deleted(results) => Ok(results),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    pub async fn get(&self, id: ChunkId) -> Result<StoredEmbedding, EmbeddingsError> {
+        match self.call(Message::Get(id)).await? {
+            Reply::StoredEmbedding(stored) => Ok(stored),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    /// Fetches the text of the chunk stored under `id`: `NotFound` if it was stored without it.
+    pub async fn chunk(&self, id: ChunkId) -> Result<StoredChunk, EmbeddingsError> {
+        match self.call(Message::GetChunk(id)).await? {
+            Reply::Chunk(chunk) => Ok(chunk),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    /// Fetches the chunk whose ingestion digest is `hash`, or else the first one stored with
+    /// the content hash `hash`: `HashNotFound` if there is none.
+    pub async fn chunk_by_hash(&self, hash: ContentHash) -> Result<StoredChunk, EmbeddingsError> {
+        match self.call(Message::GetChunkByHash(hash)).await? {
+            Reply::Chunk(chunk) => Ok(chunk),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    /// Claims the knowledge graph extraction of `chunk`, in a single request: returns the
+    /// chunk with the same content if it was ingested already, claims it again if its
+    /// extraction failed or was pending for over `pending_timeout` seconds, and otherwise
+    /// stores `chunk` under `id` as pending, deduplicated by `dedup` if set.
+    pub async fn claim_ingestion(
+        &self,
+        id: ChunkId,
+        chunk: String,
+        metadata: ChunkMetadata,
+        dedup: Option<DedupConfig>,
+        pending_timeout: u64,
+    ) -> Result<IngestionClaim, EmbeddingsError> {
+        match self
+            .call(Message::ClaimIngestion((
+                id,
+                chunk,
+                metadata,
+                dedup,
+                pending_timeout,
+            )))
+            .await?
+        {
+            Reply::IngestionClaimed(claim) => Ok(claim),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    /// Sets the knowledge graph extraction of the chunk stored under `id`.
+    pub async fn set_ingestion(
+        &self,
+        id: ChunkId,
+        ingestion: Ingestion,
+    ) -> Result<(), EmbeddingsError> {
+        match self.call(Message::SetIngestion((id, ingestion))).await? {
+            Reply::Stored => Ok(()),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    /// Fetches the chunks stored under `ids`, in order, skipping those stored without text.
+    pub async fn chunks(&self, ids: Vec<ChunkId>) -> Result<Vec<StoredChunk>, EmbeddingsError> {
+        match self.call(Message::GetChunks(ids)).await? {
+            Reply::Chunks(chunks) => Ok(chunks),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    /// Splits `document` into sections, and chunks within the token budgets of `config`.
+    pub async fn split_document(
+        &self,
+        document: String,
+        config: ChunkerConfig,
+    ) -> Result<DocumentSplit, EmbeddingsError> {
+        match self
+            .call(Message::SplitDocument((document, config)))
+            .await?
+        {
+            Reply::DocumentSplit(split) => Ok(split),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    /// Fetches the chunks of `document_id`, in document order.
+    pub async fn document(&self, document_id: String) -> Result<Vec<StoredChunk>, EmbeddingsError> {
+        match self.call(Message::GetDocument(document_id)).await? {
+            Reply::Chunks(chunks) => Ok(chunks),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    /// Deletes every chunk of `document_id`, returning their ids.
+    pub async fn delete_document(
+        &self,
+        document_id: String,
+    ) -> Result<Vec<ChunkId>, EmbeddingsError> {
+        match self.call(Message::DeleteDocument(document_id)).await? {
+            Reply::DocumentDeleted(ids) => Ok(ids),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    /// Searches the chunks closest to `chunk`, grouped by document.
+    pub async fn search_documents(
+        &self,
+        chunk: String,
+        params: SearchParams,
+    ) -> Result<Vec<DocumentResults>, EmbeddingsError> {
+        match self.call(Message::SearchDocuments((chunk, params))).await? {
+            Reply::DocumentResults(results) => Ok(results),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    pub async fn embed(&self, chunk: String) -> Result<Embedding, EmbeddingsError> {
+        match self.call(Message::ProcessChunk(chunk)).await? {
+            Reply::Embedding(embedding) => Ok(embedding),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    pub async fn search(
+        &self,
+        chunk: String,
+        params: SearchParams,
+    ) -> Result<Vec<SearchResult>, EmbeddingsError> {
+        match self.call(Message::Search((chunk, params))).await? {
+            Reply::SearchResults(results) => Ok(results),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    pub async fn search_embedding(
+        &self,
+        embedding: Embedding,
+        num_queries: u32,
+    ) -> Result<Vec<SearchResult>, EmbeddingsError> {
+        match self
+            .call(Message::Send((num_queries, embedding.into_inner())))
+            .await?
+        {
+            Reply::SearchResults(results) => Ok(results),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    pub async fn reset(&self) -> Result<Vec<Embedding>, EmbeddingsError> {
+        match self.call(Message::Reset).await? {
+            Reply::Embeddings(embeddings) => Ok(embeddings),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    /// Bytes held in memory by the index, per structure.
+    pub async fn memory_footprint(&self) -> Result<MemoryFootprint, EmbeddingsError> {
+        match self.call(Message::MemoryFootprint).await? {
+            Reply::MemoryFootprint(footprint) => Ok(footprint),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    /// Hits and misses of the embeddings cache, all zero when there is none.
+    pub async fn cache_stats(&self) -> Result<CacheStats, EmbeddingsError> {
+        match self.call(Message::CacheStats).await? {
+            Reply::CacheStats(stats) => Ok(stats),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    /// Starts encoding every stored chunk again with the model of `spec`, in the background:
+    /// the current embeddings keep being served until the migrated ones replace them.
+    pub async fn start_migration(
+        &self,
+        spec: ModelSpec,
+    ) -> Result<MigrationProgress, EmbeddingsError> {
+        match self.call(Message::StartMigration(spec)).await? {
+            Reply::Migration(Some(progress)) => Ok(progress),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    /// Progress of the last migration, if any was started.
+    pub async fn migration_progress(&self) -> Result<Option<MigrationProgress>, EmbeddingsError> {
+        match self.call(Message::MigrationProgress).await? {
+            Reply::Migration(progress) => Ok(progress),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    /// Stops the running migration, if any, leaving the stored embeddings as they were.
+    pub async fn cancel_migration(&self) -> Result<Option<MigrationProgress>, EmbeddingsError> {
+        match self.call(Message::CancelMigration).await? {
+            Reply::Migration(progress) => Ok(progress),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    /// Measures the share of the exact `k` nearest neighbours that searches find, as
+    /// configured, for up to `queries` stored vectors.
+    pub async fn measure_recall(
+        &self,
+        queries: u32,
+        k: u32,
+    ) -> Result<RecallReport, EmbeddingsError> {
+        match self.call(Message::MeasureRecall((queries, k))).await? {
+            Reply::Recall(report) => Ok(report),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    pub async fn stats(&self) -> Result<ServiceStats, EmbeddingsError> {
+        match self.call(Message::Stats).await? {
+            Reply::Stats(stats) => Ok(stats),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    /// Stops the service. Requests already queued are answered first, further ones are refused.
+    pub async fn stop(&self) -> Result<(), EmbeddingsError> {
+        match self.call(Message::Stop).await? {
+            Reply::Stopped => Ok(()),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    /// Queues `message` and waits for its reply, both within the client timeout. An error
+    /// reply is returned as such.
+    async fn call(&self, message: Message) -> Result<Reply, EmbeddingsError> {
+        let reply = tokio::time::timeout(self.timeout, async {
+            self.send(message).await?.wait().await
+        })
+        .await
+        .map_err(|_| EmbeddingsError::Timeout(self.timeout))??;
+        match reply {
+            Reply::Error(e) => Err(e),
+            reply => Ok(reply),
+        }
+    }
+}
+
+fn unexpected(reply: Reply) -> EmbeddingsError {
+    EmbeddingsError::UnexpectedReply(format!("{reply:?}"))
+}
@@ -0,0 +1,216 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{cache::CacheConfig, hnsw::HnswParams, quantization::QuantizationConfig};
+
+/// Environment variable pointing to a JSON embeddings config file.
+pub const EMBEDDINGS_CONFIG_ENV: &str = "EMBEDDINGS_CONFIG";
+/// Environment variable pointing to a local sentence-transformers model directory.
+pub const EMBEDDINGS_MODEL_DIR_ENV: &str = "EMBEDDINGS_MODEL_DIR";
+/// Environment variable holding the expected embedding dimension.
+pub const EMBEDDINGS_DIMENSION_ENV: &str = "EMBEDDINGS_DIMENSION";
+/// Environment variable pointing to the directory where embeddings are persisted.
+pub const EMBEDDINGS_STORE_DIR_ENV: &str = "EMBEDDINGS_STORE_DIR";
+/// Environment variable pointing to the file of the on-disk embeddings cache.
+pub const EMBEDDINGS_CACHE_FILE_ENV: &str = "EMBEDDINGS_CACHE_FILE";
+/// Environment variable holding the number of model workers.
+pub const EMBEDDINGS_WORKERS_ENV: &str = "EMBEDDINGS_WORKERS";
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct EmbeddingsConfig {
+    /// Local model directory. If not set, the default model is fetched from the remote hub.
+    pub model_dir: Option<PathBuf>,
+    /// Expected embedding dimension, checked against the loaded model.
+    pub embedding_dimension: Option<usize>,
+    /// Nearest neighbour search settings.
+    #[serde(default)]
+    pub index: IndexConfig,
+    /// On-disk persistence of the stored embeddings. If not set, they are kept in memory only.
+    #[serde(default)]
+    pub store: Option<StoreConfig>,
+    /// Worker pool and request queue settings.
+    #[serde(default)]
+    pub service: ServiceConfig,
+    /// Cache of the embeddings of recently encoded texts.
+    #[serde(default)]
+    pub cache: CacheConfig,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct StoreConfig {
+    /// Directory holding the snapshot and write-ahead log files.
+    pub path: PathBuf,
+    /// Number of log entries after which a new snapshot is written.
+    #[serde(default = "default_snapshot_interval")]
+    pub snapshot_interval: usize,
+}
+
+impl StoreConfig {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self {
+            path: path.into(),
+            snapshot_interval: default_snapshot_interval(),
+        }
+    }
+}
+
+fn default_snapshot_interval() -> usize {
+    1_000
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexKind {
+    /// Cosine-scan every stored vector.
+    Exact,
+    /// Approximate search over an HNSW graph.
+    #[default]
+    Hnsw,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct IndexConfig {
+    pub kind: IndexKind,
+    /// Stores with fewer vectors than this are always searched exactly.
+    pub exact_search_threshold: usize,
+    pub hnsw: HnswParams,
+    /// Compression of the stored vectors, off by default.
+    pub quantization: QuantizationConfig,
+    /// Shards the exact scans split the store into, each scanned on its own core. `0` stands
+    /// for one per core. Shards never hold fewer than 1024 vectors.
+    pub search_shards: usize,
+}
+
+impl Default for IndexConfig {
+    fn default() -> Self {
+        Self {
+            kind: IndexKind::default(),
+            exact_search_threshold: 10_000,
+            hnsw: HnswParams::default(),
+            quantization: QuantizationConfig::default(),
+            search_shards: 0,
+        }
+    }
+}
+
+/// Maximum number of chunks encoded in a single model call.
+pub const DEFAULT_BATCH_SIZE: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ServiceConfig {
+    /// Number of model workers encoding requests in parallel, over a shared index.
+    pub workers: usize,
+    /// Number of requests that can wait for a worker before callers are held back.
+    pub queue_capacity: usize,
+    /// Maximum number of queued chunks to be encoded and stored together.
+    pub batch_size: usize,
+    /// How long to wait for further chunks to fill a batch, in milliseconds.
+    pub batch_window_ms: u64,
+}
+
+impl Default for ServiceConfig {
+    fn default() -> Self {
+        Self {
+            workers: 1,
+            queue_capacity: 256,
+            batch_size: DEFAULT_BATCH_SIZE,
+            batch_window_ms: 5,
+        }
+    }
+}
+
+impl EmbeddingsConfig {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path).map_err(|e| {
+            anyhow!(
+                "Failed to read embeddings config {}, with error: {e}",
+                path.display()
+            )
+        })?;
+        serde_json::from_str(&content).map_err(|e| {
+            anyhow!(
+                "Failed to parse embeddings config {}, with error: {e}",
+                path.display()
+            )
+        })
+    }
+
+    /// Loads the config file referenced by `EMBEDDINGS_CONFIG`, if any, and overrides
+    /// its values with the `EMBEDDINGS_MODEL_DIR`, `EMBEDDINGS_STORE_DIR`,
+    /// `EMBEDDINGS_DIMENSION` and `EMBEDDINGS_WORKERS` variables.
+    pub fn from_env() -> Result<Self> {
+        let mut config = match std::env::var(EMBEDDINGS_CONFIG_ENV) {
+            Ok(path) => Self::from_file(path)?,
+            Err(_) => Self::default(),
+        };
+        if let Ok(model_dir) = std::env::var(EMBEDDINGS_MODEL_DIR_ENV) {
+            config.model_dir = Some(PathBuf::from(model_dir));
+        }
+        if let Ok(store_dir) = std::env::var(EMBEDDINGS_STORE_DIR_ENV) {
+            let snapshot_interval = config
+                .store
+                .as_ref()
+                .map(|s| s.snapshot_interval)
+                .unwrap_or_else(default_snapshot_interval);
+            config.store = Some(StoreConfig {
+                path: PathBuf::from(store_dir),
+                snapshot_interval,
+            });
+        }
+        if let Ok(cache_file) = std::env::var(EMBEDDINGS_CACHE_FILE_ENV) {
+            config.cache.path = Some(PathBuf::from(cache_file));
+        }
+        if let Ok(dimension) = std::env::var(EMBEDDINGS_DIMENSION_ENV) {
+            config.embedding_dimension = Some(dimension.parse().map_err(|e| {
+                anyhow!("Invalid {EMBEDDINGS_DIMENSION_ENV} value {dimension}, with error: {e}")
+            })?);
+        }
+        if let Ok(workers) = std::env::var(EMBEDDINGS_WORKERS_ENV) {
+            config.service.workers = workers.parse().map_err(|e| {
+                anyhow!("Invalid {EMBEDDINGS_WORKERS_ENV} value {workers}, with error: {e}")
+            })?;
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_from_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("embeddings.json");
+        std::fs::write(
+            &path,
+            r#"{"model_dir":"/models/all-MiniLM-L12-v2","embedding_dimension":384,"index":{"kind":"exact"},"service":{"workers":4}}"#,
+        )
+        .unwrap();
+
+        let config = EmbeddingsConfig::from_file(&path).unwrap();
+        assert_eq!(
+            config,
+            EmbeddingsConfig {
+                model_dir: Some(PathBuf::from("/models/all-MiniLM-L12-v2")),
+                embedding_dimension: Some(384),
+                index: IndexConfig {
+                    kind: IndexKind::Exact,
+                    ..Default::default()
+                },
+                store: None,
+                service: ServiceConfig {
+                    workers: 4,
+                    ..Default::default()
+                },
+                cache: Default::default(),
+            }
+        );
+        assert!(EmbeddingsConfig::from_file(dir.path().join("missing.json")).is_err());
+    }
+}
@@ -0,0 +1,159 @@
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{error::EmbeddingsError, id::ChunkId};
+
+/// BLAKE3 digest of a chunk text, computed over its [`normalize`]d form, so that chunks
+/// differing only by their whitespace share it. Serialized as a hexadecimal string.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct ContentHash(pub [u8; 32]);
+
+impl ContentHash {
+    pub fn of(text: &str) -> Self {
+        Self(*blake3::hash(normalize(text).as_bytes()).as_bytes())
+    }
+
+    /// Digest of a chunk along with the knowledge graph extracted from it, both [`normalize`]d.
+    /// Each part is prefixed by its length, so that no two pairs share their bytes.
+    pub fn of_extraction(text: &str, knowledge_graph: Option<&str>) -> Self {
+        let mut hasher = blake3::Hasher::new();
+        for part in [Some(text), knowledge_graph] {
+            let part = part.map(normalize);
+            let bytes = part.as_deref().map(str::as_bytes);
+            // A missing graph differs from an empty one.
+            let length = bytes.map_or(u64::MAX, |bytes| bytes.len() as u64);
+            hasher.update(&length.to_le_bytes());
+            hasher.update(bytes.unwrap_or_default());
+        }
+        Self(*hasher.finalize().as_bytes())
+    }
+}
+
+impl fmt::Display for ContentHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{byte:02x}"))
+    }
+}
+
+impl FromStr for ContentHash {
+    type Err = EmbeddingsError;
+
+    fn from_str(hash: &str) -> Result<Self, Self::Err> {
+        let invalid = || EmbeddingsError::InvalidHash(hash.to_string());
+        if hash.len() != 64 || !hash.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Err(invalid());
+        }
+        let mut bytes = [0u8; 32];
+        for (byte, digits) in bytes.iter_mut().zip(hash.as_bytes().chunks(2)) {
+            let digits = std::str::from_utf8(digits).map_err(|_| invalid())?;
+            *byte = u8::from_str_radix(digits, 16).map_err(|_| invalid())?;
+        }
+        Ok(Self(bytes))
+    }
+}
+
+impl From<ContentHash> for String {
+    fn from(hash: ContentHash) -> Self {
+        hash.to_string()
+    }
+}
+
+impl TryFrom<String> for ContentHash {
+    type Error = EmbeddingsError;
+
+    fn try_from(hash: String) -> Result<Self, Self::Error> {
+        hash.parse()
+    }
+}
+
+/// Trims `text` and collapses its whitespace runs into single spaces.
+pub fn normalize(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// What to do with a chunk duplicating a stored one.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicatePolicy {
+    /// The chunk is not stored.
+    #[default]
+    Skip,
+    /// The chunk is stored, with its metadata `duplicate_of` the chunk it matched.
+    Link,
+}
+
+/// Near-duplicate detection of ingested chunks.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct DedupConfig {
+    /// Cosine similarity from which a chunk is taken for a copy of a stored one. Chunks with
+    /// the same content hash are duplicates regardless.
+    pub similarity_threshold: f32,
+    pub policy: DuplicatePolicy,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        Self {
+            similarity_threshold: 0.95,
+            policy: DuplicatePolicy::default(),
+        }
+    }
+}
+
+/// Stored chunk that an ingested chunk duplicates.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct DuplicateMatch {
+    pub id: ChunkId,
+    /// Cosine similarity of the two chunks, 1.0 for exact copies.
+    pub score: f32,
+    /// Whether both chunks have the same content hash.
+    pub exact: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_content_hash_ignores_whitespace() {
+        let hash = ContentHash::of("Knowledge graphs\n are  great !");
+        assert_eq!(hash, ContentHash::of(" Knowledge graphs are great ! "));
+        assert_ne!(hash, ContentHash::of("Knowledge graphs are great"));
+        assert_eq!(
+            ContentHash::of("").to_string(),
+            "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"
+        );
+
+        let json = serde_json::to_string(&hash).unwrap();
+        assert_eq!(json, format!("\"{hash}\""));
+        assert_eq!(serde_json::from_str::<ContentHash>(&json).unwrap(), hash);
+        assert!("af13".parse::<ContentHash>().is_err());
+        assert!(format!("{}zz", &hash.to_string()[2..])
+            .parse::<ContentHash>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_extraction_hash() {
+        let graph = "(alice)-[KNOWS]->(bob)";
+        let hash = ContentHash::of_extraction("Alice knows  Bob.", Some(graph));
+        assert_eq!(
+            hash,
+            ContentHash::of_extraction(" Alice knows Bob.", Some("(alice)-[KNOWS]->(bob) "))
+        );
+        assert_ne!(hash, ContentHash::of_extraction("Alice knows Bob.", None));
+        assert_ne!(hash, ContentHash::of("Alice knows Bob."));
+        assert_ne!(
+            ContentHash::of_extraction("Alice knows Bob.", Some("")),
+            ContentHash::of_extraction("Alice knows Bob.", None)
+        );
+        // Moving text from the chunk to its graph changes the hash.
+        assert_ne!(
+            ContentHash::of_extraction("ab", Some("c")),
+            ContentHash::of_extraction("a", Some("bc"))
+        );
+    }
+}
@@ -0,0 +1,203 @@
+use std::fmt;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{cache::EmbeddingCache, embeddings::DEFAULT_MODEL_EMBEDDING_SIZE};
+
+/// Model, and version of its weights, that produced stored embeddings: embeddings of
+/// different tags cannot be compared.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct ModelTag {
+    pub id: String,
+    pub version: String,
+}
+
+impl ModelTag {
+    pub fn new(id: impl Into<String>, version: impl Into<String>) -> Self {
+        Self {
+            id: id.into(),
+            version: version.into(),
+        }
+    }
+}
+
+impl fmt::Display for ModelTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.version.as_str() {
+            "" => write!(f, "{}", self.id),
+            version => write!(f, "{}@{version}", self.id),
+        }
+    }
+}
+
+/// A text encoder producing fixed size vector embeddings.
+pub trait Embedder {
+    /// Encodes a batch of texts, returning one embedding per input text, in order.
+    fn encode(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>>;
+    /// Size of the embeddings produced by this encoder.
+    fn dimension(&self) -> usize;
+    /// Identifier of the underlying model.
+    fn model_id(&self) -> &str;
+    /// Version of the model weights, when the same id may load different ones. Empty if the
+    /// model is not versioned.
+    fn model_version(&self) -> &str {
+        ""
+    }
+    /// Model id and version, as tagged on the embeddings it produces.
+    fn model_tag(&self) -> ModelTag {
+        ModelTag::new(self.model_id(), self.model_version())
+    }
+    /// Number of tokens the model splits `text` into, which bounds the text it encodes whole.
+    /// Defaults to the number of words and punctuation marks of `text`.
+    fn count_tokens(&self, text: &str) -> usize {
+        let words = text
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|t| !t.is_empty())
+            .count();
+        let marks = text
+            .chars()
+            .filter(|c| !c.is_alphanumeric() && !c.is_whitespace())
+            .count();
+        words + marks
+    }
+    /// Cache the embeddings are looked up in before being encoded, if any.
+    fn cache(&self) -> Option<&EmbeddingCache> {
+        None
+    }
+}
+
+impl<E: Embedder + ?Sized> Embedder for Box<E> {
+    fn encode(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+        (**self).encode(texts)
+    }
+
+    fn dimension(&self) -> usize {
+        (**self).dimension()
+    }
+
+    fn model_id(&self) -> &str {
+        (**self).model_id()
+    }
+
+    fn model_version(&self) -> &str {
+        (**self).model_version()
+    }
+
+    fn count_tokens(&self, text: &str) -> usize {
+        (**self).count_tokens(text)
+    }
+
+    fn cache(&self) -> Option<&EmbeddingCache> {
+        (**self).cache()
+    }
+}
+
+/// Version of the hashing scheme of [`HashingEmbedder`], to be bumped whenever it changes.
+const HASHING_EMBEDDER_VERSION: &str = "1";
+
+/// Deterministic bag-of-words embedder, based on feature hashing.
+///
+/// Every lowercased alphanumeric token is hashed into one of `dimension` buckets,
+/// with a second hash bit deciding the sign of its contribution. The resulting
+/// vector is L2 normalized, so texts sharing vocabulary have high cosine similarity.
+/// It needs no model files, which makes it suitable for tests and offline environments.
+#[derive(Clone, Debug)]
+pub struct HashingEmbedder {
+    dimension: usize,
+    model_id: String,
+}
+
+impl HashingEmbedder {
+    pub fn new(dimension: usize) -> Result<Self> {
+        if dimension == 0 {
+            return Err(anyhow!("Hashing embedder dimension must be positive"));
+        }
+        Ok(Self {
+            dimension,
+            model_id: format!("hashing-bow-{dimension}"),
+        })
+    }
+
+    fn encode_one(&self, text: &str) -> Vec<f32> {
+        let mut embedding = vec![0f32; self.dimension];
+        for token in text
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|t| !t.is_empty())
+        {
+            let hash = fnv1a(token.to_lowercase().as_bytes());
+            let bucket = (hash % self.dimension as u64) as usize;
+            let sign = if (hash >> 63) == 0 { 1.0 } else { -1.0 };
+            embedding[bucket] += sign;
+        }
+
+        let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+        if norm > 0.0 {
+            embedding.iter_mut().for_each(|x| *x /= norm);
+        }
+        embedding
+    }
+}
+
+impl Default for HashingEmbedder {
+    fn default() -> Self {
+        Self::new(DEFAULT_MODEL_EMBEDDING_SIZE).expect("Default dimension is positive")
+    }
+}
+
+impl Embedder for HashingEmbedder {
+    fn encode(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+        Ok(texts.iter().map(|text| self.encode_one(text)).collect())
+    }
+
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn model_id(&self) -> &str {
+        &self.model_id
+    }
+
+    fn model_version(&self) -> &str {
+        HASHING_EMBEDDER_VERSION
+    }
+}
+
+/// 64-bit FNV-1a, used instead of `DefaultHasher` as its output is stable across Rust releases.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+    bytes.iter().fold(OFFSET_BASIS, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(PRIME)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hashing_embedder_is_deterministic() {
+        let embedder = HashingEmbedder::default();
+        let first = embedder.encode(&["Knowledge graphs are great !"]).unwrap();
+        let second = embedder.encode(&["knowledge GRAPHS are great"]).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(first[0].len(), DEFAULT_MODEL_EMBEDDING_SIZE);
+
+        let norm = first[0].iter().map(|x| x * x).sum::<f32>().sqrt();
+        assert!((norm - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_hashing_embedder_batch() {
+        let embedder = HashingEmbedder::new(16).unwrap();
+        let embeddings = embedder.encode(&["Hello world !", "", "LLMs"]).unwrap();
+        assert_eq!(embeddings.len(), 3);
+        assert!(embeddings.iter().all(|e| e.len() == 16));
+        assert!(embeddings[1].iter().all(|x| *x == 0.0));
+        assert_eq!(embedder.model_id(), "hashing-bow-16");
+        assert_eq!(embedder.model_tag().to_string(), "hashing-bow-16@1");
+        assert!(HashingEmbedder::new(0).is_err());
+        assert_eq!(embedder.count_tokens("Hello, world ! LLMs"), 5);
+    }
+}
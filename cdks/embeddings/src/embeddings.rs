@@ -1,124 +1,1122 @@
 use anyhow::{anyhow, Result};
 use log::info;
+#[cfg(feature = "rust-bert")]
 use rust_bert::pipelines::sentence_embeddings::{
     SentenceEmbeddingsBuilder, SentenceEmbeddingsModel, SentenceEmbeddingsModelType,
 };
+use serde::{Deserialize, Serialize};
+use std::{
+    path::Path,
+    sync::{Arc, PoisonError, RwLock},
+};
+
+use crate::{
+    cache::{CachedEmbedder, EmbeddingCache},
+    chunker::{split_document, ChunkerConfig, DocumentSplit},
+    config::{IndexConfig, StoreConfig, DEFAULT_BATCH_SIZE},
+    dedup::{DedupConfig, DuplicateMatch},
+    embedder::Embedder,
+    error::EmbeddingsError,
+    export::ExportedEmbeddings,
+    id::ChunkId,
+    index::{IndexEntry, MemoryFootprint, RecallReport, StoredChunk, StoredEmbedding, VectorIndex},
+    metadata::ChunkMetadata,
+    migration::Migration,
+    search::{SearchMode, SearchParams, SearchResult},
+};
+#[cfg(feature = "rust-bert")]
+use crate::{config::EmbeddingsConfig, local_model::LocalModelFiles, migration::ModelSpec};
 
 pub const DEFAULT_MODEL_EMBEDDING_SIZE: usize = 384;
 
-pub struct EmbeddingModel(SentenceEmbeddingsModel);
+/// Version of the remote models, whose weights are pinned by the rust-bert release.
+#[cfg(feature = "rust-bert")]
+const REMOTE_MODEL_VERSION: &str = "rust-bert-0.21";
 
+#[cfg(feature = "rust-bert")]
+pub struct EmbeddingModel {
+    model: SentenceEmbeddingsModel,
+    model_id: String,
+    model_version: String,
+    dimension: usize,
+}
+
+#[cfg(feature = "rust-bert")]
 impl EmbeddingModel {
     fn default_model() -> Result<Self> {
-        let model = SentenceEmbeddingsBuilder::remote(SentenceEmbeddingsModelType::AllMiniLmL12V2)
-            .create_model()?;
-        Ok(Self(model))
+        Self::new_with_model_type(SentenceEmbeddingsModelType::AllMiniLmL12V2)
     }
 
-    #[allow(dead_code)]
-    fn new_with_model_type(model_type: SentenceEmbeddingsModelType) -> Result<Self> {
+    pub fn new_with_model_type(model_type: SentenceEmbeddingsModelType) -> Result<Self> {
+        let model_id = model_type_id(&model_type).to_string();
         let model = SentenceEmbeddingsBuilder::remote(model_type).create_model()?;
-        Ok(Self(model))
+        let dimension = model.get_embedding_dim()? as usize;
+        Ok(Self {
+            model,
+            model_id,
+            model_version: REMOTE_MODEL_VERSION.to_string(),
+            dimension,
+        })
+    }
+
+    /// Loads a sentence-transformers model from a local directory, without network access.
+    pub fn from_local_dir<P: AsRef<std::path::Path>>(model_dir: P) -> Result<Self> {
+        Self::load_local(LocalModelFiles::validate(model_dir)?)
+    }
+
+    /// Loads the model described by `config`, falling back to the default remote model.
+    pub fn from_config(config: &EmbeddingsConfig) -> Result<Self> {
+        Self::load(
+            config.model_dir.as_deref(),
+            None,
+            config.embedding_dimension,
+        )
+    }
+
+    /// Loads the model described by `spec`, to migrate the stored embeddings to.
+    pub fn from_spec(spec: &ModelSpec) -> Result<Self> {
+        let model_type = match (&spec.model_dir, &spec.model_id) {
+            (None, Some(model_id)) => Some(
+                model_type_from_id(model_id).ok_or_else(|| anyhow!("Unknown model {model_id}"))?,
+            ),
+            (None, None) => return Err(anyhow!("Model spec names no model")),
+            _ => None,
+        };
+        Self::load(
+            spec.model_dir.as_deref(),
+            model_type,
+            spec.embedding_dimension,
+        )
+    }
+
+    /// Loads the model of `model_dir`, or else the remote model of `model_type`, falling back
+    /// to the default one.
+    fn load(
+        model_dir: Option<&std::path::Path>,
+        model_type: Option<SentenceEmbeddingsModelType>,
+        embedding_dimension: Option<usize>,
+    ) -> Result<Self> {
+        let model = match model_dir {
+            Some(model_dir) => {
+                let files = LocalModelFiles::validate(model_dir)?;
+                files.check_dimension(embedding_dimension)?;
+                Self::load_local(files)?
+            }
+            None => {
+                let model = match model_type {
+                    Some(model_type) => Self::new_with_model_type(model_type)?,
+                    None => Self::default_model()?,
+                };
+                if let Some(expected) = embedding_dimension {
+                    if expected != model.dimension {
+                        return Err(anyhow!(
+                            "Model {} has embedding dimension {}, but {expected} was expected",
+                            model.model_id,
+                            model.dimension
+                        ));
+                    }
+                }
+                model
+            }
+        };
+        info!(
+            "Loaded embeddings model {}, with embedding dimension {}",
+            model.model_id, model.dimension
+        );
+        Ok(model)
+    }
+
+    fn load_local(files: LocalModelFiles) -> Result<Self> {
+        info!(
+            "Loading {} model from {}, with embedding dimension {}",
+            files.model_type,
+            files.model_dir.display(),
+            files.dimension
+        );
+        let model = SentenceEmbeddingsBuilder::local(&files.model_dir).create_model()?;
+        let dimension = model.get_embedding_dim()? as usize;
+        if dimension != files.dimension {
+            return Err(anyhow!(
+                "Loaded model has embedding dimension {dimension}, but its config declares {}",
+                files.dimension
+            ));
+        }
+        Ok(Self {
+            model,
+            model_id: files.model_id(),
+            model_version: files.version()?,
+            dimension,
+        })
+    }
+}
+
+#[cfg(feature = "rust-bert")]
+impl Embedder for EmbeddingModel {
+    fn encode(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+        Ok(self.model.encode(texts)?)
+    }
+
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn model_id(&self) -> &str {
+        &self.model_id
+    }
+
+    fn model_version(&self) -> &str {
+        &self.model_version
+    }
+
+    fn count_tokens(&self, text: &str) -> usize {
+        self.model.get_tokenizer().tokenize(text).len()
+    }
+}
+
+#[cfg(feature = "rust-bert")]
+fn model_type_id(model_type: &SentenceEmbeddingsModelType) -> &'static str {
+    match model_type {
+        SentenceEmbeddingsModelType::DistiluseBaseMultilingualCased => {
+            "sentence-transformers/distiluse-base-multilingual-cased"
+        }
+        SentenceEmbeddingsModelType::BertBaseNliMeanTokens => {
+            "sentence-transformers/bert-base-nli-mean-tokens"
+        }
+        SentenceEmbeddingsModelType::AllMiniLmL12V2 => "sentence-transformers/all-MiniLM-L12-v2",
+        SentenceEmbeddingsModelType::AllMiniLmL6V2 => "sentence-transformers/all-MiniLM-L6-v2",
+        SentenceEmbeddingsModelType::AllDistilrobertaV1 => {
+            "sentence-transformers/all-distilroberta-v1"
+        }
+        SentenceEmbeddingsModelType::ParaphraseAlbertSmallV2 => {
+            "sentence-transformers/paraphrase-albert-small-v2"
+        }
+        SentenceEmbeddingsModelType::SentenceT5Base => "sentence-transformers/sentence-t5-base",
+    }
+}
+
+#[cfg(feature = "rust-bert")]
+fn model_type_from_id(model_id: &str) -> Option<SentenceEmbeddingsModelType> {
+    [
+        SentenceEmbeddingsModelType::DistiluseBaseMultilingualCased,
+        SentenceEmbeddingsModelType::BertBaseNliMeanTokens,
+        SentenceEmbeddingsModelType::AllMiniLmL12V2,
+        SentenceEmbeddingsModelType::AllMiniLmL6V2,
+        SentenceEmbeddingsModelType::AllDistilrobertaV1,
+        SentenceEmbeddingsModelType::ParaphraseAlbertSmallV2,
+        SentenceEmbeddingsModelType::SentenceT5Base,
+    ]
+    .into_iter()
+    .find(|model_type| model_type_id(model_type) == model_id)
+}
+
+/// A vector embedding, whose dimension has been checked against the model or store it belongs to.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Embedding(Vec<f32>);
+
+impl Embedding {
+    pub fn new(values: Vec<f32>, dimension: usize) -> Result<Self, EmbeddingsError> {
+        if values.len() != dimension {
+            return Err(EmbeddingsError::DimensionMismatch {
+                expected: dimension,
+                found: values.len(),
+            });
+        }
+        Ok(Self(values))
+    }
+
+    pub fn dimension(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn as_slice(&self) -> &[f32] {
+        &self.0
+    }
+
+    pub fn into_inner(self) -> Vec<f32> {
+        self.0
     }
 }
 
-pub struct Embeddings {
-    model: EmbeddingModel,
-    data: Vec<(u32, [f32; DEFAULT_MODEL_EMBEDDING_SIZE])>,
+pub struct Embeddings<E: Embedder> {
+    model: E,
+    index: VectorIndex,
 }
 
-impl Embeddings {
+#[cfg(feature = "rust-bert")]
+impl Embeddings<EmbeddingModel> {
     pub fn new() -> Result<Self> {
-        Ok(Self {
-            model: EmbeddingModel::default_model()?,
-            data: vec![],
-        })
+        Self::from_config(&EmbeddingsConfig::from_env()?)
     }
 
-    pub fn new_from_model(model: EmbeddingModel) -> Self {
+    pub fn from_config(config: &EmbeddingsConfig) -> Result<Self> {
+        let embeddings =
+            Self::new_with_index_config(EmbeddingModel::from_config(config)?, config.index);
+        match &config.store {
+            Some(store_config) => Ok(embeddings.with_store(store_config)?),
+            None => Ok(embeddings),
+        }
+    }
+
+    pub fn build_from_sentences(sentences: &[String]) -> Result<Self> {
+        Self::build_from_sentences_with_model(EmbeddingModel::default_model()?, sentences)
+    }
+}
+
+impl<E: Embedder> Embeddings<E> {
+    pub fn new_from_model(model: E) -> Self {
+        Self::new_with_index_config(model, IndexConfig::default())
+    }
+
+    pub fn new_with_index_config(model: E, index_config: IndexConfig) -> Self {
         Self {
+            index: VectorIndex::new(model.dimension(), index_config).with_model(model.model_tag()),
             model,
-            data: vec![],
         }
     }
 
-    pub fn build_from_sentences(sentences: &[String]) -> Result<Self> {
-        let model = EmbeddingModel::default_model()?;
-        let mut data = vec![];
+    /// Pairs `model` with an existing index, which must hold embeddings of the model dimension,
+    /// produced by the same model if it recorded one.
+    pub fn from_parts(model: E, mut index: VectorIndex) -> Result<Self, EmbeddingsError> {
+        if model.dimension() != index.dimension() {
+            return Err(EmbeddingsError::DimensionMismatch {
+                expected: index.dimension(),
+                found: model.dimension(),
+            });
+        }
+        index.bind_model(&model.model_tag())?;
+        Ok(Self { model, index })
+    }
+
+    pub fn into_parts(self) -> (E, VectorIndex) {
+        (self.model, self.index)
+    }
+
+    /// Persists the embeddings to the store described by `config`, first loading any
+    /// embeddings it already holds, which must have been produced by the same model.
+    pub fn with_store(mut self, config: &StoreConfig) -> Result<Self, EmbeddingsError> {
+        self.index = self.index.with_store(config)?;
+        self.index.bind_model(&self.model.model_tag())?;
+        Ok(self)
+    }
+
+    /// Looks the embeddings up in `cache` before encoding them, binding it to the model.
+    pub fn with_cache(
+        self,
+        cache: Arc<EmbeddingCache>,
+    ) -> Result<Embeddings<CachedEmbedder<E>>, EmbeddingsError> {
+        Ok(Embeddings {
+            model: CachedEmbedder::new(self.model, cache)?,
+            index: self.index,
+        })
+    }
+
+    /// Encodes every stored chunk again with `model`, replacing the stored embeddings and
+    /// tagging them with the new model, see [`Migration`]. On failure, the persisted store is
+    /// left as it was.
+    pub fn migrate<F: Embedder>(self, model: F) -> Result<Embeddings<F>, EmbeddingsError> {
+        let migration = Migration::new(self.index.model().cloned());
+        let index = RwLock::new(self.index);
+        migration.run(&index, &model)?;
+        Ok(Embeddings {
+            model,
+            index: index.into_inner().unwrap_or_else(PoisonError::into_inner),
+        })
+    }
+
+    /// Writes every stored chunk to `path`, with its id, metadata and text, in the format of
+    /// its extension, see [`ExportedEmbeddings`]. Returns the number of chunks written.
+    pub fn export<P: AsRef<Path>>(&self, path: P) -> Result<usize, EmbeddingsError> {
+        let exported = ExportedEmbeddings::from_index(&self.index)?;
+        exported.write(path.as_ref())?;
+        Ok(exported.chunks.len())
+    }
+
+    /// Stores the chunks exported to `path`, whose embeddings must have been produced by this
+    /// model. Returns the number of chunks stored.
+    pub fn import<P: AsRef<Path>>(&mut self, path: P) -> Result<usize, EmbeddingsError> {
+        ExportedEmbeddings::read(path.as_ref())?.load_into(&mut self.index)
+    }
 
-        for (id, sentence) in sentences.iter().enumerate() {
-            let embedding = model.0.encode(&[sentence])?;
-            let embedding: [f32; DEFAULT_MODEL_EMBEDDING_SIZE] = embedding[0]
-                .as_slice()
-                .try_into()
-                .map_err(|e| anyhow!("Incorrect length, error: {e}"))?;
-            data.push((id as u32, embedding));
+    pub fn build_from_sentences_with_model(model: E, sentences: &[String]) -> Result<Self> {
+        let mut embeddings = Self::new_from_model(model);
+
+        let chunks: Vec<_> = sentences
+            .iter()
+            .enumerate()
+            .map(|(id, sentence)| (ChunkId::from(id as u32), sentence.as_str()))
+            .collect();
+        for batch in chunks.chunks(DEFAULT_BATCH_SIZE) {
+            for result in embeddings.process_chunks_and_store(batch) {
+                result?;
+            }
         }
 
-        Ok(Self { model, data })
+        Ok(embeddings)
+    }
+
+    pub fn model(&self) -> &E {
+        &self.model
+    }
+
+    pub fn index(&self) -> &VectorIndex {
+        &self.index
     }
 
-    pub fn process_chunk_and_store(&mut self, id: u32, sentence: &str) -> Result<()> {
+    /// Dimension of the stored embeddings, as reported by the model.
+    pub fn dimension(&self) -> usize {
+        self.index.dimension()
+    }
+
+    pub fn index_config(&self) -> &IndexConfig {
+        self.index.index_config()
+    }
+
+    pub fn process_chunk_and_store(&mut self, id: ChunkId, sentence: &str) -> Result<()> {
+        self.process_chunk_and_store_with_metadata(id, sentence, ChunkMetadata::default())
+    }
+
+    pub fn process_chunk_and_store_with_metadata(
+        &mut self,
+        id: ChunkId,
+        sentence: &str,
+        metadata: ChunkMetadata,
+    ) -> Result<()> {
         info!("Received new sentence: {} to store and process", sentence);
-        let embedding = self.model.0.encode(&[sentence])?;
-        let embedding: [f32; DEFAULT_MODEL_EMBEDDING_SIZE] = embedding[0]
-            .as_slice()
-            .try_into()
-            .map_err(|e| anyhow!("Incorrect length, error: {e}"))?;
+        let embedding = encode_sentence(&self.model, sentence)?;
         info!("Current embedding is: {:?}", embedding);
-        self.data.push((id, embedding));
+        self.index
+            .insert_entries(vec![IndexEntry::new(id, embedding)
+                .with_metadata(metadata)
+                .with_text(sentence)])?;
         info!("New vector embedding stored!");
         Ok(())
     }
 
-    pub fn process_chunk(&self, sentence: &str) -> Result<[f32; DEFAULT_MODEL_EMBEDDING_SIZE]> {
+    /// Embeds `sentence` and stores it under `id`, unless it duplicates a stored chunk, as
+    /// set by `dedup`. Returns the stored chunk it duplicates, if any.
+    pub fn process_chunk_and_store_deduplicated(
+        &mut self,
+        id: ChunkId,
+        sentence: &str,
+        metadata: ChunkMetadata,
+        dedup: &DedupConfig,
+    ) -> Result<Option<DuplicateMatch>, EmbeddingsError> {
+        let embedding = encode_sentence(&self.model, sentence)?;
+        self.index.insert_deduplicated(
+            IndexEntry::new(id, embedding)
+                .with_metadata(metadata)
+                .with_text(sentence),
+            dedup,
+        )
+    }
+
+    /// Encodes `chunks` in a single model call and stores them in bulk, returning one result
+    /// per chunk.
+    pub fn process_chunks_and_store(
+        &mut self,
+        chunks: &[(ChunkId, &str)],
+    ) -> Vec<Result<(), EmbeddingsError>> {
+        info!("Received {} sentences to store and process", chunks.len());
+        let sentences: Vec<&str> = chunks.iter().map(|(_, sentence)| *sentence).collect();
+        let embeddings = encode_sentences(&self.model, &sentences);
+        self.index.insert_encoded(
+            chunks
+                .iter()
+                .zip(embeddings)
+                .map(|((id, sentence), embedding)| {
+                    embedding.map(|embedding| IndexEntry::new(*id, embedding).with_text(*sentence))
+                })
+                .collect(),
+        )
+    }
+
+    pub fn process_chunk(&self, sentence: &str) -> Result<Embedding> {
         info!("Received new sentence: {} to process", sentence);
-        let embedding = self.model.0.encode(&[sentence])?;
-        let embedding: [f32; DEFAULT_MODEL_EMBEDDING_SIZE] = embedding[0]
-            .as_slice()
-            .try_into()
-            .map_err(|e| anyhow!("Incorrect length, error: {e}"))?;
+        let embedding = encode_sentence(&self.model, sentence)?;
         info!("Current embedding is: {:?}", embedding);
         Ok(embedding)
     }
 
-    pub fn data(&self) -> &[(u32, [f32; DEFAULT_MODEL_EMBEDDING_SIZE])] {
-        &self.data
+    /// Ids of the stored embeddings, in no particular order.
+    pub fn ids(&self) -> &[ChunkId] {
+        self.index.ids()
+    }
+
+    /// Embeds `sentence` and stores it under `id`, replacing the embedding already stored
+    /// there, if any, but keeping its metadata. Returns whether an embedding was replaced.
+    pub fn upsert(&mut self, id: ChunkId, sentence: &str) -> Result<bool> {
+        let embedding = encode_sentence(&self.model, sentence)?;
+        Ok(self
+            .index
+            .upsert(IndexEntry::new(id, embedding).with_text(sentence))?)
     }
 
-    pub fn reset(&mut self) -> Vec<[f32; DEFAULT_MODEL_EMBEDDING_SIZE]> {
-        self.data.drain(..).map(|(_, d)| d).collect()
+    /// Deletes the embeddings of `ids`, returning one result per id.
+    pub fn delete(&mut self, ids: &[ChunkId]) -> Vec<Result<(), EmbeddingsError>> {
+        self.index.delete(ids)
     }
 
+    pub fn get(&self, id: ChunkId) -> Result<StoredEmbedding, EmbeddingsError> {
+        self.index.get(id)
+    }
+
+    /// See [`VectorIndex::memory_footprint`].
+    pub fn memory_footprint(&self) -> MemoryFootprint {
+        self.index.memory_footprint()
+    }
+
+    /// See [`VectorIndex::measure_recall`].
+    pub fn measure_recall(&self, queries: usize, k: u32) -> Result<RecallReport, EmbeddingsError> {
+        self.index.measure_recall(queries, k)
+    }
+
+    /// See [`VectorIndex::chunk`].
+    pub fn chunk(&self, id: ChunkId) -> Option<StoredChunk> {
+        self.index.chunk(id)
+    }
+
+    /// Splits `document` into sections, and chunks the model and the LLM can take whole, the
+    /// LLM tokens being counted by `llm_tokens`.
+    pub fn split_document<L>(
+        &self,
+        document: &str,
+        config: ChunkerConfig,
+        llm_tokens: L,
+    ) -> DocumentSplit
+    where
+        L: Fn(&str) -> usize,
+    {
+        split_document(&self.model, document, config, llm_tokens)
+    }
+
+    pub fn reset(&mut self) -> Result<Vec<Embedding>, EmbeddingsError> {
+        self.index.reset()
+    }
+
+    /// Finds the ids of the stored embeddings closest to `embedding`, by cosine similarity.
     pub fn find_closest_embeddings(
         &self,
-        embedding: [f32; DEFAULT_MODEL_EMBEDDING_SIZE],
+        embedding: &Embedding,
         num_queries: u32,
-    ) -> Vec<u32> {
-        // This is a very inefficient implementation. We will want to refactor this to use KDTrees. See
-        // https://sachaarbonel.medium.com/how-to-build-a-semantic-search-engine-in-rust-e96e6378cfd9 and https://en.wikipedia.org/wiki/K-d_tree
-        let mut cosine_similarities_arrs: Vec<(f32, (&u32, &[f32; DEFAULT_MODEL_EMBEDDING_SIZE]))> =
-            Vec::with_capacity(self.data.len());
-        for (id, stored_embedding) in self.data.iter() {
-            let cosine_similarity = cosine_similarity(stored_embedding, &embedding);
-            cosine_similarities_arrs.push((cosine_similarity, (id, stored_embedding)));
-        }
-        cosine_similarities_arrs.sort_by(|entry1, entry2| entry2.0.partial_cmp(&entry1.0).unwrap());
-        cosine_similarities_arrs[..(num_queries as usize)]
-            .iter()
-            .map(|(_, (id, _))| **id)
-            .collect()
+    ) -> Result<Vec<ChunkId>, EmbeddingsError> {
+        Ok(self
+            .search(embedding, &SearchParams::new(num_queries))?
+            .into_iter()
+            .map(|r| r.id)
+            .collect())
+    }
+
+    /// See [`VectorIndex::search`].
+    pub fn search(
+        &self,
+        embedding: &Embedding,
+        params: &SearchParams,
+    ) -> Result<Vec<SearchResult>, EmbeddingsError> {
+        self.index.search(embedding, params)
+    }
+
+    /// Searches the stored chunks for `query`, ranked as set by `params.mode`.
+    pub fn search_text(
+        &self,
+        query: &str,
+        params: &SearchParams,
+    ) -> Result<Vec<SearchResult>, EmbeddingsError> {
+        search_text(&self.model, &self.index, query, params)
+    }
+
+    /// See [`VectorIndex::search_exact`].
+    pub fn search_exact(
+        &self,
+        embedding: &Embedding,
+        params: &SearchParams,
+    ) -> Result<Vec<SearchResult>, EmbeddingsError> {
+        self.index.search_exact(embedding, params)
+    }
+}
+
+/// Encodes `sentences` with a single `model` call, checking the dimension of every embedding.
+/// If the call itself fails, every sentence gets its error.
+pub(crate) fn encode_sentences<E: Embedder>(
+    model: &E,
+    sentences: &[&str],
+) -> Vec<Result<Embedding, EmbeddingsError>> {
+    let embeddings = model.encode(sentences).and_then(|embeddings| {
+        if embeddings.len() != sentences.len() {
+            return Err(anyhow!(
+                "Model {} returned {} embeddings for {} sentences",
+                model.model_id(),
+                embeddings.len(),
+                sentences.len()
+            ));
+        }
+        Ok(embeddings)
+    });
+    match embeddings {
+        Ok(embeddings) => embeddings
+            .into_iter()
+            .map(|embedding| Embedding::new(embedding, model.dimension()))
+            .collect(),
+        Err(e) => {
+            let e = EmbeddingsError::Encoding(e.to_string());
+            sentences.iter().map(|_| Err(e.clone())).collect()
+        }
     }
 }
 
-fn cosine_similarity(
-    arr1: &[f32; DEFAULT_MODEL_EMBEDDING_SIZE],
-    arr2: &[f32; DEFAULT_MODEL_EMBEDDING_SIZE],
-) -> f32 {
-    let dot_product: f32 = arr1.iter().zip(arr2.iter()).map(|(x, y)| x * y).sum();
+/// Searches `index` for `query`, ranked as set by `params.mode`. Lexical searches skip
+/// `model` altogether.
+pub(crate) fn search_text<E: Embedder>(
+    model: &E,
+    index: &VectorIndex,
+    query: &str,
+    params: &SearchParams,
+) -> Result<Vec<SearchResult>, EmbeddingsError> {
+    match params.mode {
+        SearchMode::Vector => index.search(&encode_sentence(model, query)?, params),
+        SearchMode::Lexical => Ok(index.search_lexical(query, params)),
+        SearchMode::Hybrid => index.search_hybrid(query, &encode_sentence(model, query)?, params),
+    }
+}
+
+/// Encodes a single sentence with `model`, checking the embedding dimension.
+pub(crate) fn encode_sentence<E: Embedder>(
+    model: &E,
+    sentence: &str,
+) -> Result<Embedding, EmbeddingsError> {
+    encode_sentences(model, &[sentence])
+        .pop()
+        .unwrap_or_else(|| {
+            Err(EmbeddingsError::Encoding(
+                "no embedding returned".to_string(),
+            ))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{embedder::HashingEmbedder, metadata::MetadataFilter, search::Metric};
+
+    fn id(id: usize) -> ChunkId {
+        ChunkId::from(id as u32)
+    }
+
+    /// Number of an id made by [`id`].
+    fn number(id: ChunkId) -> u128 {
+        u128::from_be_bytes(*id.as_bytes())
+    }
 
-    let magnitude_arr1: f32 = arr1.iter().map(|x| x * x).sum::<f32>().sqrt();
-    let magnitude_arr2: f32 = arr2.iter().map(|y| y * y).sum::<f32>().sqrt();
+    #[test]
+    fn test_find_closest_embeddings() {
+        let sentences = vec![
+            "Hello world !".to_string(),
+            "Knowledge graphs are great !".to_string(),
+            "LLMs are amazing, as well !".to_string(),
+        ];
+        let embeddings =
+            Embeddings::build_from_sentences_with_model(HashingEmbedder::default(), &sentences)
+                .unwrap();
+        assert_eq!(embeddings.ids().len(), 3);
+
+        let query = embeddings.process_chunk("LLMs are amazing").unwrap();
+        assert_eq!(
+            embeddings.find_closest_embeddings(&query, 2).unwrap()[0],
+            id(2)
+        );
+    }
+
+    #[test]
+    fn test_runtime_sized_embeddings() {
+        let embeddings = Embeddings::new_from_model(HashingEmbedder::new(768).unwrap());
+        assert_eq!(embeddings.dimension(), 768);
+        assert_eq!(
+            embeddings
+                .process_chunk("Hello world !")
+                .unwrap()
+                .dimension(),
+            768
+        );
+
+        let query = Embedding::new(vec![0.0; 384], 384).unwrap();
+        assert_eq!(
+            embeddings.find_closest_embeddings(&query, 1),
+            Err(EmbeddingsError::DimensionMismatch {
+                expected: 768,
+                found: 384
+            })
+        );
+        assert!(Embedding::new(vec![1.0, 2.0], 3).is_err());
+    }
+
+    #[test]
+    fn test_hnsw_search_matches_exact_search() {
+        let sentences = (0..200)
+            .map(|i| format!("chunk number {i} about topic {} and {}", i % 7, i % 13))
+            .collect::<Vec<_>>();
+        let mut embeddings = Embeddings::new_with_index_config(
+            HashingEmbedder::default(),
+            IndexConfig {
+                exact_search_threshold: 0,
+                ..Default::default()
+            },
+        );
+        for (i, sentence) in sentences.iter().enumerate() {
+            embeddings.process_chunk_and_store(id(i), sentence).unwrap();
+        }
+
+        let query = embeddings.process_chunk(&sentences[42]).unwrap();
+        assert_eq!(
+            embeddings.search(&query, &SearchParams::new(1)).unwrap(),
+            embeddings
+                .search_exact(&query, &SearchParams::new(1))
+                .unwrap()
+        );
+
+        embeddings.reset().unwrap();
+        assert!(embeddings
+            .find_closest_embeddings(&query, 1)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_embeddings_reload_from_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = StoreConfig {
+            path: dir.path().to_path_buf(),
+            snapshot_interval: 2,
+        };
+        let sentences = [
+            "Hello world !",
+            "Knowledge graphs are great !",
+            "LLMs are amazing, as well !",
+        ];
+        {
+            let mut embeddings = Embeddings::new_from_model(HashingEmbedder::default())
+                .with_store(&config)
+                .unwrap();
+            for (i, sentence) in sentences.iter().enumerate() {
+                embeddings.process_chunk_and_store(id(i), sentence).unwrap();
+            }
+        }
 
-    dot_product / (magnitude_arr1 * magnitude_arr2)
+        let embeddings = Embeddings::new_from_model(HashingEmbedder::default())
+            .with_store(&config)
+            .unwrap();
+        assert_eq!(embeddings.ids().len(), 3);
+        let query = embeddings.process_chunk("LLMs are amazing").unwrap();
+        assert_eq!(
+            embeddings.find_closest_embeddings(&query, 1).unwrap(),
+            vec![id(2)]
+        );
+
+        assert!(
+            Embeddings::new_from_model(HashingEmbedder::new(16).unwrap())
+                .with_store(&config)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_search_is_bounded_and_scored() {
+        let sentences = vec![
+            "Hello world !".to_string(),
+            "Knowledge graphs are great !".to_string(),
+        ];
+        let embeddings =
+            Embeddings::build_from_sentences_with_model(HashingEmbedder::default(), &sentences)
+                .unwrap();
+
+        let query = embeddings.process_chunk("Knowledge graphs").unwrap();
+        let results = embeddings.search(&query, &SearchParams::new(10)).unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].id, id(1));
+        assert!(results[0].score > results[1].score);
+
+        let params = SearchParams {
+            num_queries: 10,
+            metric: Metric::Euclidean,
+            min_score: Some(-1.0),
+            ..SearchParams::new(10)
+        };
+        let results = embeddings.search(&query, &params).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].id, id(1));
+
+        // A zero query has no cosine similarity with anything.
+        let zero =
+            Embedding::new(vec![0.0; embeddings.dimension()], embeddings.dimension()).unwrap();
+        assert!(embeddings
+            .search(&zero, &SearchParams::new(1))
+            .unwrap()
+            .is_empty());
+    }
+
+    /// Embeds every sentence in a single `encode` call, failing on sentences containing "bad".
+    struct BatchEmbedder {
+        inner: HashingEmbedder,
+        calls: std::cell::Cell<usize>,
+    }
+
+    impl Embedder for BatchEmbedder {
+        fn encode(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+            self.calls.set(self.calls.get() + 1);
+            let mut embeddings = self.inner.encode(texts)?;
+            for (text, embedding) in texts.iter().zip(embeddings.iter_mut()) {
+                if text.contains("bad") {
+                    embedding.pop();
+                }
+            }
+            Ok(embeddings)
+        }
+
+        fn dimension(&self) -> usize {
+            self.inner.dimension()
+        }
+
+        fn model_id(&self) -> &str {
+            self.inner.model_id()
+        }
+
+        fn model_version(&self) -> &str {
+            self.inner.model_version()
+        }
+    }
+
+    #[test]
+    fn test_process_chunks_and_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut embeddings = Embeddings::new_from_model(BatchEmbedder {
+            inner: HashingEmbedder::default(),
+            calls: Default::default(),
+        })
+        .with_store(&StoreConfig::new(dir.path()))
+        .unwrap();
+
+        let results = embeddings.process_chunks_and_store(&[
+            (id(0), "Hello world !"),
+            (id(1), "a bad chunk"),
+            (id(2), "Knowledge graphs are great !"),
+        ]);
+        assert_eq!(embeddings.model().calls.get(), 1);
+        assert!(results[0].is_ok());
+        assert_eq!(
+            results[1],
+            Err(EmbeddingsError::DimensionMismatch {
+                expected: embeddings.dimension(),
+                found: embeddings.dimension() - 1
+            })
+        );
+        assert!(results[2].is_ok());
+
+        let embeddings = Embeddings::new_from_model(HashingEmbedder::default())
+            .with_store(&StoreConfig::new(dir.path()))
+            .unwrap();
+        let ids: Vec<ChunkId> = embeddings.ids().to_vec();
+        assert_eq!(ids, vec![id(0), id(2)]);
+    }
+
+    #[test]
+    fn test_search_with_metadata_filter() {
+        let dir = tempfile::tempdir().unwrap();
+        let store_config = StoreConfig {
+            path: dir.path().to_path_buf(),
+            snapshot_interval: 50,
+        };
+        let index_config = IndexConfig {
+            exact_search_threshold: 50,
+            ..Default::default()
+        };
+        let sentences = (0..200)
+            .map(|i| format!("chunk number {i} about topic {} and {}", i % 7, i % 13))
+            .collect::<Vec<_>>();
+        let metadata = |i: u32| ChunkMetadata {
+            namespace: Some(if i.is_multiple_of(2) { "even" } else { "odd" }.to_string()),
+            created_at: Some(i as u64),
+            tags: if i.is_multiple_of(7) {
+                vec!["seven".to_string()]
+            } else {
+                vec![]
+            },
+            ..Default::default()
+        };
+        {
+            let mut embeddings =
+                Embeddings::new_with_index_config(HashingEmbedder::default(), index_config)
+                    .with_store(&store_config)
+                    .unwrap();
+            for (i, sentence) in sentences.iter().enumerate() {
+                embeddings
+                    .process_chunk_and_store_with_metadata(id(i), sentence, metadata(i as u32))
+                    .unwrap();
+            }
+        }
+
+        let embeddings =
+            Embeddings::new_with_index_config(HashingEmbedder::default(), index_config)
+                .with_store(&store_config)
+                .unwrap();
+        assert_eq!(
+            embeddings.index().metadata(id(42)).unwrap().tags,
+            vec!["seven"]
+        );
+        assert!(embeddings
+            .index()
+            .metadata(id(42))
+            .unwrap()
+            .ingested_at
+            .is_some());
+
+        let search = |query: &str, filter: MetadataFilter| {
+            let query = embeddings.process_chunk(query).unwrap();
+            let params = SearchParams {
+                filter: Some(filter),
+                ..SearchParams::new(5)
+            };
+            embeddings
+                .search(&query, &params)
+                .unwrap()
+                .into_iter()
+                .map(|result| result.id)
+                .collect::<Vec<_>>()
+        };
+
+        // Half of the store passes, so the HNSW traversal applies the filter.
+        let even = MetadataFilter {
+            namespace: Some("even".to_string()),
+            ..Default::default()
+        };
+        let ids = search(&sentences[42], even.clone());
+        assert_eq!(ids.len(), 5);
+        assert_eq!(ids[0], id(42));
+        assert!(ids.iter().all(|id| number(*id).is_multiple_of(2)));
+        assert!(search(&sentences[43], even.clone())
+            .iter()
+            .all(|id| number(*id).is_multiple_of(2)));
+
+        // Few chunks pass, so they are searched exactly.
+        let ids = search(
+            &sentences[43],
+            MetadataFilter {
+                tags: vec!["seven".to_string()],
+                ..even
+            },
+        );
+        assert_eq!(ids.len(), 5);
+        assert!(ids.iter().all(|id| number(*id).is_multiple_of(14)));
+
+        let ids = search(
+            &sentences[100],
+            MetadataFilter {
+                since: Some(40),
+                until: Some(42),
+                ..Default::default()
+            },
+        );
+        let mut ids = ids;
+        ids.sort();
+        assert_eq!(ids, vec![id(40), id(41), id(42)]);
+    }
+
+    #[test]
+    fn test_upsert_delete_and_get() {
+        let dir = tempfile::tempdir().unwrap();
+        let store_config = StoreConfig::new(dir.path());
+        let index_config = IndexConfig {
+            exact_search_threshold: 0,
+            ..Default::default()
+        };
+        let sentences = [
+            "Hello world !",
+            "Knowledge graphs are great !",
+            "LLMs are amazing, as well !",
+        ];
+        {
+            let mut embeddings =
+                Embeddings::new_with_index_config(HashingEmbedder::default(), index_config)
+                    .with_store(&store_config)
+                    .unwrap();
+            for (i, sentence) in sentences.iter().enumerate() {
+                let metadata = ChunkMetadata {
+                    document_id: Some(format!("doc-{i}")),
+                    ..Default::default()
+                };
+                embeddings
+                    .process_chunk_and_store_with_metadata(id(i), sentence, metadata)
+                    .unwrap();
+            }
+
+            assert!(embeddings.upsert(id(1), "Rust is fast !").unwrap());
+            assert!(!embeddings.upsert(id(5), "Neo4j stores graphs").unwrap());
+            assert_eq!(embeddings.ids().len(), 4);
+            let query = embeddings.process_chunk("Rust is fast !").unwrap();
+            // The HNSW index and the exact scan both see the replaced embedding.
+            let params = SearchParams::new(4);
+            let ids = |results: Vec<SearchResult>| {
+                let mut ids = results.iter().map(|result| result.id).collect::<Vec<_>>();
+                ids.sort();
+                ids
+            };
+            assert_eq!(embeddings.search(&query, &params).unwrap()[0].id, id(1));
+            assert_eq!(
+                ids(embeddings.search(&query, &params).unwrap()),
+                ids(embeddings.search_exact(&query, &params).unwrap())
+            );
+
+            assert_eq!(
+                embeddings.delete(&[id(0), id(9)]),
+                vec![Ok(()), Err(EmbeddingsError::NotFound(id(9)))]
+            );
+            assert!(embeddings.get(id(0)).is_err());
+            let query = embeddings.process_chunk(sentences[0]).unwrap();
+            assert!(embeddings
+                .search(&query, &SearchParams::new(10))
+                .unwrap()
+                .iter()
+                .all(|result| result.id != id(0)));
+        }
+
+        let embeddings =
+            Embeddings::new_with_index_config(HashingEmbedder::default(), index_config)
+                .with_store(&store_config)
+                .unwrap();
+        let mut ids: Vec<ChunkId> = embeddings.ids().to_vec();
+        ids.sort();
+        assert_eq!(ids, vec![id(1), id(2), id(5)]);
+        let stored = embeddings.get(id(1)).unwrap();
+        assert_eq!(
+            stored.embedding,
+            embeddings.process_chunk("Rust is fast !").unwrap()
+        );
+        // The upsert kept the metadata of the replaced chunk.
+        assert_eq!(
+            stored.metadata.unwrap().document_id,
+            Some("doc-1".to_string())
+        );
+    }
+
+    #[test]
+    fn test_lexical_and_hybrid_search() {
+        let dir = tempfile::tempdir().unwrap();
+        let store_config = StoreConfig::new(dir.path());
+        let mut sentences = (0..100)
+            .map(|i| format!("chunk number {i} about topic {} and {}", i % 7, i % 13))
+            .collect::<Vec<_>>();
+        sentences[17] = "Firmware XR2000 ships with topic 3".to_string();
+        {
+            let mut embeddings = Embeddings::new_from_model(HashingEmbedder::default())
+                .with_store(&store_config)
+                .unwrap();
+            let chunks: Vec<_> = sentences
+                .iter()
+                .enumerate()
+                .map(|(i, sentence)| (id(i), sentence.as_str()))
+                .collect();
+            for result in embeddings.process_chunks_and_store(&chunks) {
+                result.unwrap();
+            }
+        }
+
+        // The texts are reloaded, and indexed again, along with the embeddings.
+        let embeddings = Embeddings::new_from_model(HashingEmbedder::default())
+            .with_store(&store_config)
+            .unwrap();
+        assert_eq!(
+            embeddings.index().text(id(17)),
+            Some(sentences[17].as_str())
+        );
+        let search = |query: &str, mode: SearchMode| {
+            let params = SearchParams {
+                mode,
+                ..SearchParams::new(5)
+            };
+            embeddings
+                .search_text(query, &params)
+                .unwrap()
+                .into_iter()
+                .map(|result| result.id)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(search("xr2000", SearchMode::Lexical), vec![id(17)]);
+        assert!(search("unknown", SearchMode::Lexical).is_empty());
+        let ids = search("xr2000 firmware topic", SearchMode::Hybrid);
+        assert_eq!(ids.len(), 5);
+        assert_eq!(ids[0], id(17));
+        assert_eq!(
+            search(&sentences[42], SearchMode::Vector)[0],
+            search(&sentences[42], SearchMode::Hybrid)[0]
+        );
+
+        // `min_score` filters the vector ranking, on its cosine scale, before fusion.
+        let params = SearchParams {
+            mode: SearchMode::Hybrid,
+            min_score: Some(0.99),
+            ..SearchParams::new(5)
+        };
+        let results = embeddings.search_text(&sentences[42], &params).unwrap();
+        assert_eq!(results[0].id, id(42));
+        let params = SearchParams {
+            mode: SearchMode::Vector,
+            ..params
+        };
+        assert_eq!(
+            embeddings
+                .search_text(&sentences[42], &params)
+                .unwrap()
+                .iter()
+                .map(|r| r.id)
+                .collect::<Vec<_>>(),
+            vec![id(42)]
+        );
+
+        // Only the chunks passing the filter are ranked, lexically too.
+        let params = SearchParams {
+            mode: SearchMode::Lexical,
+            filter: Some(MetadataFilter {
+                namespace: Some("none".to_string()),
+                ..Default::default()
+            }),
+            ..SearchParams::new(5)
+        };
+        assert!(embeddings
+            .search_text("xr2000", &params)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_diversified_search() {
+        let mut embeddings = Embeddings::new_from_model(HashingEmbedder::default());
+        let chunks = [
+            (id(0), "Knowledge graphs store facts about entities"),
+            (id(1), "Knowledge graphs store facts about entities"),
+            (id(2), "Knowledge graphs store facts about entities !"),
+            (id(3), "Graphs of knowledge link entities through relations"),
+            (id(4), "The weather is sunny today"),
+        ];
+        for result in embeddings.process_chunks_and_store(&chunks) {
+            result.unwrap();
+        }
+
+        let search = |mmr_lambda| {
+            let params = SearchParams {
+                mmr_lambda,
+                ..SearchParams::new(2)
+            };
+            let mut ids = embeddings
+                .search_text("knowledge graphs facts", &params)
+                .unwrap()
+                .into_iter()
+                .map(|result| result.id)
+                .collect::<Vec<_>>();
+            ids.sort();
+            ids
+        };
+        assert!(search(None).iter().all(|found| *found <= id(2)));
+        let ids = search(Some(0.5));
+        assert!(ids[0] <= id(2));
+        assert_eq!(ids[1], id(3));
+    }
 }
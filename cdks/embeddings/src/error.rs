@@ -0,0 +1,97 @@
+use std::{fmt, path::PathBuf, time::Duration};
+
+use crate::{dedup::ContentHash, embedder::ModelTag, id::ChunkId};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EmbeddingsError {
+    /// An embedding does not have the dimension of the store, or of the model.
+    DimensionMismatch { expected: usize, found: usize },
+    /// Reading or writing the persisted store failed.
+    Storage(String),
+    /// A persisted store file failed its integrity checks.
+    CorruptedStore { path: PathBuf, reason: String },
+    /// The model failed to encode a chunk.
+    Encoding(String),
+    /// The embeddings service did not reply in time, and the request was cancelled.
+    Timeout(Duration),
+    /// The request was cancelled before a reply arrived.
+    Cancelled,
+    /// The embeddings service is no longer receiving requests.
+    ServiceStopped,
+    /// The embeddings service dropped the request without replying.
+    NoReply,
+    /// The embeddings service replied with a reply of the wrong kind.
+    UnexpectedReply(String),
+    /// No embedding is stored under this id.
+    NotFound(ChunkId),
+    /// No chunk is stored with this content or ingestion hash.
+    HashNotFound(ContentHash),
+    /// A message could not be parsed.
+    InvalidMessage(String),
+    /// The worker handling the request panicked, and was restarted.
+    WorkerPanicked(String),
+    /// A content hash is not 64 hexadecimal digits.
+    InvalidHash(String),
+    /// A chunk id is neither a UUID nor a number.
+    InvalidChunkId(String),
+    /// The stored embeddings were produced by another model than the one loaded.
+    ModelMismatch { expected: ModelTag, found: ModelTag },
+    /// A stored chunk has no text to be encoded again from.
+    MissingText(ChunkId),
+    /// Another migration of the stored embeddings is still running.
+    MigrationInProgress,
+    /// The service was not set up to load other models.
+    MigrationUnsupported,
+    /// The model to migrate to failed to load.
+    ModelLoading(String),
+    /// An export file could not be read, or holds no model.
+    InvalidExport(String),
+}
+
+impl fmt::Display for EmbeddingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DimensionMismatch { expected, found } => write!(
+                f,
+                "Embedding has dimension {found}, but {expected} was expected"
+            ),
+            Self::Storage(reason) => write!(f, "Embeddings store error: {reason}"),
+            Self::CorruptedStore { path, reason } => write!(
+                f,
+                "Embeddings store file {} is corrupted: {reason}",
+                path.display()
+            ),
+            Self::Encoding(reason) => write!(f, "Failed to encode chunk: {reason}"),
+            Self::Timeout(timeout) => {
+                write!(f, "Embeddings service did not reply within {timeout:?}")
+            }
+            Self::Cancelled => write!(f, "Embeddings request was cancelled"),
+            Self::ServiceStopped => write!(f, "Embeddings service is stopped"),
+            Self::NoReply => write!(f, "Embeddings service dropped the request"),
+            Self::UnexpectedReply(reply) => {
+                write!(f, "Unexpected reply from embeddings service: {reply}")
+            }
+            Self::NotFound(id) => write!(f, "No embedding stored under id {id}"),
+            Self::HashNotFound(hash) => write!(f, "No chunk stored with hash {hash}"),
+            Self::InvalidMessage(reason) => write!(f, "Invalid embeddings message: {reason}"),
+            Self::WorkerPanicked(reason) => {
+                write!(f, "Embeddings worker panicked: {reason}")
+            }
+            Self::InvalidHash(hash) => write!(f, "Invalid content hash {hash}"),
+            Self::InvalidChunkId(id) => write!(f, "Invalid chunk id {id}"),
+            Self::ModelMismatch { expected, found } => write!(
+                f,
+                "Embeddings were produced by model {expected}, but model {found} is loaded"
+            ),
+            Self::MissingText(id) => write!(f, "No text stored for chunk {id}"),
+            Self::MigrationInProgress => write!(f, "A model migration is already running"),
+            Self::MigrationUnsupported => {
+                write!(f, "Embeddings service cannot migrate to another model")
+            }
+            Self::ModelLoading(reason) => write!(f, "Failed to load model: {reason}"),
+            Self::InvalidExport(reason) => write!(f, "Invalid embeddings export: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for EmbeddingsError {}
@@ -0,0 +1,719 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use arrow::{
+    array::{Array, ArrayRef, AsArray, FixedSizeListArray, Float32Array, RecordBatch, StringArray},
+    datatypes::{DataType, Field, Float32Type, Schema, SchemaRef},
+    error::ArrowError,
+    ipc::{reader::FileReader, writer::FileWriter},
+};
+use npyz::{npz::NpzArchive, DType, NpyFile, TypeStr, WriterBuilder};
+use parquet::{
+    arrow::{arrow_reader::ParquetRecordBatchReaderBuilder, ArrowWriter},
+    basic::Compression,
+    file::properties::WriterProperties,
+};
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+use crate::{
+    embedder::ModelTag,
+    embeddings::Embedding,
+    error::EmbeddingsError,
+    id::ChunkId,
+    index::{IndexEntry, VectorIndex},
+    metadata::ChunkMetadata,
+    store::storage_error,
+};
+
+/// Rows of the record batches written to Arrow and Parquet files.
+const RECORD_BATCH_ROWS: usize = 8_192;
+/// Chunks inserted into the index at once on import.
+const IMPORT_BATCH_SIZE: usize = 1_024;
+
+/// Names of the arrays of a NumPy export, and of the schema metadata of an Arrow one.
+const IDS: &str = "ids";
+const EMBEDDINGS: &str = "embeddings";
+const METADATA: &str = "metadata";
+const TEXTS: &str = "texts";
+const MODEL: &str = "model";
+const MODEL_ID: &str = "model_id";
+const MODEL_VERSION: &str = "model_version";
+
+/// File format of an export, picked from the file extension.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// `.npy`: the embeddings matrix, with the other arrays of [`Self::Npz`] in sibling
+    /// `<stem>.<array>.npy` files.
+    Npy,
+    /// `.npz`: the `ids`, `embeddings`, `metadata`, `texts` and `model` arrays.
+    Npz,
+    /// `.arrow`, `.feather` or `.ipc`: an Arrow IPC file.
+    Arrow,
+    /// `.parquet`: a Parquet file of the same schema as [`Self::Arrow`].
+    Parquet,
+}
+
+impl ExportFormat {
+    pub fn from_path(path: &Path) -> Result<Self, EmbeddingsError> {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("npy") => Ok(Self::Npy),
+            Some("npz") => Ok(Self::Npz),
+            Some("arrow" | "feather" | "ipc") => Ok(Self::Arrow),
+            Some("parquet") => Ok(Self::Parquet),
+            _ => Err(invalid_export(
+                path,
+                "unknown format, expected a .npy, .npz, .arrow or .parquet file",
+            )),
+        }
+    }
+}
+
+/// A stored chunk, as exported.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExportedChunk {
+    pub id: ChunkId,
+    pub embedding: Embedding,
+    pub metadata: Option<ChunkMetadata>,
+    pub text: Option<String>,
+}
+
+/// The chunks of an index, along with the model that produced their embeddings, as written
+/// to and read from export files.
+///
+/// NumPy exports hold the ids as `<U36` strings, the embeddings as an `(n, dimension)` `<f4`
+/// matrix, the metadata as JSON and the texts as UTF-8 `|S` byte strings, empty if missing,
+/// and the model id and version as a `<U` array of two strings. Arrow and Parquet exports hold
+/// an `id` string column, an `embedding` fixed size list of floats, and `metadata` (as JSON)
+/// and `text` nullable string columns, the model being recorded in the schema metadata.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExportedEmbeddings {
+    /// Model that produced the embeddings, if recorded.
+    pub model: Option<ModelTag>,
+    pub dimension: usize,
+    pub chunks: Vec<ExportedChunk>,
+}
+
+impl ExportedEmbeddings {
+    /// Every chunk stored in `index`, in the order of [`VectorIndex::ids`].
+    pub fn from_index(index: &VectorIndex) -> Result<Self, EmbeddingsError> {
+        let chunks = index
+            .ids()
+            .iter()
+            .map(|&id| {
+                let stored = index.get(id)?;
+                Ok(ExportedChunk {
+                    id,
+                    embedding: stored.embedding,
+                    metadata: stored.metadata,
+                    text: index.text(id).map(str::to_string),
+                })
+            })
+            .collect::<Result<_, EmbeddingsError>>()?;
+        Ok(Self {
+            model: index.model().cloned(),
+            dimension: index.dimension(),
+            chunks,
+        })
+    }
+
+    /// Writes the chunks to `path`, in the format of its extension.
+    pub fn write(&self, path: &Path) -> Result<(), EmbeddingsError> {
+        match ExportFormat::from_path(path)? {
+            ExportFormat::Npy => self.write_npy(&mut NpySink::Files(path.to_path_buf())),
+            ExportFormat::Npz => {
+                let file = File::create(path).map_err(|e| storage_error(path, e))?;
+                let mut sink = NpySink::Zip(ZipWriter::new(BufWriter::new(file)));
+                self.write_npy(&mut sink)?;
+                if let NpySink::Zip(zip) = &mut sink {
+                    zip.finish()
+                        .and_then(|mut file| file.flush().map_err(Into::into))
+                        .map_err(|e| storage_error(path, e.into()))?;
+                }
+                Ok(())
+            }
+            ExportFormat::Arrow => {
+                let file = File::create(path).map_err(|e| storage_error(path, e))?;
+                let schema = self.schema();
+                let mut writer = FileWriter::try_new(BufWriter::new(file), &schema)
+                    .map_err(|e| arrow_error(path, e))?;
+                for batch in self.record_batches(&schema) {
+                    writer
+                        .write(&batch.map_err(|e| arrow_error(path, e))?)
+                        .map_err(|e| arrow_error(path, e))?;
+                }
+                writer.finish().map_err(|e| arrow_error(path, e))
+            }
+            ExportFormat::Parquet => {
+                let file = File::create(path).map_err(|e| storage_error(path, e))?;
+                let schema = self.schema();
+                let properties = WriterProperties::builder()
+                    .set_compression(Compression::SNAPPY)
+                    .build();
+                let parquet_error = |e: parquet::errors::ParquetError| {
+                    EmbeddingsError::Storage(format!("{}: {e}", path.display()))
+                };
+                let mut writer = ArrowWriter::try_new(file, schema.clone(), Some(properties))
+                    .map_err(parquet_error)?;
+                for batch in self.record_batches(&schema) {
+                    writer
+                        .write(&batch.map_err(|e| arrow_error(path, e))?)
+                        .map_err(parquet_error)?;
+                }
+                writer.close().map(drop).map_err(parquet_error)
+            }
+        }
+    }
+
+    /// Reads the chunks exported to `path`, checking that every embedding has the dimension
+    /// of the file.
+    pub fn read(path: &Path) -> Result<Self, EmbeddingsError> {
+        match ExportFormat::from_path(path)? {
+            ExportFormat::Npy => read_npy(path, &mut NpySource::Files(path.to_path_buf())),
+            ExportFormat::Npz => {
+                let archive = NpzArchive::open(path).map_err(|e| invalid_export(path, e))?;
+                read_npy(path, &mut NpySource::Zip(archive))
+            }
+            ExportFormat::Arrow => {
+                let file = File::open(path).map_err(|e| storage_error(path, e))?;
+                let reader = FileReader::try_new(BufReader::new(file), None)
+                    .map_err(|e| invalid_export(path, e))?;
+                read_record_batches(path, reader.schema(), reader)
+            }
+            ExportFormat::Parquet => {
+                let file = File::open(path).map_err(|e| storage_error(path, e))?;
+                let builder = ParquetRecordBatchReaderBuilder::try_new(file)
+                    .map_err(|e| invalid_export(path, e))?;
+                let schema = builder.schema().clone();
+                let reader = builder.build().map_err(|e| invalid_export(path, e))?;
+                read_record_batches(path, schema, reader)
+            }
+        }
+    }
+
+    /// Inserts the chunks into `index`, replacing the ones stored under the same ids. The
+    /// embeddings must have the dimension of `index`, and have been produced by its model,
+    /// which is recorded if `index` has none yet. Returns the number of chunks inserted.
+    pub fn load_into(self, index: &mut VectorIndex) -> Result<usize, EmbeddingsError> {
+        if self.dimension != index.dimension() {
+            return Err(EmbeddingsError::DimensionMismatch {
+                expected: index.dimension(),
+                found: self.dimension,
+            });
+        }
+        let model = self
+            .model
+            .ok_or_else(|| EmbeddingsError::InvalidExport("no model is recorded".to_string()))?;
+        match index.model() {
+            Some(loaded) if *loaded != model => {
+                return Err(EmbeddingsError::ModelMismatch {
+                    expected: model,
+                    found: loaded.clone(),
+                })
+            }
+            Some(_) => {}
+            None => index.bind_model(&model)?,
+        }
+
+        let count = self.chunks.len();
+        let mut chunks = self.chunks.into_iter().peekable();
+        while chunks.peek().is_some() {
+            let entries = chunks
+                .by_ref()
+                .take(IMPORT_BATCH_SIZE)
+                .map(|chunk| {
+                    let mut entry = IndexEntry::new(chunk.id, chunk.embedding);
+                    if let Some(metadata) = chunk.metadata {
+                        entry = entry.with_metadata(metadata);
+                    }
+                    if let Some(text) = chunk.text {
+                        entry = entry.with_text(text);
+                    }
+                    entry
+                })
+                .collect();
+            index.insert_entries(entries)?;
+        }
+        Ok(count)
+    }
+
+    fn write_npy(&self, sink: &mut NpySink) -> Result<(), EmbeddingsError> {
+        let ids: Vec<String> = self
+            .chunks
+            .iter()
+            .map(|chunk| chunk.id.to_string())
+            .collect();
+        sink.write(IDS, &[ids.len() as u64], unicode(&ids), &ids)?;
+
+        let rows = self.chunks.len() as u64;
+        let shape = [rows, self.dimension as u64];
+        let values: Vec<f32> = self
+            .chunks
+            .iter()
+            .flat_map(|chunk| chunk.embedding.as_slice().iter().copied())
+            .collect();
+        sink.write(EMBEDDINGS, &shape, type_str("<f4"), &values)?;
+
+        let metadata: Vec<Vec<u8>> = self
+            .chunks
+            .iter()
+            .map(|chunk| {
+                chunk
+                    .metadata
+                    .as_ref()
+                    .map(metadata_json)
+                    .unwrap_or_default()
+                    .into_bytes()
+            })
+            .collect();
+        sink.write(METADATA, &[rows], bytes(&metadata), &metadata)?;
+
+        let texts: Vec<Vec<u8>> = self
+            .chunks
+            .iter()
+            .map(|chunk| chunk.text.clone().unwrap_or_default().into_bytes())
+            .collect();
+        sink.write(TEXTS, &[rows], bytes(&texts), &texts)?;
+
+        let model = self.model.clone().unwrap_or_default();
+        let model = [model.id, model.version];
+        sink.write(MODEL, &[2], unicode(&model), &model)
+    }
+
+    fn schema(&self) -> SchemaRef {
+        let mut metadata = HashMap::new();
+        if let Some(model) = &self.model {
+            metadata.insert(MODEL_ID.to_string(), model.id.clone());
+            metadata.insert(MODEL_VERSION.to_string(), model.version.clone());
+        }
+        Arc::new(
+            Schema::new(vec![
+                Field::new("id", DataType::Utf8, false),
+                Field::new(
+                    "embedding",
+                    DataType::FixedSizeList(embedding_item(), self.dimension as i32),
+                    false,
+                ),
+                Field::new("metadata", DataType::Utf8, true),
+                Field::new("text", DataType::Utf8, true),
+            ])
+            .with_metadata(metadata),
+        )
+    }
+
+    fn record_batches<'a>(
+        &'a self,
+        schema: &'a SchemaRef,
+    ) -> impl Iterator<Item = Result<RecordBatch, ArrowError>> + 'a {
+        let mut batches: Vec<&[ExportedChunk]> = self.chunks.chunks(RECORD_BATCH_ROWS).collect();
+        if batches.is_empty() {
+            // An empty export still gets a batch, for readers to find the columns in.
+            batches.push(&[]);
+        }
+        batches.into_iter().map(move |chunks| {
+            let ids =
+                StringArray::from_iter_values(chunks.iter().map(|chunk| chunk.id.to_string()));
+            let values = Float32Array::from_iter_values(
+                chunks
+                    .iter()
+                    .flat_map(|chunk| chunk.embedding.as_slice().iter().copied()),
+            );
+            let embeddings = FixedSizeListArray::try_new(
+                embedding_item(),
+                self.dimension as i32,
+                Arc::new(values),
+                None,
+            )?;
+            let metadata: StringArray = chunks
+                .iter()
+                .map(|chunk| chunk.metadata.as_ref().map(metadata_json))
+                .collect();
+            let texts: StringArray = chunks.iter().map(|chunk| chunk.text.as_deref()).collect();
+            RecordBatch::try_new(
+                schema.clone(),
+                vec![
+                    Arc::new(ids) as ArrayRef,
+                    Arc::new(embeddings),
+                    Arc::new(metadata),
+                    Arc::new(texts),
+                ],
+            )
+        })
+    }
+}
+
+/// Where the arrays of a NumPy export are written: sibling `.npy` files, or a `.npz` archive.
+enum NpySink {
+    Files(PathBuf),
+    Zip(ZipWriter<BufWriter<File>>),
+}
+
+impl NpySink {
+    fn write<T: npyz::Serialize>(
+        &mut self,
+        name: &str,
+        shape: &[u64],
+        type_str: TypeStr,
+        values: &[T],
+    ) -> Result<(), EmbeddingsError> {
+        let (path, mut writer): (_, Box<dyn Write + '_>) = match self {
+            Self::Files(path) => {
+                let path = array_path(path, name);
+                let file = File::create(&path).map_err(|e| storage_error(&path, e))?;
+                (path, Box::new(BufWriter::new(file)))
+            }
+            Self::Zip(zip) => {
+                let options =
+                    FileOptions::default().compression_method(CompressionMethod::Deflated);
+                zip.start_file(npyz::npz::file_name_from_array_name(name), options)
+                    .map_err(|e| EmbeddingsError::Storage(format!("{name}: {e}")))?;
+                (PathBuf::from(name), Box::new(zip))
+            }
+        };
+        npyz::WriteOptions::new()
+            .dtype(DType::Plain(type_str))
+            .shape(shape)
+            .writer(&mut writer)
+            .begin_nd()
+            .and_then(|mut array| {
+                for value in values {
+                    array.push(value)?;
+                }
+                array.finish()
+            })
+            .and_then(|()| writer.flush())
+            .map_err(|e| storage_error(&path, e))
+    }
+}
+
+/// Where the arrays of a NumPy export are read from.
+enum NpySource {
+    Files(PathBuf),
+    Zip(NpzArchive<BufReader<File>>),
+}
+
+impl NpySource {
+    /// Shape and values of the array `name`.
+    fn read<T: npyz::Deserialize>(&mut self, name: &str) -> Result<(Vec<u64>, Vec<T>), String> {
+        fn values<T: npyz::Deserialize, R: io::Read>(
+            array: NpyFile<R>,
+        ) -> Result<(Vec<u64>, Vec<T>), String> {
+            let shape = array.shape().to_vec();
+            let values = array.into_vec().map_err(|e| e.to_string())?;
+            Ok((shape, values))
+        }
+
+        match self {
+            Self::Files(path) => {
+                let path = array_path(path, name);
+                let file = File::open(&path).map_err(|e| format!("{}: {e}", path.display()))?;
+                values(NpyFile::new(BufReader::new(file)).map_err(|e| e.to_string())?)
+            }
+            Self::Zip(archive) => match archive.by_name(name).map_err(|e| e.to_string())? {
+                Some(array) => values(array),
+                None => Err(format!("no {name} array")),
+            },
+        }
+        .map_err(|reason| format!("{name}: {reason}"))
+    }
+}
+
+fn read_npy(path: &Path, source: &mut NpySource) -> Result<ExportedEmbeddings, EmbeddingsError> {
+    let corrupted = |reason: String| invalid_export(path, reason);
+
+    let (shape, values) = source.read::<f32>(EMBEDDINGS).map_err(corrupted)?;
+    let [rows, dimension] = shape[..] else {
+        return Err(corrupted(format!(
+            "embeddings have shape {shape:?}, not (n, dimension)"
+        )));
+    };
+    let (rows, dimension) = (rows as usize, dimension as usize);
+    let (_, ids) = source.read::<String>(IDS).map_err(corrupted)?;
+    let (_, metadata) = source.read::<Vec<u8>>(METADATA).map_err(corrupted)?;
+    let (_, texts) = source.read::<Vec<u8>>(TEXTS).map_err(corrupted)?;
+    let (_, model) = source.read::<String>(MODEL).map_err(corrupted)?;
+    if [ids.len(), metadata.len(), texts.len()] != [rows; 3] {
+        return Err(corrupted(format!("arrays do not all hold {rows} chunks")));
+    }
+    let model = match &model[..] {
+        [id, _] if id.is_empty() => None,
+        [id, version] => Some(ModelTag::new(id, version)),
+        _ => return Err(corrupted("model is not an id and a version".to_string())),
+    };
+
+    let chunks = ids
+        .iter()
+        .zip(values.chunks(dimension.max(1)))
+        .zip(metadata.into_iter().zip(texts))
+        .map(|((id, values), (metadata, text))| {
+            Ok(ExportedChunk {
+                id: id.parse()?,
+                embedding: Embedding::new(values.to_vec(), dimension)?,
+                metadata: match metadata.is_empty() {
+                    true => None,
+                    false => Some(parse_metadata(&metadata).map_err(corrupted)?),
+                },
+                text: match text.is_empty() {
+                    true => None,
+                    false => Some(String::from_utf8(text).map_err(|e| corrupted(e.to_string()))?),
+                },
+            })
+        })
+        .collect::<Result<_, EmbeddingsError>>()?;
+    Ok(ExportedEmbeddings {
+        model,
+        dimension,
+        chunks,
+    })
+}
+
+fn read_record_batches(
+    path: &Path,
+    schema: SchemaRef,
+    batches: impl Iterator<Item = Result<RecordBatch, ArrowError>>,
+) -> Result<ExportedEmbeddings, EmbeddingsError> {
+    let corrupted = |reason: String| invalid_export(path, reason);
+
+    let dimension = match schema.field_with_name("embedding").map(Field::data_type) {
+        Ok(DataType::FixedSizeList(_, dimension)) => *dimension as usize,
+        _ => {
+            return Err(corrupted(
+                "no embedding column of fixed size lists".to_string(),
+            ))
+        }
+    };
+    let model = schema.metadata().get(MODEL_ID).map(|id| {
+        let version = schema.metadata().get(MODEL_VERSION);
+        ModelTag::new(id, version.cloned().unwrap_or_default())
+    });
+
+    let mut chunks = vec![];
+    for batch in batches {
+        let batch = batch.map_err(|e| invalid_export(path, e))?;
+        let column = |name: &str| {
+            batch
+                .column_by_name(name)
+                .ok_or_else(|| corrupted(format!("no {name} column")))
+        };
+        let strings = |name: &str| {
+            column(name)?
+                .as_string_opt::<i32>()
+                .ok_or_else(|| corrupted(format!("{name} is not a string column")))
+        };
+        let ids = strings("id")?;
+        let metadata = strings("metadata")?;
+        let texts = strings("text")?;
+        let embeddings = column("embedding")?
+            .as_fixed_size_list_opt()
+            .filter(|embeddings| embeddings.value_type() == DataType::Float32)
+            .ok_or_else(|| corrupted("embedding is not a float list column".to_string()))?;
+
+        for row in 0..batch.num_rows() {
+            if ids.is_null(row) || embeddings.is_null(row) {
+                return Err(corrupted(format!("row {row} has no id or embedding")));
+            }
+            let values = embeddings.value(row);
+            chunks.push(ExportedChunk {
+                id: ids.value(row).parse()?,
+                embedding: Embedding::new(
+                    values.as_primitive::<Float32Type>().values().to_vec(),
+                    dimension,
+                )?,
+                metadata: match metadata.is_valid(row) {
+                    true => {
+                        Some(parse_metadata(metadata.value(row).as_bytes()).map_err(corrupted)?)
+                    }
+                    false => None,
+                },
+                text: texts.is_valid(row).then(|| texts.value(row).to_string()),
+            });
+        }
+    }
+    Ok(ExportedEmbeddings {
+        model,
+        dimension,
+        chunks,
+    })
+}
+
+/// Path of the array `name` of the `.npy` export `path`: `path` itself for the embeddings.
+fn array_path(path: &Path, name: &str) -> PathBuf {
+    match name {
+        EMBEDDINGS => path.to_path_buf(),
+        _ => path.with_extension(format!("{name}.npy")),
+    }
+}
+
+fn embedding_item() -> Arc<Field> {
+    Arc::new(Field::new("item", DataType::Float32, false))
+}
+
+/// `<U` type of the longest of `strings`.
+fn unicode(strings: &[String]) -> TypeStr {
+    let len = strings.iter().map(|s| s.chars().count()).max().unwrap_or(0);
+    type_str(&format!("<U{}", len.max(1)))
+}
+
+/// `|S` type of the longest of `bytes`.
+fn bytes(bytes: &[Vec<u8>]) -> TypeStr {
+    let len = bytes.iter().map(Vec::len).max().unwrap_or(0);
+    type_str(&format!("|S{}", len.max(1)))
+}
+
+fn type_str(type_str: &str) -> TypeStr {
+    // Only ever given well formed type strings.
+    type_str.parse().expect("invalid type string")
+}
+
+fn metadata_json(metadata: &ChunkMetadata) -> String {
+    // Serializing plain strings and integers cannot fail.
+    serde_json::to_string(metadata).unwrap_or_default()
+}
+
+fn parse_metadata(json: &[u8]) -> Result<ChunkMetadata, String> {
+    serde_json::from_slice(json).map_err(|e| format!("invalid metadata: {e}"))
+}
+
+fn invalid_export(path: &Path, reason: impl ToString) -> EmbeddingsError {
+    EmbeddingsError::InvalidExport(format!("{}: {}", path.display(), reason.to_string()))
+}
+
+fn arrow_error(path: &Path, error: ArrowError) -> EmbeddingsError {
+    EmbeddingsError::Storage(format!("{}: {error}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::IndexConfig,
+        embedder::{Embedder, HashingEmbedder},
+        embeddings::Embeddings,
+        search::SearchParams,
+    };
+
+    const SENTENCES: [&str; 3] = [
+        "Hello world !",
+        "Knowledge graphs are great !",
+        "LLMs are amazing, as well !",
+    ];
+
+    fn id(id: u32) -> ChunkId {
+        ChunkId::from(id)
+    }
+
+    fn embeddings() -> Embeddings<HashingEmbedder> {
+        let mut embeddings = Embeddings::new_from_model(HashingEmbedder::new(16).unwrap());
+        for (i, sentence) in SENTENCES.iter().enumerate() {
+            let metadata = ChunkMetadata {
+                namespace: Some("acme".to_string()),
+                tags: vec![format!("tag-{i}")],
+                ..Default::default()
+            };
+            embeddings
+                .process_chunk_and_store_with_metadata(id(i as u32), sentence, metadata)
+                .unwrap();
+        }
+        // Neither metadata nor text beyond the ingestion time.
+        let (model, mut index) = embeddings.into_parts();
+        index
+            .insert(id(3), Embedding::new(vec![0.25; 16], 16).unwrap())
+            .unwrap();
+        Embeddings::from_parts(model, index).unwrap()
+    }
+
+    #[test]
+    fn test_export_and_import_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let embeddings = embeddings();
+        let expected = ExportedEmbeddings::from_index(embeddings.index()).unwrap();
+        assert_eq!(expected.chunks.len(), 4);
+
+        for file in ["chunks.npy", "chunks.npz", "chunks.arrow", "chunks.parquet"] {
+            let path = dir.path().join(file);
+            assert_eq!(embeddings.export(&path), Ok(4));
+            assert_eq!(
+                ExportedEmbeddings::read(&path).as_ref(),
+                Ok(&expected),
+                "{file}"
+            );
+
+            let mut imported = Embeddings::new_from_model(HashingEmbedder::new(16).unwrap());
+            assert_eq!(imported.import(&path), Ok(4));
+            for i in 0..4 {
+                assert_eq!(imported.get(id(i)), embeddings.get(id(i)));
+                assert_eq!(imported.chunk(id(i)), embeddings.chunk(id(i)));
+            }
+            let results = imported
+                .search_text("Are knowledge graphs great ?", &SearchParams::new(1))
+                .unwrap();
+            assert_eq!(results[0].id, id(1));
+        }
+        assert!(dir.path().join("chunks.ids.npy").exists());
+
+        // Empty exports are read back as such.
+        let empty = Embeddings::new_from_model(HashingEmbedder::new(16).unwrap());
+        for file in ["empty.npz", "empty.parquet"] {
+            let path = dir.path().join(file);
+            assert_eq!(empty.export(&path), Ok(0));
+            let exported = ExportedEmbeddings::read(&path).unwrap();
+            assert_eq!((exported.dimension, exported.chunks.len()), (16, 0));
+        }
+    }
+
+    #[test]
+    fn test_import_checks_dimension_and_model() {
+        let dir = tempfile::tempdir().unwrap();
+        let embeddings = embeddings();
+        let path = dir.path().join("chunks.parquet");
+        embeddings.export(&path).unwrap();
+
+        let mut other = Embeddings::new_from_model(HashingEmbedder::new(32).unwrap());
+        assert_eq!(
+            other.import(&path),
+            Err(EmbeddingsError::DimensionMismatch {
+                expected: 32,
+                found: 16
+            })
+        );
+        assert!(other.ids().is_empty());
+
+        let mut index = VectorIndex::new(16, IndexConfig::default())
+            .with_model(ModelTag::new("hashing-bow-16", "2"));
+        assert_eq!(
+            ExportedEmbeddings::read(&path)
+                .unwrap()
+                .load_into(&mut index),
+            Err(EmbeddingsError::ModelMismatch {
+                expected: embeddings.model().model_tag(),
+                found: ModelTag::new("hashing-bow-16", "2"),
+            })
+        );
+        assert!(index.is_empty());
+
+        // An index without a model takes the one of the file, and a file without one is refused.
+        let mut index = VectorIndex::new(16, IndexConfig::default());
+        let mut exported = ExportedEmbeddings::read(&path).unwrap();
+        assert_eq!(exported.clone().load_into(&mut index), Ok(4));
+        assert_eq!(index.model(), Some(&embeddings.model().model_tag()));
+        exported.model = None;
+        assert!(matches!(
+            exported.load_into(&mut index),
+            Err(EmbeddingsError::InvalidExport(_))
+        ));
+
+        assert!(matches!(
+            embeddings.export(dir.path().join("chunks.csv")),
+            Err(EmbeddingsError::InvalidExport(_))
+        ));
+        std::fs::write(dir.path().join("broken.npz"), b"not an archive").unwrap();
+        assert!(matches!(
+            other.import(dir.path().join("broken.npz")),
+            Err(EmbeddingsError::InvalidExport(_))
+        ));
+    }
+}
@@ -0,0 +1,552 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BinaryHeap, HashMap, HashSet},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{id::ChunkId, search::dot_product};
+
+/// Tuning parameters of the HNSW graph, see <https://arxiv.org/abs/1603.09320>.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct HnswParams {
+    /// Number of neighbours per node on the upper layers (twice as many on layer 0).
+    /// Higher values improve recall, at the cost of memory and insertion time.
+    pub m: usize,
+    /// Size of the candidate list while inserting. Higher values build a better graph.
+    pub ef_construction: usize,
+    /// Size of the candidate list while searching. Higher values improve recall,
+    /// at the cost of latency. It is never lower than the number of requested results.
+    pub ef_search: usize,
+    /// Seed of the level generator, so that graphs are reproducible.
+    pub seed: u64,
+}
+
+impl Default for HnswParams {
+    fn default() -> Self {
+        Self {
+            m: 16,
+            ef_construction: 200,
+            ef_search: 64,
+            seed: 0x5eed,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Scored {
+    similarity: f32,
+    node: usize,
+}
+
+impl Eq for Scored {}
+
+impl PartialOrd for Scored {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scored {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.similarity
+            .total_cmp(&other.similarity)
+            .then_with(|| self.node.cmp(&other.node))
+    }
+}
+
+/// Normalized vector of a node, either in full precision or as 8-bit integers.
+enum NodeVector {
+    Full(Vec<f32>),
+    /// Components scaled to `[-127, 127]`, which the unit norm bounds them to.
+    Int8(Vec<i8>),
+}
+
+impl NodeVector {
+    fn new(vector: Vec<f32>, int8: bool) -> Self {
+        if int8 {
+            Self::Int8(vector.iter().map(|x| (x * 127.0).round() as i8).collect())
+        } else {
+            Self::Full(vector)
+        }
+    }
+
+    fn dot(&self, query: &[f32]) -> f32 {
+        match self {
+            Self::Full(vector) => dot_product(query, vector),
+            Self::Int8(vector) => {
+                query
+                    .iter()
+                    .zip(vector)
+                    .map(|(x, &y)| x * y as f32)
+                    .sum::<f32>()
+                    / 127.0
+            }
+        }
+    }
+
+    fn to_f32(&self) -> Vec<f32> {
+        match self {
+            Self::Full(vector) => vector.clone(),
+            Self::Int8(vector) => vector.iter().map(|&x| x as f32 / 127.0).collect(),
+        }
+    }
+
+    fn memory_bytes(&self) -> usize {
+        match self {
+            Self::Full(vector) => 4 * vector.len(),
+            Self::Int8(vector) => vector.len(),
+        }
+    }
+}
+
+struct Node {
+    id: ChunkId,
+    vector: NodeVector,
+    /// Neighbours of the node, per layer, from layer 0 up to the node level.
+    neighbours: Vec<Vec<usize>>,
+    deleted: bool,
+}
+
+/// Hierarchical navigable small world graph, for approximate cosine similarity search.
+///
+/// Vectors are normalized on insertion, so that similarity is a plain dot product.
+/// Deletions mark nodes as tombstones, which are still traversed but never returned.
+/// Once tombstones make up for more than half of the graph, it is rebuilt.
+///
+/// Graphs built by [`HnswIndex::int8`] keep their vectors as 8-bit integers, a quarter of
+/// their full size: similarities are then approximate, and are meant to be re-scored.
+pub struct HnswIndex {
+    params: HnswParams,
+    int8: bool,
+    nodes: Vec<Node>,
+    ids: HashMap<ChunkId, usize>,
+    entry_point: Option<usize>,
+    level_multiplier: f64,
+    rng_state: u64,
+}
+
+impl HnswIndex {
+    pub fn new(params: HnswParams) -> Self {
+        let m = params.m.max(2);
+        Self {
+            params: HnswParams { m, ..params },
+            int8: false,
+            nodes: vec![],
+            ids: HashMap::new(),
+            entry_point: None,
+            level_multiplier: 1.0 / (m as f64).ln(),
+            rng_state: params.seed.max(1),
+        }
+    }
+
+    /// Graph keeping its vectors as 8-bit integers.
+    pub fn int8(params: HnswParams) -> Self {
+        Self {
+            int8: true,
+            ..Self::new(params)
+        }
+    }
+
+    pub fn params(&self) -> &HnswParams {
+        &self.params
+    }
+
+    pub fn set_ef_search(&mut self, ef_search: usize) {
+        self.params.ef_search = ef_search;
+    }
+
+    /// Number of live (non deleted) vectors in the index.
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    pub fn contains(&self, id: ChunkId) -> bool {
+        self.ids.contains_key(&id)
+    }
+
+    pub fn clear(&mut self) {
+        self.nodes.clear();
+        self.ids.clear();
+        self.entry_point = None;
+        self.rng_state = self.params.seed.max(1);
+    }
+
+    /// Inserts a new vector, replacing any previous vector with the same id.
+    pub fn insert(&mut self, id: ChunkId, vector: &[f32]) {
+        self.remove(id);
+
+        let vector = NodeVector::new(normalize(vector), self.int8);
+        let level = self.random_level();
+        let node = self.nodes.len();
+        self.nodes.push(Node {
+            id,
+            vector,
+            neighbours: vec![vec![]; level + 1],
+            deleted: false,
+        });
+        self.ids.insert(id, node);
+
+        let Some(entry_point) = self.entry_point else {
+            self.entry_point = Some(node);
+            return;
+        };
+
+        let query = self.nodes[node].vector.to_f32();
+        let top_level = self.level(entry_point);
+        let mut entry_points = vec![self.scored(&query, entry_point)];
+        for layer in (level + 1..=top_level).rev() {
+            entry_points = self.search_layer(&query, &entry_points, 1, layer, &|_| true);
+        }
+
+        for layer in (0..=level.min(top_level)).rev() {
+            let candidates = self.search_layer(
+                &query,
+                &entry_points,
+                self.params.ef_construction,
+                layer,
+                &|_| true,
+            );
+            let neighbours = candidates
+                .iter()
+                .take(self.params.m)
+                .map(|s| s.node)
+                .collect::<Vec<_>>();
+            for &neighbour in &neighbours {
+                self.nodes[neighbour].neighbours[layer].push(node);
+                self.prune(neighbour, layer);
+            }
+            self.nodes[node].neighbours[layer] = neighbours;
+            entry_points = candidates;
+        }
+
+        if level > top_level {
+            self.entry_point = Some(node);
+        }
+    }
+
+    /// Removes the vector with the given id, returning whether it was present.
+    pub fn remove(&mut self, id: ChunkId) -> bool {
+        let Some(node) = self.ids.remove(&id) else {
+            return false;
+        };
+        self.nodes[node].deleted = true;
+        if self.ids.is_empty() {
+            self.clear();
+        } else if self.nodes.len() > 2 * self.ids.len() {
+            self.rebuild();
+        }
+        true
+    }
+
+    /// Returns up to `k` ids, with their cosine similarity to `query`, most similar first.
+    pub fn search(&self, query: &[f32], k: usize) -> Vec<(ChunkId, f32)> {
+        self.search_filtered(query, k, |_| true)
+    }
+
+    /// Returns up to `k` ids accepted by `filter`, most similar first.
+    ///
+    /// The filter is applied while traversing the graph: rejected nodes are still visited,
+    /// to reach the accepted ones past them, but never take a place in the results.
+    pub fn search_filtered(
+        &self,
+        query: &[f32],
+        k: usize,
+        filter: impl Fn(ChunkId) -> bool,
+    ) -> Vec<(ChunkId, f32)> {
+        let Some(entry_point) = self.entry_point else {
+            return vec![];
+        };
+        if k == 0 {
+            return vec![];
+        }
+
+        let query = normalize(query);
+        let mut entry_points = vec![self.scored(&query, entry_point)];
+        for layer in (1..=self.level(entry_point)).rev() {
+            entry_points = self.search_layer(&query, &entry_points, 1, layer, &|_| true);
+        }
+        let ef = self.params.ef_search.max(k);
+        let accept = |node: usize| !self.nodes[node].deleted && filter(self.nodes[node].id);
+        self.search_layer(&query, &entry_points, ef, 0, &accept)
+            .into_iter()
+            .take(k)
+            .map(|s| (self.nodes[s.node].id, s.similarity))
+            .collect()
+    }
+
+    /// Rebuilds the graph from its live vectors, dropping tombstones.
+    pub fn rebuild(&mut self) {
+        let nodes = std::mem::take(&mut self.nodes);
+        self.clear();
+        for node in nodes.into_iter().filter(|n| !n.deleted) {
+            self.insert(node.id, &node.vector.to_f32());
+        }
+    }
+
+    /// Bytes taken by the vectors and links of the graph, tombstones included.
+    pub fn memory_bytes(&self) -> usize {
+        self.nodes
+            .iter()
+            .map(|node| {
+                node.vector.memory_bytes()
+                    + node
+                        .neighbours
+                        .iter()
+                        .map(|n| n.len() * std::mem::size_of::<usize>())
+                        .sum::<usize>()
+            })
+            .sum()
+    }
+
+    fn level(&self, node: usize) -> usize {
+        self.nodes[node].neighbours.len() - 1
+    }
+
+    fn scored(&self, query: &[f32], node: usize) -> Scored {
+        Scored {
+            similarity: self.nodes[node].vector.dot(query),
+            node,
+        }
+    }
+
+    /// Greedy beam search on a single layer, returning up to `ef` nodes, most similar first.
+    /// Nodes rejected by `accept` are traversed, but not returned.
+    fn search_layer(
+        &self,
+        query: &[f32],
+        entry_points: &[Scored],
+        ef: usize,
+        layer: usize,
+        accept: &dyn Fn(usize) -> bool,
+    ) -> Vec<Scored> {
+        let mut visited = entry_points.iter().map(|s| s.node).collect::<HashSet<_>>();
+        let mut candidates = entry_points.iter().copied().collect::<BinaryHeap<_>>();
+        let mut results = entry_points
+            .iter()
+            .copied()
+            .filter(|s| accept(s.node))
+            .map(Reverse)
+            .collect::<BinaryHeap<_>>();
+        while results.len() > ef {
+            results.pop();
+        }
+
+        while let Some(candidate) = candidates.pop() {
+            let worst = results.peek().map(|r| r.0.similarity).unwrap_or(f32::MIN);
+            if candidate.similarity < worst && results.len() >= ef {
+                break;
+            }
+            for &neighbour in &self.nodes[candidate.node].neighbours[layer] {
+                if !visited.insert(neighbour) {
+                    continue;
+                }
+                let scored = self.scored(query, neighbour);
+                let worst = results.peek().map(|r| r.0.similarity).unwrap_or(f32::MIN);
+                if results.len() < ef || scored.similarity > worst {
+                    candidates.push(scored);
+                    if accept(neighbour) {
+                        results.push(Reverse(scored));
+                        if results.len() > ef {
+                            results.pop();
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut results = results.into_iter().map(|r| r.0).collect::<Vec<_>>();
+        results.sort_by(|a, b| b.cmp(a));
+        results
+    }
+
+    /// Keeps only the most similar neighbours of `node`, once it has too many.
+    fn prune(&mut self, node: usize, layer: usize) {
+        let max_neighbours = if layer == 0 {
+            2 * self.params.m
+        } else {
+            self.params.m
+        };
+        if self.nodes[node].neighbours[layer].len() <= max_neighbours {
+            return;
+        }
+        let vector = self.nodes[node].vector.to_f32();
+        let mut neighbours = self.nodes[node].neighbours[layer]
+            .iter()
+            .map(|&n| self.scored(&vector, n))
+            .collect::<Vec<_>>();
+        neighbours.sort_by(|a, b| b.cmp(a));
+        self.nodes[node].neighbours[layer] = neighbours
+            .into_iter()
+            .take(max_neighbours)
+            .map(|s| s.node)
+            .collect();
+    }
+
+    fn random_level(&mut self) -> usize {
+        // xorshift64*, enough to draw levels from an exponential distribution.
+        self.rng_state ^= self.rng_state >> 12;
+        self.rng_state ^= self.rng_state << 25;
+        self.rng_state ^= self.rng_state >> 27;
+        let random = self.rng_state.wrapping_mul(0x2545f4914f6cdd1d);
+        let uniform = ((random >> 11) as f64 + 1.0) / (1u64 << 53) as f64;
+        (-uniform.ln() * self.level_multiplier) as usize
+    }
+}
+
+fn normalize(vector: &[f32]) -> Vec<f32> {
+    let norm = dot_product(vector, vector).sqrt();
+    if norm > 0.0 {
+        vector.iter().map(|x| x / norm).collect()
+    } else {
+        vector.to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vectors::random_vectors;
+
+    fn id(id: usize) -> ChunkId {
+        ChunkId::from(id as u32)
+    }
+
+    /// Positions in `vectors` of the `k` closest vectors to `query`.
+    fn exact_search(vectors: &[Vec<f32>], query: &[f32], k: usize) -> Vec<usize> {
+        let query = normalize(query);
+        let mut scored = vectors
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (i, dot_product(&normalize(v), &query)))
+            .collect::<Vec<_>>();
+        scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+        scored.into_iter().take(k).map(|(id, _)| id).collect()
+    }
+
+    #[test]
+    fn test_hnsw_recall() {
+        let vectors = random_vectors(1000, 32, 7);
+        let mut index = HnswIndex::new(HnswParams::default());
+        for (i, vector) in vectors.iter().enumerate() {
+            index.insert(id(i), vector);
+        }
+        assert_eq!(index.len(), 1000);
+
+        let queries = random_vectors(50, 32, 11);
+        let mut hits = 0;
+        for query in &queries {
+            let expected: Vec<ChunkId> = exact_search(&vectors, query, 10)
+                .into_iter()
+                .map(id)
+                .collect();
+            let found = index.search(query, 10);
+            assert_eq!(found.len(), 10);
+            assert!(found.windows(2).all(|w| w[0].1 >= w[1].1));
+            hits += found.iter().filter(|(id, _)| expected.contains(id)).count();
+        }
+        let recall = hits as f32 / (10 * queries.len()) as f32;
+        assert!(recall > 0.9, "recall is {recall}");
+    }
+
+    #[test]
+    fn test_hnsw_int8_recall() {
+        let vectors = random_vectors(1000, 32, 7);
+        let mut index = HnswIndex::int8(HnswParams::default());
+        let mut full = HnswIndex::new(HnswParams::default());
+        for (i, vector) in vectors.iter().enumerate() {
+            index.insert(id(i), vector);
+            full.insert(id(i), vector);
+        }
+        assert!(4 * index.memory_bytes() < 3 * full.memory_bytes());
+
+        let queries = random_vectors(50, 32, 11);
+        let mut hits = 0;
+        for query in &queries {
+            let expected: Vec<ChunkId> = exact_search(&vectors, query, 10)
+                .into_iter()
+                .map(id)
+                .collect();
+            let found = index.search(query, 20);
+            hits += found.iter().filter(|(id, _)| expected.contains(id)).count();
+        }
+        let recall = hits as f32 / (10 * queries.len()) as f32;
+        assert!(recall > 0.9, "recall is {recall}");
+    }
+
+    #[test]
+    fn test_hnsw_filtered_search() {
+        let vectors = random_vectors(1000, 32, 5);
+        let mut index = HnswIndex::new(HnswParams::default());
+        for (i, vector) in vectors.iter().enumerate() {
+            index.insert(id(i), vector);
+        }
+
+        // Only one vector in ten is accepted, yet the filtered search still fills its results.
+        let accepted = |id: ChunkId| u128::from_be_bytes(*id.as_bytes()) % 10 == 3;
+        let accepted_vectors: Vec<_> = vectors
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| accepted(id(*i)))
+            .map(|(_, v)| v.clone())
+            .collect();
+        let queries = random_vectors(20, 32, 13);
+        let mut hits = 0;
+        for query in &queries {
+            let expected: Vec<ChunkId> = exact_search(&accepted_vectors, query, 5)
+                .into_iter()
+                .map(|i| id(10 * i + 3))
+                .collect();
+            let found = index.search_filtered(query, 5, accepted);
+            assert_eq!(found.len(), 5);
+            assert!(found.iter().all(|(id, _)| accepted(*id)));
+            hits += found.iter().filter(|(id, _)| expected.contains(id)).count();
+        }
+        let recall = hits as f32 / (5 * queries.len()) as f32;
+        assert!(recall > 0.9, "recall is {recall}");
+    }
+
+    #[test]
+    fn test_hnsw_insert_and_remove() {
+        let vectors = random_vectors(100, 8, 3);
+        let mut index = HnswIndex::new(HnswParams {
+            m: 4,
+            ..Default::default()
+        });
+        for (i, vector) in vectors.iter().enumerate() {
+            index.insert(id(i), vector);
+        }
+
+        assert_eq!(index.search(&vectors[42], 1)[0].0, id(42));
+        assert!(index.remove(id(42)));
+        assert!(!index.remove(id(42)));
+        assert!(!index.contains(id(42)));
+        assert!(index
+            .search(&vectors[42], 100)
+            .iter()
+            .all(|(found, _)| *found != id(42)));
+        assert_eq!(index.search(&vectors[42], 200).len(), 99);
+
+        // Replacing a vector keeps a single entry for its id.
+        index.insert(id(7), &vectors[8]);
+        assert_eq!(index.len(), 99);
+
+        // Removing most vectors triggers a rebuild, which drops tombstones.
+        (0..90).for_each(|i| {
+            index.remove(id(i));
+        });
+        assert_eq!(index.len(), 10);
+        assert_eq!(index.search(&vectors[95], 1)[0].0, id(95));
+
+        (90..100).for_each(|i| {
+            index.remove(id(i));
+        });
+        assert!(index.is_empty());
+        assert!(index.search(&vectors[0], 1).is_empty());
+    }
+}
@@ -0,0 +1,108 @@
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use uuid::Uuid;
+
+use crate::error::EmbeddingsError;
+
+/// Identity of a stored chunk, shared by the vector store, the knowledge graph and the API.
+///
+/// New ids are UUIDv7, allocated once when a chunk is ingested: they are unique without any
+/// coordination between requests, and sort in ingestion order. Numeric ids, as stored before
+/// chunk ids existed, map to the UUID of the same value. Serialized as a hyphenated string,
+/// and deserialized from either form.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ChunkId(Uuid);
+
+impl ChunkId {
+    /// Allocates a new chunk id.
+    pub fn new() -> Self {
+        Self(Uuid::now_v7())
+    }
+
+    pub fn from_bytes(bytes: [u8; 16]) -> Self {
+        Self(Uuid::from_bytes(bytes))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        self.0.as_bytes()
+    }
+}
+
+impl Default for ChunkId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<u32> for ChunkId {
+    fn from(id: u32) -> Self {
+        Self(Uuid::from_u128(id as u128))
+    }
+}
+
+impl fmt::Display for ChunkId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.hyphenated().fmt(f)
+    }
+}
+
+impl FromStr for ChunkId {
+    type Err = EmbeddingsError;
+
+    fn from_str(id: &str) -> Result<Self, Self::Err> {
+        match id.parse::<u32>() {
+            Ok(id) => Ok(id.into()),
+            Err(_) => Uuid::parse_str(id)
+                .map(Self)
+                .map_err(|_| EmbeddingsError::InvalidChunkId(id.to_string())),
+        }
+    }
+}
+
+impl Serialize for ChunkId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ChunkId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Number(u32),
+            Text(String),
+        }
+
+        match Repr::deserialize(deserializer)? {
+            Repr::Number(id) => Ok(id.into()),
+            Repr::Text(id) => id.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_ids() {
+        let first = ChunkId::new();
+        let second = ChunkId::new();
+        assert!(first < second);
+
+        let json = serde_json::to_string(&first).unwrap();
+        assert_eq!(json, format!("\"{first}\""));
+        assert_eq!(serde_json::from_str::<ChunkId>(&json).unwrap(), first);
+        assert_eq!(first.to_string().parse::<ChunkId>(), Ok(first));
+
+        // Numeric ids, as numbers or strings.
+        let legacy = ChunkId::from(7);
+        assert_eq!(legacy.to_string(), "00000000-0000-0000-0000-000000000007");
+        assert_eq!(serde_json::from_str::<ChunkId>("7").unwrap(), legacy);
+        assert_eq!("7".parse::<ChunkId>(), Ok(legacy));
+        assert_eq!(ChunkId::from_bytes(*legacy.as_bytes()), legacy);
+        assert!("chunk-7".parse::<ChunkId>().is_err());
+    }
+}
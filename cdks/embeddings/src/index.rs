@@ -0,0 +1,1249 @@
+use std::collections::{HashMap, HashSet};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{IndexConfig, IndexKind, StoreConfig},
+    dedup::{ContentHash, DedupConfig, DuplicateMatch, DuplicatePolicy},
+    embedder::ModelTag,
+    embeddings::Embedding,
+    error::EmbeddingsError,
+    hnsw::HnswIndex,
+    id::ChunkId,
+    lexical::Bm25Index,
+    metadata::{
+        unix_timestamp, ChunkMetadata, ChunkPosition, Ingestion, IngestionStatus, MetadataFilter,
+        MetadataIndex,
+    },
+    quantization::{QuantizedVectors, Quantizer},
+    scan::{shard_count, top_k},
+    search::{
+        fuse, group_by_document, mmr, norm, rank, DocumentResults, Metric, QueryScorer,
+        SearchParams, SearchResult,
+    },
+    store::{VectorStore, WalEntry},
+    vectors::{FullVectors, VectorFile},
+};
+
+/// File of the store directory holding the full precision vectors, once quantized.
+const VECTORS_FILE: &str = "vectors.bin";
+
+/// An embedding to be stored, along with what is known of its chunk.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IndexEntry {
+    pub id: ChunkId,
+    pub embedding: Embedding,
+    pub metadata: ChunkMetadata,
+    /// Chunk text, indexed for lexical search.
+    pub text: Option<String>,
+}
+
+impl IndexEntry {
+    pub fn new(id: ChunkId, embedding: Embedding) -> Self {
+        Self {
+            id,
+            embedding,
+            metadata: ChunkMetadata::default(),
+            text: None,
+        }
+    }
+
+    pub fn with_metadata(mut self, metadata: ChunkMetadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    pub fn with_text(mut self, text: impl Into<String>) -> Self {
+        self.text = Some(text.into());
+        self
+    }
+}
+
+/// An embedding stored under `id`, as returned by [`VectorIndex::get`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoredEmbedding {
+    pub id: ChunkId,
+    pub embedding: Embedding,
+    pub metadata: Option<ChunkMetadata>,
+    /// Model that produced the embedding, unless stored before models were recorded.
+    pub model: Option<ModelTag>,
+}
+
+/// Text of a chunk stored under `id`, as returned by [`VectorIndex::chunk`].
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct StoredChunk {
+    pub id: ChunkId,
+    pub text: String,
+    pub hash: ContentHash,
+    /// URI the chunk was read from, as given in its metadata.
+    pub source: Option<String>,
+    /// Time the chunk was stored, in seconds since the Unix epoch.
+    pub ingested_at: Option<u64>,
+    /// Document the chunk was split from, and its position in it.
+    pub document_id: Option<String>,
+    pub position: Option<ChunkPosition>,
+    /// Knowledge graph extraction of the chunk, as given in its metadata.
+    pub ingestion: Option<Ingestion>,
+}
+
+/// Outcome of [`VectorIndex::claim_ingestion`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum IngestionClaim {
+    /// A chunk with the same content was ingested already, or is being ingested.
+    Existing(Box<StoredChunk>),
+    /// The extraction of a chunk with the same content failed, or was pending for too long,
+    /// and is claimed again under its id.
+    Retry(ChunkId),
+    /// The chunk was stored under its own id, unless it duplicates the given stored chunk.
+    Stored(Option<DuplicateMatch>),
+}
+
+/// Bytes held in memory by an index, per structure. Hash map overheads are left out.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct MemoryFootprint {
+    /// Full precision vectors, unless moved to the store directory.
+    pub vectors: usize,
+    /// Full precision vectors moved to the store directory, which are not held in memory.
+    pub vectors_on_disk: usize,
+    /// Quantized codes, their norms and codebook.
+    pub codes: usize,
+    /// Vectors and links of the HNSW graph.
+    pub graph: usize,
+    /// Chunk texts.
+    pub texts: usize,
+}
+
+impl MemoryFootprint {
+    /// Bytes held in memory overall.
+    pub fn total(&self) -> usize {
+        self.vectors + self.codes + self.graph + self.texts
+    }
+}
+
+/// Share of the exact nearest neighbours found by [`VectorIndex::search`], as measured by
+/// [`VectorIndex::measure_recall`].
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct RecallReport {
+    /// Number of stored vectors searched for.
+    pub queries: usize,
+    /// Results wanted per search.
+    pub k: u32,
+    /// Average share of the exact top `k` that the search returned.
+    pub recall: f32,
+    /// `1 - recall`.
+    pub recall_loss: f32,
+}
+
+/// Stored embeddings and their search structures, independent of the model producing them,
+/// so that several model workers can share one index. Each id holds a single embedding.
+pub struct VectorIndex {
+    dimension: usize,
+    // Model that produced every stored vector, once bound.
+    model: Option<ModelTag>,
+    ids: Vec<ChunkId>,
+    // Position of every id in `ids`, `vectors` and `quantized`.
+    positions: HashMap<ChunkId, usize>,
+    vectors: FullVectors,
+    // Codes of the vectors, once enough of them were stored to train the quantizer.
+    quantized: Option<QuantizedVectors>,
+    metadata: MetadataIndex,
+    texts: HashMap<ChunkId, String>,
+    lexical: Bm25Index,
+    hashes: HashMap<ContentHash, HashSet<ChunkId>>,
+    index_config: IndexConfig,
+    hnsw: Option<HnswIndex>,
+    store: Option<VectorStore>,
+    store_config: Option<StoreConfig>,
+}
+
+impl VectorIndex {
+    pub fn new(dimension: usize, index_config: IndexConfig) -> Self {
+        let hnsw = match index_config.kind {
+            IndexKind::Exact => None,
+            IndexKind::Hnsw if index_config.quantization.is_enabled() => {
+                Some(HnswIndex::int8(index_config.hnsw))
+            }
+            IndexKind::Hnsw => Some(HnswIndex::new(index_config.hnsw)),
+        };
+        Self {
+            dimension,
+            model: None,
+            ids: vec![],
+            positions: HashMap::new(),
+            vectors: FullVectors::new(dimension),
+            quantized: None,
+            metadata: MetadataIndex::default(),
+            texts: HashMap::new(),
+            lexical: Bm25Index::default(),
+            hashes: HashMap::new(),
+            index_config,
+            hnsw,
+            store: None,
+            store_config: None,
+        }
+    }
+
+    /// Tags the vectors of a new index with the model producing them.
+    pub fn with_model(mut self, model: ModelTag) -> Self {
+        self.model = Some(model);
+        self
+    }
+
+    /// Persists the embeddings to the store described by `config`, first loading any
+    /// embeddings it already holds.
+    pub fn with_store(mut self, config: &StoreConfig) -> Result<Self, EmbeddingsError> {
+        self.open_store(config)?;
+        Ok(self)
+    }
+
+    /// Rebuilds the index from the persisted store, or from the stored embeddings when not
+    /// persisted, e.g. after a panicking worker left it half updated.
+    pub fn reload(&mut self) -> Result<(), EmbeddingsError> {
+        match self.store_config.clone() {
+            Some(config) => {
+                // Closes the current log first, flushing whatever it buffered.
+                self.store = None;
+                self.open_store(&config)
+            }
+            None => {
+                self.quantized = None;
+                self.rebuild_hnsw()?;
+                self.rebuild_texts();
+                self.train_if_due()
+            }
+        }
+    }
+
+    fn open_store(&mut self, config: &StoreConfig) -> Result<(), EmbeddingsError> {
+        let (store, contents) = VectorStore::open(config, self.dimension)?;
+        let (ids, vectors): (Vec<_>, Vec<_>) = contents.embeddings.into_iter().unzip();
+        self.positions = positions(&ids);
+        self.ids = ids;
+        self.vectors = FullVectors::from_embeddings(self.dimension, vectors);
+        self.quantized = None;
+        self.metadata = MetadataIndex::from_entries(contents.metadata);
+        self.texts = contents.texts;
+        self.model = contents.model;
+        self.rebuild_hnsw()?;
+        self.rebuild_texts();
+        self.store = Some(store);
+        self.store_config = Some(config.clone());
+        self.train_if_due()
+    }
+
+    fn rebuild_hnsw(&mut self) -> Result<(), EmbeddingsError> {
+        if let Some(hnsw) = self.hnsw.as_mut() {
+            hnsw.clear();
+            for (position, id) in self.ids.iter().enumerate() {
+                hnsw.insert(*id, &self.vectors.get(position)?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Trains the quantizer on a sample of the stored vectors and encodes them all, once the
+    /// index holds enough of them. The full precision vectors of a persisted index then move
+    /// to its store directory.
+    fn train_if_due(&mut self) -> Result<(), EmbeddingsError> {
+        let config = self.index_config.quantization;
+        let sample_size = config.training_vectors.max(1);
+        if self.quantized.is_some() || !config.is_enabled() || self.len() < sample_size {
+            return Ok(());
+        }
+        let len = self.len();
+        let sample = (0..sample_size)
+            .map(|i| self.vectors.get(i * len / sample_size))
+            .collect::<Result<Vec<_>, _>>()?;
+        let sample: Vec<&[f32]> = sample.iter().map(AsRef::as_ref).collect();
+        let Some(quantizer) = Quantizer::train(&config, self.dimension, &sample) else {
+            return Ok(());
+        };
+        let mut quantized = QuantizedVectors::new(quantizer);
+        for position in 0..len {
+            quantized.set(position, &self.vectors.get(position)?);
+        }
+        info!(
+            "Trained {:?} quantization on {sample_size} of {len} vectors",
+            config.kind
+        );
+        self.quantized = Some(quantized);
+        self.move_vectors_to_store()
+    }
+
+    /// Moves the full precision vectors of a quantized and persisted index to its store
+    /// directory.
+    fn move_vectors_to_store(&mut self) -> Result<(), EmbeddingsError> {
+        if let (Some(store), Some(_), FullVectors::Memory { values, .. }) =
+            (&self.store, &self.quantized, &self.vectors)
+        {
+            let path = store.path().join(VECTORS_FILE);
+            let file = VectorFile::create(&path, self.dimension, values)?;
+            self.vectors = FullVectors::File(file);
+        }
+        Ok(())
+    }
+
+    fn rebuild_texts(&mut self) {
+        self.lexical.clear();
+        self.hashes.clear();
+        for (id, text) in std::mem::take(&mut self.texts) {
+            self.set_text(id, Some(text));
+        }
+    }
+
+    /// Sets the text of `id`, indexing it for lexical search and duplicate detection.
+    fn set_text(&mut self, id: ChunkId, text: Option<String>) {
+        if let Some(previous) = self.texts.remove(&id) {
+            self.lexical.remove(id);
+            let hash = ContentHash::of(&previous);
+            if let Some(ids) = self.hashes.get_mut(&hash) {
+                ids.remove(&id);
+                if ids.is_empty() {
+                    self.hashes.remove(&hash);
+                }
+            }
+        }
+        if let Some(text) = text {
+            self.lexical.insert(id, &text);
+            self.hashes
+                .entry(ContentHash::of(&text))
+                .or_default()
+                .insert(id);
+            self.texts.insert(id, text);
+        }
+    }
+
+    pub fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    /// Model that produced the stored vectors, once bound.
+    pub fn model(&self) -> Option<&ModelTag> {
+        self.model.as_ref()
+    }
+
+    /// Checks that the stored vectors were produced by `model`, recording it if no model was
+    /// yet: `ModelMismatch` if they were produced by another one.
+    pub fn bind_model(&mut self, model: &ModelTag) -> Result<(), EmbeddingsError> {
+        match &self.model {
+            Some(stored) if stored == model => Ok(()),
+            Some(stored) => Err(EmbeddingsError::ModelMismatch {
+                expected: stored.clone(),
+                found: model.clone(),
+            }),
+            None => {
+                let snapshot_due = match self.store.as_mut() {
+                    Some(store) => store.set_model(model.clone())?,
+                    None => false,
+                };
+                self.model = Some(model.clone());
+                self.snapshot_if(snapshot_due)
+            }
+        }
+    }
+
+    pub fn index_config(&self) -> &IndexConfig {
+        &self.index_config
+    }
+
+    /// Ids of the stored embeddings, in no particular order.
+    pub fn ids(&self) -> &[ChunkId] {
+        &self.ids
+    }
+
+    pub fn metadata(&self, id: ChunkId) -> Option<&ChunkMetadata> {
+        self.metadata.get(id)
+    }
+
+    /// Text of the chunk stored under `id`, if it was given.
+    pub fn text(&self, id: ChunkId) -> Option<&str> {
+        self.texts.get(&id).map(String::as_str)
+    }
+
+    pub fn contains(&self, id: ChunkId) -> bool {
+        self.positions.contains_key(&id)
+    }
+
+    /// Embedding stored under `id`: `NotFound` if there is none.
+    pub fn get(&self, id: ChunkId) -> Result<StoredEmbedding, EmbeddingsError> {
+        let position = *self
+            .positions
+            .get(&id)
+            .ok_or(EmbeddingsError::NotFound(id))?;
+        Ok(StoredEmbedding {
+            id,
+            embedding: self.embedding(position)?,
+            metadata: self.metadata.get(id).cloned(),
+            model: self.model.clone(),
+        })
+    }
+
+    fn embedding(&self, position: usize) -> Result<Embedding, EmbeddingsError> {
+        Embedding::new(self.vectors.get(position)?.into_owned(), self.dimension)
+    }
+
+    /// Chunk stored under `id`, if it was stored along with its text.
+    pub fn chunk(&self, id: ChunkId) -> Option<StoredChunk> {
+        let text = self.texts.get(&id)?;
+        let metadata = self.metadata.get(id);
+        Some(StoredChunk {
+            id,
+            text: text.clone(),
+            hash: ContentHash::of(text),
+            source: metadata.and_then(|metadata| metadata.source.clone()),
+            ingested_at: metadata.and_then(|metadata| metadata.ingested_at),
+            document_id: metadata.and_then(|metadata| metadata.document_id.clone()),
+            position: metadata.and_then(|metadata| metadata.position),
+            ingestion: metadata.and_then(|metadata| metadata.ingestion.clone()),
+        })
+    }
+
+    /// Chunk whose ingestion digest is `hash`, or else the first chunk stored with the content
+    /// hash `hash`.
+    pub fn chunk_by_hash(&self, hash: &ContentHash) -> Option<StoredChunk> {
+        let id = self.metadata.with_ingestion_hash(hash).or_else(|| {
+            self.hashes
+                .get(hash)
+                .and_then(|ids| ids.iter().min().copied())
+        })?;
+        self.chunk(id)
+    }
+
+    /// Ids of the chunks of `document_id`, in document order.
+    pub fn document(&self, document_id: &str) -> Vec<ChunkId> {
+        self.metadata.document(document_id)
+    }
+
+    /// Groups search `results` by the document of their chunks.
+    pub fn group_by_document(&self, results: Vec<SearchResult>) -> Vec<DocumentResults> {
+        group_by_document(results, |id| {
+            self.metadata
+                .get(id)
+                .and_then(|metadata| metadata.document_id.clone())
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// Bytes held in memory by the stored vectors, texts and search structures.
+    pub fn memory_footprint(&self) -> MemoryFootprint {
+        MemoryFootprint {
+            vectors: self.vectors.memory_bytes(),
+            vectors_on_disk: self.vectors.file_bytes(),
+            codes: self
+                .quantized
+                .as_ref()
+                .map_or(0, QuantizedVectors::memory_bytes),
+            graph: self.hnsw.as_ref().map_or(0, HnswIndex::memory_bytes),
+            texts: self.texts.values().map(String::len).sum(),
+        }
+    }
+
+    pub fn insert(&mut self, id: ChunkId, embedding: Embedding) -> Result<(), EmbeddingsError> {
+        self.insert_entries(vec![IndexEntry::new(id, embedding)])
+    }
+
+    /// Stores `entry`, replacing the embedding stored under its id, if any. The metadata of
+    /// the replaced embedding is kept when `entry` brings none. Returns whether an embedding
+    /// was replaced.
+    pub fn upsert(&mut self, mut entry: IndexEntry) -> Result<bool, EmbeddingsError> {
+        let replaced = self.contains(entry.id);
+        if entry.metadata == ChunkMetadata::default() {
+            if let Some(metadata) = self.metadata.get(entry.id) {
+                entry.metadata = ChunkMetadata {
+                    ingested_at: None,
+                    ..metadata.clone()
+                };
+            }
+        }
+        self.insert_entries(vec![entry])?;
+        Ok(replaced)
+    }
+
+    pub fn insert_with_metadata(
+        &mut self,
+        id: ChunkId,
+        embedding: Embedding,
+        metadata: ChunkMetadata,
+    ) -> Result<(), EmbeddingsError> {
+        self.insert_entries(vec![IndexEntry::new(id, embedding).with_metadata(metadata)])
+    }
+
+    /// Inserts `entries` in bulk, with a single write to the store. Either every entry is
+    /// inserted, or none is. An id already stored has its embedding and metadata replaced.
+    pub fn insert_batch(
+        &mut self,
+        entries: Vec<(ChunkId, Embedding)>,
+    ) -> Result<(), EmbeddingsError> {
+        self.insert_entries(
+            entries
+                .into_iter()
+                .map(|(id, embedding)| IndexEntry::new(id, embedding))
+                .collect(),
+        )
+    }
+
+    /// Inserts `entries` along with their metadata and texts, as [`Self::insert_batch`] does.
+    /// Missing ingestion times are set to the current time.
+    pub fn insert_entries(&mut self, entries: Vec<IndexEntry>) -> Result<(), EmbeddingsError> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        for entry in &entries {
+            self.check_dimension(&entry.embedding)?;
+        }
+        let now = unix_timestamp();
+        let entries: Vec<_> = entries
+            .into_iter()
+            .map(|mut entry| {
+                entry.metadata.ingested_at.get_or_insert(now);
+                entry
+            })
+            .collect();
+        let snapshot_due = match self.store.as_mut() {
+            Some(store) => store.append_all(
+                &entries
+                    .iter()
+                    .flat_map(|entry| {
+                        [
+                            Some(WalEntry::Insert(entry.id, entry.embedding.clone())),
+                            Some(WalEntry::Metadata(
+                                entry.id,
+                                Box::new(entry.metadata.clone()),
+                            )),
+                            entry
+                                .text
+                                .as_ref()
+                                .map(|text| WalEntry::Text(entry.id, text.clone())),
+                        ]
+                    })
+                    .flatten()
+                    .collect::<Vec<_>>(),
+            )?,
+            None => false,
+        };
+        for IndexEntry {
+            id,
+            embedding,
+            metadata,
+            text,
+        } in entries
+        {
+            if let Some(hnsw) = self.hnsw.as_mut() {
+                hnsw.insert(id, embedding.as_slice());
+            }
+            self.metadata.insert(id, metadata);
+            self.set_text(id, text);
+            self.set_vector(id, embedding)?;
+        }
+        self.train_if_due()?;
+        self.snapshot_if(snapshot_due)
+    }
+
+    /// Sets the full precision vector of `id`, and its code once quantized.
+    fn set_vector(&mut self, id: ChunkId, embedding: Embedding) -> Result<(), EmbeddingsError> {
+        let position = self.positions.get(&id).copied().unwrap_or(self.ids.len());
+        if let Some(quantized) = self.quantized.as_mut() {
+            quantized.set(position, embedding.as_slice());
+        }
+        self.vectors.set(position, embedding)?;
+        if position == self.ids.len() {
+            self.positions.insert(id, position);
+            self.ids.push(id);
+        }
+        Ok(())
+    }
+
+    /// Sets the knowledge graph extraction of the chunk stored under `id`, keeping the rest of
+    /// its metadata.
+    pub fn set_ingestion(
+        &mut self,
+        id: ChunkId,
+        ingestion: Ingestion,
+    ) -> Result<(), EmbeddingsError> {
+        if !self.contains(id) {
+            return Err(EmbeddingsError::NotFound(id));
+        }
+        let metadata = ChunkMetadata {
+            ingestion: Some(ingestion),
+            ..self.metadata.get(id).cloned().unwrap_or_default()
+        };
+        let snapshot_due = match self.store.as_mut() {
+            Some(store) => {
+                store.append_all(&[WalEntry::Metadata(id, Box::new(metadata.clone()))])?
+            }
+            None => false,
+        };
+        self.metadata.insert(id, metadata);
+        self.snapshot_if(snapshot_due)
+    }
+
+    /// Claims the knowledge graph extraction of `entry`, whose text is looked up by content
+    /// hash: a chunk already ingested, or being ingested, is returned, one whose extraction
+    /// failed, or was pending for over `pending_timeout` seconds, is claimed again, and
+    /// otherwise `entry` is stored as pending, deduplicated by `dedup` if set. Looking up and
+    /// storing under a single borrow, concurrent posts of a chunk claim it only once.
+    pub fn claim_ingestion(
+        &mut self,
+        mut entry: IndexEntry,
+        dedup: Option<&DedupConfig>,
+        pending_timeout: u64,
+    ) -> Result<IngestionClaim, EmbeddingsError> {
+        let now = unix_timestamp();
+        let claim = Ingestion {
+            claimed_at: Some(now),
+            ..Default::default()
+        };
+        let hash = ContentHash::of(entry.text.as_deref().unwrap_or_default());
+        if let Some(chunk) = self.chunk_by_hash(&hash) {
+            let retryable = |ingestion: &Ingestion| match ingestion.status {
+                IngestionStatus::Failed => true,
+                IngestionStatus::Pending => ingestion
+                    .claimed_at
+                    .is_none_or(|claimed_at| claimed_at + pending_timeout < now),
+                IngestionStatus::Extracted | IngestionStatus::Duplicate => false,
+            };
+            match &chunk.ingestion {
+                Some(ingestion) if retryable(ingestion) => {
+                    self.set_ingestion(chunk.id, claim)?;
+                    return Ok(IngestionClaim::Retry(chunk.id));
+                }
+                Some(_) => return Ok(IngestionClaim::Existing(Box::new(chunk))),
+                None => {}
+            }
+        }
+        entry.metadata.ingestion = Some(claim);
+        let duplicate = match dedup {
+            Some(dedup) => self.insert_deduplicated(entry, dedup)?,
+            None => {
+                self.insert_entries(vec![entry])?;
+                None
+            }
+        };
+        Ok(IngestionClaim::Stored(duplicate))
+    }
+
+    /// Stores `entry` unless it duplicates a stored chunk, as set by `dedup`. Returns the
+    /// stored chunk it duplicates, if any.
+    pub fn insert_deduplicated(
+        &mut self,
+        mut entry: IndexEntry,
+        dedup: &DedupConfig,
+    ) -> Result<Option<DuplicateMatch>, EmbeddingsError> {
+        let duplicate = self.find_duplicate(
+            entry.text.as_deref(),
+            &entry.embedding,
+            dedup.similarity_threshold,
+        )?;
+        match (duplicate, dedup.policy) {
+            (None, _) => self.insert_entries(vec![entry])?,
+            (Some(duplicate), DuplicatePolicy::Link) => {
+                entry.metadata.duplicate_of = Some(duplicate.id);
+                self.insert_entries(vec![entry])?;
+            }
+            (Some(_), DuplicatePolicy::Skip) => {}
+        }
+        Ok(duplicate)
+    }
+
+    /// Finds a stored chunk with the same content hash as `text`, or else the one most similar
+    /// to `embedding`, if its cosine similarity reaches `threshold`. Chunks linked to another
+    /// one as its duplicates are reported as that one.
+    pub fn find_duplicate(
+        &self,
+        text: Option<&str>,
+        embedding: &Embedding,
+        threshold: f32,
+    ) -> Result<Option<DuplicateMatch>, EmbeddingsError> {
+        let original = |id: ChunkId| {
+            self.metadata
+                .get(id)
+                .and_then(|metadata| metadata.duplicate_of)
+                .unwrap_or(id)
+        };
+        let copy = text
+            .and_then(|text| self.hashes.get(&ContentHash::of(text)))
+            .and_then(|ids| ids.iter().min());
+        if let Some(&id) = copy {
+            return Ok(Some(DuplicateMatch {
+                id: original(id),
+                score: 1.0,
+                exact: true,
+            }));
+        }
+        let params = SearchParams {
+            min_score: Some(threshold),
+            ..SearchParams::new(1)
+        };
+        Ok(self
+            .search(embedding, &params)?
+            .first()
+            .map(|result| DuplicateMatch {
+                id: original(result.id),
+                score: result.score,
+                exact: false,
+            }))
+    }
+
+    /// Inserts the successfully encoded `entries` in bulk, returning one result per entry.
+    pub fn insert_encoded(
+        &mut self,
+        entries: Vec<Result<IndexEntry, EmbeddingsError>>,
+    ) -> Vec<Result<(), EmbeddingsError>> {
+        let mut results = Vec::with_capacity(entries.len());
+        let mut batch = vec![];
+        for entry in entries {
+            match entry.and_then(|entry| {
+                self.check_dimension(&entry.embedding)?;
+                Ok(entry)
+            }) {
+                Ok(entry) => {
+                    batch.push(entry);
+                    results.push(Ok(()));
+                }
+                Err(e) => results.push(Err(e)),
+            }
+        }
+        if let Err(e) = self.insert_entries(batch) {
+            results
+                .iter_mut()
+                .filter(|result| result.is_ok())
+                .for_each(|result| *result = Err(e.clone()));
+        }
+        results
+    }
+
+    /// Deletes the embeddings of `ids`, with a single write to the store, returning one result
+    /// per id: ids with no stored embedding get `NotFound`.
+    pub fn delete(&mut self, ids: &[ChunkId]) -> Vec<Result<(), EmbeddingsError>> {
+        let mut found = HashSet::new();
+        let mut results: Vec<_> = ids
+            .iter()
+            .map(|&id| {
+                if !self.contains(id) {
+                    return Err(EmbeddingsError::NotFound(id));
+                }
+                found.insert(id);
+                Ok(())
+            })
+            .collect();
+        if found.is_empty() {
+            return results;
+        }
+
+        let deleted: Vec<ChunkId> = ids.iter().copied().filter(|id| found.remove(id)).collect();
+        let snapshot_due = match self.store.as_mut() {
+            Some(store) => store.append_all(
+                &deleted
+                    .iter()
+                    .map(|id| WalEntry::Delete(*id))
+                    .collect::<Vec<_>>(),
+            ),
+            None => Ok(false),
+        };
+        let result = snapshot_due.and_then(|snapshot_due| {
+            deleted.iter().try_for_each(|id| self.remove(*id))?;
+            self.snapshot_if(snapshot_due)
+        });
+        if let Err(e) = result {
+            results
+                .iter_mut()
+                .filter(|result| result.is_ok())
+                .for_each(|result| *result = Err(e.clone()));
+        }
+        results
+    }
+
+    /// Deletes every chunk of `document_id`, returning their ids.
+    pub fn delete_document(&mut self, document_id: &str) -> Result<Vec<ChunkId>, EmbeddingsError> {
+        let ids = self.document(document_id);
+        self.delete(&ids).into_iter().collect::<Result<(), _>>()?;
+        Ok(ids)
+    }
+
+    fn remove(&mut self, id: ChunkId) -> Result<(), EmbeddingsError> {
+        let Some(&position) = self.positions.get(&id) else {
+            return Ok(());
+        };
+        self.vectors.swap_remove(position)?;
+        if let Some(quantized) = self.quantized.as_mut() {
+            quantized.swap_remove(position);
+        }
+        self.positions.remove(&id);
+        self.ids.swap_remove(position);
+        if let Some(&moved) = self.ids.get(position) {
+            self.positions.insert(moved, position);
+        }
+        if let Some(hnsw) = self.hnsw.as_mut() {
+            hnsw.remove(id);
+        }
+        self.metadata.remove(id);
+        self.set_text(id, None);
+        Ok(())
+    }
+
+    fn snapshot_if(&mut self, snapshot_due: bool) -> Result<(), EmbeddingsError> {
+        match self.store.as_mut() {
+            Some(store) if snapshot_due => {
+                let data: Vec<_> = self
+                    .ids
+                    .iter()
+                    .copied()
+                    .zip(self.vectors.to_vec()?)
+                    .collect();
+                store.snapshot(&data, self.metadata.entries(), &self.texts)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn reset(&mut self) -> Result<Vec<Embedding>, EmbeddingsError> {
+        let embeddings = self.vectors.to_vec()?;
+        if let Some(store) = self.store.as_mut() {
+            store.snapshot(&[], &Default::default(), &Default::default())?;
+        }
+        if let Some(hnsw) = self.hnsw.as_mut() {
+            hnsw.clear();
+        }
+        self.vectors.clear()?;
+        self.quantized = None;
+        self.ids.clear();
+        self.metadata.clear();
+        self.positions.clear();
+        self.texts.clear();
+        self.rebuild_texts();
+        Ok(embeddings)
+    }
+
+    /// Replaces the whole index with `other`, e.g. built by a migration to another model,
+    /// which takes over the store, if any. The store is replaced atomically: the current
+    /// contents are snapshotted first, then those of `other` as a new snapshot.
+    pub fn replace_with(&mut self, mut other: VectorIndex) -> Result<(), EmbeddingsError> {
+        if let Some(store) = self.store.as_mut() {
+            let data: Vec<_> = self
+                .ids
+                .iter()
+                .copied()
+                .zip(self.vectors.to_vec()?)
+                .collect();
+            store.snapshot(&data, self.metadata.entries(), &self.texts)?;
+            let data: Vec<_> = other
+                .ids
+                .iter()
+                .copied()
+                .zip(other.vectors.to_vec()?)
+                .collect();
+            store.replace(
+                other.dimension,
+                other.model.clone(),
+                &data,
+                other.metadata.entries(),
+                &other.texts,
+            )?;
+        }
+        other.store = self.store.take();
+        other.store_config = self.store_config.take();
+        // Left behind by the current vectors, if moved to the store directory.
+        if let Err(e) = self.vectors.clear() {
+            warn!("Failed to remove the replaced vectors, with error: {e}");
+        }
+        *self = other;
+        self.move_vectors_to_store()
+    }
+
+    /// Finds the stored embeddings closest to `embedding`, best first. Cosine searches on
+    /// stores larger than the configured `exact_search_threshold` go through the HNSW
+    /// index, if enabled, while any other search scans the store exactly.
+    ///
+    /// With quantization enabled, the HNSW traversal or the scan of the codes picks
+    /// `rescore_factor` candidates per result wanted, which are then re-scored in full
+    /// precision.
+    ///
+    /// A metadata filter is applied before scoring: the HNSW traversal skips the chunks it
+    /// rejects, and a filter narrowing the store below the threshold is searched exactly.
+    ///
+    /// A zero query has no cosine similarity with anything, so cosine searches for it find
+    /// nothing, whichever way the store would be searched.
+    pub fn search(
+        &self,
+        embedding: &Embedding,
+        params: &SearchParams,
+    ) -> Result<Vec<SearchResult>, EmbeddingsError> {
+        if params.metric == Metric::Cosine && norm(embedding.as_slice()) == 0.0 {
+            self.check_dimension(embedding)?;
+            return Ok(vec![]);
+        }
+        if let Some(lambda) = params.mmr_lambda {
+            let results = self.search(embedding, &params.candidates())?;
+            return Ok(self.diversify(results, lambda, params));
+        }
+        let threshold = self.index_config.exact_search_threshold;
+        let filter = search_filter(params);
+        let selective = filter
+            .and_then(|filter| self.metadata.candidates(filter))
+            .is_some_and(|candidates| candidates.len() < threshold);
+        match &self.hnsw {
+            Some(hnsw)
+                if params.metric == Metric::Cosine && self.len() >= threshold && !selective =>
+            {
+                self.check_dimension(embedding)?;
+                let quantization = &self.index_config.quantization;
+                let num_queries = if quantization.is_enabled() {
+                    quantization.candidates(params.num_queries as usize)
+                } else {
+                    params.num_queries as usize
+                };
+                let results = match filter {
+                    Some(filter) => hnsw.search_filtered(embedding.as_slice(), num_queries, |id| {
+                        self.metadata.matches(id, filter)
+                    }),
+                    None => hnsw.search(embedding.as_slice(), num_queries),
+                };
+                if quantization.is_enabled() {
+                    return self.rescore(embedding, results.into_iter().map(|(id, _)| id), params);
+                }
+                let results = results
+                    .into_iter()
+                    .map(|(id, score)| SearchResult { id, score })
+                    .collect();
+                Ok(rank(results, params))
+            }
+            _ => match &self.quantized {
+                Some(quantized) => self.search_quantized(quantized, embedding, params),
+                None => self.search_exact(embedding, params),
+            },
+        }
+    }
+
+    /// Scans the codes of the stored vectors, and re-scores the best of them.
+    fn search_quantized(
+        &self,
+        quantized: &QuantizedVectors,
+        embedding: &Embedding,
+        params: &SearchParams,
+    ) -> Result<Vec<SearchResult>, EmbeddingsError> {
+        self.check_dimension(embedding)?;
+        let filter = search_filter(params);
+        let scorer = quantized.scorer(embedding.as_slice(), params.metric);
+        let k = self
+            .index_config
+            .quantization
+            .candidates(params.num_queries as usize);
+        let candidates = self.scan(k, filter, |position| Ok(quantized.score(&scorer, position)))?;
+        let ids = candidates
+            .into_iter()
+            .map(|(position, _)| self.ids[position]);
+        self.rescore(embedding, ids, params)
+    }
+
+    /// Best `k` positions accepted by `filter`, by `score`, scanning the shards of the index in
+    /// parallel.
+    fn scan(
+        &self,
+        k: usize,
+        filter: Option<&MetadataFilter>,
+        score: impl Fn(usize) -> Result<f32, EmbeddingsError> + Sync,
+    ) -> Result<Vec<(usize, f32)>, EmbeddingsError> {
+        let shards = shard_count(self.index_config.search_shards, self.len());
+        top_k(self.len(), k, shards, |position| {
+            if filter.is_some_and(|filter| !self.metadata.matches(self.ids[position], filter)) {
+                return Ok(None);
+            }
+            score(position).map(Some)
+        })
+    }
+
+    /// Ranks `ids` by the score of their full precision vectors against `embedding`.
+    fn rescore(
+        &self,
+        embedding: &Embedding,
+        ids: impl Iterator<Item = ChunkId>,
+        params: &SearchParams,
+    ) -> Result<Vec<SearchResult>, EmbeddingsError> {
+        let scorer = QueryScorer::new(params.metric, embedding.as_slice());
+        let results = ids
+            .filter_map(|id| Some((id, *self.positions.get(&id)?)))
+            .map(|(id, position)| {
+                Ok(SearchResult {
+                    id,
+                    score: scorer.score(&self.vectors.get(position)?),
+                })
+            })
+            .collect::<Result<_, EmbeddingsError>>()?;
+        Ok(rank(results, params))
+    }
+
+    /// Measures the share of the exact nearest neighbours that [`Self::search`] finds, as
+    /// configured, searching for `k` results of up to `queries` stored vectors evenly spread
+    /// over the index.
+    pub fn measure_recall(&self, queries: usize, k: u32) -> Result<RecallReport, EmbeddingsError> {
+        let queries = queries.min(self.len());
+        let params = SearchParams::new(k);
+        let (mut found, mut expected) = (0, 0);
+        for i in 0..queries {
+            let query = self.embedding(i * self.len() / queries)?;
+            let exact: HashSet<ChunkId> = self
+                .search_exact(&query, &params)?
+                .into_iter()
+                .map(|result| result.id)
+                .collect();
+            found += self
+                .search(&query, &params)?
+                .iter()
+                .filter(|result| exact.contains(&result.id))
+                .count();
+            expected += exact.len();
+        }
+        let recall = match expected {
+            0 => 1.0,
+            expected => found as f32 / expected as f32,
+        };
+        Ok(RecallReport {
+            queries,
+            k,
+            recall,
+            recall_loss: 1.0 - recall,
+        })
+    }
+
+    /// Ranks the stored chunks by BM25 score of `query` against their text. Chunks stored
+    /// without text are never found.
+    pub fn search_lexical(&self, query: &str, params: &SearchParams) -> Vec<SearchResult> {
+        if let Some(lambda) = params.mmr_lambda {
+            let results = self.search_lexical(query, &params.candidates());
+            return self.diversify(results, lambda, params);
+        }
+        let filter = search_filter(params);
+        let results = self
+            .lexical
+            .search(query, params.num_queries as usize, |id| {
+                filter.is_none_or(|filter| self.metadata.matches(id, filter))
+            });
+        rank(results, params)
+    }
+
+    /// Fuses the similarity search of `embedding` with the lexical search of `query`, the
+    /// text it was encoded from, as set by `params.hybrid`.
+    pub fn search_hybrid(
+        &self,
+        query: &str,
+        embedding: &Embedding,
+        params: &SearchParams,
+    ) -> Result<Vec<SearchResult>, EmbeddingsError> {
+        if let Some(lambda) = params.mmr_lambda {
+            let results = self.search_hybrid(query, embedding, &params.candidates())?;
+            return Ok(self.diversify(results, lambda, params));
+        }
+        // `min_score` is on the scale of the vector scores, so it filters the vector ranking,
+        // rather than the BM25 or fused scores.
+        let candidates = params.candidates();
+        let vector = self.search(embedding, &candidates)?;
+        let unfiltered = SearchParams {
+            min_score: None,
+            ..candidates
+        };
+        let lexical = self.search_lexical(query, &unfiltered);
+        let fused = SearchParams {
+            min_score: None,
+            ..params.clone()
+        };
+        Ok(rank(fuse(&vector, &lexical, &params.hybrid), &fused))
+    }
+
+    /// Picks `params.num_queries` of the ranked `results` by maximal marginal relevance, so
+    /// that near copies of a chunk do not crowd out the others.
+    fn diversify(
+        &self,
+        results: Vec<SearchResult>,
+        lambda: f32,
+        params: &SearchParams,
+    ) -> Vec<SearchResult> {
+        let candidates: Vec<_> = results
+            .into_iter()
+            .filter_map(|result| {
+                let position = *self.positions.get(&result.id)?;
+                match self.vectors.get(position) {
+                    Ok(vector) => Some((result, vector)),
+                    Err(e) => {
+                        warn!(
+                            "Leaving chunk {} out of diversified results: {e}",
+                            result.id
+                        );
+                        None
+                    }
+                }
+            })
+            .collect();
+        let candidates = candidates
+            .iter()
+            .map(|(result, vector)| (*result, vector.as_ref()))
+            .collect();
+        mmr(candidates, lambda, params.num_queries as usize)
+    }
+
+    /// Exact nearest neighbour search, scanning every stored embedding in full precision. The
+    /// store is split into `search_shards` shards, scanned in parallel.
+    pub fn search_exact(
+        &self,
+        embedding: &Embedding,
+        params: &SearchParams,
+    ) -> Result<Vec<SearchResult>, EmbeddingsError> {
+        self.check_dimension(embedding)?;
+        let scorer = QueryScorer::new(params.metric, embedding.as_slice());
+        let results = self
+            .scan(
+                params.num_queries as usize,
+                search_filter(params),
+                |position| Ok(scorer.score(&self.vectors.get(position)?)),
+            )?
+            .into_iter()
+            .map(|(position, score)| SearchResult {
+                id: self.ids[position],
+                score,
+            })
+            .collect();
+        Ok(rank(results, params))
+    }
+
+    fn check_dimension(&self, embedding: &Embedding) -> Result<(), EmbeddingsError> {
+        if embedding.dimension() != self.dimension {
+            return Err(EmbeddingsError::DimensionMismatch {
+                expected: self.dimension,
+                found: embedding.dimension(),
+            });
+        }
+        Ok(())
+    }
+}
+
+fn search_filter(params: &SearchParams) -> Option<&MetadataFilter> {
+    params.filter.as_ref().filter(|filter| !filter.is_empty())
+}
+
+fn positions(ids: &[ChunkId]) -> HashMap<ChunkId, usize> {
+    ids.iter()
+        .enumerate()
+        .map(|(position, id)| (*id, position))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        quantization::{QuantizationConfig, QuantizationKind},
+        vectors::random_vectors,
+    };
+
+    fn id(id: usize) -> ChunkId {
+        ChunkId::from(id as u32)
+    }
+
+    #[test]
+    fn test_zero_query_finds_nothing() {
+        let embeddings = random_vectors(100, 16, 7)
+            .into_iter()
+            .map(|values| Embedding::new(values, 16).unwrap())
+            .collect::<Vec<_>>();
+        let zero = Embedding::new(vec![0.0; 16], 16).unwrap();
+        for exact_search_threshold in [0, 1000] {
+            let mut index = VectorIndex::new(
+                16,
+                IndexConfig {
+                    exact_search_threshold,
+                    ..Default::default()
+                },
+            );
+            index
+                .insert_batch(
+                    embeddings
+                        .iter()
+                        .enumerate()
+                        .map(|(i, embedding)| (id(i), embedding.clone()))
+                        .collect(),
+                )
+                .unwrap();
+
+            assert!(index
+                .search(&zero, &SearchParams::new(5))
+                .unwrap()
+                .is_empty());
+            let params = SearchParams {
+                metric: Metric::DotProduct,
+                ..SearchParams::new(5)
+            };
+            assert_eq!(index.search(&zero, &params).unwrap().len(), 5);
+        }
+    }
+
+    #[test]
+    fn test_quantized_store() {
+        let embeddings = random_vectors(1000, 32, 7)
+            .into_iter()
+            .map(|values| Embedding::new(values, 32).unwrap())
+            .collect::<Vec<_>>();
+        for kind in [IndexKind::Exact, IndexKind::Hnsw] {
+            for quantization in [QuantizationKind::Scalar, QuantizationKind::Product] {
+                let dir = tempfile::tempdir().unwrap();
+                let store_config = StoreConfig::new(dir.path());
+                let index_config = IndexConfig {
+                    kind,
+                    exact_search_threshold: 0,
+                    quantization: QuantizationConfig {
+                        kind: quantization,
+                        training_vectors: 500,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                };
+                {
+                    let mut index = VectorIndex::new(32, index_config)
+                        .with_store(&store_config)
+                        .unwrap();
+                    index
+                        .insert_batch(
+                            embeddings
+                                .iter()
+                                .enumerate()
+                                .map(|(i, embedding)| (id(i), embedding.clone()))
+                                .collect(),
+                        )
+                        .unwrap();
+
+                    // Full precision vectors moved to the store directory, once quantized.
+                    let footprint = index.memory_footprint();
+                    assert_eq!(footprint.vectors, 0);
+                    assert_eq!(footprint.vectors_on_disk, 1000 * 4 * 32);
+                    assert!(2 * footprint.codes < footprint.vectors_on_disk);
+
+                    let report = index.measure_recall(50, 10).unwrap();
+                    assert_eq!(report.queries, 50);
+                    assert!(report.recall > 0.9, "{kind:?} {quantization:?}: {report:?}");
+                    assert_eq!(report.recall_loss, 1.0 - report.recall);
+
+                    assert_eq!(index.delete(&[id(0)]), vec![Ok(())]);
+                }
+
+                let index = VectorIndex::new(32, index_config)
+                    .with_store(&store_config)
+                    .unwrap();
+                assert_eq!(index.len(), 999);
+                assert_eq!(index.memory_footprint().vectors, 0);
+                let results = index
+                    .search(&embeddings[42], &SearchParams::new(3))
+                    .unwrap();
+                // Candidates are re-scored in full precision.
+                assert_eq!(results[0].id, id(42));
+                assert!((results[0].score - 1.0).abs() < 1e-5);
+                assert_eq!(index.get(id(42)).unwrap().embedding, embeddings[42]);
+            }
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{id::ChunkId, search::SearchResult};
+
+/// Term frequency saturation of BM25.
+const K1: f32 = 1.2;
+/// Document length normalization of BM25.
+const B: f32 = 0.75;
+
+/// BM25 inverted index over the chunk texts, keyed by the same ids as the vector index.
+///
+/// Lexical search finds exact identifiers, product codes and rare names, which embeddings
+/// tend to blur.
+#[derive(Debug, Default)]
+pub struct Bm25Index {
+    // Term frequencies of every indexed chunk.
+    documents: HashMap<ChunkId, HashMap<String, u32>>,
+    // Chunks containing every term.
+    postings: HashMap<String, HashSet<ChunkId>>,
+    // Sum of the number of terms of every chunk.
+    total_terms: u64,
+}
+
+impl Bm25Index {
+    pub fn len(&self) -> usize {
+        self.documents.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.documents.is_empty()
+    }
+
+    /// Indexes `text` under `id`, replacing the text previously indexed there.
+    pub fn insert(&mut self, id: ChunkId, text: &str) {
+        self.remove(id);
+        let mut frequencies: HashMap<String, u32> = HashMap::new();
+        for term in tokenize(text) {
+            *frequencies.entry(term).or_default() += 1;
+        }
+        for term in frequencies.keys() {
+            self.postings.entry(term.clone()).or_default().insert(id);
+        }
+        self.total_terms += frequencies.values().map(|&f| f as u64).sum::<u64>();
+        self.documents.insert(id, frequencies);
+    }
+
+    pub fn remove(&mut self, id: ChunkId) -> bool {
+        let Some(frequencies) = self.documents.remove(&id) else {
+            return false;
+        };
+        for term in frequencies.keys() {
+            if let Some(ids) = self.postings.get_mut(term) {
+                ids.remove(&id);
+                if ids.is_empty() {
+                    self.postings.remove(term);
+                }
+            }
+        }
+        self.total_terms -= frequencies.values().map(|&f| f as u64).sum::<u64>();
+        true
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Returns up to `k` chunks accepted by `filter`, by decreasing BM25 score of `query`.
+    /// Chunks sharing no term with the query are not returned.
+    pub fn search(
+        &self,
+        query: &str,
+        k: usize,
+        filter: impl Fn(ChunkId) -> bool,
+    ) -> Vec<SearchResult> {
+        if self.documents.is_empty() {
+            return vec![];
+        }
+        let num_documents = self.documents.len() as f32;
+        let average_length = self.total_terms as f32 / num_documents;
+        let terms: HashSet<String> = tokenize(query).collect();
+
+        let mut scores: HashMap<ChunkId, f32> = HashMap::new();
+        for term in &terms {
+            let Some(ids) = self.postings.get(term) else {
+                continue;
+            };
+            let document_frequency = ids.len() as f32;
+            let idf = (1.0
+                + (num_documents - document_frequency + 0.5) / (document_frequency + 0.5))
+                .ln();
+            for &id in ids.iter().filter(|id| filter(**id)) {
+                let frequencies = &self.documents[&id];
+                let frequency = frequencies[term] as f32;
+                let length = frequencies.values().sum::<u32>() as f32;
+                let norm = K1 * (1.0 - B + B * length / average_length.max(f32::EPSILON));
+                *scores.entry(id).or_default() += idf * frequency * (K1 + 1.0) / (frequency + norm);
+            }
+        }
+
+        let mut results: Vec<SearchResult> = scores
+            .into_iter()
+            .map(|(id, score)| SearchResult { id, score })
+            .collect();
+        results.sort_by(|r1, r2| r2.score.total_cmp(&r1.score).then(r1.id.cmp(&r2.id)));
+        results.truncate(k);
+        results
+    }
+}
+
+/// Splits `text` into lowercase alphanumeric terms.
+fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|term| !term.is_empty())
+        .map(str::to_lowercase)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(id: u32) -> ChunkId {
+        ChunkId::from(id)
+    }
+
+    fn ids(results: Vec<SearchResult>) -> Vec<ChunkId> {
+        results.iter().map(|r| r.id).collect()
+    }
+
+    #[test]
+    fn test_bm25_ranks_rare_terms_first() {
+        let mut index = Bm25Index::default();
+        index.insert(id(0), "The router model XR-2000 ships with two antennas.");
+        index.insert(id(1), "The router ships with a power cable.");
+        index.insert(id(2), "Our routers, the best routers, ship worldwide.");
+        index.insert(id(3), "Knowledge graphs are great !");
+
+        assert_eq!(
+            ids(index.search("xr-2000 router", 10, |_| true)),
+            vec![id(0), id(1)]
+        );
+        assert_eq!(
+            ids(index.search("XR-2000", 10, |found| found != id(0))),
+            Vec::<ChunkId>::new()
+        );
+        assert_eq!(ids(index.search("graphs", 1, |_| true)), vec![id(3)]);
+
+        // Replacing and removing texts update the postings.
+        index.insert(id(3), "A second XR-2000 manual.");
+        assert_eq!(ids(index.search("xr 2000", 10, |_| true)).len(), 2);
+        assert!(index.remove(id(0)));
+        assert!(!index.remove(id(0)));
+        assert_eq!(ids(index.search("xr 2000", 10, |_| true)), vec![id(3)]);
+        assert!(index.search("graphs", 10, |_| true).is_empty());
+        assert_eq!(index.len(), 3);
+    }
+}
@@ -1,2 +1,22 @@
+pub mod cache;
+pub mod chunker;
+pub mod client;
+pub mod config;
+pub mod dedup;
+pub mod embedder;
 pub mod embeddings;
+pub mod error;
+pub mod export;
+pub mod hnsw;
+pub mod id;
+pub mod index;
+pub mod lexical;
+pub mod local_model;
+pub mod metadata;
+pub mod migration;
+pub mod quantization;
+pub mod scan;
+pub mod search;
 pub mod service;
+pub mod store;
+pub mod vectors;
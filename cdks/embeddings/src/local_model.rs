@@ -0,0 +1,316 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+use serde_json::Value;
+
+const TRANSFORMER_MODULE: &str = "sentence_transformers.models.Transformer";
+const POOLING_MODULE: &str = "sentence_transformers.models.Pooling";
+const DENSE_MODULE: &str = "sentence_transformers.models.Dense";
+
+#[derive(Debug)]
+pub enum LocalModelError {
+    /// The model directory does not exist, or is not a directory.
+    NotADirectory(PathBuf),
+    /// A file required to load the model is missing.
+    MissingFile(PathBuf),
+    /// A configuration file could not be read or parsed.
+    InvalidConfig { path: PathBuf, reason: String },
+    /// The transformer architecture is not supported for sentence embeddings.
+    UnsupportedModelType(String),
+    /// The embedding dimension of the model does not match the expected one.
+    DimensionMismatch { expected: usize, found: usize },
+}
+
+impl fmt::Display for LocalModelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotADirectory(path) => {
+                write!(f, "Model directory {} does not exist", path.display())
+            }
+            Self::MissingFile(path) => write!(f, "Missing model file {}", path.display()),
+            Self::InvalidConfig { path, reason } => {
+                write!(f, "Invalid model config {}: {reason}", path.display())
+            }
+            Self::UnsupportedModelType(model_type) => write!(
+                f,
+                "Unsupported transformer model type {model_type} for sentence embeddings"
+            ),
+            Self::DimensionMismatch { expected, found } => write!(
+                f,
+                "Model embedding dimension is {found}, but {expected} was expected"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LocalModelError {}
+
+#[derive(Debug, Deserialize)]
+struct ModuleConfig {
+    path: String,
+    #[serde(rename = "type")]
+    module_type: String,
+}
+
+/// Validated layout of a local sentence-transformers model directory, as expected by
+/// `rust_bert::pipelines::sentence_embeddings::SentenceEmbeddingsBuilder::local`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LocalModelFiles {
+    pub model_dir: PathBuf,
+    pub model_type: String,
+    pub dimension: usize,
+}
+
+impl LocalModelFiles {
+    /// Checks that every file needed to load the model is present, and resolves the
+    /// embedding dimension from the pooling (and, if any, dense) module configs.
+    pub fn validate<P: AsRef<Path>>(model_dir: P) -> Result<Self, LocalModelError> {
+        let model_dir = model_dir.as_ref().to_path_buf();
+        if !model_dir.is_dir() {
+            return Err(LocalModelError::NotADirectory(model_dir));
+        }
+
+        let modules_path = model_dir.join("modules.json");
+        let modules: Vec<ModuleConfig> = read_json(&modules_path)?;
+        if !modules.iter().any(|m| m.module_type == TRANSFORMER_MODULE) {
+            return Err(LocalModelError::InvalidConfig {
+                path: modules_path,
+                reason: "no transformer module".to_string(),
+            });
+        }
+        let pooling = modules
+            .iter()
+            .find(|m| m.module_type == POOLING_MODULE)
+            .ok_or_else(|| LocalModelError::InvalidConfig {
+                path: modules_path.clone(),
+                reason: "no pooling module".to_string(),
+            })?;
+
+        let config_path = model_dir.join("config.json");
+        let config: Value = read_json(&config_path)?;
+        let model_type = config["model_type"]
+            .as_str()
+            .ok_or_else(|| LocalModelError::InvalidConfig {
+                path: config_path.clone(),
+                reason: "missing `model_type`".to_string(),
+            })?
+            .to_string();
+
+        let tokenizer_files: &[&str] = match model_type.as_str() {
+            "bert" | "distilbert" => &["vocab.txt"],
+            "roberta" => &["vocab.json", "merges.txt"],
+            "albert" | "t5" => &["spiece.model"],
+            _ => return Err(LocalModelError::UnsupportedModelType(model_type)),
+        };
+        for file in [
+            "rust_model.ot",
+            "tokenizer_config.json",
+            "sentence_bert_config.json",
+        ]
+        .iter()
+        .chain(tokenizer_files)
+        {
+            require_file(model_dir.join(file))?;
+        }
+
+        let pooling_config_path = model_dir.join(&pooling.path).join("config.json");
+        let pooling_config: Value = read_json(&pooling_config_path)?;
+        let mut dimension = read_dimension(
+            &pooling_config,
+            "word_embedding_dimension",
+            &pooling_config_path,
+        )?;
+
+        if let Some(dense) = modules.iter().find(|m| m.module_type == DENSE_MODULE) {
+            let dense_dir = model_dir.join(&dense.path);
+            require_file(dense_dir.join("rust_model.ot"))?;
+            let dense_config_path = dense_dir.join("config.json");
+            let dense_config: Value = read_json(&dense_config_path)?;
+            let in_features = read_dimension(&dense_config, "in_features", &dense_config_path)?;
+            if in_features != dimension {
+                return Err(LocalModelError::InvalidConfig {
+                    path: dense_config_path,
+                    reason: format!(
+                        "dense module expects {in_features} input features, but pooling outputs {dimension}"
+                    ),
+                });
+            }
+            dimension = read_dimension(&dense_config, "out_features", &dense_config_path)?;
+        }
+
+        Ok(Self {
+            model_dir,
+            model_type,
+            dimension,
+        })
+    }
+
+    /// Checks the resolved dimension against an expected one, if any.
+    pub fn check_dimension(&self, expected: Option<usize>) -> Result<(), LocalModelError> {
+        match expected {
+            Some(expected) if expected != self.dimension => {
+                Err(LocalModelError::DimensionMismatch {
+                    expected,
+                    found: self.dimension,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Model identifier, derived from the model directory name.
+    pub fn model_id(&self) -> String {
+        let name = self
+            .model_dir
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| self.model_dir.display().to_string());
+        format!("local/{name}")
+    }
+
+    /// Model version, as the first 16 hexadecimal digits of the BLAKE3 hash of the transformer
+    /// config and weights, so that retrained weights under the same directory name are told
+    /// apart.
+    pub fn version(&self) -> Result<String, LocalModelError> {
+        let mut hasher = blake3::Hasher::new();
+        for file in ["config.json", "rust_model.ot"] {
+            let path = self.model_dir.join(file);
+            std::fs::File::open(&path)
+                .and_then(|mut file| std::io::copy(&mut file, &mut hasher))
+                .map_err(|e| LocalModelError::InvalidConfig {
+                    path,
+                    reason: e.to_string(),
+                })?;
+        }
+        Ok(hasher.finalize().to_hex()[..16].to_string())
+    }
+}
+
+fn require_file(path: PathBuf) -> Result<(), LocalModelError> {
+    if path.is_file() {
+        Ok(())
+    } else {
+        Err(LocalModelError::MissingFile(path))
+    }
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T, LocalModelError> {
+    require_file(path.to_path_buf())?;
+    let content = std::fs::read_to_string(path).map_err(|e| LocalModelError::InvalidConfig {
+        path: path.to_path_buf(),
+        reason: e.to_string(),
+    })?;
+    serde_json::from_str(&content).map_err(|e| LocalModelError::InvalidConfig {
+        path: path.to_path_buf(),
+        reason: e.to_string(),
+    })
+}
+
+fn read_dimension(config: &Value, key: &str, path: &Path) -> Result<usize, LocalModelError> {
+    config[key]
+        .as_u64()
+        .filter(|d| *d > 0)
+        .map(|d| d as usize)
+        .ok_or_else(|| LocalModelError::InvalidConfig {
+            path: path.to_path_buf(),
+            reason: format!("missing or invalid `{key}`"),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_model_dir(dir: &Path, with_dense: bool) {
+        let mut modules = vec![
+            r#"{"idx":0,"name":"0","path":"","type":"sentence_transformers.models.Transformer"}"#,
+            r#"{"idx":1,"name":"1","path":"1_Pooling","type":"sentence_transformers.models.Pooling"}"#,
+        ];
+        if with_dense {
+            modules.push(
+                r#"{"idx":2,"name":"2","path":"2_Dense","type":"sentence_transformers.models.Dense"}"#,
+            );
+        }
+        std::fs::write(dir.join("modules.json"), format!("[{}]", modules.join(","))).unwrap();
+        std::fs::write(dir.join("config.json"), r#"{"model_type":"bert"}"#).unwrap();
+        for file in [
+            "rust_model.ot",
+            "tokenizer_config.json",
+            "sentence_bert_config.json",
+            "vocab.txt",
+        ] {
+            std::fs::write(dir.join(file), "").unwrap();
+        }
+        std::fs::create_dir_all(dir.join("1_Pooling")).unwrap();
+        std::fs::write(
+            dir.join("1_Pooling/config.json"),
+            r#"{"word_embedding_dimension":384}"#,
+        )
+        .unwrap();
+        if with_dense {
+            std::fs::create_dir_all(dir.join("2_Dense")).unwrap();
+            std::fs::write(
+                dir.join("2_Dense/config.json"),
+                r#"{"in_features":384,"out_features":768}"#,
+            )
+            .unwrap();
+            std::fs::write(dir.join("2_Dense/rust_model.ot"), "").unwrap();
+        }
+    }
+
+    #[test]
+    fn test_validate_local_model_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        write_model_dir(dir.path(), false);
+
+        let files = LocalModelFiles::validate(dir.path()).unwrap();
+        assert_eq!(files.dimension, 384);
+        assert_eq!(files.model_type, "bert");
+        let version = files.version().unwrap();
+        assert_eq!(version.len(), 16);
+        std::fs::write(dir.path().join("rust_model.ot"), "retrained").unwrap();
+        assert_ne!(files.version().unwrap(), version);
+        assert!(files.check_dimension(Some(384)).is_ok());
+        assert!(matches!(
+            files.check_dimension(Some(768)),
+            Err(LocalModelError::DimensionMismatch {
+                expected: 768,
+                found: 384
+            })
+        ));
+    }
+
+    #[test]
+    fn test_validate_local_model_dir_with_dense_module() {
+        let dir = tempfile::tempdir().unwrap();
+        write_model_dir(dir.path(), true);
+
+        let files = LocalModelFiles::validate(dir.path()).unwrap();
+        assert_eq!(files.dimension, 768);
+    }
+
+    #[test]
+    fn test_validate_local_model_dir_missing_files() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(matches!(
+            LocalModelFiles::validate(dir.path().join("missing")),
+            Err(LocalModelError::NotADirectory(_))
+        ));
+
+        write_model_dir(dir.path(), false);
+        std::fs::remove_file(dir.path().join("vocab.txt")).unwrap();
+        match LocalModelFiles::validate(dir.path()) {
+            Err(LocalModelError::MissingFile(path)) => assert!(path.ends_with("vocab.txt")),
+            other => panic!("Unexpected result: {other:?}"),
+        }
+
+        std::fs::write(dir.path().join("config.json"), r#"{"model_type":"gpt2"}"#).unwrap();
+        assert!(matches!(
+            LocalModelFiles::validate(dir.path()),
+            Err(LocalModelError::UnsupportedModelType(_))
+        ));
+    }
+}
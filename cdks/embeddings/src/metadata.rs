@@ -0,0 +1,365 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{dedup::ContentHash, id::ChunkId};
+
+/// Metadata attached to a stored chunk.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ChunkMetadata {
+    /// Corpus the chunk belongs to, e.g. a single customer.
+    pub namespace: Option<String>,
+    pub document_id: Option<String>,
+    /// URI the chunk was read from.
+    pub source: Option<String>,
+    /// Creation time of the chunk content, in seconds since the Unix epoch.
+    pub created_at: Option<u64>,
+    /// Time the chunk was stored, in seconds since the Unix epoch. Set on insertion, if missing.
+    pub ingested_at: Option<u64>,
+    pub tags: Vec<String>,
+    /// Stored chunk this one was found to duplicate on ingestion.
+    pub duplicate_of: Option<ChunkId>,
+    /// Position of the chunk in the document `document_id`, if it was split from one.
+    pub position: Option<ChunkPosition>,
+    /// Extraction of the knowledge graph of the chunk, for chunks ingested by the server.
+    pub ingestion: Option<Ingestion>,
+}
+
+impl ChunkMetadata {
+    /// Time used by date filters: `created_at` if known, otherwise `ingested_at`.
+    pub fn timestamp(&self) -> Option<u64> {
+        self.created_at.or(self.ingested_at)
+    }
+}
+
+/// Position of a chunk in the document it was split from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ChunkPosition {
+    /// Section of the document the chunk belongs to.
+    pub section: u32,
+    /// Position of the chunk among the chunks of the document.
+    pub index: u32,
+    /// Byte offset of the chunk start in the document.
+    pub start: u64,
+    /// Byte offset of the chunk end in the document, exclusive.
+    pub end: u64,
+}
+
+/// Where the knowledge graph extraction of a chunk stands.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IngestionStatus {
+    /// The chunk is stored, and its knowledge graph is being extracted. Claimed again once
+    /// pending for too long, e.g. if the server stopped mid-extraction.
+    #[default]
+    Pending,
+    /// The knowledge graph was extracted, and sent to Neo4j.
+    Extracted,
+    /// The extraction failed, and may be retried by posting the chunk again.
+    Failed,
+    /// The chunk duplicates a stored one, whose knowledge graph it shares.
+    Duplicate,
+}
+
+/// Knowledge graph extraction of a stored chunk.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Ingestion {
+    pub status: IngestionStatus,
+    /// Digest of the chunk and of its knowledge graph, set once extracted. See
+    /// [`ContentHash::of_extraction`].
+    pub hash: Option<ContentHash>,
+    /// Knowledge graph extracted from the chunk, if the LLM found one.
+    pub knowledge_graph: Option<String>,
+    /// Time the extraction was last claimed, in seconds since the Unix epoch.
+    pub claimed_at: Option<u64>,
+}
+
+/// Restricts a search to the chunks whose metadata meets every given condition.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MetadataFilter {
+    pub namespace: Option<String>,
+    pub document_id: Option<String>,
+    pub source: Option<String>,
+    /// Earliest chunk timestamp, inclusive, in seconds since the Unix epoch.
+    pub since: Option<u64>,
+    /// Latest chunk timestamp, inclusive, in seconds since the Unix epoch.
+    pub until: Option<u64>,
+    /// Tags that chunks must all carry.
+    pub tags: Vec<String>,
+}
+
+impl MetadataFilter {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn matches(&self, metadata: &ChunkMetadata) -> bool {
+        let equals = |condition: &Option<String>, value: &Option<String>| {
+            condition.is_none() || condition == value
+        };
+        let timestamp = metadata.timestamp();
+        equals(&self.namespace, &metadata.namespace)
+            && equals(&self.document_id, &metadata.document_id)
+            && equals(&self.source, &metadata.source)
+            && self
+                .since
+                .is_none_or(|since| timestamp.is_some_and(|t| t >= since))
+            && self
+                .until
+                .is_none_or(|until| timestamp.is_some_and(|t| t <= until))
+            && self.tags.iter().all(|tag| metadata.tags.contains(tag))
+    }
+}
+
+/// Metadata of the stored chunks, by id.
+///
+/// Namespaces, documents and tags are indexed, so that a filter on any of them narrows the
+/// candidates down before a single vector gets scored.
+#[derive(Debug, Default)]
+pub struct MetadataIndex {
+    entries: HashMap<ChunkId, ChunkMetadata>,
+    by_namespace: HashMap<String, HashSet<ChunkId>>,
+    by_document: HashMap<String, HashSet<ChunkId>>,
+    by_tag: HashMap<String, HashSet<ChunkId>>,
+    by_ingestion_hash: HashMap<ContentHash, ChunkId>,
+}
+
+impl MetadataIndex {
+    pub fn from_entries(entries: HashMap<ChunkId, ChunkMetadata>) -> Self {
+        let mut index = Self::default();
+        entries
+            .into_iter()
+            .for_each(|(id, metadata)| index.insert(id, metadata));
+        index
+    }
+
+    pub fn entries(&self) -> &HashMap<ChunkId, ChunkMetadata> {
+        &self.entries
+    }
+
+    pub fn get(&self, id: ChunkId) -> Option<&ChunkMetadata> {
+        self.entries.get(&id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Sets the metadata of `id`, replacing the previous one.
+    pub fn insert(&mut self, id: ChunkId, metadata: ChunkMetadata) {
+        self.remove(id);
+        if let Some(namespace) = &metadata.namespace {
+            posting(&mut self.by_namespace, namespace).insert(id);
+        }
+        if let Some(document_id) = &metadata.document_id {
+            posting(&mut self.by_document, document_id).insert(id);
+        }
+        for tag in &metadata.tags {
+            posting(&mut self.by_tag, tag).insert(id);
+        }
+        if let Some(hash) = ingestion_hash(&metadata) {
+            self.by_ingestion_hash.insert(hash, id);
+        }
+        self.entries.insert(id, metadata);
+    }
+
+    pub fn remove(&mut self, id: ChunkId) -> Option<ChunkMetadata> {
+        let metadata = self.entries.remove(&id)?;
+        if let Some(namespace) = &metadata.namespace {
+            unpost(&mut self.by_namespace, namespace, id);
+        }
+        if let Some(document_id) = &metadata.document_id {
+            unpost(&mut self.by_document, document_id, id);
+        }
+        for tag in &metadata.tags {
+            unpost(&mut self.by_tag, tag, id);
+        }
+        if let Some(hash) = ingestion_hash(&metadata) {
+            if self.by_ingestion_hash.get(&hash) == Some(&id) {
+                self.by_ingestion_hash.remove(&hash);
+            }
+        }
+        Some(metadata)
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Whether the chunk `id` passes `filter`. Chunks without metadata only pass an empty filter.
+    pub fn matches(&self, id: ChunkId, filter: &MetadataFilter) -> bool {
+        filter.is_empty()
+            || self
+                .get(id)
+                .is_some_and(|metadata| filter.matches(metadata))
+    }
+
+    /// Ids of the chunks of `document_id`, in document order.
+    pub fn document(&self, document_id: &str) -> Vec<ChunkId> {
+        let mut ids: Vec<ChunkId> = self
+            .by_document
+            .get(document_id)
+            .map(|ids| ids.iter().copied().collect())
+            .unwrap_or_default();
+        ids.sort_by_key(|id| {
+            (
+                self.entries[id].position.map(|position| position.index),
+                *id,
+            )
+        });
+        ids
+    }
+
+    /// Chunk whose ingestion digest is `hash`.
+    pub fn with_ingestion_hash(&self, hash: &ContentHash) -> Option<ChunkId> {
+        self.by_ingestion_hash.get(hash).copied()
+    }
+
+    /// Ids that may pass `filter`, from the indexed conditions only, or `None` if it has none.
+    pub fn candidates(&self, filter: &MetadataFilter) -> Option<HashSet<ChunkId>> {
+        let empty = HashSet::new();
+        let mut postings = vec![];
+        if let Some(namespace) = &filter.namespace {
+            postings.push(self.by_namespace.get(namespace).unwrap_or(&empty));
+        }
+        if let Some(document_id) = &filter.document_id {
+            postings.push(self.by_document.get(document_id).unwrap_or(&empty));
+        }
+        for tag in &filter.tags {
+            postings.push(self.by_tag.get(tag).unwrap_or(&empty));
+        }
+        postings.sort_by_key(|posting| posting.len());
+        let (smallest, others) = postings.split_first()?;
+        Some(
+            smallest
+                .iter()
+                .filter(|id| others.iter().all(|posting| posting.contains(id)))
+                .copied()
+                .collect(),
+        )
+    }
+}
+
+/// Current time, in seconds since the Unix epoch.
+pub(crate) fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+fn ingestion_hash(metadata: &ChunkMetadata) -> Option<ContentHash> {
+    metadata
+        .ingestion
+        .as_ref()
+        .and_then(|ingestion| ingestion.hash)
+}
+
+fn posting<'a>(
+    postings: &'a mut HashMap<String, HashSet<ChunkId>>,
+    key: &str,
+) -> &'a mut HashSet<ChunkId> {
+    postings.entry(key.to_string()).or_default()
+}
+
+fn unpost(postings: &mut HashMap<String, HashSet<ChunkId>>, key: &str, id: ChunkId) {
+    if let Some(ids) = postings.get_mut(key) {
+        ids.remove(&id);
+        if ids.is_empty() {
+            postings.remove(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(id: u32) -> ChunkId {
+        ChunkId::from(id)
+    }
+
+    fn metadata(
+        namespace: &str,
+        document_id: &str,
+        created_at: u64,
+        tags: &[&str],
+    ) -> ChunkMetadata {
+        ChunkMetadata {
+            namespace: Some(namespace.to_string()),
+            document_id: Some(document_id.to_string()),
+            created_at: Some(created_at),
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_filter_candidates_and_matches() {
+        let mut index = MetadataIndex::default();
+        index.insert(id(0), metadata("acme", "doc-1", 100, &["rust"]));
+        index.insert(id(1), metadata("acme", "doc-2", 200, &["rust", "llm"]));
+        index.insert(id(2), metadata("globex", "doc-3", 300, &["llm"]));
+
+        let filter = MetadataFilter {
+            namespace: Some("acme".to_string()),
+            tags: vec!["llm".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(index.candidates(&filter), Some(HashSet::from([id(1)])));
+        assert!(index.matches(id(1), &filter));
+        assert!(!index.matches(id(2), &filter));
+
+        let filter = MetadataFilter {
+            since: Some(150),
+            until: Some(300),
+            ..Default::default()
+        };
+        assert_eq!(index.candidates(&filter), None);
+        assert!(!index.matches(id(0), &filter));
+        assert!(index.matches(id(2), &filter));
+        // Chunks without metadata only pass the empty filter.
+        assert!(!index.matches(id(3), &filter));
+        assert!(index.matches(id(3), &MetadataFilter::default()));
+
+        // Replacing metadata moves the chunk out of its previous postings.
+        index.insert(id(1), metadata("globex", "doc-2", 200, &[]));
+        let filter = MetadataFilter {
+            namespace: Some("acme".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(index.candidates(&filter), Some(HashSet::from([id(0)])));
+        assert_eq!(index.remove(id(0)).map(|m| m.created_at), Some(Some(100)));
+        assert_eq!(index.candidates(&filter), Some(HashSet::new()));
+    }
+
+    #[test]
+    fn test_document_chunks_in_order() {
+        let mut index = MetadataIndex::default();
+        for (i, position) in [(5, 1), (3, 2), (9, 0)] {
+            index.insert(
+                id(i),
+                ChunkMetadata {
+                    position: Some(ChunkPosition {
+                        index: position,
+                        ..Default::default()
+                    }),
+                    ..metadata("acme", "doc-1", 100, &[])
+                },
+            );
+        }
+        index.insert(id(4), metadata("acme", "doc-2", 100, &[]));
+        assert_eq!(index.document("doc-1"), vec![id(9), id(5), id(3)]);
+        assert_eq!(index.document("doc-2"), vec![id(4)]);
+        assert!(index.document("doc-3").is_empty());
+    }
+}
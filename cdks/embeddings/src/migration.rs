@@ -0,0 +1,473 @@
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, PoisonError, RwLock,
+    },
+};
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::DEFAULT_BATCH_SIZE,
+    embedder::{Embedder, ModelTag},
+    embeddings::encode_sentences,
+    error::EmbeddingsError,
+    id::ChunkId,
+    index::{IndexEntry, VectorIndex},
+    metadata::{unix_timestamp, ChunkMetadata},
+};
+
+/// Model to migrate the stored embeddings to.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ModelSpec {
+    /// Local sentence-transformers model directory.
+    pub model_dir: Option<PathBuf>,
+    /// Id of a remote model, e.g. `sentence-transformers/all-MiniLM-L6-v2`, if no `model_dir`.
+    pub model_id: Option<String>,
+    /// Expected embedding dimension, checked against the loaded model.
+    pub embedding_dimension: Option<usize>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MigrationState {
+    Running,
+    /// The migrated embeddings replaced the stored ones.
+    Completed,
+    /// The stored embeddings were left as they were.
+    Failed,
+    Cancelled,
+}
+
+/// Progress of a [`Migration`].
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct MigrationProgress {
+    pub state: MigrationState,
+    /// Model the stored embeddings were produced by, if recorded.
+    pub from: Option<ModelTag>,
+    /// Model the embeddings are migrated to, once loaded.
+    pub to: Option<ModelTag>,
+    /// Chunks stored when the migration started.
+    pub total: usize,
+    /// Chunks of `total` encoded with the new model so far.
+    pub migrated: usize,
+    /// Chunks stored or changed while migrating, encoded again before the swap.
+    pub caught_up: usize,
+    /// Times the migration started and finished, in seconds since the Unix epoch.
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+    /// Why the migration failed.
+    pub error: Option<String>,
+}
+
+/// Encodes the chunks of an index again with another model, into a new index, while the
+/// current one keeps serving, then swaps them.
+///
+/// [`Self::build`] only holds the read lock of the index to fetch chunks, so that requests
+/// are served in the meantime. [`Self::finish`] then catches up with the chunks stored or
+/// changed since, under the write lock, and replaces the index, along with its store.
+/// Chunks can only be migrated from their stored text: the migration fails on the first one
+/// stored without any.
+pub struct Migration {
+    progress: Mutex<MigrationProgress>,
+    cancelled: AtomicBool,
+}
+
+impl Migration {
+    /// Starts a migration of the embeddings produced by `from`.
+    pub fn new(from: Option<ModelTag>) -> Self {
+        Self {
+            progress: Mutex::new(MigrationProgress {
+                state: MigrationState::Running,
+                from,
+                to: None,
+                total: 0,
+                migrated: 0,
+                caught_up: 0,
+                started_at: unix_timestamp(),
+                finished_at: None,
+                error: None,
+            }),
+            cancelled: AtomicBool::new(false),
+        }
+    }
+
+    pub fn progress(&self) -> MigrationProgress {
+        self.progress
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.progress().state == MigrationState::Running
+    }
+
+    /// Stops the migration before its next batch, leaving the stored embeddings as they were.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    fn update(&self, f: impl FnOnce(&mut MigrationProgress)) {
+        f(&mut self.progress.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    /// Ends the migration on `error`.
+    pub fn fail(&self, error: &EmbeddingsError) {
+        self.update(|progress| {
+            progress.state = match error {
+                EmbeddingsError::Cancelled => MigrationState::Cancelled,
+                _ => MigrationState::Failed,
+            };
+            progress.error = Some(error.to_string());
+            progress.finished_at = Some(unix_timestamp());
+        });
+    }
+
+    fn record<T>(&self, result: Result<T, EmbeddingsError>) -> Result<T, EmbeddingsError> {
+        if let Err(e) = &result {
+            self.fail(e);
+        }
+        result
+    }
+
+    /// Builds and swaps in the migrated index, see [`Self::build`] and [`Self::finish`].
+    pub fn run<E: Embedder>(
+        &self,
+        index: &RwLock<VectorIndex>,
+        model: &E,
+    ) -> Result<(), EmbeddingsError> {
+        let migrated = self.build(index, model)?;
+        self.finish(
+            &mut index.write().unwrap_or_else(PoisonError::into_inner),
+            migrated,
+            model,
+        )
+    }
+
+    /// Encodes every chunk of `index` with `model`, in batches, into a new index.
+    pub fn build<E: Embedder>(
+        &self,
+        index: &RwLock<VectorIndex>,
+        model: &E,
+    ) -> Result<VectorIndex, EmbeddingsError> {
+        self.record(self.try_build(index, model))
+    }
+
+    fn try_build<E: Embedder>(
+        &self,
+        index: &RwLock<VectorIndex>,
+        model: &E,
+    ) -> Result<VectorIndex, EmbeddingsError> {
+        let read = || index.read().unwrap_or_else(PoisonError::into_inner);
+        let (ids, index_config) = {
+            let index = read();
+            (index.ids().to_vec(), *index.index_config())
+        };
+        self.update(|progress| {
+            progress.to = Some(model.model_tag());
+            progress.total = ids.len();
+        });
+        info!(
+            "Migrating {} chunks to model {}..",
+            ids.len(),
+            model.model_tag()
+        );
+
+        let mut migrated =
+            VectorIndex::new(model.dimension(), index_config).with_model(model.model_tag());
+        for batch in ids.chunks(DEFAULT_BATCH_SIZE) {
+            if self.cancelled.load(Ordering::Relaxed) {
+                return Err(EmbeddingsError::Cancelled);
+            }
+            let chunks = {
+                let index = read();
+                batch
+                    .iter()
+                    .filter(|id| index.contains(**id))
+                    .map(|id| stored_chunk(&index, *id))
+                    .collect::<Result<Vec<_>, _>>()?
+            };
+            migrated.insert_entries(encode_chunks(model, chunks)?)?;
+            self.update(|progress| progress.migrated += batch.len());
+        }
+        // Most of the chunks changed in the meantime are caught up with here, rather than
+        // under the write lock.
+        self.catch_up(&read(), &mut migrated, model)?;
+        Ok(migrated)
+    }
+
+    /// Catches up with the chunks of `index` stored, changed or deleted since `migrated` was
+    /// built, and replaces `index` with it.
+    pub fn finish<E: Embedder>(
+        &self,
+        index: &mut VectorIndex,
+        migrated: VectorIndex,
+        model: &E,
+    ) -> Result<(), EmbeddingsError> {
+        self.record(self.try_finish(index, migrated, model))
+    }
+
+    fn try_finish<E: Embedder>(
+        &self,
+        index: &mut VectorIndex,
+        mut migrated: VectorIndex,
+        model: &E,
+    ) -> Result<(), EmbeddingsError> {
+        if self.cancelled.load(Ordering::Relaxed) {
+            return Err(EmbeddingsError::Cancelled);
+        }
+        self.catch_up(index, &mut migrated, model)?;
+        index.replace_with(migrated)?;
+        self.update(|progress| {
+            progress.state = MigrationState::Completed;
+            progress.finished_at = Some(unix_timestamp());
+        });
+        info!(
+            "Migrated {} chunks to model {}",
+            index.len(),
+            model.model_tag()
+        );
+        Ok(())
+    }
+
+    fn catch_up<E: Embedder>(
+        &self,
+        index: &VectorIndex,
+        migrated: &mut VectorIndex,
+        model: &E,
+    ) -> Result<(), EmbeddingsError> {
+        let deleted: Vec<_> = migrated
+            .ids()
+            .iter()
+            .copied()
+            .filter(|id| !index.contains(*id))
+            .collect();
+        migrated
+            .delete(&deleted)
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()?;
+        let changed = index
+            .ids()
+            .iter()
+            .copied()
+            .filter(|id| {
+                // Chunks stored without metadata are migrated with the default one.
+                !migrated.contains(*id)
+                    || migrated.text(*id) != index.text(*id)
+                    || index
+                        .metadata(*id)
+                        .is_some_and(|metadata| migrated.metadata(*id) != Some(metadata))
+            })
+            .map(|id| stored_chunk(index, id))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.update(|progress| progress.caught_up += changed.len());
+        migrated.insert_entries(encode_chunks(model, changed)?)
+    }
+}
+
+fn stored_chunk(
+    index: &VectorIndex,
+    id: ChunkId,
+) -> Result<(ChunkId, String, ChunkMetadata), EmbeddingsError> {
+    let text = index.text(id).ok_or(EmbeddingsError::MissingText(id))?;
+    let metadata = index.metadata(id).cloned().unwrap_or_default();
+    Ok((id, text.to_string(), metadata))
+}
+
+fn encode_chunks<E: Embedder>(
+    model: &E,
+    chunks: Vec<(ChunkId, String, ChunkMetadata)>,
+) -> Result<Vec<IndexEntry>, EmbeddingsError> {
+    if chunks.is_empty() {
+        return Ok(vec![]);
+    }
+    let sentences: Vec<&str> = chunks.iter().map(|(_, text, _)| text.as_str()).collect();
+    let embeddings = encode_sentences(model, &sentences);
+    chunks
+        .into_iter()
+        .zip(embeddings)
+        .map(|((id, text, metadata), embedding)| {
+            Ok(IndexEntry::new(id, embedding?)
+                .with_metadata(metadata)
+                .with_text(text))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{IndexConfig, StoreConfig},
+        embedder::HashingEmbedder,
+        embeddings::{Embedding, Embeddings},
+        search::SearchParams,
+    };
+
+    const SENTENCES: [&str; 4] = [
+        "Hello world !",
+        "Knowledge graphs are great !",
+        "LLMs are amazing, as well !",
+        "Rust is fast and safe",
+    ];
+
+    fn id(id: u32) -> ChunkId {
+        ChunkId::from(id)
+    }
+
+    fn metadata(namespace: &str) -> ChunkMetadata {
+        ChunkMetadata {
+            namespace: Some(namespace.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_migrate_persisted_embeddings() {
+        let dir = tempfile::tempdir().unwrap();
+        let store_config = StoreConfig::new(dir.path());
+        let mut embeddings = Embeddings::new_from_model(HashingEmbedder::new(16).unwrap())
+            .with_store(&store_config)
+            .unwrap();
+        for (i, sentence) in SENTENCES.iter().enumerate() {
+            embeddings
+                .process_chunk_and_store_with_metadata(id(i as u32), sentence, metadata("acme"))
+                .unwrap();
+        }
+
+        let embeddings = embeddings
+            .migrate(HashingEmbedder::new(32).unwrap())
+            .unwrap();
+        let stored = embeddings.get(id(1)).unwrap();
+        assert_eq!(stored.embedding.dimension(), 32);
+        assert_eq!(stored.model, Some(ModelTag::new("hashing-bow-32", "1")));
+        assert_eq!(stored.metadata.unwrap().namespace.as_deref(), Some("acme"));
+        let results = embeddings
+            .search_text("Are knowledge graphs great ?", &SearchParams::new(1))
+            .unwrap();
+        assert_eq!(results[0].id, id(1));
+        drop(embeddings);
+
+        // The store now holds the embeddings of the new model, and only those.
+        let embeddings = Embeddings::new_from_model(HashingEmbedder::new(32).unwrap())
+            .with_store(&store_config)
+            .unwrap();
+        assert_eq!(embeddings.ids().len(), SENTENCES.len());
+        assert_eq!(embeddings.chunk(id(3)).unwrap().text, SENTENCES[3]);
+        assert!(matches!(
+            Embeddings::new_from_model(HashingEmbedder::new(16).unwrap()).with_store(&store_config),
+            Err(EmbeddingsError::DimensionMismatch { .. })
+        ));
+        let mut index = VectorIndex::new(32, IndexConfig::default())
+            .with_store(&store_config)
+            .unwrap();
+        assert_eq!(
+            index.bind_model(&ModelTag::new("hashing-bow-32", "2")),
+            Err(EmbeddingsError::ModelMismatch {
+                expected: ModelTag::new("hashing-bow-32", "1"),
+                found: ModelTag::new("hashing-bow-32", "2"),
+            })
+        );
+    }
+
+    #[test]
+    fn test_migration_catches_up_with_changes() {
+        let mut embeddings = Embeddings::new_from_model(HashingEmbedder::new(16).unwrap());
+        for (i, sentence) in SENTENCES.iter().enumerate() {
+            embeddings
+                .process_chunk_and_store(id(i as u32), sentence)
+                .unwrap();
+        }
+        let (_, index) = embeddings.into_parts();
+        let index = RwLock::new(index);
+        let model = HashingEmbedder::new(32).unwrap();
+        let migration = Migration::new(index.read().unwrap().model().cloned());
+        let migrated = migration.build(&index, &model).unwrap();
+        assert_eq!(migration.progress().migrated, SENTENCES.len());
+
+        // Changes made while the migration was running are caught up with before the swap.
+        let old_model = HashingEmbedder::new(16).unwrap();
+        let embed = |text: &str| Embedding::new(old_model.encode(&[text]).unwrap()[0].clone(), 16);
+        {
+            let mut index = index.write().unwrap();
+            index.delete(&[id(0)]).pop().unwrap().unwrap();
+            index
+                .insert_entries(vec![
+                    IndexEntry::new(id(2), embed("Graph databases").unwrap())
+                        .with_text("Graph databases"),
+                    IndexEntry::new(id(4), embed("Embeddings are vectors").unwrap())
+                        .with_metadata(metadata("globex"))
+                        .with_text("Embeddings are vectors"),
+                ])
+                .unwrap();
+        }
+        migration
+            .finish(&mut index.write().unwrap(), migrated, &model)
+            .unwrap();
+
+        let progress = migration.progress();
+        assert_eq!(progress.state, MigrationState::Completed);
+        assert_eq!(progress.from, Some(ModelTag::new("hashing-bow-16", "1")));
+        assert_eq!(progress.to, Some(ModelTag::new("hashing-bow-32", "1")));
+        assert_eq!((progress.total, progress.caught_up), (4, 2));
+        assert!(progress.finished_at.is_some());
+
+        let index = index.into_inner().unwrap();
+        let mut ids = index.ids().to_vec();
+        ids.sort();
+        assert_eq!(ids, vec![id(1), id(2), id(3), id(4)]);
+        assert_eq!(index.text(id(2)), Some("Graph databases"));
+        assert_eq!(
+            index.metadata(id(4)).unwrap().namespace.as_deref(),
+            Some("globex")
+        );
+        assert_eq!(
+            index.get(id(2)).unwrap().embedding.as_slice(),
+            model.encode(&["Graph databases"]).unwrap()[0]
+        );
+    }
+
+    #[test]
+    fn test_failed_migration_keeps_index() {
+        let mut index = VectorIndex::new(16, IndexConfig::default());
+        let model = HashingEmbedder::new(16).unwrap();
+        index
+            .insert_entries(vec![IndexEntry::new(
+                id(0),
+                Embedding::new(model.encode(&["Hello"]).unwrap().remove(0), 16).unwrap(),
+            )
+            .with_text("Hello")])
+            .unwrap();
+        // Stored without its text, which cannot be encoded again.
+        index
+            .insert(id(1), Embedding::new(vec![0.5; 16], 16).unwrap())
+            .unwrap();
+        let index = RwLock::new(index);
+
+        let migration = Migration::new(None);
+        assert_eq!(
+            migration.run(&index, &HashingEmbedder::new(32).unwrap()),
+            Err(EmbeddingsError::MissingText(id(1)))
+        );
+        let progress = migration.progress();
+        assert_eq!(progress.state, MigrationState::Failed);
+        assert_eq!(
+            progress.error,
+            Some(EmbeddingsError::MissingText(id(1)).to_string())
+        );
+        assert_eq!(index.read().unwrap().dimension(), 16);
+
+        let migration = Migration::new(None);
+        migration.cancel();
+        assert_eq!(
+            migration.run(&index, &HashingEmbedder::new(32).unwrap()),
+            Err(EmbeddingsError::Cancelled)
+        );
+        assert_eq!(migration.progress().state, MigrationState::Cancelled);
+        assert_eq!(index.read().unwrap().len(), 2);
+    }
+}
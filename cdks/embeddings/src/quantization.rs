@@ -0,0 +1,507 @@
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+
+use crate::search::{dot_product, norm, squared_distance, Metric};
+
+/// Largest number of centroids per subspace, so that codes fit in a byte.
+const MAX_CENTROIDS: usize = 256;
+
+/// How stored vectors are compressed for the scans.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuantizationKind {
+    /// Vectors are scanned in full precision.
+    #[default]
+    None,
+    /// Every component is mapped to one of 256 levels between the lowest and the highest value
+    /// it takes in the training vectors: one byte per dimension.
+    Scalar,
+    /// Vectors are cut into subspaces, each one encoded by the closest of up to 256 centroids
+    /// learnt by k-means: one byte per subspace.
+    Product,
+}
+
+/// Compression of the stored vectors, see [`QuantizationKind`].
+///
+/// Quantized searches rank the stored vectors by their codes, then re-score the best
+/// candidates in full precision. Once the codes are learnt, the full precision vectors of a
+/// persisted index are moved to its store directory, and only read back for the re-score.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct QuantizationConfig {
+    pub kind: QuantizationKind,
+    /// Candidates re-scored in full precision per result wanted. Higher values recover more
+    /// of the exact results, at the cost of more vectors read back.
+    pub rescore_factor: usize,
+    /// Number of stored vectors the quantizer is trained on, once the index holds that many.
+    /// Smaller indexes are scanned in full precision.
+    pub training_vectors: usize,
+    /// Dimensions per subspace, in product quantization.
+    pub subspace_dimension: usize,
+    /// Centroids per subspace, in product quantization, at most 256.
+    pub centroids: usize,
+    /// Rounds of k-means refining the centroids.
+    pub iterations: usize,
+}
+
+impl Default for QuantizationConfig {
+    fn default() -> Self {
+        Self {
+            kind: QuantizationKind::default(),
+            rescore_factor: 4,
+            training_vectors: 4_096,
+            subspace_dimension: 8,
+            centroids: MAX_CENTROIDS,
+            iterations: 10,
+        }
+    }
+}
+
+impl QuantizationConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.kind != QuantizationKind::None
+    }
+
+    /// Number of candidates re-scored to answer a search for `k` results.
+    pub(crate) fn candidates(&self, k: usize) -> usize {
+        k.saturating_mul(self.rescore_factor.max(1))
+    }
+}
+
+/// Codebook turning vectors into byte codes, learnt from a sample of the stored vectors.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Quantizer {
+    Scalar {
+        /// Lowest value of every dimension.
+        min: Vec<f32>,
+        /// Gap between two consecutive levels of every dimension.
+        step: Vec<f32>,
+    },
+    Product {
+        subspaces: Vec<Range<usize>>,
+        /// Centroids of every subspace, one after the other.
+        centroids: Vec<Vec<f32>>,
+    },
+}
+
+impl Quantizer {
+    /// Learns the codebook of `config.kind` from `vectors`, all of dimension `dimension`.
+    /// Returns `None` if quantization is disabled, or there is nothing to learn from.
+    pub fn train(
+        config: &QuantizationConfig,
+        dimension: usize,
+        vectors: &[&[f32]],
+    ) -> Option<Self> {
+        if vectors.is_empty() || dimension == 0 {
+            return None;
+        }
+        match config.kind {
+            QuantizationKind::None => None,
+            QuantizationKind::Scalar => {
+                let mut min = vec![f32::MAX; dimension];
+                let mut max = vec![f32::MIN; dimension];
+                for vector in vectors {
+                    for (d, &value) in vector.iter().enumerate() {
+                        min[d] = min[d].min(value);
+                        max[d] = max[d].max(value);
+                    }
+                }
+                let step = min
+                    .iter()
+                    .zip(&max)
+                    .map(|(min, max)| (max - min) / 255.0)
+                    .collect();
+                Some(Self::Scalar { min, step })
+            }
+            QuantizationKind::Product => {
+                let width = config.subspace_dimension.clamp(1, dimension);
+                let subspaces: Vec<_> = (0..dimension)
+                    .step_by(width)
+                    .map(|start| start..(start + width).min(dimension))
+                    .collect();
+                let k = config.centroids.clamp(1, MAX_CENTROIDS).min(vectors.len());
+                let centroids = subspaces
+                    .iter()
+                    .map(|subspace| {
+                        let points: Vec<&[f32]> = vectors
+                            .iter()
+                            .map(|vector| &vector[subspace.clone()])
+                            .collect();
+                        kmeans(&points, k, config.iterations)
+                    })
+                    .collect();
+                Some(Self::Product {
+                    subspaces,
+                    centroids,
+                })
+            }
+        }
+    }
+
+    /// Number of bytes of every code.
+    pub fn code_size(&self) -> usize {
+        match self {
+            Self::Scalar { min, .. } => min.len(),
+            Self::Product { subspaces, .. } => subspaces.len(),
+        }
+    }
+
+    /// Writes the code of `vector` to `code`, which holds [`Self::code_size`] bytes.
+    pub fn encode(&self, vector: &[f32], code: &mut [u8]) {
+        match self {
+            Self::Scalar { min, step } => {
+                for (d, byte) in code.iter_mut().enumerate() {
+                    *byte = if step[d] > 0.0 {
+                        ((vector[d] - min[d]) / step[d]).round().clamp(0.0, 255.0) as u8
+                    } else {
+                        0
+                    };
+                }
+            }
+            Self::Product {
+                subspaces,
+                centroids,
+            } => {
+                for ((subspace, centroids), byte) in subspaces.iter().zip(centroids).zip(code) {
+                    let width = subspace.len();
+                    *byte = nearest(&vector[subspace.clone()], centroids, width) as u8;
+                }
+            }
+        }
+    }
+
+    /// Vector that `code` stands for.
+    pub fn decode(&self, code: &[u8]) -> Vec<f32> {
+        match self {
+            Self::Scalar { min, step } => code
+                .iter()
+                .enumerate()
+                .map(|(d, &byte)| min[d] + step[d] * byte as f32)
+                .collect(),
+            Self::Product {
+                subspaces,
+                centroids,
+            } => subspaces
+                .iter()
+                .zip(centroids)
+                .zip(code)
+                .flat_map(|((subspace, centroids), &byte)| {
+                    let width = subspace.len();
+                    centroids[byte as usize * width..(byte as usize + 1) * width]
+                        .iter()
+                        .copied()
+                })
+                .collect(),
+        }
+    }
+
+    /// Scores codes against `query` by `metric`, without decoding them.
+    pub fn scorer(&self, query: &[f32], metric: Metric) -> CodeScorer {
+        let lookup = match self {
+            // dot(q, x) = sum(q * min) + sum(q * step * level)
+            Self::Scalar { min, step } => Lookup::Levels {
+                offset: dot_product(query, min),
+                weights: query.iter().zip(step).map(|(q, step)| q * step).collect(),
+            },
+            Self::Product {
+                subspaces,
+                centroids,
+            } => Lookup::Table(
+                subspaces
+                    .iter()
+                    .zip(centroids)
+                    .flat_map(|(subspace, centroids)| {
+                        let query = &query[subspace.clone()];
+                        centroids
+                            .chunks(subspace.len())
+                            .map(|centroid| dot_product(query, centroid))
+                            .chain(std::iter::repeat(0.0))
+                            .take(MAX_CENTROIDS)
+                    })
+                    .collect(),
+            ),
+        };
+        CodeScorer {
+            lookup,
+            metric,
+            query_norm: norm(query),
+        }
+    }
+
+    fn memory_bytes(&self) -> usize {
+        match self {
+            Self::Scalar { min, step } => 4 * (min.len() + step.len()),
+            Self::Product { centroids, .. } => centroids.iter().map(|c| 4 * c.len()).sum(),
+        }
+    }
+}
+
+enum Lookup {
+    Levels {
+        offset: f32,
+        weights: Vec<f32>,
+    },
+    /// Dot products of the query with the 256 centroids of every subspace.
+    Table(Vec<f32>),
+}
+
+/// Scores of the codes against a single query, by asymmetric distance: the query keeps its
+/// full precision.
+pub struct CodeScorer {
+    lookup: Lookup,
+    metric: Metric,
+    query_norm: f32,
+}
+
+impl CodeScorer {
+    /// Score of the vector encoded by `code`, whose decoded form has the norm `norm`.
+    pub fn score(&self, code: &[u8], norm: f32) -> f32 {
+        let dot = match &self.lookup {
+            Lookup::Levels { offset, weights } => {
+                offset
+                    + weights
+                        .iter()
+                        .zip(code)
+                        .map(|(weight, &byte)| weight * byte as f32)
+                        .sum::<f32>()
+            }
+            Lookup::Table(table) => code
+                .iter()
+                .enumerate()
+                .map(|(subspace, &byte)| table[subspace * MAX_CENTROIDS + byte as usize])
+                .sum(),
+        };
+        match self.metric {
+            Metric::Cosine => dot / (self.query_norm * norm),
+            Metric::DotProduct => dot,
+            Metric::Euclidean => -(self.query_norm * self.query_norm - 2.0 * dot + norm * norm)
+                .max(0.0)
+                .sqrt(),
+        }
+    }
+}
+
+/// Codes of the stored vectors, by position, along with the norms of the vectors they decode
+/// to.
+#[derive(Clone, Debug)]
+pub struct QuantizedVectors {
+    quantizer: Quantizer,
+    codes: Vec<u8>,
+    norms: Vec<f32>,
+}
+
+impl QuantizedVectors {
+    pub fn new(quantizer: Quantizer) -> Self {
+        Self {
+            quantizer,
+            codes: vec![],
+            norms: vec![],
+        }
+    }
+
+    pub fn quantizer(&self) -> &Quantizer {
+        &self.quantizer
+    }
+
+    pub fn len(&self) -> usize {
+        self.norms.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.norms.is_empty()
+    }
+
+    /// Encodes `vector` at `position`, which is either taken, or the next free one.
+    pub fn set(&mut self, position: usize, vector: &[f32]) {
+        let size = self.quantizer.code_size();
+        if position == self.len() {
+            self.codes.resize(self.codes.len() + size, 0);
+            self.norms.push(0.0);
+        }
+        let code = &mut self.codes[position * size..(position + 1) * size];
+        self.quantizer.encode(vector, code);
+        self.norms[position] = norm(&self.quantizer.decode(code));
+    }
+
+    /// Removes the code at `position`, moving the last one in its place.
+    pub fn swap_remove(&mut self, position: usize) {
+        let size = self.quantizer.code_size();
+        let last = self.len() - 1;
+        self.codes
+            .copy_within(last * size..(last + 1) * size, position * size);
+        self.codes.truncate(last * size);
+        self.norms.swap_remove(position);
+    }
+
+    /// Scores the codes against `query`, see [`Self::score`].
+    pub fn scorer(&self, query: &[f32], metric: Metric) -> CodeScorer {
+        self.quantizer.scorer(query, metric)
+    }
+
+    /// Score of the code at `position`, by a scorer of [`Self::scorer`].
+    pub fn score(&self, scorer: &CodeScorer, position: usize) -> f32 {
+        let size = self.quantizer.code_size();
+        scorer.score(
+            &self.codes[position * size..(position + 1) * size],
+            self.norms[position],
+        )
+    }
+
+    /// Bytes taken by the codes, their norms and the codebook.
+    pub fn memory_bytes(&self) -> usize {
+        self.codes.len() + 4 * self.norms.len() + self.quantizer.memory_bytes()
+    }
+}
+
+/// Position of the centroid of `centroids`, laid out one after the other, closest to `point`.
+fn nearest(point: &[f32], centroids: &[f32], width: usize) -> usize {
+    centroids
+        .chunks(width)
+        .map(|centroid| squared_distance(point, centroid))
+        .enumerate()
+        .min_by(|(_, d1), (_, d2)| d1.total_cmp(d2))
+        .map(|(position, _)| position)
+        .unwrap_or_default()
+}
+
+/// Lloyd's k-means over `points`, starting from `k` of them picked farthest first, so that
+/// the centroids are reproducible. Returns the centroids one after the other. Clusters left
+/// empty keep their previous centroid.
+fn kmeans(points: &[&[f32]], k: usize, iterations: usize) -> Vec<f32> {
+    let width = points[0].len();
+    let mut centroids: Vec<f32> = points[0].to_vec();
+    let mut distances: Vec<f32> = points
+        .iter()
+        .map(|point| squared_distance(point, points[0]))
+        .collect();
+    for _ in 1..k {
+        let farthest = (0..points.len())
+            .max_by(|&p1, &p2| distances[p1].total_cmp(&distances[p2]))
+            .unwrap_or_default();
+        centroids.extend_from_slice(points[farthest]);
+        for (distance, point) in distances.iter_mut().zip(points) {
+            *distance = distance.min(squared_distance(point, points[farthest]));
+        }
+    }
+    for _ in 0..iterations {
+        let mut sums = vec![0f32; k * width];
+        let mut counts = vec![0usize; k];
+        for point in points {
+            let cluster = nearest(point, &centroids, width);
+            counts[cluster] += 1;
+            sums[cluster * width..(cluster + 1) * width]
+                .iter_mut()
+                .zip(point.iter())
+                .for_each(|(sum, value)| *sum += value);
+        }
+        let mut moved = false;
+        for (cluster, &count) in counts.iter().enumerate() {
+            if count == 0 {
+                continue;
+            }
+            for d in cluster * width..(cluster + 1) * width {
+                let mean = sums[d] / count as f32;
+                moved |= mean != centroids[d];
+                centroids[d] = mean;
+            }
+        }
+        if !moved {
+            break;
+        }
+    }
+    centroids
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic vectors spread over the unit square, along both diagonals.
+    fn vectors() -> Vec<Vec<f32>> {
+        (0..64)
+            .map(|i| {
+                let t = i as f32 / 63.0;
+                if i % 2 == 0 {
+                    vec![t, t, 1.0 - t, 0.5]
+                } else {
+                    vec![t, 1.0 - t, t, -0.5]
+                }
+            })
+            .collect()
+    }
+
+    fn train(kind: QuantizationKind) -> Quantizer {
+        let config = QuantizationConfig {
+            kind,
+            subspace_dimension: 2,
+            centroids: 32,
+            ..Default::default()
+        };
+        let vectors = vectors();
+        let vectors: Vec<&[f32]> = vectors.iter().map(Vec::as_slice).collect();
+        Quantizer::train(&config, 4, &vectors).unwrap()
+    }
+
+    #[test]
+    fn test_codes_decode_close_to_vectors() {
+        for (kind, code_size, tolerance) in [
+            (QuantizationKind::Scalar, 4, 0.01),
+            (QuantizationKind::Product, 2, 0.1),
+        ] {
+            let quantizer = train(kind);
+            assert_eq!(quantizer.code_size(), code_size);
+            let mut code = vec![0; code_size];
+            for vector in vectors() {
+                quantizer.encode(&vector, &mut code);
+                let decoded = quantizer.decode(&code);
+                assert!(
+                    squared_distance(&vector, &decoded).sqrt() < tolerance,
+                    "{kind:?} decodes {vector:?} to {decoded:?}"
+                );
+            }
+        }
+        assert!(Quantizer::train(&QuantizationConfig::default(), 4, &[&[1.0; 4]]).is_none());
+    }
+
+    #[test]
+    fn test_code_scores_match_decoded_vectors() {
+        let query = [0.3, -0.2, 0.9, 0.1];
+        for kind in [QuantizationKind::Scalar, QuantizationKind::Product] {
+            let mut quantized = QuantizedVectors::new(train(kind));
+            for (position, vector) in vectors().iter().enumerate() {
+                quantized.set(position, vector);
+            }
+            for metric in [Metric::Cosine, Metric::DotProduct, Metric::Euclidean] {
+                let scorer = quantized.scorer(&query, metric);
+                for position in 0..quantized.len() {
+                    let score = quantized.score(&scorer, position);
+                    let size = quantized.quantizer().code_size();
+                    let decoded = quantized
+                        .quantizer()
+                        .decode(&quantized.codes[position * size..(position + 1) * size]);
+                    let expected = metric.score(&query, &decoded);
+                    assert!(
+                        (score - expected).abs() < 1e-4,
+                        "{kind:?} {metric:?}: {score} != {expected}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_swap_remove_codes() {
+        let mut quantized = QuantizedVectors::new(train(QuantizationKind::Scalar));
+        let vectors = vectors();
+        for (position, vector) in vectors.iter().take(3).enumerate() {
+            quantized.set(position, vector);
+        }
+        quantized.swap_remove(0);
+        assert_eq!(quantized.len(), 2);
+        let scorer = quantized.scorer(&vectors[2], Metric::Euclidean);
+        // The last code took the place of the removed one.
+        assert!(quantized.score(&scorer, 0) > -0.01);
+        assert_eq!(quantized.memory_bytes(), 2 * 4 + 2 * 4 + 2 * 4 * 4);
+    }
+}
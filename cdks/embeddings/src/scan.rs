@@ -0,0 +1,208 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+};
+
+use rayon::prelude::*;
+
+use crate::error::EmbeddingsError;
+
+/// Fewest positions a shard is given, below which splitting a scan costs more than it saves.
+pub const MIN_SHARD_SIZE: usize = 1_024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Candidate {
+    position: usize,
+    score: f32,
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Higher scores first, then lower positions, as a stable sort of the scores would rank them.
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.score
+            .total_cmp(&other.score)
+            .then_with(|| other.position.cmp(&self.position))
+    }
+}
+
+/// Best `k` of a shard, the worst on top of the heap.
+type TopK = BinaryHeap<Reverse<Candidate>>;
+
+fn push(top: &mut TopK, candidate: Candidate, k: usize) {
+    if top.len() < k {
+        top.push(Reverse(candidate));
+    } else if top.peek().is_some_and(|worst| candidate > worst.0) {
+        top.pop();
+        top.push(Reverse(candidate));
+    }
+}
+
+/// Number of shards to split a scan of `len` positions into, when `shards` are wanted: `0`
+/// stands for one per thread of the pool.
+pub fn shard_count(shards: usize, len: usize) -> usize {
+    let shards = match shards {
+        0 => rayon::current_num_threads(),
+        shards => shards,
+    };
+    shards.min(len.div_ceil(MIN_SHARD_SIZE)).max(1)
+}
+
+/// Positions in `0..len` of the `k` best scores, best first, ties going to the lowest
+/// position. `score` skips the positions it returns `None` for, and NaN scores are dropped.
+///
+/// The positions are split into `shards` contiguous ranges, scanned in parallel, each keeping
+/// its own best `k` in a heap, which are then merged.
+pub fn top_k(
+    len: usize,
+    k: usize,
+    shards: usize,
+    score: impl Fn(usize) -> Result<Option<f32>, EmbeddingsError> + Sync,
+) -> Result<Vec<(usize, f32)>, EmbeddingsError> {
+    if k == 0 || len == 0 {
+        return Ok(vec![]);
+    }
+    let shards = shards.clamp(1, len);
+    let top = (0..shards)
+        .into_par_iter()
+        .map(|shard| {
+            let mut top = TopK::with_capacity(k.min(len / shards + 1));
+            for position in shard * len / shards..(shard + 1) * len / shards {
+                match score(position)? {
+                    Some(score) if !score.is_nan() => {
+                        push(&mut top, Candidate { position, score }, k)
+                    }
+                    _ => {}
+                }
+            }
+            Ok(top)
+        })
+        .try_reduce(TopK::new, |mut top, other| {
+            for Reverse(candidate) in other {
+                push(&mut top, candidate, k);
+            }
+            Ok(top)
+        })?;
+    Ok(top
+        .into_sorted_vec()
+        .into_iter()
+        .map(|Reverse(candidate)| (candidate.position, candidate.score))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{IndexConfig, IndexKind},
+        embeddings::Embedding,
+        id::ChunkId,
+        index::VectorIndex,
+        search::{Metric, SearchParams, SearchResult},
+        vectors::random_vectors,
+    };
+
+    fn id(id: usize) -> ChunkId {
+        ChunkId::from(id as u32)
+    }
+
+    #[test]
+    fn test_top_k_matches_sorted_scan() {
+        // Scores with ties, skipped positions and NaNs.
+        let score = |position: usize| -> Result<Option<f32>, EmbeddingsError> {
+            Ok(match position % 11 {
+                3 => None,
+                7 => Some(f32::NAN),
+                _ => Some(((position * 37) % 101) as f32 / 10.0),
+            })
+        };
+        let len = 5_000;
+        let mut expected: Vec<(usize, f32)> = (0..len)
+            .filter_map(|position| Some((position, score(position).unwrap()?)))
+            .filter(|(_, score)| !score.is_nan())
+            .collect();
+        expected.sort_by(|(_, s1), (_, s2)| s2.total_cmp(s1));
+
+        for shards in [1, 2, 7, 64] {
+            for k in [1, 10, 250, len] {
+                let expected = &expected[..k.min(expected.len())];
+                assert_eq!(top_k(len, k, shards, score).unwrap(), expected);
+            }
+        }
+        assert!(top_k(len, 0, 4, score).unwrap().is_empty());
+        assert!(top_k(0, 10, 4, score).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_top_k_reports_errors() {
+        let result = top_k(100, 5, 4, |position| match position {
+            42 => Err(EmbeddingsError::Storage("unreadable".to_string())),
+            _ => Ok(Some(position as f32)),
+        });
+        assert_eq!(
+            result,
+            Err(EmbeddingsError::Storage("unreadable".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_shard_count() {
+        assert_eq!(shard_count(8, 100), 1);
+        assert_eq!(shard_count(8, 3 * MIN_SHARD_SIZE), 3);
+        assert_eq!(shard_count(2, 100 * MIN_SHARD_SIZE), 2);
+        assert!(shard_count(0, 100 * MIN_SHARD_SIZE) >= 1);
+    }
+
+    #[test]
+    fn test_sharded_exact_search() {
+        let embeddings = random_vectors(5_000, 32, 7)
+            .into_iter()
+            .map(|values| Embedding::new(values, 32).unwrap())
+            .collect::<Vec<_>>();
+        let mut index = VectorIndex::new(
+            32,
+            IndexConfig {
+                kind: IndexKind::Exact,
+                search_shards: 4,
+                ..Default::default()
+            },
+        );
+        index
+            .insert_batch(
+                embeddings
+                    .iter()
+                    .enumerate()
+                    .map(|(i, embedding)| (id(i), embedding.clone()))
+                    .collect(),
+            )
+            .unwrap();
+
+        for metric in [Metric::Cosine, Metric::DotProduct, Metric::Euclidean] {
+            for query in &embeddings[..20] {
+                let params = SearchParams {
+                    metric,
+                    ..SearchParams::new(25)
+                };
+                // Sequential scan, fully sorted, as exact searches used to rank.
+                let mut expected: Vec<SearchResult> = embeddings
+                    .iter()
+                    .enumerate()
+                    .map(|(i, embedding)| SearchResult {
+                        id: id(i),
+                        score: metric.score(embedding.as_slice(), query.as_slice()),
+                    })
+                    .collect();
+                expected.sort_by(|r1, r2| r2.score.total_cmp(&r1.score));
+                expected.truncate(25);
+                assert_eq!(index.search_exact(query, &params).unwrap(), expected);
+            }
+        }
+    }
+}
@@ -0,0 +1,451 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{id::ChunkId, metadata::MetadataFilter};
+
+/// Independent sums kept by the vector kernels, which the compiler maps to SIMD lanes.
+const LANES: usize = 8;
+
+/// Number of candidates fetched per result wanted, when the results are picked from a larger
+/// ranking, as hybrid and diversified searches do.
+const CANDIDATES_PER_RESULT: u32 = 4;
+
+/// Similarity metric used to rank stored embeddings against a query.
+///
+/// Scores are always "higher is better": the Euclidean score is the negated distance.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    #[default]
+    Cosine,
+    DotProduct,
+    Euclidean,
+}
+
+impl Metric {
+    pub fn score(&self, arr1: &[f32], arr2: &[f32]) -> f32 {
+        QueryScorer::new(*self, arr2).score(arr1)
+    }
+}
+
+/// Scores stored vectors against a query by a metric, computing the query norm only once.
+pub(crate) struct QueryScorer<'a> {
+    metric: Metric,
+    query: &'a [f32],
+    query_norm: f32,
+}
+
+impl<'a> QueryScorer<'a> {
+    pub(crate) fn new(metric: Metric, query: &'a [f32]) -> Self {
+        Self {
+            metric,
+            query,
+            query_norm: norm(query),
+        }
+    }
+
+    pub(crate) fn score(&self, vector: &[f32]) -> f32 {
+        match self.metric {
+            Metric::Cosine => dot_product(vector, self.query) / (norm(vector) * self.query_norm),
+            Metric::DotProduct => dot_product(vector, self.query),
+            Metric::Euclidean => -squared_distance(vector, self.query).sqrt(),
+        }
+    }
+}
+
+/// Ranking used to answer a search.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchMode {
+    /// Similarity of the embeddings.
+    #[default]
+    Vector,
+    /// BM25 score of the query terms against the chunk texts.
+    Lexical,
+    /// Both rankings, fused as set by [`HybridParams`].
+    Hybrid,
+}
+
+/// How a hybrid search fuses its vector and lexical rankings.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Fusion {
+    /// Sums the weighted `1 / (rrf_k + rank)` of every ranking a chunk appears in. Only
+    /// ranks matter, so scores of different scales need no calibration.
+    #[default]
+    ReciprocalRank,
+    /// Sums the weighted scores of every ranking, each min-max normalized to `[0, 1]`.
+    Weighted,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct HybridParams {
+    pub fusion: Fusion,
+    pub vector_weight: f32,
+    pub lexical_weight: f32,
+    /// Damps the advantage of the top ranks in reciprocal rank fusion.
+    pub rrf_k: f32,
+}
+
+impl Default for HybridParams {
+    fn default() -> Self {
+        Self {
+            fusion: Fusion::default(),
+            vector_weight: 1.0,
+            lexical_weight: 1.0,
+            rrf_k: 60.0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct SearchParams {
+    /// Maximum number of results.
+    pub num_queries: u32,
+    #[serde(default)]
+    pub metric: Metric,
+    /// Results scoring below this threshold are dropped.
+    #[serde(default)]
+    pub min_score: Option<f32>,
+    /// Only chunks whose metadata passes this filter are searched.
+    #[serde(default)]
+    pub filter: Option<MetadataFilter>,
+    #[serde(default)]
+    pub mode: SearchMode,
+    /// Fusion of the rankings, in the hybrid mode.
+    #[serde(default)]
+    pub hybrid: HybridParams,
+    /// Re-ranks the results by maximal marginal relevance, from pure relevance at 1.0 down to
+    /// pure diversity at 0.0.
+    #[serde(default)]
+    pub mmr_lambda: Option<f32>,
+}
+
+impl SearchParams {
+    pub fn new(num_queries: u32) -> Self {
+        Self {
+            num_queries,
+            metric: Metric::default(),
+            min_score: None,
+            filter: None,
+            mode: SearchMode::default(),
+            hybrid: HybridParams::default(),
+            mmr_lambda: None,
+        }
+    }
+
+    /// Parameters fetching the candidates that a re-ranking stage picks the results from.
+    pub(crate) fn candidates(&self) -> Self {
+        Self {
+            num_queries: self.num_queries.saturating_mul(CANDIDATES_PER_RESULT),
+            mmr_lambda: None,
+            ..self.clone()
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct SearchResult {
+    pub id: ChunkId,
+    pub score: f32,
+}
+
+/// Search results of a single document, best first.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct DocumentResults {
+    /// Document of the results, `None` for the chunks stored outside of any document.
+    pub document_id: Option<String>,
+    pub results: Vec<SearchResult>,
+}
+
+/// Groups `results`, best first, by the document `document_of` tells each one belongs to.
+/// Documents come in the order of their best result.
+pub fn group_by_document(
+    results: Vec<SearchResult>,
+    document_of: impl Fn(ChunkId) -> Option<String>,
+) -> Vec<DocumentResults> {
+    let mut groups: Vec<DocumentResults> = vec![];
+    for result in results {
+        let document_id = document_of(result.id);
+        match groups
+            .iter_mut()
+            .find(|group| group.document_id == document_id)
+        {
+            Some(group) => group.results.push(result),
+            None => groups.push(DocumentResults {
+                document_id,
+                results: vec![result],
+            }),
+        }
+    }
+    groups
+}
+
+/// Keeps the `num_queries` best scored results, best first and by id among equal scores,
+/// dropping NaN scores and scores below `min_score`.
+pub(crate) fn rank(mut results: Vec<SearchResult>, params: &SearchParams) -> Vec<SearchResult> {
+    results.retain(|r| !r.score.is_nan() && params.min_score.is_none_or(|min| r.score >= min));
+    results.sort_by(|r1, r2| r2.score.total_cmp(&r1.score).then(r1.id.cmp(&r2.id)));
+    results.truncate(params.num_queries as usize);
+    results
+}
+
+/// Fuses the `vector` and `lexical` rankings, both best first, into one unordered list.
+pub(crate) fn fuse(
+    vector: &[SearchResult],
+    lexical: &[SearchResult],
+    params: &HybridParams,
+) -> Vec<SearchResult> {
+    let mut scores: HashMap<ChunkId, f32> = HashMap::new();
+    for (results, weight) in [
+        (vector, params.vector_weight),
+        (lexical, params.lexical_weight),
+    ] {
+        let (min, max) = results.iter().fold((f32::MAX, f32::MIN), |(min, max), r| {
+            (min.min(r.score), max.max(r.score))
+        });
+        for (rank, result) in results.iter().enumerate() {
+            let score = match params.fusion {
+                Fusion::ReciprocalRank => 1.0 / (params.rrf_k + rank as f32 + 1.0),
+                Fusion::Weighted if max > min => (result.score - min) / (max - min),
+                Fusion::Weighted => 1.0,
+            };
+            *scores.entry(result.id).or_default() += weight * score;
+        }
+    }
+    scores
+        .into_iter()
+        .map(|(id, score)| SearchResult { id, score })
+        .collect()
+}
+
+/// Greedily picks `k` of the `candidates` and their embeddings, each one maximizing
+/// `lambda * relevance - (1 - lambda) * similarity`: its min-max normalized score, against
+/// its highest cosine similarity to the candidates already picked. Picked results keep their
+/// scores, but are listed in the order they were picked.
+pub(crate) fn mmr(
+    candidates: Vec<(SearchResult, &[f32])>,
+    lambda: f32,
+    k: usize,
+) -> Vec<SearchResult> {
+    let lambda = lambda.clamp(0.0, 1.0);
+    let (min, max) = candidates
+        .iter()
+        .fold((f32::MAX, f32::MIN), |(min, max), (r, _)| {
+            (min.min(r.score), max.max(r.score))
+        });
+    let relevance = |score: f32| {
+        if max > min {
+            (score - min) / (max - min)
+        } else {
+            1.0
+        }
+    };
+
+    let mut remaining: Vec<_> = candidates
+        .into_iter()
+        .map(|(result, embedding)| (result, embedding, f32::MIN))
+        .collect();
+    let mut picked = Vec::with_capacity(k.min(remaining.len()));
+    while picked.len() < k && !remaining.is_empty() {
+        let marginal = |(result, _, similarity): &(SearchResult, &[f32], f32)| {
+            let similarity = if picked.is_empty() { 0.0 } else { *similarity };
+            lambda * relevance(result.score) - (1.0 - lambda) * similarity
+        };
+        let best = (0..remaining.len())
+            .max_by(|&i, &j| {
+                marginal(&remaining[i])
+                    .total_cmp(&marginal(&remaining[j]))
+                    .then(j.cmp(&i))
+            })
+            .unwrap_or_default();
+        let (result, embedding, _) = remaining.swap_remove(best);
+        for (_, other, similarity) in remaining.iter_mut() {
+            // Zero vectors have no cosine similarity, and count as unrelated.
+            let to_picked = cosine_similarity(embedding, other);
+            *similarity = similarity.max(if to_picked.is_nan() { 0.0 } else { to_picked });
+        }
+        picked.push(result);
+    }
+    picked
+}
+
+/// Sums `term` over the components of `arr1` and `arr2`, which have the same length, in
+/// [`LANES`] independent sums that vectorize.
+#[inline(always)]
+fn lane_sum(arr1: &[f32], arr2: &[f32], term: impl Fn(f32, f32) -> f32) -> f32 {
+    let chunks1 = arr1.chunks_exact(LANES);
+    let chunks2 = arr2.chunks_exact(LANES);
+    let tail: f32 = chunks1
+        .remainder()
+        .iter()
+        .zip(chunks2.remainder())
+        .map(|(&x, &y)| term(x, y))
+        .sum();
+    let mut sums = [0f32; LANES];
+    for (chunk1, chunk2) in chunks1.zip(chunks2) {
+        for ((sum, &x), &y) in sums.iter_mut().zip(chunk1).zip(chunk2) {
+            *sum += term(x, y);
+        }
+    }
+    sums.iter().sum::<f32>() + tail
+}
+
+pub(crate) fn dot_product(arr1: &[f32], arr2: &[f32]) -> f32 {
+    lane_sum(arr1, arr2, |x, y| x * y)
+}
+
+pub(crate) fn squared_distance(arr1: &[f32], arr2: &[f32]) -> f32 {
+    lane_sum(arr1, arr2, |x, y| (x - y) * (x - y))
+}
+
+pub(crate) fn norm(vector: &[f32]) -> f32 {
+    dot_product(vector, vector).sqrt()
+}
+
+pub(crate) fn cosine_similarity(arr1: &[f32], arr2: &[f32]) -> f32 {
+    Metric::Cosine.score(arr1, arr2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(id: u32) -> ChunkId {
+        ChunkId::from(id)
+    }
+
+    #[test]
+    fn test_metrics() {
+        let arr1 = [1.0, 0.0];
+        let arr2 = [2.0, 2.0];
+        assert!((Metric::Cosine.score(&arr1, &arr2) - 0.5f32.sqrt()).abs() < 1e-6);
+        assert_eq!(Metric::DotProduct.score(&arr1, &arr2), 2.0);
+        assert!((Metric::Euclidean.score(&arr1, &arr2) + 5f32.sqrt()).abs() < 1e-6);
+        assert!(Metric::Cosine.score(&[0.0, 0.0], &arr2).is_nan());
+    }
+
+    #[test]
+    fn test_rank() {
+        let results = vec![
+            SearchResult {
+                id: id(0),
+                score: 0.2,
+            },
+            SearchResult {
+                id: id(1),
+                score: f32::NAN,
+            },
+            SearchResult {
+                id: id(2),
+                score: 0.9,
+            },
+            SearchResult {
+                id: id(3),
+                score: 0.5,
+            },
+        ];
+        let params = SearchParams {
+            num_queries: 10,
+            metric: Metric::Cosine,
+            min_score: Some(0.3),
+            ..SearchParams::new(10)
+        };
+        assert_eq!(
+            rank(results.clone(), &params),
+            vec![
+                SearchResult {
+                    id: id(2),
+                    score: 0.9
+                },
+                SearchResult {
+                    id: id(3),
+                    score: 0.5
+                }
+            ]
+        );
+        assert_eq!(
+            rank(results, &SearchParams::new(1)),
+            vec![SearchResult {
+                id: id(2),
+                score: 0.9
+            }]
+        );
+
+        // Equal scores, as reciprocal rank fusion often gives, are ranked by id.
+        let ties = [3, 1, 2, 0].map(|i| SearchResult {
+            id: id(i),
+            score: 0.5,
+        });
+        assert_eq!(
+            rank(ties.to_vec(), &SearchParams::new(3))
+                .iter()
+                .map(|r| r.id)
+                .collect::<Vec<_>>(),
+            vec![id(0), id(1), id(2)]
+        );
+    }
+
+    #[test]
+    fn test_fuse() {
+        let result = |i, score| SearchResult { id: id(i), score };
+        let vector = [result(0, 0.9), result(1, 0.8), result(2, 0.1)];
+        let lexical = [result(1, 7.0), result(2, 3.0)];
+        let ids = |params: &HybridParams| {
+            let mut fused = fuse(&vector, &lexical, params);
+            fused.sort_by(|r1, r2| r2.score.total_cmp(&r1.score));
+            fused.iter().map(|r| r.id).collect::<Vec<_>>()
+        };
+
+        // Chunk 1 ranks well in both lists.
+        assert_eq!(ids(&HybridParams::default()), vec![id(1), id(2), id(0)]);
+        let lexical_only = HybridParams {
+            vector_weight: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(ids(&lexical_only)[..2], [id(1), id(2)]);
+        let weighted = HybridParams {
+            fusion: Fusion::Weighted,
+            lexical_weight: 0.5,
+            ..Default::default()
+        };
+        // 0: 1.0, 1: 0.875 + 0.5, 2: 0.0 + 0.0
+        assert_eq!(ids(&weighted), vec![id(1), id(0), id(2)]);
+    }
+
+    #[test]
+    fn test_mmr() {
+        let result = |i, score| SearchResult { id: id(i), score };
+        let candidates = || {
+            vec![
+                (result(0, 0.9), [1.0, 0.0].as_slice()),
+                (result(1, 0.89), [1.0, 0.01].as_slice()),
+                (result(2, 0.5), [0.0, 1.0].as_slice()),
+            ]
+        };
+        let ids = |results: Vec<SearchResult>| results.iter().map(|r| r.id).collect::<Vec<_>>();
+
+        assert_eq!(ids(mmr(candidates(), 1.0, 2)), vec![id(0), id(1)]);
+        // The near copy of the best result gives way to the other one.
+        assert_eq!(ids(mmr(candidates(), 0.5, 2)), vec![id(0), id(2)]);
+        assert_eq!(ids(mmr(candidates(), 0.5, 5)), vec![id(0), id(2), id(1)]);
+        assert_eq!(mmr(candidates(), 0.5, 1), vec![result(0, 0.9)]);
+    }
+
+    #[test]
+    fn test_mmr_with_zero_vector() {
+        let result = |i, score| SearchResult { id: id(i), score };
+        let candidates = vec![
+            (result(0, 0.9), [1.0, 0.0].as_slice()),
+            (result(1, 0.6), [0.0, 1.0].as_slice()),
+            (result(2, 0.1), [0.0, 0.0].as_slice()),
+        ];
+        let ids = mmr(candidates, 0.5, 3)
+            .iter()
+            .map(|r| r.id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![id(0), id(1), id(2)]);
+    }
+}
@@ -1,115 +1,2046 @@
 use anyhow::{anyhow, Error};
 
-use crate::embeddings::{Embeddings, DEFAULT_MODEL_EMBEDDING_SIZE};
-use log::info;
+use crate::{
+    cache::CacheStats,
+    chunker::{
+        approximate_llm_tokens, split_document, ChunkerConfig, DocumentSplit, LlmTokenCounter,
+    },
+    client::{CancellationToken, PendingReply},
+    config::ServiceConfig,
+    dedup::{ContentHash, DedupConfig, DuplicateMatch},
+    embedder::Embedder,
+    embeddings::{encode_sentence, encode_sentences, search_text, Embedding, Embeddings},
+    error::EmbeddingsError,
+    id::ChunkId,
+    index::{
+        IndexEntry, IngestionClaim, MemoryFootprint, RecallReport, StoredChunk, StoredEmbedding,
+        VectorIndex,
+    },
+    metadata::{ChunkMetadata, Ingestion},
+    migration::{Migration, MigrationProgress, ModelSpec},
+    search::{DocumentResults, SearchParams, SearchResult},
+};
+#[cfg(feature = "rust-bert")]
+use crate::{
+    cache::{CachedEmbedder, EmbeddingCache},
+    config::EmbeddingsConfig,
+    embeddings::EmbeddingModel,
+};
+use log::{error, info};
 use serde::{Deserialize, Serialize};
-use std::sync::mpsc::{Receiver, Sender};
+use std::{
+    any::Any,
+    panic::{self, AssertUnwindSafe},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc, Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    task::{Context, Poll, Wake, Waker},
+    thread::Thread,
+    time::{Duration, Instant},
+};
+use tokio::sync::{
+    mpsc::Receiver,
+    oneshot::{self, Sender},
+};
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
 pub enum Message {
-    ChunkText((u32, String)),
+    ChunkText((ChunkId, String)),
     Reset,
     Send((u32, Vec<f32>)),
     ProcessChunk(String),
     Stop,
     GetChunkId((String, u32)),
+    Search((String, SearchParams)),
+    ChunkTextBatch(Vec<(ChunkId, String)>),
+    Stats,
+    ChunkTextWithMetadata((ChunkId, String, ChunkMetadata)),
+    Delete(Vec<ChunkId>),
+    Upsert((ChunkId, String)),
+    Get(ChunkId),
+    /// Stores a chunk unless it duplicates a stored one.
+    Ingest((ChunkId, String, ChunkMetadata, DedupConfig)),
+    GetChunk(ChunkId),
+    /// Fetches the chunks of several ids, skipping those stored without text.
+    GetChunks(Vec<ChunkId>),
+    /// Splits a document into sections and chunks within the model and LLM token budgets.
+    SplitDocument((String, ChunkerConfig)),
+    /// Fetches the chunks of a document, in document order.
+    GetDocument(String),
+    /// Deletes every chunk of a document.
+    DeleteDocument(String),
+    /// Searches, and groups the results by document.
+    SearchDocuments((String, SearchParams)),
+    /// Sets the knowledge graph extraction of a stored chunk.
+    SetIngestion((ChunkId, Ingestion)),
+    /// Fetches the chunk with an ingestion or content hash.
+    GetChunkByHash(ContentHash),
+    /// Stores a chunk to have its knowledge graph extracted, unless a chunk with the same
+    /// content was, deduplicating it if set. Extractions pending for over the given seconds
+    /// are claimed again.
+    ClaimIngestion((ChunkId, String, ChunkMetadata, Option<DedupConfig>, u64)),
+    /// Reports the bytes held in memory by the index.
+    MemoryFootprint,
+    /// Measures the recall of the configured search against exact search, over a number of
+    /// stored vectors, each searching for a number of results.
+    MeasureRecall((u32, u32)),
+    /// Reports the hits and misses of the embeddings cache.
+    CacheStats,
+    /// Starts encoding every stored chunk again with another model, in the background.
+    StartMigration(ModelSpec),
+    /// Reports the progress of the last migration.
+    MigrationProgress,
+    /// Stops the running migration, leaving the stored embeddings as they were.
+    CancelMigration,
 }
 
-pub struct EmbeddingsService {
-    pub(crate) chunk_receiver: Receiver<String>,
-    pub(crate) embeddings: Embeddings,
-    pub(crate) embedding_sender: Sender<[f32; DEFAULT_MODEL_EMBEDDING_SIZE]>,
-    pub(crate) embedding_index_sender: Sender<u32>,
+impl Message {
+    /// Whether the message stores a single chunk, and can be batched with similar ones.
+    fn is_chunk(&self) -> bool {
+        matches!(self, Self::ChunkText(_) | Self::ChunkTextWithMetadata(_))
+    }
 }
 
-impl EmbeddingsService {
-    pub fn new(
-        chunk_receiver: Receiver<String>,
-        embedding_sender: Sender<[f32; DEFAULT_MODEL_EMBEDDING_SIZE]>,
-        embedding_index_sender: Sender<u32>,
-    ) -> Result<Self, Error> {
-        Ok(Self {
-            chunk_receiver,
-            embeddings: Embeddings::new()?,
-            embedding_sender,
-            embedding_index_sender,
-        })
+impl FromStr for Message {
+    type Err = EmbeddingsError;
+
+    fn from_str(message: &str) -> Result<Self, Self::Err> {
+        serde_json::from_str(message).map_err(|e| EmbeddingsError::InvalidMessage(e.to_string()))
+    }
+}
+
+/// Reply to a single `Message`, sent back on the channel of the request that carried it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Reply {
+    /// `ChunkText` or `ChunkTextWithMetadata` was embedded and stored, or `SetIngestion` was
+    /// stored.
+    Stored,
+    /// `ProcessChunk` embedding.
+    Embedding(Embedding),
+    /// Embeddings removed by `Reset`.
+    Embeddings(Vec<Embedding>),
+    /// Results of `Send`, `GetChunkId` and `Search`, best first.
+    SearchResults(Vec<SearchResult>),
+    /// Outcome of every chunk of a `ChunkTextBatch`, in order.
+    BatchStored(Vec<Result<(), EmbeddingsError>>),
+    /// Outcome of every id of a `Delete`, in order.
+    Deleted(Vec<Result<(), EmbeddingsError>>),
+    /// `Upsert` was stored, telling whether it replaced an embedding.
+    Upserted(bool),
+    /// Embedding found by `Get`.
+    StoredEmbedding(StoredEmbedding),
+    /// `Ingest` was handled, reporting the stored chunk it duplicates, if any.
+    Ingested(Option<DuplicateMatch>),
+    /// Outcome of `ClaimIngestion`.
+    IngestionClaimed(IngestionClaim),
+    /// Chunk found by `GetChunk` or `GetChunkByHash`.
+    Chunk(StoredChunk),
+    /// Chunks found by `GetChunks` or `GetDocument`, in order.
+    Chunks(Vec<StoredChunk>),
+    /// Sections and chunks of a `SplitDocument`.
+    DocumentSplit(DocumentSplit),
+    /// Ids of the chunks removed by `DeleteDocument`, in document order.
+    DocumentDeleted(Vec<ChunkId>),
+    /// Results of `SearchDocuments`, grouped by document.
+    DocumentResults(Vec<DocumentResults>),
+    /// Bytes held in memory by the index, for `MemoryFootprint`.
+    MemoryFootprint(MemoryFootprint),
+    /// Recall of the configured search, for `MeasureRecall`.
+    Recall(RecallReport),
+    /// Counters of the embeddings cache, for `CacheStats`.
+    CacheStats(CacheStats),
+    /// Progress of the migration started, or of the last one, if any.
+    Migration(Option<MigrationProgress>),
+    /// Counters of the service, for `Stats`.
+    Stats(ServiceStats),
+    Stopped,
+    /// The message failed, and the service moved on to the next one.
+    Error(EmbeddingsError),
+}
+
+/// Number of requests handled by the service since it started.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ServiceStats {
+    pub handled: u64,
+    /// Requests answered with an error, including those whose worker panicked.
+    pub failed: u64,
+    /// Workers restarted after a panic.
+    pub restarts: u64,
+}
+
+/// Counters behind `ServiceStats`, shared by the workers of a pool.
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    handled: AtomicU64,
+    failed: AtomicU64,
+    restarts: AtomicU64,
+}
+
+impl Counters {
+    fn stats(&self) -> ServiceStats {
+        ServiceStats {
+            handled: self.handled.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            restarts: self.restarts.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A `Message` together with the channel its reply goes to.
+pub struct Request {
+    pub(crate) message: Message,
+    pub(crate) reply_sender: Sender<Reply>,
+    pub(crate) cancellation: CancellationToken,
+}
+
+impl Request {
+    pub fn new(message: Message) -> (Self, PendingReply) {
+        let (reply_sender, reply_receiver) = oneshot::channel();
+        let cancellation = CancellationToken::default();
+        let request = Self {
+            message,
+            reply_sender,
+            cancellation: cancellation.clone(),
+        };
+        (request, PendingReply::new(reply_receiver, cancellation))
+    }
+
+    pub fn message(&self) -> &Message {
+        &self.message
+    }
+}
+
+pub struct EmbeddingsService<E: Embedder> {
+    pub(crate) request_receiver: Receiver<Request>,
+    pub(crate) worker: Worker<E>,
+    pub(crate) config: ServiceConfig,
+}
+
+/// Encodes and answers requests. The workers of a pool share the index, while each owns its
+/// model, so that requests are encoded in parallel.
+pub(crate) struct Worker<E: Embedder> {
+    pub(crate) model: E,
+    pub(crate) index: Arc<RwLock<VectorIndex>>,
+    pub(crate) counters: Arc<Counters>,
+    // Builds a fresh model when the worker restarts, otherwise the current one is kept.
+    pub(crate) new_model: Option<Arc<ModelFactory<E>>>,
+    pub(crate) migrations: Option<Migrations<E>>,
+    // Number of migrations the model of the worker follows, and the model of the last one,
+    // rebuilt instead of the first when the worker restarts.
+    pub(crate) generation: u64,
+    pub(crate) spec: Option<ModelSpec>,
+    // Counts the LLM tokens of the chunks documents are split into.
+    pub(crate) llm_tokens: Arc<LlmTokenCounter>,
+}
+
+pub(crate) type ModelFactory<E> = dyn Fn() -> Result<E, Error> + Send + Sync;
+
+pub(crate) type SpecFactory<E> = dyn Fn(&ModelSpec) -> Result<E, Error> + Send + Sync;
+
+type MigrationModelFactory =
+    dyn Fn(&ModelSpec) -> Result<Box<dyn Embedder + Send>, Error> + Send + Sync;
+
+/// Migrations of the stored embeddings to other models, shared by the workers of a pool.
+pub(crate) struct Migrations<E: Embedder> {
+    // Builds the model of a worker, once the index was migrated.
+    new_model: Arc<SpecFactory<E>>,
+    // Builds the model a migration encodes with, on its own thread.
+    new_migration_model: Arc<MigrationModelFactory>,
+    shared: Arc<MigrationSlot>,
+}
+
+impl<E: Embedder> Clone for Migrations<E> {
+    fn clone(&self) -> Self {
+        Self {
+            new_model: self.new_model.clone(),
+            new_migration_model: self.new_migration_model.clone(),
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct MigrationSlot {
+    // Held for reading while a request is served, and for writing while the index is
+    // swapped, so that every request is served by the model of the index it searches.
+    gate: RwLock<()>,
+    state: Mutex<MigrationSlotState>,
+}
+
+#[derive(Default)]
+struct MigrationSlotState {
+    migration: Option<Arc<Migration>>,
+    // Number of migrations completed, and the model of the last one.
+    generation: u64,
+    spec: Option<ModelSpec>,
+}
+
+impl MigrationSlot {
+    fn state(&self) -> std::sync::MutexGuard<'_, MigrationSlotState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<E: Embedder> Migrations<E> {
+    /// Migrates `index` to the model of `spec` on a new thread, unless a migration is running.
+    fn start(
+        &self,
+        index: Arc<RwLock<VectorIndex>>,
+        spec: ModelSpec,
+    ) -> Result<MigrationProgress, EmbeddingsError> {
+        let mut state = self.shared.state();
+        if state.migration.as_ref().is_some_and(|m| m.is_running()) {
+            return Err(EmbeddingsError::MigrationInProgress);
+        }
+        let from = index
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .model()
+            .cloned();
+        let migration = Arc::new(Migration::new(from));
+        state.migration = Some(migration.clone());
+        let progress = migration.progress();
+
+        let shared = self.shared.clone();
+        let new_model = self.new_migration_model.clone();
+        info!("Starting migration to model {spec:?}..");
+        std::thread::spawn(move || {
+            let model = match new_model(&spec) {
+                Ok(model) => model,
+                Err(e) => return migration.fail(&EmbeddingsError::ModelLoading(e.to_string())),
+            };
+            let Ok(migrated) = migration.build(&index, &model) else {
+                return;
+            };
+            let _gate = shared.gate.write().unwrap_or_else(PoisonError::into_inner);
+            let mut index = index.write().unwrap_or_else(PoisonError::into_inner);
+            if migration.finish(&mut index, migrated, &model).is_ok() {
+                let mut state = shared.state();
+                state.generation += 1;
+                state.spec = Some(spec);
+            }
+        });
+        Ok(progress)
+    }
+
+    fn current(&self) -> Option<Arc<Migration>> {
+        self.shared.state().migration.clone()
     }
+}
+
+/// Requests handed out to a worker: queued chunks are encoded and stored together.
+pub(crate) enum Work {
+    Single(Box<Request>),
+    ChunkBatch(Vec<Request>),
+}
 
+#[cfg(feature = "rust-bert")]
+impl EmbeddingsService<EmbeddingModel> {
+    pub fn new(request_receiver: Receiver<Request>) -> Result<Self, Error> {
+        Ok(Self::from_embeddings(Embeddings::new()?, request_receiver))
+    }
+
+    /// Spawns the worker pool described by the environment, see [`EmbeddingsConfig::from_env`].
     pub fn spawn(
-        chunk_receiver: Receiver<String>,
-        embedding_sender: Sender<[f32; DEFAULT_MODEL_EMBEDDING_SIZE]>,
-        embedding_index_sender: Sender<u32>,
+        request_receiver: Receiver<Request>,
     ) -> std::thread::JoinHandle<Result<(), Error>> {
-        info!("Starting Embeddings service..");
+        match EmbeddingsConfig::from_env() {
+            Ok(config) => Self::spawn_with_config(config, request_receiver),
+            Err(e) => std::thread::spawn(move || Err(e)),
+        }
+    }
+
+    pub fn spawn_with_config(
+        config: EmbeddingsConfig,
+        request_receiver: Receiver<Request>,
+    ) -> std::thread::JoinHandle<Result<(), Error>> {
+        info!(
+            "Starting Embeddings service with {} workers..",
+            config.service.workers
+        );
         std::thread::spawn(move || {
-            Self::new(chunk_receiver, embedding_sender, embedding_index_sender)?.run()
+            let cache = Arc::new(EmbeddingCache::new(config.cache.clone()));
+            let embeddings = Embeddings::from_config(&config)?.with_cache(cache.clone())?;
+            let migration_cache = cache.clone();
+            EmbeddingsService::from_embeddings(embeddings, request_receiver)
+                .with_config(config.service)
+                .with_migrations(move |spec| {
+                    Ok(CachedEmbedder::new(
+                        EmbeddingModel::from_spec(spec)?,
+                        migration_cache.clone(),
+                    )?)
+                })
+                .with_migration_model(EmbeddingModel::from_spec)
+                .run_pool(config.service.workers, move || {
+                    Ok(CachedEmbedder::new(
+                        EmbeddingModel::from_config(&config)?,
+                        cache.clone(),
+                    )?)
+                })
         })
     }
+}
 
-    pub fn run(&mut self) -> Result<(), Error> {
-        while let Ok(message) = self.chunk_receiver.recv() {
-            info!("Received new message: {}", message);
-            let message: Message = serde_json::from_str(&message)?;
-            info!("Message deserialized: {:?}", message);
-            match message {
-                Message::ChunkText((id, chunk)) => {
-                    info!("Process and storing new received text chunk..");
-                    self.embeddings.process_chunk_and_store(id, &chunk)?;
-                    info!("Chunk has being successfully processed and stored");
-                }
-                Message::Reset => {
-                    let data = self.embeddings.reset();
+impl<E: Embedder> EmbeddingsService<E> {
+    pub fn new_with_embedder(embedder: E, request_receiver: Receiver<Request>) -> Self {
+        Self::from_embeddings(Embeddings::new_from_model(embedder), request_receiver)
+    }
 
-                    for embedding in data {
-                        self.embedding_sender.send(embedding)?;
-                    }
-                }
-                Message::Send((num_queries, query_embedding)) => {
-                    if query_embedding.len() != DEFAULT_MODEL_EMBEDDING_SIZE {
-                        return Err(anyhow!("Query embedding is of incorrect length"));
-                    }
-                    let query_embedding: [f32; DEFAULT_MODEL_EMBEDDING_SIZE] =
-                        query_embedding.try_into().unwrap();
-                    let indices = self
-                        .embeddings
-                        .find_closest_embeddings(query_embedding, num_queries);
-                    for index in indices {
-                        self.embedding_index_sender.send(index)?;
+    /// Builds the service on top of already set up embeddings, e.g. reloaded from a store.
+    pub fn from_embeddings(embeddings: Embeddings<E>, request_receiver: Receiver<Request>) -> Self {
+        let (model, index) = embeddings.into_parts();
+        Self {
+            request_receiver,
+            worker: Worker {
+                model,
+                index: Arc::new(RwLock::new(index)),
+                counters: Arc::default(),
+                new_model: None,
+                migrations: None,
+                generation: 0,
+                spec: None,
+                llm_tokens: Arc::new(approximate_llm_tokens),
+            },
+            config: ServiceConfig::default(),
+        }
+    }
+
+    /// Lets the service migrate the stored embeddings to the models `new_model` builds, see
+    /// `Message::StartMigration`. Once a migration swapped the index, every worker builds the
+    /// new model before serving its next request, and answers `ModelLoading` until it does.
+    pub fn with_migrations<F>(mut self, new_model: F) -> Self
+    where
+        E: Send + 'static,
+        F: Fn(&ModelSpec) -> Result<E, Error> + Send + Sync + 'static,
+    {
+        let new_model: Arc<SpecFactory<E>> = Arc::new(new_model);
+        let new_migration_model = {
+            let new_model = new_model.clone();
+            Arc::new(move |spec: &ModelSpec| {
+                Ok(Box::new(new_model(spec)?) as Box<dyn Embedder + Send>)
+            })
+        };
+        self.worker.migrations = Some(Migrations {
+            new_model,
+            new_migration_model,
+            shared: Arc::default(),
+        });
+        self
+    }
+
+    /// Has migrations encode with the models `new_model` builds, rather than with the ones of
+    /// [`Self::with_migrations`], which must have been called first. Workers sharing an
+    /// [`EmbeddingCache`](crate::cache::EmbeddingCache) need this, as binding the cache to the migrated model while the
+    /// workers still serve the current one would empty it.
+    pub fn with_migration_model<F, M>(mut self, new_model: F) -> Self
+    where
+        M: Embedder + Send + 'static,
+        F: Fn(&ModelSpec) -> Result<M, Error> + Send + Sync + 'static,
+    {
+        if let Some(migrations) = self.worker.migrations.as_mut() {
+            migrations.new_migration_model = Arc::new(move |spec: &ModelSpec| {
+                Ok(Box::new(new_model(spec)?) as Box<dyn Embedder + Send>)
+            });
+        }
+        self
+    }
+
+    /// Has documents split into chunks whose LLM tokens, as counted by `llm_tokens`, fit the
+    /// LLM budget, rather than [approximated](approximate_llm_tokens) ones.
+    pub fn with_llm_tokens<L>(mut self, llm_tokens: L) -> Self
+    where
+        L: Fn(&str) -> usize + Send + Sync + 'static,
+    {
+        self.worker.llm_tokens = Arc::new(llm_tokens);
+        self
+    }
+
+    /// Sets the batching settings. The pool size is given to [`Self::run_pool`].
+    pub fn with_config(mut self, config: ServiceConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn spawn_with_embedder(
+        embedder: E,
+        request_receiver: Receiver<Request>,
+    ) -> std::thread::JoinHandle<Result<(), Error>>
+    where
+        E: Send + 'static,
+    {
+        info!(
+            "Starting Embeddings service with model {}..",
+            embedder.model_id()
+        );
+        std::thread::spawn(move || Self::new_with_embedder(embedder, request_receiver).run())
+    }
+
+    /// Spawns a pool of `workers` model workers over `embeddings`: the first worker uses the
+    /// model of `embeddings`, and every other one a model built by `new_model`.
+    pub fn spawn_pool<F>(
+        embeddings: Embeddings<E>,
+        request_receiver: Receiver<Request>,
+        workers: usize,
+        new_model: F,
+    ) -> std::thread::JoinHandle<Result<(), Error>>
+    where
+        E: Send + 'static,
+        F: Fn() -> Result<E, Error> + Send + Sync + 'static,
+    {
+        info!(
+            "Starting Embeddings service with {workers} workers of model {}..",
+            embeddings.model().model_id()
+        );
+        std::thread::spawn(move || {
+            Self::from_embeddings(embeddings, request_receiver).run_pool(workers, new_model)
+        })
+    }
+
+    /// Handles requests one at a time on the current thread, until the service is stopped or
+    /// every client is dropped. A failing request is answered with `Reply::Error`, and a
+    /// panicking one restarts the worker, without ending the loop.
+    ///
+    /// The thread is blocked while waiting for requests, without driving a runtime, so that
+    /// the service may also run from async code. There, it holds a runtime worker thread for
+    /// as long as it runs, so it is best spawned on a thread of its own, as [`Self::spawn`] does.
+    pub fn run(self) -> Result<(), Error> {
+        let mut worker = self.worker;
+        let mut intake = Intake::new(self.request_receiver, &self.config);
+        while let Some(work) = intake.next() {
+            worker.serve(work);
+        }
+        intake.reply_stopped();
+        Ok(())
+    }
+
+    /// Dispatches requests to `workers` worker threads, until the service is stopped or every
+    /// client is dropped.
+    pub fn run_pool<F>(mut self, workers: usize, new_model: F) -> Result<(), Error>
+    where
+        E: Send + 'static,
+        F: Fn() -> Result<E, Error> + Send + Sync + 'static,
+    {
+        let new_model: Arc<ModelFactory<E>> = Arc::new(new_model);
+        self.worker.new_model = Some(new_model.clone());
+        if workers <= 1 {
+            return self.run();
+        }
+
+        // Bounded, so that a busy pool holds requests back in the client queue.
+        let (work_sender, work_receiver) = mpsc::sync_channel::<Work>(workers);
+        let work_receiver = Arc::new(Mutex::new(work_receiver));
+        let index = self.worker.index.clone();
+        let counters = self.worker.counters.clone();
+        let migrations = self.worker.migrations.clone();
+        let llm_tokens = self.worker.llm_tokens.clone();
+        let mut first_worker = Some(self.worker);
+        let join_handles: Vec<_> = (0..workers)
+            .map(|worker_id| {
+                let work_receiver = work_receiver.clone();
+                let index = index.clone();
+                let counters = counters.clone();
+                let new_model = new_model.clone();
+                let migrations = migrations.clone();
+                let llm_tokens = llm_tokens.clone();
+                let worker = first_worker.take();
+                std::thread::spawn(move || {
+                    let mut worker = match worker {
+                        Some(worker) => worker,
+                        None => Worker::new(
+                            new_model()?,
+                            index,
+                            counters,
+                            Some(new_model),
+                            migrations,
+                            llm_tokens,
+                        )?,
+                    };
+                    info!("Embeddings worker {worker_id} started");
+                    loop {
+                        let work = work_receiver
+                            .lock()
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .recv();
+                        match work {
+                            Ok(work) => worker.serve(work),
+                            Err(_) => return Ok(()),
+                        }
                     }
-                }
-                Message::ProcessChunk(chunk) => {
-                    let embedding = self.embeddings.process_chunk(&chunk)?;
-                    self.embedding_sender.send(embedding)?;
-                }
-                Message::Stop => {
+                })
+            })
+            .collect();
+
+        let mut intake = Intake::new(self.request_receiver, &self.config);
+        while let Some(work) = intake.next() {
+            if work_sender.send(work).is_err() {
+                error!("Every embeddings worker has stopped");
+                break;
+            }
+        }
+        // Lets the workers finish the queued requests, then exit.
+        drop(work_sender);
+
+        let mut result = Ok(());
+        for join_handle in join_handles {
+            let worker_result = join_handle
+                .join()
+                .unwrap_or_else(|_| Err(anyhow!("Embeddings worker panicked")));
+            if let Err(e) = worker_result {
+                error!("Embeddings worker failed, with error: {e}");
+                result = result.and(Err(e));
+            }
+        }
+        intake.reply_stopped();
+        result
+    }
+}
+
+/// Receives requests from the clients and groups them into work for the workers.
+struct Intake {
+    request_receiver: Receiver<Request>,
+    batch_size: usize,
+    batch_window: Duration,
+    // Request received while filling a batch, that does not belong to it.
+    held_back: Option<Request>,
+    stop_requests: Vec<Request>,
+}
+
+impl Intake {
+    fn new(request_receiver: Receiver<Request>, config: &ServiceConfig) -> Self {
+        Self {
+            request_receiver,
+            batch_size: config.batch_size,
+            batch_window: Duration::from_millis(config.batch_window_ms),
+            held_back: None,
+            stop_requests: vec![],
+        }
+    }
+
+    /// Returns the next work to be handled. A chunk is batched with the ones following it, up
+    /// to `batch_size` of them or until `batch_window` has elapsed.
+    fn next(&mut self) -> Option<Work> {
+        let request = match self.held_back.take() {
+            Some(request) => request,
+            None => self.recv(None)?,
+        };
+        if !request.message.is_chunk() || self.batch_size <= 1 {
+            return Some(Work::Single(Box::new(request)));
+        }
+
+        let deadline = Instant::now() + self.batch_window;
+        let mut batch = vec![request];
+        while batch.len() < self.batch_size {
+            match self.recv(Some(deadline)) {
+                Some(request) if request.message.is_chunk() => batch.push(request),
+                Some(request) => {
+                    self.held_back = Some(request);
                     break;
                 }
-                Message::GetChunkId((chunk, num_queries)) => {
-                    let embedding = self.embeddings.process_chunk(&chunk)?;
-                    let indices = self
-                        .embeddings
-                        .find_closest_embeddings(embedding, num_queries);
-                    for index in indices {
-                        self.embedding_index_sender.send(index)?;
+                None => break,
+            }
+        }
+        Some(Work::ChunkBatch(batch))
+    }
+
+    /// Receives the next request, if any arrives before `deadline`. A `Stop` request closes
+    /// the queue: the requests already queued are still handed out, and the `Stop` requests
+    /// are answered last.
+    fn recv(&mut self, deadline: Option<Instant>) -> Option<Request> {
+        loop {
+            let request = self.recv_until(deadline)?;
+            if let Message::Stop = request.message {
+                info!("Stopping Embeddings service..");
+                self.request_receiver.close();
+                self.stop_requests.push(request);
+                continue;
+            }
+            return Some(request);
+        }
+    }
+
+    /// Blocks the thread until a request arrives, the queue is closed or `deadline` passes,
+    /// parking it in between, so that waiting needs no runtime.
+    fn recv_until(&mut self, deadline: Option<Instant>) -> Option<Request> {
+        let waker = Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+        let mut context = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(request) = self.request_receiver.poll_recv(&mut context) {
+                return request;
+            }
+            // Parking may end early, so the queue is polled again either way.
+            match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return None;
                     }
+                    std::thread::park_timeout(deadline - now);
                 }
+                None => std::thread::park(),
+            }
+        }
+    }
+
+    fn reply_stopped(self) {
+        for request in self.stop_requests {
+            // Nobody may be waiting for the reply anymore.
+            let _ = request.reply_sender.send(Reply::Stopped);
+        }
+    }
+}
+
+/// Wakes a thread parked by [`Intake::recv_until`].
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+impl<E: Embedder> Worker<E> {
+    fn new(
+        model: E,
+        index: Arc<RwLock<VectorIndex>>,
+        counters: Arc<Counters>,
+        new_model: Option<Arc<ModelFactory<E>>>,
+        migrations: Option<Migrations<E>>,
+        llm_tokens: Arc<LlmTokenCounter>,
+    ) -> Result<Self, Error> {
+        check_model(
+            &model,
+            &index.read().unwrap_or_else(PoisonError::into_inner),
+        )?;
+        Ok(Self {
+            model,
+            index,
+            counters,
+            new_model,
+            migrations,
+            generation: 0,
+            spec: None,
+            llm_tokens,
+        })
+    }
+
+    fn serve(&mut self, work: Work) {
+        let migrations = self.migrations.clone();
+        let _gate = migrations.as_ref().map(|migrations| {
+            let gate = migrations.shared.gate.read();
+            gate.unwrap_or_else(PoisonError::into_inner)
+        });
+        let followed = migrations
+            .as_ref()
+            .map(|migrations| self.follow_migration(migrations));
+        if let Some(Err(e)) = followed {
+            return self.refuse(work, e);
+        }
+        match work {
+            Work::Single(request) => self.serve_request(*request),
+            Work::ChunkBatch(requests) => self.serve_chunk_batch(requests),
+        }
+    }
+
+    fn serve_request(&mut self, request: Request) {
+        let Request {
+            message,
+            reply_sender,
+            cancellation,
+        } = request;
+        if cancellation.is_cancelled() {
+            info!("Skipping cancelled message: {:?}", message);
+            return;
+        }
+        info!("Received new message: {:?}", message);
+        let reply = self
+            .supervised(|worker| worker.handle(message))
+            .unwrap_or_else(Reply::Error);
+        self.reply(reply_sender, reply);
+    }
+
+    /// Answers every request of `work` with `e`.
+    fn refuse(&self, work: Work, e: EmbeddingsError) {
+        let requests = match work {
+            Work::Single(request) => vec![*request],
+            Work::ChunkBatch(requests) => requests,
+        };
+        for request in requests {
+            self.reply(request.reply_sender, Reply::Error(e.clone()));
+        }
+    }
+
+    /// Encodes the chunks of `requests` in a single model call, and stores them in bulk.
+    fn serve_chunk_batch(&mut self, requests: Vec<Request>) {
+        let mut chunks = vec![];
+        let mut reply_senders = vec![];
+        for request in requests {
+            if request.cancellation.is_cancelled() {
+                info!("Skipping cancelled message: {:?}", request.message);
+                continue;
+            }
+            let chunk = match request.message {
+                Message::ChunkText((id, chunk)) => (id, chunk, ChunkMetadata::default()),
+                Message::ChunkTextWithMetadata(chunk) => chunk,
+                // The intake only batches chunks.
+                message => {
+                    error!("Unexpected message in a chunk batch: {:?}", message);
+                    continue;
+                }
+            };
+            chunks.push(chunk);
+            reply_senders.push(request.reply_sender);
+        }
+        info!("Process and storing {} text chunks..", chunks.len());
+        let results = match self.supervised(|worker| Ok(worker.store_chunks(&chunks))) {
+            Ok(results) => results,
+            Err(e) => chunks.iter().map(|_| Err(e.clone())).collect(),
+        };
+        for (reply_sender, result) in reply_senders.into_iter().zip(results) {
+            let reply = match result {
+                Ok(()) => Reply::Stored,
+                Err(e) => Reply::Error(e),
+            };
+            self.reply(reply_sender, reply);
+        }
+    }
+
+    fn store_chunks(
+        &self,
+        chunks: &[(ChunkId, String, ChunkMetadata)],
+    ) -> Vec<Result<(), EmbeddingsError>> {
+        let sentences: Vec<&str> = chunks.iter().map(|(_, chunk, _)| chunk.as_str()).collect();
+        let embeddings = encode_sentences(&self.model, &sentences);
+        self.write_index().insert_encoded(
+            chunks
+                .iter()
+                .zip(embeddings)
+                .map(|((id, chunk, metadata), embedding)| {
+                    embedding.map(|embedding| {
+                        IndexEntry::new(*id, embedding)
+                            .with_metadata(metadata.clone())
+                            .with_text(chunk)
+                    })
+                })
+                .collect(),
+        )
+    }
+
+    fn store_chunk(
+        &self,
+        id: ChunkId,
+        chunk: &str,
+        metadata: ChunkMetadata,
+    ) -> Result<Reply, EmbeddingsError> {
+        info!("Process and storing new received text chunk..");
+        let embedding = encode_sentence(&self.model, chunk)?;
+        self.write_index()
+            .insert_entries(vec![IndexEntry::new(id, embedding)
+                .with_metadata(metadata)
+                .with_text(chunk)])?;
+        info!("Chunk has being successfully processed and stored");
+        Ok(Reply::Stored)
+    }
+
+    fn handle(&self, message: Message) -> Result<Reply, EmbeddingsError> {
+        let reply = match message {
+            Message::ChunkText((id, chunk)) => {
+                self.store_chunk(id, &chunk, ChunkMetadata::default())?
+            }
+            Message::ChunkTextWithMetadata((id, chunk, metadata)) => {
+                self.store_chunk(id, &chunk, metadata)?
+            }
+            Message::ChunkTextBatch(chunks) => Reply::BatchStored(
+                self.store_chunks(
+                    &chunks
+                        .into_iter()
+                        .map(|(id, chunk)| (id, chunk, ChunkMetadata::default()))
+                        .collect::<Vec<_>>(),
+                ),
+            ),
+            Message::Reset => Reply::Embeddings(self.write_index().reset()?),
+            Message::Send((num_queries, query_embedding)) => {
+                let query_embedding = Embedding::new(query_embedding, self.model.dimension())?;
+                Reply::SearchResults(
+                    self.read_index()
+                        .search(&query_embedding, &SearchParams::new(num_queries))?,
+                )
+            }
+            Message::ProcessChunk(chunk) => Reply::Embedding(encode_sentence(&self.model, &chunk)?),
+            Message::Stop => Reply::Stopped,
+            Message::GetChunkId((chunk, num_queries)) => Reply::SearchResults(search_text(
+                &self.model,
+                &self.read_index(),
+                &chunk,
+                &SearchParams::new(num_queries),
+            )?),
+            Message::Search((chunk, params)) => Reply::SearchResults(search_text(
+                &self.model,
+                &self.read_index(),
+                &chunk,
+                &params,
+            )?),
+            Message::Stats => Reply::Stats(self.counters.stats()),
+            Message::Delete(ids) => Reply::Deleted(self.write_index().delete(&ids)),
+            Message::Upsert((id, chunk)) => {
+                let embedding = encode_sentence(&self.model, &chunk)?;
+                Reply::Upserted(
+                    self.write_index()
+                        .upsert(IndexEntry::new(id, embedding).with_text(chunk))?,
+                )
+            }
+            Message::Get(id) => Reply::StoredEmbedding(self.read_index().get(id)?),
+            Message::GetChunk(id) => Reply::Chunk(
+                self.read_index()
+                    .chunk(id)
+                    .ok_or(EmbeddingsError::NotFound(id))?,
+            ),
+            Message::GetChunks(ids) => {
+                let index = self.read_index();
+                Reply::Chunks(ids.into_iter().filter_map(|id| index.chunk(id)).collect())
+            }
+            Message::SplitDocument((document, config)) => Reply::DocumentSplit(split_document(
+                &self.model,
+                &document,
+                config,
+                &*self.llm_tokens,
+            )),
+            Message::GetDocument(document_id) => {
+                let index = self.read_index();
+                Reply::Chunks(
+                    index
+                        .document(&document_id)
+                        .into_iter()
+                        .filter_map(|id| index.chunk(id))
+                        .collect(),
+                )
+            }
+            Message::DeleteDocument(document_id) => {
+                Reply::DocumentDeleted(self.write_index().delete_document(&document_id)?)
+            }
+            Message::SearchDocuments((chunk, params)) => {
+                let index = self.read_index();
+                let results = search_text(&self.model, &index, &chunk, &params)?;
+                Reply::DocumentResults(index.group_by_document(results))
+            }
+            Message::Ingest((id, chunk, metadata, dedup)) => {
+                let embedding = encode_sentence(&self.model, &chunk)?;
+                // Checked and stored under a single lock, so that concurrent copies of a
+                // chunk are not both stored.
+                Reply::Ingested(
+                    self.write_index().insert_deduplicated(
+                        IndexEntry::new(id, embedding)
+                            .with_metadata(metadata)
+                            .with_text(chunk),
+                        &dedup,
+                    )?,
+                )
+            }
+            Message::SetIngestion((id, ingestion)) => {
+                self.write_index().set_ingestion(id, ingestion)?;
+                Reply::Stored
             }
+            Message::ClaimIngestion((id, chunk, metadata, dedup, pending_timeout)) => {
+                let embedding = encode_sentence(&self.model, &chunk)?;
+                Reply::IngestionClaimed(
+                    self.write_index().claim_ingestion(
+                        IndexEntry::new(id, embedding)
+                            .with_metadata(metadata)
+                            .with_text(chunk),
+                        dedup.as_ref(),
+                        pending_timeout,
+                    )?,
+                )
+            }
+            Message::GetChunkByHash(hash) => Reply::Chunk(
+                self.read_index()
+                    .chunk_by_hash(&hash)
+                    .ok_or(EmbeddingsError::HashNotFound(hash))?,
+            ),
+            Message::MemoryFootprint => {
+                Reply::MemoryFootprint(self.read_index().memory_footprint())
+            }
+            Message::MeasureRecall((queries, k)) => {
+                Reply::Recall(self.read_index().measure_recall(queries as usize, k)?)
+            }
+            Message::CacheStats => {
+                Reply::CacheStats(self.model.cache().map(|c| c.stats()).unwrap_or_default())
+            }
+            Message::StartMigration(spec) => {
+                let migrations = self
+                    .migrations
+                    .as_ref()
+                    .ok_or(EmbeddingsError::MigrationUnsupported)?;
+                Reply::Migration(Some(migrations.start(self.index.clone(), spec)?))
+            }
+            Message::MigrationProgress => Reply::Migration(
+                self.migrations
+                    .as_ref()
+                    .and_then(Migrations::current)
+                    .map(|migration| migration.progress()),
+            ),
+            Message::CancelMigration => {
+                Reply::Migration(self.migrations.as_ref().and_then(Migrations::current).map(
+                    |migration| {
+                        migration.cancel();
+                        migration.progress()
+                    },
+                ))
+            }
+        };
+        Ok(reply)
+    }
+
+    /// Runs `f`, restarting the worker if it panics, so that the next requests are served from
+    /// a consistent state.
+    fn supervised<T>(
+        &mut self,
+        f: impl FnOnce(&Self) -> Result<T, EmbeddingsError>,
+    ) -> Result<T, EmbeddingsError> {
+        match panic::catch_unwind(AssertUnwindSafe(|| f(self))) {
+            Ok(result) => result,
+            Err(panic) => {
+                let reason = panic_message(panic.as_ref());
+                error!("Embeddings worker panicked, with error: {reason}. Restarting it..");
+                self.restart();
+                Err(EmbeddingsError::WorkerPanicked(reason))
+            }
+        }
+    }
+
+    /// Builds the model of the last completed migration, unless the worker already did. Until
+    /// it loads, the worker follows the previous migration, so that it tries again on its next
+    /// request, rather than encoding with a model the index was migrated from.
+    fn follow_migration(&mut self, migrations: &Migrations<E>) -> Result<(), EmbeddingsError> {
+        let (generation, spec) = {
+            let state = migrations.shared.state();
+            (state.generation, state.spec.clone())
+        };
+        if generation == self.generation {
+            return Ok(());
         }
+        let Some(spec) = spec else {
+            self.generation = generation;
+            return Ok(());
+        };
+        let model = (migrations.new_model)(&spec)
+            .and_then(|model| {
+                check_model(&model, &self.read_index())?;
+                Ok(model)
+            })
+            .map_err(|e| {
+                error!("Failed to load the migrated model, with error: {e}");
+                EmbeddingsError::ModelLoading(e.to_string())
+            })?;
+        info!("Embeddings worker switched to model {}", model.model_tag());
+        self.model = model;
+        self.generation = generation;
+        self.spec = Some(spec);
         Ok(())
     }
+
+    /// Rebuilds the model, if the worker knows how to, and reloads the index from its store,
+    /// as the panic may have left either half updated.
+    fn restart(&mut self) {
+        self.counters.restarts.fetch_add(1, Ordering::Relaxed);
+        let model = match (&self.migrations, &self.spec, &self.new_model) {
+            (Some(migrations), Some(spec), _) => Some((migrations.new_model)(spec)),
+            (_, _, Some(new_model)) => Some(new_model()),
+            _ => None,
+        };
+        if let Some(model) = model {
+            let model = model.and_then(|model| {
+                check_model(&model, &self.read_index())?;
+                Ok(model)
+            });
+            match model {
+                Ok(model) => self.model = model,
+                Err(e) => error!("Failed to reload the model, keeping the current one: {e}"),
+            }
+        }
+        if let Err(e) = self.write_index().reload() {
+            error!("Failed to reload the embeddings index, with error: {e}");
+        }
+        self.index.clear_poison();
+        info!("Embeddings worker restarted");
+    }
+
+    fn reply(&self, reply_sender: Sender<Reply>, reply: Reply) {
+        self.counters.handled.fetch_add(1, Ordering::Relaxed);
+        if let Reply::Error(e) = &reply {
+            self.counters.failed.fetch_add(1, Ordering::Relaxed);
+            error!("Failed to handle message, with error: {e}");
+        }
+        // The caller may have timed out in the meantime, in which case nobody listens.
+        if reply_sender.send(reply).is_err() {
+            info!("Dropping reply to a cancelled request");
+        }
+    }
+
+    fn read_index(&self) -> RwLockReadGuard<'_, VectorIndex> {
+        self.index.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write_index(&self) -> RwLockWriteGuard<'_, VectorIndex> {
+        self.index.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+fn check_model<E: Embedder>(model: &E, index: &VectorIndex) -> Result<(), EmbeddingsError> {
+    if model.dimension() != index.dimension() {
+        return Err(EmbeddingsError::DimensionMismatch {
+            expected: index.dimension(),
+            found: model.dimension(),
+        });
+    }
+    match index.model() {
+        Some(stored) if *stored != model.model_tag() => Err(EmbeddingsError::ModelMismatch {
+            expected: stored.clone(),
+            found: model.model_tag(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => panic
+            .downcast_ref::<String>()
+            .cloned()
+            .unwrap_or_else(|| "unknown panic".to_string()),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{
+        client,
+        dedup::{ContentHash, DuplicatePolicy},
+        embedder::{HashingEmbedder, ModelTag},
+        metadata::{ChunkPosition, IngestionStatus},
+        migration::MigrationState,
+    };
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    fn id(id: usize) -> ChunkId {
+        ChunkId::from(id as u32)
+    }
+
+    const SENTENCES: [&str; 3] = [
+        "Hello world !",
+        "Knowledge graphs are great !",
+        "LLMs are amazing, as well !",
+    ];
+
+    #[tokio::test]
+    async fn test_service_with_hashing_embedder() {
+        let (client, request_receiver) = client::channel(16);
+        let join_handle =
+            EmbeddingsService::spawn_with_embedder(HashingEmbedder::default(), request_receiver);
+
+        for (i, sentence) in SENTENCES.iter().enumerate() {
+            client.insert(id(i), sentence.to_string()).await.unwrap();
+        }
+
+        let results = client
+            .search(
+                "Are knowledge graphs great ?".to_string(),
+                SearchParams::new(1),
+            )
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].id, id(1));
+
+        // Only 3 chunks are stored, so fewer than `num_queries` results come back.
+        let results = client
+            .search(
+                "Are knowledge graphs great ?".to_string(),
+                SearchParams::new(10),
+            )
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 3);
+
+        // A query embedding of the wrong dimension is answered with an error, and the service
+        // keeps serving.
+        let pending = client
+            .send(Message::Send((1, vec![1.0, 2.0])))
+            .await
+            .unwrap();
+        assert_eq!(
+            pending.wait().await,
+            Ok(Reply::Error(EmbeddingsError::DimensionMismatch {
+                expected: HashingEmbedder::default().dimension(),
+                found: 2
+            }))
+        );
+        assert_eq!(
+            client.stats().await,
+            Ok(ServiceStats {
+                handled: 6,
+                failed: 1,
+                restarts: 0
+            })
+        );
+
+        client.stop().await.unwrap();
+        join_handle.join().unwrap().unwrap();
+        assert_eq!(
+            client.embed("Hello".to_string()).await,
+            Err(EmbeddingsError::ServiceStopped)
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_worker_pool_replies_to_each_caller() {
+        let (client, request_receiver) = client::channel(4);
+        let join_handle = EmbeddingsService::spawn_pool(
+            Embeddings::new_from_model(HashingEmbedder::default()),
+            request_receiver,
+            3,
+            || Ok(HashingEmbedder::default()),
+        );
+
+        for (i, sentence) in SENTENCES.iter().enumerate() {
+            client.insert(id(i), sentence.to_string()).await.unwrap();
+        }
+
+        let tasks: Vec<_> = (0..32)
+            .map(|i| {
+                let client = client.clone();
+                let i = i % SENTENCES.len();
+                tokio::spawn(async move {
+                    let results = client
+                        .search(SENTENCES[i].to_string(), SearchParams::new(1))
+                        .await
+                        .unwrap();
+                    assert_eq!(results[0].id, id(i));
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        client.stop().await.unwrap();
+        join_handle.join().unwrap().unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_migration_while_serving() {
+        let (client, request_receiver) = client::channel(16);
+        let join_handle = std::thread::spawn(move || {
+            EmbeddingsService::from_embeddings(
+                Embeddings::new_from_model(HashingEmbedder::default()),
+                request_receiver,
+            )
+            .with_migrations(|spec| HashingEmbedder::new(spec.embedding_dimension.unwrap_or(384)))
+            .run_pool(3, || Ok(HashingEmbedder::default()))
+        });
+
+        for (i, sentence) in SENTENCES.iter().enumerate() {
+            client.insert(id(i), sentence.to_string()).await.unwrap();
+        }
+        assert_eq!(client.migration_progress().await, Ok(None));
+
+        let spec = ModelSpec {
+            embedding_dimension: Some(32),
+            ..Default::default()
+        };
+        let progress = client.start_migration(spec.clone()).await.unwrap();
+        assert_eq!(progress.from, Some(HashingEmbedder::default().model_tag()));
+
+        // Searches are answered throughout the migration, by either model.
+        let progress = loop {
+            let results = client
+                .search(SENTENCES[1].to_string(), SearchParams::new(1))
+                .await
+                .unwrap();
+            assert_eq!(results[0].id, id(1));
+            let progress = client.migration_progress().await.unwrap().unwrap();
+            if progress.state != MigrationState::Running {
+                break progress;
+            }
+        };
+        assert_eq!(progress.state, MigrationState::Completed);
+        assert_eq!(progress.to, Some(ModelTag::new("hashing-bow-32", "1")));
+        assert_eq!(progress.migrated, SENTENCES.len());
+
+        // Every worker now encodes with the new model.
+        let tasks: Vec<_> = (0..8)
+            .map(|i| {
+                let client = client.clone();
+                let i = i % SENTENCES.len();
+                tokio::spawn(async move {
+                    assert_eq!(
+                        client
+                            .embed(SENTENCES[i].to_string())
+                            .await
+                            .unwrap()
+                            .dimension(),
+                        32
+                    );
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        let stored = client.get(id(2)).await.unwrap();
+        assert_eq!(stored.embedding.dimension(), 32);
+        assert_eq!(stored.model, Some(ModelTag::new("hashing-bow-32", "1")));
+
+        // A model that fails to load leaves the index as it was.
+        let spec = ModelSpec {
+            embedding_dimension: Some(0),
+            ..Default::default()
+        };
+        client.start_migration(spec).await.unwrap();
+        let progress = loop {
+            let progress = client.migration_progress().await.unwrap().unwrap();
+            if progress.state != MigrationState::Running {
+                break progress;
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        };
+        assert_eq!(progress.state, MigrationState::Failed);
+        assert!(progress.error.unwrap().starts_with("Failed to load model"));
+        assert_eq!(client.get(id(2)).await.unwrap().embedding.dimension(), 32);
+
+        client.stop().await.unwrap();
+        join_handle.join().unwrap().unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_migrated_model_failing_to_load() {
+        // Only the model of the migration itself loads, until the workers' ones are available.
+        let available = Arc::new(AtomicBool::new(false));
+        let loads = Arc::new(AtomicUsize::new(0));
+        let (client, request_receiver) = client::channel(16);
+        let join_handle = {
+            let available = available.clone();
+            std::thread::spawn(move || {
+                EmbeddingsService::from_embeddings(
+                    Embeddings::new_from_model(HashingEmbedder::default()),
+                    request_receiver,
+                )
+                .with_migrations(move |spec| {
+                    let first = loads.fetch_add(1, Ordering::SeqCst) == 0;
+                    match first || available.load(Ordering::SeqCst) {
+                        true => HashingEmbedder::new(spec.embedding_dimension.unwrap_or(384)),
+                        false => HashingEmbedder::new(0),
+                    }
+                })
+                .run_pool(2, || Ok(HashingEmbedder::default()))
+            })
+        };
+
+        for (i, sentence) in SENTENCES.iter().enumerate() {
+            client.insert(id(i), sentence.to_string()).await.unwrap();
+        }
+        let spec = ModelSpec {
+            embedding_dimension: Some(32),
+            ..Default::default()
+        };
+        client.start_migration(spec).await.unwrap();
+
+        // Once the index is swapped, workers refuse requests rather than encode with the model
+        // the index was migrated from.
+        loop {
+            match client.embed(SENTENCES[1].to_string()).await {
+                Ok(embedding) => assert_eq!(embedding.dimension(), 384),
+                Err(e) => {
+                    assert!(matches!(e, EmbeddingsError::ModelLoading(_)));
+                    break;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert!(matches!(
+            client
+                .search(SENTENCES[1].to_string(), SearchParams::new(1))
+                .await,
+            Err(EmbeddingsError::ModelLoading(_))
+        ));
+
+        // And try loading the model again on every request, until it loads.
+        available.store(true, Ordering::SeqCst);
+        for _ in 0..4 {
+            let embedding = client.embed(SENTENCES[1].to_string()).await.unwrap();
+            assert_eq!(embedding.dimension(), 32);
+        }
+        let results = client
+            .search(SENTENCES[1].to_string(), SearchParams::new(1))
+            .await
+            .unwrap();
+        assert_eq!(results[0].id, id(1));
+
+        client.stop().await.unwrap();
+        join_handle.join().unwrap().unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_migration_keeps_shared_cache() {
+        use crate::cache::{CacheConfig, CachedEmbedder, EmbeddingCache};
+
+        let cache = Arc::new(EmbeddingCache::new(CacheConfig::default()));
+        let cached = |dimension: usize, cache: &Arc<EmbeddingCache>| {
+            Ok(CachedEmbedder::new(
+                HashingEmbedder::new(dimension)?,
+                cache.clone(),
+            )?)
+        };
+        let embeddings = Embeddings::new_from_model(cached(16, &cache).unwrap());
+        let (client, request_receiver) = client::channel(16);
+        let (worker_cache, migration_cache) = (cache.clone(), cache.clone());
+        let join_handle = std::thread::spawn(move || {
+            EmbeddingsService::from_embeddings(embeddings, request_receiver)
+                .with_migrations(move |spec| {
+                    cached(spec.embedding_dimension.unwrap_or(16), &migration_cache)
+                })
+                // Fails on the chunk mentioning "fail", after binding nothing.
+                .with_migration_model(|spec| {
+                    Ok(FaultyEmbedder(HashingEmbedder::new(
+                        spec.embedding_dimension.unwrap_or(16),
+                    )?))
+                })
+                .run_pool(2, move || cached(16, &worker_cache))
+        });
+
+        for (i, sentence) in SENTENCES.iter().chain(["fail"].iter()).enumerate() {
+            client.insert(id(i), sentence.to_string()).await.unwrap();
+        }
+        let spec = ModelSpec {
+            embedding_dimension: Some(32),
+            ..Default::default()
+        };
+        client.start_migration(spec.clone()).await.unwrap();
+        let progress = loop {
+            let progress = client.migration_progress().await.unwrap().unwrap();
+            if progress.state != MigrationState::Running {
+                break progress;
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        };
+        assert_eq!(progress.state, MigrationState::Failed);
+
+        // The cache still serves the current model.
+        let hits = client.cache_stats().await.unwrap().hits;
+        client.embed(SENTENCES[0].to_string()).await.unwrap();
+        let stats = client.cache_stats().await.unwrap();
+        assert_eq!(stats.hits, hits + 1);
+        assert_eq!(stats.invalidations, 0);
+
+        client.stop().await.unwrap();
+        join_handle.join().unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_timeout_cancellation_and_backpressure() {
+        let (client, request_receiver) = client::channel(2);
+        let client = client.with_timeout(Duration::from_millis(10));
+
+        // Nothing serves requests yet, so these never get a reply.
+        assert_eq!(
+            client.insert(id(0), "Hello world !".to_string()).await,
+            Err(EmbeddingsError::Timeout(Duration::from_millis(10)))
+        );
+        let pending = client
+            .send(Message::ChunkText((id(1), "Knowledge graphs".to_string())))
+            .await
+            .unwrap();
+        pending.cancel();
+        assert_eq!(pending.wait().await, Err(EmbeddingsError::Cancelled));
+
+        // The queue is full, so further requests wait for room instead of piling up.
+        assert!(tokio::time::timeout(
+            Duration::from_millis(10),
+            client.send(Message::ProcessChunk("LLMs".to_string()))
+        )
+        .await
+        .is_err());
+
+        // Once the service starts, it skips both the timed out and the cancelled chunks.
+        let join_handle =
+            EmbeddingsService::spawn_with_embedder(HashingEmbedder::default(), request_receiver);
+        let client = client.with_timeout(Duration::from_secs(10));
+        let results = client
+            .search("Hello world !".to_string(), SearchParams::new(10))
+            .await
+            .unwrap();
+        assert!(results.is_empty());
+
+        client.stop().await.unwrap();
+        join_handle.join().unwrap().unwrap();
+    }
+
+    /// Counts the `encode` calls, to check that chunks get batched.
+    struct CountingEmbedder {
+        inner: HashingEmbedder,
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl Embedder for CountingEmbedder {
+        fn encode(&self, texts: &[&str]) -> anyhow::Result<Vec<Vec<f32>>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            self.inner.encode(texts)
+        }
+
+        fn dimension(&self) -> usize {
+            self.inner.dimension()
+        }
+
+        fn model_id(&self) -> &str {
+            self.inner.model_id()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_queued_chunks_are_batched() {
+        let (client, request_receiver) = client::channel(16);
+        let calls = Arc::new(AtomicUsize::new(0));
+        let service = EmbeddingsService::new_with_embedder(
+            CountingEmbedder {
+                inner: HashingEmbedder::default(),
+                calls: calls.clone(),
+            },
+            request_receiver,
+        )
+        .with_config(ServiceConfig {
+            batch_size: 8,
+            ..Default::default()
+        });
+
+        // Queue the chunks before the service starts, so that they are all waiting for it.
+        let mut pending = vec![];
+        for i in 0..10 {
+            pending.push(
+                client
+                    .send(Message::ChunkText((id(i), format!("chunk number {i}"))))
+                    .await
+                    .unwrap(),
+            );
+        }
+        let join_handle = std::thread::spawn(move || service.run());
+        for pending in pending {
+            assert_eq!(pending.wait().await, Ok(Reply::Stored));
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        let results = client
+            .insert_batch(vec![
+                (id(10), "Hello world !".to_string()),
+                (id(11), "Knowledge graphs are great !".to_string()),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(results, vec![Ok(()), Ok(())]);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        let results = client
+            .search("chunk number 3".to_string(), SearchParams::new(1))
+            .await
+            .unwrap();
+        assert_eq!(results[0].id, id(3));
+
+        client.stop().await.unwrap();
+        join_handle.join().unwrap().unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_run_from_async_code() {
+        let (client, request_receiver) = client::channel(16);
+        let service =
+            EmbeddingsService::new_with_embedder(HashingEmbedder::default(), request_receiver)
+                .with_config(ServiceConfig {
+                    batch_size: 4,
+                    ..Default::default()
+                });
+        let task = tokio::spawn(async move { service.run() });
+
+        let results = client
+            .insert_batch(vec![
+                (id(0), "Hello world !".to_string()),
+                (id(1), "Knowledge graphs are great !".to_string()),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(results, vec![Ok(()), Ok(())]);
+
+        client.stop().await.unwrap();
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_upsert_delete_and_get() {
+        let (client, request_receiver) = client::channel(16);
+        let join_handle =
+            EmbeddingsService::spawn_with_embedder(HashingEmbedder::default(), request_receiver);
+
+        for (i, sentence) in SENTENCES.iter().enumerate() {
+            client.insert(id(i), sentence.to_string()).await.unwrap();
+        }
+        assert_eq!(
+            client.upsert(id(0), "Rust is fast !".to_string()).await,
+            Ok(true)
+        );
+        assert_eq!(client.upsert(id(3), "Neo4j".to_string()).await, Ok(false));
+        let stored = client.get(id(0)).await.unwrap();
+        assert_eq!(
+            stored.embedding,
+            client.embed("Rust is fast !".to_string()).await.unwrap()
+        );
+
+        assert_eq!(
+            client.delete(vec![id(1), id(7)]).await,
+            Ok(vec![Ok(()), Err(EmbeddingsError::NotFound(id(7)))])
+        );
+        assert_eq!(
+            client.get(id(1)).await,
+            Err(EmbeddingsError::NotFound(id(1)))
+        );
+
+        let chunk = client.chunk(id(0)).await.unwrap();
+        assert_eq!(chunk.text, "Rust is fast !");
+        assert_eq!(chunk.hash, ContentHash::of("Rust is fast !"));
+        assert!(chunk.ingested_at.is_some());
+        assert_eq!(
+            client.chunk(id(1)).await,
+            Err(EmbeddingsError::NotFound(id(1)))
+        );
+        let chunks = client.chunks(vec![id(2), id(1), id(0)]).await.unwrap();
+        assert_eq!(
+            chunks.iter().map(|chunk| chunk.id).collect::<Vec<_>>(),
+            vec![id(2), id(0)]
+        );
+        let results = client
+            .search(
+                "Knowledge graphs are great !".to_string(),
+                SearchParams::new(10),
+            )
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 3);
+        assert!(results.iter().all(|result| result.id != id(1)));
+
+        client.stop().await.unwrap();
+        join_handle.join().unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ingest_detects_duplicates() {
+        let (client, request_receiver) = client::channel(16);
+        let join_handle =
+            EmbeddingsService::spawn_with_embedder(HashingEmbedder::default(), request_receiver);
+        let ingest = |id: ChunkId, chunk: &str, policy| {
+            let dedup = DedupConfig {
+                similarity_threshold: 0.9,
+                policy,
+            };
+            client.ingest(id, chunk.to_string(), ChunkMetadata::default(), dedup)
+        };
+
+        for (i, sentence) in SENTENCES.iter().enumerate() {
+            assert_eq!(
+                ingest(id(i), sentence, DuplicatePolicy::Skip).await,
+                Ok(None)
+            );
+        }
+        // Whitespace aside, an exact copy.
+        assert_eq!(
+            ingest(
+                id(10),
+                " Knowledge graphs  are great !",
+                DuplicatePolicy::Skip
+            )
+            .await,
+            Ok(Some(DuplicateMatch {
+                id: id(1),
+                score: 1.0,
+                exact: true
+            }))
+        );
+        assert_eq!(
+            client.get(id(10)).await.map(|_| ()),
+            Err(EmbeddingsError::NotFound(id(10)))
+        );
+
+        // A near copy, linked to the chunk it matched.
+        let duplicate = ingest(id(11), "Knowledge graphs are great", DuplicatePolicy::Link)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((duplicate.id, duplicate.exact), (id(1), false));
+        assert!(duplicate.score >= 0.9);
+        let stored = client.get(id(11)).await.unwrap();
+        assert_eq!(stored.metadata.unwrap().duplicate_of, Some(id(1)));
+        // Copies of a linked chunk match the chunk it was linked to.
+        assert_eq!(
+            ingest(id(12), "Knowledge graphs are great", DuplicatePolicy::Skip)
+                .await
+                .unwrap()
+                .map(|duplicate| duplicate.id),
+            Some(id(1))
+        );
+
+        client.stop().await.unwrap();
+        join_handle.join().unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ingestion_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let store_config = crate::config::StoreConfig::new(dir.path());
+        let spawn = || {
+            let embeddings = Embeddings::new_from_model(HashingEmbedder::default())
+                .with_store(&store_config)
+                .unwrap();
+            let (client, request_receiver) = client::channel(16);
+            let join_handle =
+                EmbeddingsService::spawn_pool(embeddings, request_receiver, 1, || {
+                    Ok(HashingEmbedder::default())
+                });
+            (client, join_handle)
+        };
+
+        let (client, join_handle) = spawn();
+        let metadata = ChunkMetadata {
+            document_id: Some("doc-1".to_string()),
+            ingestion: Some(Ingestion::default()),
+            ..Default::default()
+        };
+        client
+            .insert_with_metadata(id(1), SENTENCES[1].to_string(), metadata)
+            .await
+            .unwrap();
+        let chunk = client
+            .chunk_by_hash(ContentHash::of(SENTENCES[1]))
+            .await
+            .unwrap();
+        assert_eq!(chunk.id, id(1));
+        assert_eq!(
+            chunk.ingestion.map(|ingestion| ingestion.status),
+            Some(IngestionStatus::Pending)
+        );
+
+        let graph = "(knowledge graphs)-[ARE]->(great)";
+        let hash = ContentHash::of_extraction(SENTENCES[1], Some(graph));
+        let ingestion = Ingestion {
+            status: IngestionStatus::Extracted,
+            hash: Some(hash),
+            knowledge_graph: Some(graph.to_string()),
+            ..Default::default()
+        };
+        client
+            .set_ingestion(id(1), ingestion.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            client.set_ingestion(id(2), ingestion.clone()).await,
+            Err(EmbeddingsError::NotFound(id(2)))
+        );
+        assert_eq!(
+            client.chunk_by_hash(ContentHash::of(SENTENCES[0])).await,
+            Err(EmbeddingsError::HashNotFound(ContentHash::of(SENTENCES[0])))
+        );
+        client.stop().await.unwrap();
+        join_handle.join().unwrap().unwrap();
+
+        // Both hashes lead to the chunk once reopened, which kept the rest of its metadata.
+        let (client, join_handle) = spawn();
+        let chunk = client.chunk_by_hash(hash).await.unwrap();
+        assert_eq!(chunk.id, id(1));
+        assert_eq!(chunk.document_id.as_deref(), Some("doc-1"));
+        assert_eq!(chunk.ingestion, Some(ingestion));
+        assert_eq!(
+            client
+                .chunk_by_hash(ContentHash::of(SENTENCES[1]))
+                .await
+                .map(|chunk| chunk.id),
+            Ok(id(1))
+        );
+
+        client.delete(vec![id(1)]).await.unwrap();
+        assert_eq!(
+            client.chunk_by_hash(hash).await,
+            Err(EmbeddingsError::HashNotFound(hash))
+        );
+        client.stop().await.unwrap();
+        join_handle.join().unwrap().unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_claim_ingestion() {
+        let (client, request_receiver) = client::channel(16);
+        let join_handle = EmbeddingsService::spawn_pool(
+            Embeddings::new_from_model(HashingEmbedder::default()),
+            request_receiver,
+            4,
+            || Ok(HashingEmbedder::default()),
+        );
+
+        // Concurrent posts of a chunk store it once, and the others find it.
+        let claim = |i: usize| {
+            let client = client.clone();
+            async move {
+                client
+                    .claim_ingestion(
+                        id(i),
+                        SENTENCES[1].to_string(),
+                        ChunkMetadata::default(),
+                        None,
+                        600,
+                    )
+                    .await
+                    .unwrap()
+            }
+        };
+        let tasks: Vec<_> = (0..8).map(|i| (id(i), tokio::spawn(claim(i)))).collect();
+        let mut stored = vec![];
+        let mut existing = vec![];
+        for (chunk_id, task) in tasks {
+            match task.await.unwrap() {
+                IngestionClaim::Stored(None) => stored.push(chunk_id),
+                IngestionClaim::Existing(chunk) => existing.push(chunk.id),
+                claim => panic!("Unexpected claim {claim:?}"),
+            }
+        }
+        assert_eq!(stored.len(), 1);
+        assert_eq!(existing, vec![stored[0]; 7]);
+        assert_eq!(
+            client.chunks((0..8).map(id).collect()).await.unwrap().len(),
+            1
+        );
+
+        // A failed extraction is claimed again, under the id of the stored chunk.
+        let failed = Ingestion {
+            status: IngestionStatus::Failed,
+            ..Default::default()
+        };
+        client.set_ingestion(stored[0], failed).await.unwrap();
+        assert_eq!(claim(8).await, IngestionClaim::Retry(stored[0]));
+        let IngestionClaim::Existing(chunk) = claim(9).await else {
+            panic!("Expected the claimed chunk");
+        };
+        let ingestion = chunk.ingestion.unwrap();
+        assert_eq!(ingestion.status, IngestionStatus::Pending);
+        assert!(ingestion.claimed_at.is_some());
+
+        // So is one pending for too long, e.g. after a crash mid-extraction.
+        let stale = Ingestion {
+            claimed_at: Some(0),
+            ..Default::default()
+        };
+        client.set_ingestion(stored[0], stale).await.unwrap();
+        assert_eq!(claim(10).await, IngestionClaim::Retry(stored[0]));
+        assert!(matches!(claim(11).await, IngestionClaim::Existing(_)));
+
+        client.stop().await.unwrap();
+        join_handle.join().unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_split_document_counts_llm_tokens() {
+        let (client, request_receiver) = client::channel(16);
+        let join_handle = std::thread::spawn(move || {
+            EmbeddingsService::new_with_embedder(HashingEmbedder::default(), request_receiver)
+                .with_llm_tokens(|text| 10 * text.split_whitespace().count())
+                .run()
+        });
+
+        let config = ChunkerConfig {
+            max_llm_tokens: 20,
+            overlap_tokens: 0,
+            ..Default::default()
+        };
+        let split = client
+            .split_document(SENTENCES[1].to_string(), config)
+            .await
+            .unwrap();
+        let texts: Vec<_> = split
+            .chunks
+            .iter()
+            .map(|chunk| chunk.text.as_str())
+            .collect();
+        assert_eq!(texts, vec!["Knowledge graphs", "are great", "!"]);
+
+        client.stop().await.unwrap();
+        join_handle.join().unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_documents() {
+        let (client, request_receiver) = client::channel(16);
+        let join_handle =
+            EmbeddingsService::spawn_with_embedder(HashingEmbedder::default(), request_receiver);
+
+        let document = "# Graphs\n\nKnowledge graphs are great !\n\n# Models\n\nLLMs are amazing.";
+        let split = client
+            .split_document(
+                document.to_string(),
+                ChunkerConfig {
+                    max_tokens: 6,
+                    overlap_tokens: 0,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(split.sections.len(), 2);
+        // Stored in reverse, to check that the document is read back in order.
+        for chunk in split.chunks.iter().rev() {
+            let metadata = ChunkMetadata {
+                document_id: Some("doc-1".to_string()),
+                position: Some(ChunkPosition {
+                    section: chunk.section as u32,
+                    index: chunk.index as u32,
+                    start: chunk.start as u64,
+                    end: chunk.end as u64,
+                }),
+                ..Default::default()
+            };
+            client
+                .insert_with_metadata(id(10 + chunk.index), chunk.text.clone(), metadata)
+                .await
+                .unwrap();
+        }
+        client
+            .insert(id(0), SENTENCES[1].to_string())
+            .await
+            .unwrap();
+
+        let chunks = client.document("doc-1".to_string()).await.unwrap();
+        let texts: Vec<_> = chunks.iter().map(|chunk| chunk.text.as_str()).collect();
+        assert_eq!(
+            texts,
+            vec![
+                "# Graphs",
+                "Knowledge graphs are great !",
+                "# Models\n\nLLMs are amazing."
+            ]
+        );
+        assert_eq!(chunks[2].position.map(|position| position.section), Some(1));
+
+        let groups = client
+            .search_documents(SENTENCES[1].to_string(), SearchParams::new(3))
+            .await
+            .unwrap();
+        let documents: Vec<_> = groups
+            .iter()
+            .map(|group| (group.document_id.as_deref(), group.results.len()))
+            .collect();
+        assert!(documents.contains(&(None, 1)));
+        assert!(documents.contains(&(Some("doc-1"), 2)));
+
+        assert_eq!(
+            client.delete_document("doc-1".to_string()).await,
+            Ok(vec![id(10), id(11), id(12)])
+        );
+        assert!(client
+            .document("doc-1".to_string())
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(client.get(id(0)).await.is_ok());
+
+        client.stop().await.unwrap();
+        join_handle.join().unwrap().unwrap();
+    }
+
+    /// Panics on chunks containing "panic", and fails on those containing "fail".
+    struct FaultyEmbedder(HashingEmbedder);
+
+    impl Embedder for FaultyEmbedder {
+        fn encode(&self, texts: &[&str]) -> anyhow::Result<Vec<Vec<f32>>> {
+            if texts.iter().any(|text| text.contains("panic")) {
+                panic!("model crashed");
+            }
+            if texts.iter().any(|text| text.contains("fail")) {
+                return Err(anyhow!("model failed"));
+            }
+            self.0.encode(texts)
+        }
+
+        fn dimension(&self) -> usize {
+            self.0.dimension()
+        }
+
+        fn model_id(&self) -> &str {
+            self.0.model_id()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_worker_survives_errors_and_panics() {
+        let dir = tempfile::tempdir().unwrap();
+        let store_config = crate::config::StoreConfig::new(dir.path());
+        let embeddings = Embeddings::new_from_model(FaultyEmbedder(HashingEmbedder::default()))
+            .with_store(&store_config)
+            .unwrap();
+        let (client, request_receiver) = client::channel(16);
+        let join_handle = EmbeddingsService::spawn_pool(embeddings, request_receiver, 1, || {
+            Ok(FaultyEmbedder(HashingEmbedder::default()))
+        });
+
+        client
+            .insert(id(0), "Hello world !".to_string())
+            .await
+            .unwrap();
+        assert_eq!(
+            client.insert(id(1), "fail".to_string()).await,
+            Err(EmbeddingsError::Encoding("model failed".to_string()))
+        );
+        assert_eq!(
+            client.embed("panic".to_string()).await,
+            Err(EmbeddingsError::WorkerPanicked("model crashed".to_string()))
+        );
+        assert_eq!(
+            client
+                .insert_batch(vec![
+                    (id(2), "Knowledge graphs".to_string()),
+                    (id(3), "panic".to_string())
+                ])
+                .await,
+            Err(EmbeddingsError::WorkerPanicked("model crashed".to_string()))
+        );
+
+        // The restarted worker reloaded the persisted chunk, and serves further requests.
+        client
+            .insert(id(4), "LLMs are amazing".to_string())
+            .await
+            .unwrap();
+        let results = client
+            .search("Hello world !".to_string(), SearchParams::new(10))
+            .await
+            .unwrap();
+        let mut ids: Vec<_> = results.iter().map(|result| result.id).collect();
+        ids.sort();
+        assert_eq!(ids, vec![id(0), id(4)]);
+        assert_eq!(
+            client.stats().await,
+            Ok(ServiceStats {
+                handled: 6,
+                failed: 3,
+                restarts: 2
+            })
+        );
+
+        client.stop().await.unwrap();
+        join_handle.join().unwrap().unwrap();
+    }
 
     #[test]
     fn message_to_string() {
-        let message = Message::ChunkText((0, "Hello world !".to_string()));
+        let message = Message::ChunkText((id(0), "Hello world !".to_string()));
         assert_eq!(
-            String::from(r#"{"chunk_text":[0,"Hello world !"]}"#),
+            String::from(
+                r#"{"chunk_text":["00000000-0000-0000-0000-000000000000","Hello world !"]}"#
+            ),
             serde_json::to_string(&message).unwrap()
         );
         let message = Message::ProcessChunk("Hello world!".to_string());
@@ -133,8 +2064,8 @@ mod tests {
             serde_json::to_string(&message).unwrap()
         );
 
-        let send_string = r#"{"chunk_text":[1,"The complexity of an integrated circuit is bounded by physical limitations on the number of transistors that can be put onto one chip, the number of package terminations that can connect the processor to other parts of the system, the number of interconnections it is possible to make on the chip, and the heat that the chip can dissipate. Advancing technology makes more complex and powerful chips feasible to manufacture. A minimal hypothetical microprocessor might include only an arithmetic logic unit (ALU), and a control logic section. The ALU performs addition, subtraction, and operations such as AND or OR. Each operation of the ALU sets one or more flags in a status register, which indicate the results of the last operation (zero value, negative number, overflow, or others). The control logic retrieves instruction codes from memory and initiates the sequence of operations required for the ALU to carry out the instruction. A single operation code might affect many individual data paths, registers, and other elements of the processor. As integrated circuit technology advanced, it was feasible to manufacture more and more complex processors on a single chip. The size of data objects became larger; allowing more transistors on a chip allowed word sizes to increase from 4- and 8-bit words up to today's 64-bit words. Additional features were added to the processor architecture; more on-chip registers sped up programs, and complex instructions could be used to make more compact programs. Floating-point arithmetic, for example, was often not available on 8-bit microprocessors, but had to be carried out in software. Integration of the floating-point unit, first as a separate integrated circuit and then as part of the same microprocessor chip, sped up floating-point calculations. Occasionally, physical limitations of integrated circuits made such practices as a bit slice approach necessary. Instead of processing all of a long word on one integrated circuit, multiple circuits in parallel processed subsets of each word. While this required extra logic to handle, for example, carry and overflow within each slice, the result was a system that could handle, for example, 32-bit words using integrated circuits with a capacity for only four bits each. The ability to put large numbers of transistors on one chip makes it feasible to integrate memory on the same die as the processor. This CPU cache has the advantage of faster access than off-chip memory and increases the processing speed of the system for many applications. Processor clock frequency has increased more rapidly than external memory speed, so cache memory is necessary if the processor is not to be delayed by slower external memory."]}"#;
-        let message = Message::ChunkText((1, "The complexity of an integrated circuit is bounded by physical limitations on the number of transistors that can be put onto one chip, the number of package terminations that can connect the processor to other parts of the system, the number of interconnections it is possible to make on the chip, and the heat that the chip can dissipate. Advancing technology makes more complex and powerful chips feasible to manufacture. A minimal hypothetical microprocessor might include only an arithmetic logic unit (ALU), and a control logic section. The ALU performs addition, subtraction, and operations such as AND or OR. Each operation of the ALU sets one or more flags in a status register, which indicate the results of the last operation (zero value, negative number, overflow, or others). The control logic retrieves instruction codes from memory and initiates the sequence of operations required for the ALU to carry out the instruction. A single operation code might affect many individual data paths, registers, and other elements of the processor. As integrated circuit technology advanced, it was feasible to manufacture more and more complex processors on a single chip. The size of data objects became larger; allowing more transistors on a chip allowed word sizes to increase from 4- and 8-bit words up to today's 64-bit words. Additional features were added to the processor architecture; more on-chip registers sped up programs, and complex instructions could be used to make more compact programs. Floating-point arithmetic, for example, was often not available on 8-bit microprocessors, but had to be carried out in software. Integration of the floating-point unit, first as a separate integrated circuit and then as part of the same microprocessor chip, sped up floating-point calculations. Occasionally, physical limitations of integrated circuits made such practices as a bit slice approach necessary. Instead of processing all of a long word on one integrated circuit, multiple circuits in parallel processed subsets of each word. While this required extra logic to handle, for example, carry and overflow within each slice, the result was a system that could handle, for example, 32-bit words using integrated circuits with a capacity for only four bits each. The ability to put large numbers of transistors on one chip makes it feasible to integrate memory on the same die as the processor. This CPU cache has the advantage of faster access than off-chip memory and increases the processing speed of the system for many applications. Processor clock frequency has increased more rapidly than external memory speed, so cache memory is necessary if the processor is not to be delayed by slower external memory.".to_string()));
+        let send_string = r#"{"chunk_text":["00000000-0000-0000-0000-000000000001","The complexity of an integrated circuit is bounded by physical limitations on the number of transistors that can be put onto one chip, the number of package terminations that can connect the processor to other parts of the system, the number of interconnections it is possible to make on the chip, and the heat that the chip can dissipate. Advancing technology makes more complex and powerful chips feasible to manufacture. A minimal hypothetical microprocessor might include only an arithmetic logic unit (ALU), and a control logic section. The ALU performs addition, subtraction, and operations such as AND or OR. Each operation of the ALU sets one or more flags in a status register, which indicate the results of the last operation (zero value, negative number, overflow, or others). The control logic retrieves instruction codes from memory and initiates the sequence of operations required for the ALU to carry out the instruction. A single operation code might affect many individual data paths, registers, and other elements of the processor. As integrated circuit technology advanced, it was feasible to manufacture more and more complex processors on a single chip. The size of data objects became larger; allowing more transistors on a chip allowed word sizes to increase from 4- and 8-bit words up to today's 64-bit words. Additional features were added to the processor architecture; more on-chip registers sped up programs, and complex instructions could be used to make more compact programs. Floating-point arithmetic, for example, was often not available on 8-bit microprocessors, but had to be carried out in software. Integration of the floating-point unit, first as a separate integrated circuit and then as part of the same microprocessor chip, sped up floating-point calculations. Occasionally, physical limitations of integrated circuits made such practices as a bit slice approach necessary. Instead of processing all of a long word on one integrated circuit, multiple circuits in parallel processed subsets of each word. While this required extra logic to handle, for example, carry and overflow within each slice, the result was a system that could handle, for example, 32-bit words using integrated circuits with a capacity for only four bits each. The ability to put large numbers of transistors on one chip makes it feasible to integrate memory on the same die as the processor. This CPU cache has the advantage of faster access than off-chip memory and increases the processing speed of the system for many applications. Processor clock frequency has increased more rapidly than external memory speed, so cache memory is necessary if the processor is not to be delayed by slower external memory."]}"#;
+        let message = Message::ChunkText((id(1), "The complexity of an integrated circuit is bounded by physical limitations on the number of transistors that can be put onto one chip, the number of package terminations that can connect the processor to other parts of the system, the number of interconnections it is possible to make on the chip, and the heat that the chip can dissipate. Advancing technology makes more complex and powerful chips feasible to manufacture. A minimal hypothetical microprocessor might include only an arithmetic logic unit (ALU), and a control logic section. The ALU performs addition, subtraction, and operations such as AND or OR. Each operation of the ALU sets one or more flags in a status register, which indicate the results of the last operation (zero value, negative number, overflow, or others). The control logic retrieves instruction codes from memory and initiates the sequence of operations required for the ALU to carry out the instruction. A single operation code might affect many individual data paths, registers, and other elements of the processor. As integrated circuit technology advanced, it was feasible to manufacture more and more complex processors on a single chip. The size of data objects became larger; allowing more transistors on a chip allowed word sizes to increase from 4- and 8-bit words up to today's 64-bit words. Additional features were added to the processor architecture; more on-chip registers sped up programs, and complex instructions could be used to make more compact programs. Floating-point arithmetic, for example, was often not available on 8-bit microprocessors, but had to be carried out in software. Integration of the floating-point unit, first as a separate integrated circuit and then as part of the same microprocessor chip, sped up floating-point calculations. Occasionally, physical limitations of integrated circuits made such practices as a bit slice approach necessary. Instead of processing all of a long word on one integrated circuit, multiple circuits in parallel processed subsets of each word. While this required extra logic to handle, for example, carry and overflow within each slice, the result was a system that could handle, for example, 32-bit words using integrated circuits with a capacity for only four bits each. The ability to put large numbers of transistors on one chip makes it feasible to integrate memory on the same die as the processor. This CPU cache has the advantage of faster access than off-chip memory and increases the processing speed of the system for many applications. Processor clock frequency has increased more rapidly than external memory speed, so cache memory is necessary if the processor is not to be delayed by slower external memory.".to_string()));
         assert_eq!(send_string, serde_json::to_string(&message).unwrap());
 
         let message = Message::GetChunkId(("Hello world!".to_string(), 4));
@@ -143,4 +2074,85 @@ mod tests {
             serde_json::to_string(&message).unwrap()
         );
     }
+
+    #[test]
+    fn search_message_from_string() {
+        let message: Message = serde_json::from_str(
+            r#"{"search":["Hello world!",{"num_queries":4,"metric":"dot_product"}]}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            message,
+            Message::Search((
+                _,
+                SearchParams {
+                    num_queries: 4,
+                    metric: crate::search::Metric::DotProduct,
+                    min_score: None,
+                    filter: None,
+                    mode: crate::search::SearchMode::Vector,
+                    ..
+                }
+            ))
+        ));
+    }
+
+    #[test]
+    fn stats_message_from_string() {
+        assert!(matches!("\"stats\"".parse::<Message>(), Ok(Message::Stats)));
+    }
+
+    #[test]
+    fn invalid_message_from_string() {
+        assert!(matches!(
+            r#"{"send":[1,"not a vector"]}"#.parse::<Message>(),
+            Err(EmbeddingsError::InvalidMessage(_))
+        ));
+    }
+
+    #[test]
+    fn measure_recall_message_from_string() {
+        assert!(matches!(
+            r#"{"measure_recall":[100,10]}"#.parse::<Message>(),
+            Ok(Message::MeasureRecall((100, 10)))
+        ));
+    }
+
+    #[test]
+    fn cache_stats_message_from_string() {
+        assert!(matches!(
+            "\"cache_stats\"".parse::<Message>(),
+            Ok(Message::CacheStats)
+        ));
+    }
+
+    #[test]
+    fn migration_messages_from_string() {
+        assert!(matches!(
+            "\"migration_progress\"".parse::<Message>(),
+            Ok(Message::MigrationProgress)
+        ));
+        assert!(matches!(
+            r#"{"start_migration":{"embedding_dimension":32}}"#.parse::<Message>(),
+            Ok(Message::StartMigration(ModelSpec {
+                embedding_dimension: Some(32),
+                model_dir: None,
+                model_id: None,
+            }))
+        ));
+    }
+
+    #[test]
+    fn legacy_chunk_id_from_string() {
+        // Integer chunk ids sent by older clients are still accepted, and sent back as UUIDs.
+        let message: Message =
+            serde_json::from_str(r#"{"chunk_text":[1,"Hello world !"]}"#).unwrap();
+        assert!(matches!(&message, Message::ChunkText((chunk_id, _)) if *chunk_id == id(1)));
+        assert_eq!(
+            String::from(
+                r#"{"chunk_text":["00000000-0000-0000-0000-000000000001","Hello world !"]}"#
+            ),
+            serde_json::to_string(&message).unwrap()
+        );
+    }
 }
@@ -0,0 +1,907 @@
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use log::{info, warn};
+
+use crate::{
+    config::StoreConfig, embedder::ModelTag, embeddings::Embedding, error::EmbeddingsError,
+    id::ChunkId, metadata::ChunkMetadata,
+};
+
+const SNAPSHOT_FILE: &str = "snapshot.bin";
+const WAL_FILE: &str = "wal.log";
+const SNAPSHOT_MAGIC: &[u8; 4] = b"CDKS";
+// Version 2 appends the chunk metadata to the embeddings, version 3 the chunk texts,
+// version 4 keys them all by 16 bytes chunk ids instead of 4 bytes integers, and version 5
+// tags them with the model that produced them.
+const SNAPSHOT_VERSION: u32 = 5;
+
+const RESET_TAG: u8 = 1;
+const INSERT_TAG: u8 = 5;
+const METADATA_TAG: u8 = 6;
+const DELETE_TAG: u8 = 7;
+const TEXT_TAG: u8 = 8;
+const MODEL_TAG: u8 = 9;
+// Entries logged before chunk ids, keyed by integers, which are still replayed.
+const LEGACY_INSERT_TAG: u8 = 0;
+const LEGACY_METADATA_TAG: u8 = 2;
+const LEGACY_DELETE_TAG: u8 = 3;
+const LEGACY_TEXT_TAG: u8 = 4;
+
+/// A mutation of the vector store, as recorded in the write-ahead log.
+#[derive(Clone, Debug, PartialEq)]
+pub enum WalEntry {
+    Insert(ChunkId, Embedding),
+    Reset,
+    /// Sets the metadata of a chunk.
+    Metadata(ChunkId, Box<ChunkMetadata>),
+    /// Removes a chunk, along with its metadata and text.
+    Delete(ChunkId),
+    /// Sets the text of a chunk, until its embedding is replaced.
+    Text(ChunkId, String),
+    /// Tags the embeddings with the model that produced them.
+    Model(ModelTag),
+}
+
+/// Everything held by a store, as loaded when it is opened. Embeddings are unique by id.
+#[derive(Debug, Default)]
+pub struct StoreContents {
+    pub embeddings: Vec<(ChunkId, Embedding)>,
+    pub metadata: HashMap<ChunkId, ChunkMetadata>,
+    pub texts: HashMap<ChunkId, String>,
+    /// Model that produced the embeddings, unless written before models were recorded.
+    pub model: Option<ModelTag>,
+    positions: HashMap<ChunkId, usize>,
+}
+
+impl StoreContents {
+    fn insert(&mut self, id: ChunkId, embedding: Embedding) {
+        self.texts.remove(&id);
+        match self.positions.get(&id) {
+            Some(&position) => self.embeddings[position].1 = embedding,
+            None => {
+                self.positions.insert(id, self.embeddings.len());
+                self.embeddings.push((id, embedding));
+            }
+        }
+    }
+
+    fn delete(&mut self, id: ChunkId) {
+        self.metadata.remove(&id);
+        self.texts.remove(&id);
+        if let Some(position) = self.positions.remove(&id) {
+            self.embeddings.swap_remove(position);
+            if let Some((moved, _)) = self.embeddings.get(position) {
+                self.positions.insert(*moved, position);
+            }
+        }
+    }
+}
+
+impl WalEntry {
+    fn encode(&self) -> Vec<u8> {
+        match self {
+            Self::Insert(id, embedding) => {
+                let mut bytes = Vec::with_capacity(17 + 4 * embedding.dimension());
+                bytes.push(INSERT_TAG);
+                bytes.extend_from_slice(id.as_bytes());
+                encode_vector(&mut bytes, embedding.as_slice());
+                bytes
+            }
+            Self::Reset => vec![RESET_TAG],
+            Self::Metadata(id, metadata) => {
+                let mut bytes = vec![METADATA_TAG];
+                bytes.extend_from_slice(id.as_bytes());
+                encode_metadata(&mut bytes, metadata);
+                bytes
+            }
+            Self::Delete(id) => {
+                let mut bytes = vec![DELETE_TAG];
+                bytes.extend_from_slice(id.as_bytes());
+                bytes
+            }
+            Self::Text(id, text) => {
+                let mut bytes = Vec::with_capacity(17 + text.len());
+                bytes.push(TEXT_TAG);
+                bytes.extend_from_slice(id.as_bytes());
+                bytes.extend_from_slice(text.as_bytes());
+                bytes
+            }
+            Self::Model(model) => {
+                let mut bytes = vec![MODEL_TAG];
+                encode_model(&mut bytes, Some(model));
+                bytes
+            }
+        }
+    }
+
+    fn decode(bytes: &[u8], dimension: usize) -> Result<Self, String> {
+        let mut reader = ByteReader::new(bytes);
+        let tag = reader.u8()?;
+        let legacy = matches!(
+            tag,
+            LEGACY_INSERT_TAG | LEGACY_METADATA_TAG | LEGACY_DELETE_TAG | LEGACY_TEXT_TAG
+        );
+        let entry = match tag {
+            INSERT_TAG | LEGACY_INSERT_TAG => {
+                let id = reader.id(legacy)?;
+                let vector = reader.vector(dimension)?;
+                Self::Insert(
+                    id,
+                    Embedding::new(vector, dimension).map_err(|e| e.to_string())?,
+                )
+            }
+            RESET_TAG => Self::Reset,
+            METADATA_TAG | LEGACY_METADATA_TAG => {
+                let id = reader.id(legacy)?;
+                Self::Metadata(id, Box::new(reader.metadata()?))
+            }
+            DELETE_TAG | LEGACY_DELETE_TAG => Self::Delete(reader.id(legacy)?),
+            TEXT_TAG | LEGACY_TEXT_TAG => {
+                let id = reader.id(legacy)?;
+                Self::Text(id, reader.text()?)
+            }
+            MODEL_TAG => Self::Model(reader.model()?.unwrap_or_default()),
+            tag => return Err(format!("unknown entry tag {tag}")),
+        };
+        reader.finish()?;
+        Ok(entry)
+    }
+}
+
+/// Durable storage of the `Embeddings` data, as a snapshot file plus a write-ahead log.
+///
+/// Every mutation is appended to the log, framed with its length and a CRC32 checksum,
+/// before being applied in memory. Every `snapshot_interval` entries, the whole store is
+/// written to a new snapshot (atomically, through a rename) and the log is truncated.
+/// On load, the snapshot is read and the log replayed on top of it. Checksum mismatches
+/// are reported as a corrupted store, while an incomplete last log record, left over
+/// by a crash in the middle of a write, is discarded.
+pub struct VectorStore {
+    dir: PathBuf,
+    dimension: usize,
+    model: Option<ModelTag>,
+    wal: BufWriter<File>,
+    wal_entries: usize,
+    snapshot_interval: usize,
+}
+
+impl VectorStore {
+    /// Opens (or creates) the store at `config.path`, returning it along with its contents.
+    pub fn open(
+        config: &StoreConfig,
+        dimension: usize,
+    ) -> Result<(Self, StoreContents), EmbeddingsError> {
+        let dir = config.path.clone();
+        std::fs::create_dir_all(&dir).map_err(|e| storage_error(&dir, e))?;
+
+        let mut contents = read_snapshot(&dir.join(SNAPSHOT_FILE), dimension)?;
+        let (entries, valid_len) = read_wal(&dir.join(WAL_FILE), dimension)?;
+        let wal_entries = entries.len();
+        for entry in entries {
+            match entry {
+                WalEntry::Insert(id, embedding) => contents.insert(id, embedding),
+                WalEntry::Reset => {
+                    contents = StoreContents {
+                        model: contents.model.take(),
+                        ..Default::default()
+                    }
+                }
+                WalEntry::Metadata(id, metadata) => {
+                    contents.metadata.insert(id, *metadata);
+                }
+                WalEntry::Delete(id) => contents.delete(id),
+                WalEntry::Text(id, text) => {
+                    contents.texts.insert(id, text);
+                }
+                WalEntry::Model(model) => contents.model = Some(model),
+            }
+        }
+
+        let wal_path = dir.join(WAL_FILE);
+        let wal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&wal_path)
+            .map_err(|e| storage_error(&wal_path, e))?;
+        if wal
+            .metadata()
+            .map_err(|e| storage_error(&wal_path, e))?
+            .len()
+            > valid_len
+        {
+            warn!(
+                "Discarding incomplete trailing record of {}",
+                wal_path.display()
+            );
+            wal.set_len(valid_len)
+                .map_err(|e| storage_error(&wal_path, e))?;
+        }
+
+        info!(
+            "Loaded {} embeddings from {} ({} log entries replayed)",
+            contents.embeddings.len(),
+            dir.display(),
+            wal_entries
+        );
+
+        Ok((
+            Self {
+                dir,
+                dimension,
+                model: contents.model.clone(),
+                wal: BufWriter::new(wal),
+                wal_entries,
+                snapshot_interval: config.snapshot_interval.max(1),
+            },
+            contents,
+        ))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Model that produced the stored embeddings, if recorded.
+    pub fn model(&self) -> Option<&ModelTag> {
+        self.model.as_ref()
+    }
+
+    /// Records the model that produced the stored embeddings. Returns whether a snapshot is
+    /// due.
+    pub fn set_model(&mut self, model: ModelTag) -> Result<bool, EmbeddingsError> {
+        let snapshot_due = self.append(&WalEntry::Model(model.clone()))?;
+        self.model = Some(model);
+        Ok(snapshot_due)
+    }
+
+    /// Durably appends a new entry to the log. Returns whether a snapshot is due.
+    pub fn append(&mut self, entry: &WalEntry) -> Result<bool, EmbeddingsError> {
+        self.append_all(std::slice::from_ref(entry))
+    }
+
+    /// Durably appends `entries` to the log, with a single sync. Returns whether a snapshot
+    /// is due.
+    pub fn append_all(&mut self, entries: &[WalEntry]) -> Result<bool, EmbeddingsError> {
+        let wal_path = self.dir.join(WAL_FILE);
+        let mut records = vec![];
+        for entry in entries {
+            let payload = entry.encode();
+            records.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+            records.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+            records.extend_from_slice(&payload);
+        }
+        self.wal
+            .write_all(&records)
+            .and_then(|_| self.wal.flush())
+            .and_then(|_| self.wal.get_ref().sync_data())
+            .map_err(|e| storage_error(&wal_path, e))?;
+        self.wal_entries += entries.len();
+        Ok(self.wal_entries >= self.snapshot_interval)
+    }
+
+    /// Writes `data`, its `metadata` and `texts` as the new snapshot, and truncates the log.
+    pub fn snapshot(
+        &mut self,
+        data: &[(ChunkId, Embedding)],
+        metadata: &HashMap<ChunkId, ChunkMetadata>,
+        texts: &HashMap<ChunkId, String>,
+    ) -> Result<(), EmbeddingsError> {
+        let snapshot_path = self.dir.join(SNAPSHOT_FILE);
+        let tmp_path = self.dir.join(format!("{SNAPSHOT_FILE}.tmp"));
+
+        let mut bytes = Vec::with_capacity(20 + data.len() * (16 + 4 * self.dimension));
+        bytes.extend_from_slice(SNAPSHOT_MAGIC);
+        bytes.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&(self.dimension as u32).to_le_bytes());
+        encode_model(&mut bytes, self.model.as_ref());
+        bytes.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        for (id, embedding) in data {
+            bytes.extend_from_slice(id.as_bytes());
+            encode_vector(&mut bytes, embedding.as_slice());
+        }
+        encode_section(&mut bytes, metadata, encode_metadata);
+        encode_section(&mut bytes, texts, |bytes, text| {
+            bytes.extend_from_slice(text.as_bytes())
+        });
+        let checksum = crc32fast::hash(&bytes);
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+
+        let mut file = File::create(&tmp_path).map_err(|e| storage_error(&tmp_path, e))?;
+        file.write_all(&bytes)
+            .and_then(|_| file.sync_all())
+            .map_err(|e| storage_error(&tmp_path, e))?;
+        std::fs::rename(&tmp_path, &snapshot_path).map_err(|e| storage_error(&snapshot_path, e))?;
+
+        let wal_path = self.dir.join(WAL_FILE);
+        self.wal
+            .get_ref()
+            .set_len(0)
+            .and_then(|_| self.wal.get_ref().sync_all())
+            .map_err(|e| storage_error(&wal_path, e))?;
+        self.wal_entries = 0;
+
+        info!(
+            "Snapshot of {} embeddings written to {}",
+            data.len(),
+            snapshot_path.display()
+        );
+        Ok(())
+    }
+
+    /// Replaces the whole store with `data` of `dimension`, produced by `model`, along with
+    /// its `metadata` and `texts`, as a new snapshot.
+    ///
+    /// The log must have just been emptied by a snapshot of the current contents, so that a
+    /// crash before the new snapshot is renamed in place reloads the current contents whole,
+    /// and one after it reloads the new contents alone.
+    pub fn replace(
+        &mut self,
+        dimension: usize,
+        model: Option<ModelTag>,
+        data: &[(ChunkId, Embedding)],
+        metadata: &HashMap<ChunkId, ChunkMetadata>,
+        texts: &HashMap<ChunkId, String>,
+    ) -> Result<(), EmbeddingsError> {
+        let previous_dimension = std::mem::replace(&mut self.dimension, dimension);
+        let previous_model = std::mem::replace(&mut self.model, model);
+        let result = self.snapshot(data, metadata, texts);
+        if result.is_err() {
+            self.dimension = previous_dimension;
+            self.model = previous_model;
+        }
+        result
+    }
+}
+
+fn read_snapshot(path: &Path, dimension: usize) -> Result<StoreContents, EmbeddingsError> {
+    if !path.exists() {
+        return Ok(StoreContents::default());
+    }
+    let bytes = std::fs::read(path).map_err(|e| storage_error(path, e))?;
+    let corrupted = |reason: String| EmbeddingsError::CorruptedStore {
+        path: path.to_path_buf(),
+        reason,
+    };
+
+    if bytes.len() < 24 || &bytes[..4] != SNAPSHOT_MAGIC {
+        return Err(corrupted("not a snapshot file".to_string()));
+    }
+    let (content, checksum) = bytes.split_at(bytes.len() - 4);
+    if crc32fast::hash(content).to_le_bytes() != checksum {
+        return Err(corrupted("checksum mismatch".to_string()));
+    }
+
+    let mut reader = ByteReader::new(&content[4..]);
+    let version = reader.u32().map_err(corrupted)?;
+    if version == 0 || version > SNAPSHOT_VERSION {
+        return Err(corrupted(format!("unsupported version {version}")));
+    }
+    let stored_dimension = reader.u32().map_err(corrupted)? as usize;
+    if stored_dimension != dimension {
+        return Err(EmbeddingsError::DimensionMismatch {
+            expected: dimension,
+            found: stored_dimension,
+        });
+    }
+    let legacy = version < 4;
+    let model = match version {
+        5.. => reader.model().map_err(corrupted)?,
+        _ => None,
+    };
+    let count = reader.u64().map_err(corrupted)?;
+    let mut contents = StoreContents {
+        model,
+        ..Default::default()
+    };
+    for _ in 0..count {
+        let id = reader.id(legacy).map_err(corrupted)?;
+        let vector = reader.vector(dimension).map_err(corrupted)?;
+        contents.insert(id, Embedding::new(vector, dimension)?);
+    }
+    if version >= 2 {
+        contents.metadata =
+            read_section(&mut reader, legacy, |section| section.metadata()).map_err(corrupted)?;
+    }
+    if version >= 3 {
+        contents.texts =
+            read_section(&mut reader, legacy, |section| section.text()).map_err(corrupted)?;
+    }
+    reader.finish().map_err(corrupted)?;
+    Ok(contents)
+}
+
+/// Reads every complete log record, returning them along with the length of the valid prefix.
+fn read_wal(path: &Path, dimension: usize) -> Result<(Vec<WalEntry>, u64), EmbeddingsError> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((vec![], 0)),
+        Err(e) => return Err(storage_error(path, e)),
+    };
+    let mut bytes = vec![];
+    BufReader::new(file)
+        .read_to_end(&mut bytes)
+        .map_err(|e| storage_error(path, e))?;
+
+    let mut entries = vec![];
+    let mut offset = 0;
+    while bytes.len() - offset >= 8 {
+        let len = u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap()) as usize;
+        let checksum = u32::from_le_bytes(bytes[offset + 4..offset + 8].try_into().unwrap());
+        let corrupted = |reason: String| EmbeddingsError::CorruptedStore {
+            path: path.to_path_buf(),
+            reason: format!("record at offset {offset}: {reason}"),
+        };
+        let Some(payload) = bytes.get(offset + 8..offset + 8 + len) else {
+            // A record running past the end of the log is the torn tail of the last write,
+            // unless a part of what follows it matches its checksum: the record is then
+            // complete, its length was corrupted, and the records after it would be lost.
+            if has_prefix_with_checksum(&bytes[offset + 8..], checksum) {
+                return Err(corrupted(format!("length {len} past the end of the log")));
+            }
+            break;
+        };
+        if crc32fast::hash(payload) != checksum {
+            return Err(corrupted("checksum mismatch".to_string()));
+        }
+        entries.push(WalEntry::decode(payload, dimension).map_err(corrupted)?);
+        offset += 8 + len;
+    }
+    Ok((entries, offset as u64))
+}
+
+/// Whether a non-empty prefix of `bytes` hashes to `checksum`.
+fn has_prefix_with_checksum(bytes: &[u8], checksum: u32) -> bool {
+    let mut hasher = crc32fast::Hasher::new();
+    bytes.iter().any(|byte| {
+        hasher.update(std::slice::from_ref(byte));
+        hasher.clone().finalize() == checksum
+    })
+}
+
+fn encode_vector(bytes: &mut Vec<u8>, vector: &[f32]) {
+    vector
+        .iter()
+        .for_each(|x| bytes.extend_from_slice(&x.to_le_bytes()));
+}
+
+/// Writes the `values` of a snapshot section, by id, each prefixed with its length.
+fn encode_section<T>(
+    bytes: &mut Vec<u8>,
+    values: &HashMap<ChunkId, T>,
+    encode: impl Fn(&mut Vec<u8>, &T),
+) {
+    let mut values: Vec<_> = values.iter().collect();
+    values.sort_by_key(|(id, _)| **id);
+    bytes.extend_from_slice(&(values.len() as u64).to_le_bytes());
+    for (id, value) in values {
+        bytes.extend_from_slice(id.as_bytes());
+        let start = bytes.len();
+        bytes.extend_from_slice(&[0; 4]);
+        encode(bytes, value);
+        let len = (bytes.len() - start - 4) as u32;
+        bytes[start..start + 4].copy_from_slice(&len.to_le_bytes());
+    }
+}
+
+fn read_section<T>(
+    reader: &mut ByteReader,
+    legacy: bool,
+    decode: impl Fn(&mut ByteReader) -> Result<T, String>,
+) -> Result<HashMap<ChunkId, T>, String> {
+    let count = reader.u64()?;
+    let mut values = HashMap::new();
+    for _ in 0..count {
+        let id = reader.id(legacy)?;
+        let len = reader.u32()? as usize;
+        values.insert(id, decode(&mut ByteReader::new(reader.take(len)?))?);
+    }
+    Ok(values)
+}
+
+/// Writes the id and version of `model`, each prefixed with its length, an empty id standing
+/// for no model.
+fn encode_model(bytes: &mut Vec<u8>, model: Option<&ModelTag>) {
+    let (id, version) = model.map_or(("", ""), |m| (m.id.as_str(), m.version.as_str()));
+    for part in [id, version] {
+        bytes.extend_from_slice(&(part.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(part.as_bytes());
+    }
+}
+
+fn encode_metadata(bytes: &mut Vec<u8>, metadata: &ChunkMetadata) {
+    // Serializing plain strings and integers cannot fail.
+    bytes.extend_from_slice(&serde_json::to_vec(metadata).unwrap_or_default());
+}
+
+pub(crate) fn storage_error(path: &Path, error: std::io::Error) -> EmbeddingsError {
+    EmbeddingsError::Storage(format!("{}: {error}", path.display()))
+}
+
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, offset: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let slice = self
+            .bytes
+            .get(self.offset..self.offset + len)
+            .ok_or_else(|| "unexpected end of data".to_string())?;
+        self.offset += len;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// Reads a chunk id, or the integer id written before chunk ids if `legacy`.
+    fn id(&mut self, legacy: bool) -> Result<ChunkId, String> {
+        if legacy {
+            return Ok(self.u32()?.into());
+        }
+        Ok(ChunkId::from_bytes(self.take(16)?.try_into().unwrap()))
+    }
+
+    fn vector(&mut self, dimension: usize) -> Result<Vec<f32>, String> {
+        Ok(self
+            .take(4 * dimension)?
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+            .collect())
+    }
+
+    /// Reads the remaining bytes as UTF-8 text.
+    fn text(&mut self) -> Result<String, String> {
+        let bytes = self.take(self.bytes.len() - self.offset)?;
+        String::from_utf8(bytes.to_vec()).map_err(|e| format!("invalid text: {e}"))
+    }
+
+    /// Reads a model id and version, as written by `encode_model`.
+    fn model(&mut self) -> Result<Option<ModelTag>, String> {
+        let mut part = || -> Result<String, String> {
+            let len = self.u32()? as usize;
+            String::from_utf8(self.take(len)?.to_vec()).map_err(|e| format!("invalid model: {e}"))
+        };
+        let (id, version) = (part()?, part()?);
+        Ok((!id.is_empty()).then(|| ModelTag::new(id, version)))
+    }
+
+    /// Reads the remaining bytes as JSON encoded metadata.
+    fn metadata(&mut self) -> Result<ChunkMetadata, String> {
+        let bytes = self.take(self.bytes.len() - self.offset)?;
+        serde_json::from_slice(bytes).map_err(|e| format!("invalid metadata: {e}"))
+    }
+
+    fn finish(&self) -> Result<(), String> {
+        if self.offset != self.bytes.len() {
+            return Err(format!(
+                "{} unexpected trailing bytes",
+                self.bytes.len() - self.offset
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(id: u32) -> ChunkId {
+        ChunkId::from(id)
+    }
+
+    fn embedding(x: f32) -> Embedding {
+        Embedding::new(vec![x, x + 1.0, x + 2.0], 3).unwrap()
+    }
+
+    fn config(path: &Path, snapshot_interval: usize) -> StoreConfig {
+        StoreConfig {
+            path: path.to_path_buf(),
+            snapshot_interval,
+        }
+    }
+
+    #[test]
+    fn test_store_reloads_snapshot_and_wal() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let (mut store, contents) = VectorStore::open(&config(dir.path(), 3), 3).unwrap();
+            assert!(contents.embeddings.is_empty());
+            let mut data = vec![];
+            for i in 0..5 {
+                data.push((id(i), embedding(i as f32)));
+                if store
+                    .append(&WalEntry::Insert(id(i), embedding(i as f32)))
+                    .unwrap()
+                {
+                    store
+                        .snapshot(&data, &HashMap::new(), &HashMap::new())
+                        .unwrap();
+                }
+            }
+        }
+
+        let (_, contents) = VectorStore::open(&config(dir.path(), 3), 3).unwrap();
+        assert_eq!(
+            contents.embeddings,
+            (0..5)
+                .map(|i| (id(i), embedding(i as f32)))
+                .collect::<Vec<_>>()
+        );
+
+        let (mut store, _) = VectorStore::open(&config(dir.path(), 3), 3).unwrap();
+        store.append(&WalEntry::Reset).unwrap();
+        store
+            .append(&WalEntry::Insert(id(9), embedding(9.0)))
+            .unwrap();
+        drop(store);
+        let (_, contents) = VectorStore::open(&config(dir.path(), 3), 3).unwrap();
+        assert_eq!(contents.embeddings, vec![(id(9), embedding(9.0))]);
+    }
+
+    #[test]
+    fn test_store_reloads_metadata_and_deletions() {
+        let dir = tempfile::tempdir().unwrap();
+        let metadata = |namespace: &str| ChunkMetadata {
+            namespace: Some(namespace.to_string()),
+            tags: vec!["rust".to_string()],
+            ..Default::default()
+        };
+        {
+            let (mut store, _) = VectorStore::open(&config(dir.path(), 100), 3).unwrap();
+            store
+                .snapshot(
+                    &[(id(0), embedding(0.0))],
+                    &HashMap::from([(id(0), metadata("acme"))]),
+                    &HashMap::from([(id(0), "Hello".to_string())]),
+                )
+                .unwrap();
+            store
+                .append_all(&[
+                    WalEntry::Insert(id(1), embedding(1.0)),
+                    WalEntry::Metadata(id(1), Box::new(metadata("globex"))),
+                    WalEntry::Text(id(1), "world".to_string()),
+                    WalEntry::Insert(id(2), embedding(2.0)),
+                    WalEntry::Text(id(2), "Knowledge".to_string()),
+                    WalEntry::Metadata(id(2), Box::new(metadata("initech"))),
+                    WalEntry::Insert(id(1), embedding(3.0)),
+                    WalEntry::Text(id(1), "graphs".to_string()),
+                    WalEntry::Delete(id(2)),
+                ])
+                .unwrap();
+        }
+
+        // A second insert replaces the embedding, and a deletion drops both.
+        let (_, contents) = VectorStore::open(&config(dir.path(), 100), 3).unwrap();
+        assert_eq!(
+            contents.embeddings,
+            vec![(id(0), embedding(0.0)), (id(1), embedding(3.0))]
+        );
+        assert_eq!(
+            contents.metadata,
+            HashMap::from([(id(0), metadata("acme")), (id(1), metadata("globex"))])
+        );
+        assert_eq!(
+            contents.texts,
+            HashMap::from([(id(0), "Hello".to_string()), (id(1), "graphs".to_string())])
+        );
+    }
+
+    #[test]
+    fn test_store_detects_corruption() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let (mut store, _) = VectorStore::open(&config(dir.path(), 100), 3).unwrap();
+            store
+                .append(&WalEntry::Insert(id(0), embedding(0.0)))
+                .unwrap();
+            store
+                .snapshot(&[(id(0), embedding(0.0))], &HashMap::new(), &HashMap::new())
+                .unwrap();
+            store
+                .append(&WalEntry::Insert(id(1), embedding(1.0)))
+                .unwrap();
+        }
+
+        // A torn trailing record is discarded.
+        let wal_path = dir.path().join(WAL_FILE);
+        let mut wal = std::fs::read(&wal_path).unwrap();
+        let valid_len = wal.len();
+        wal.extend_from_slice(&[42, 0, 0, 0, 1, 2]);
+        std::fs::write(&wal_path, &wal).unwrap();
+        let (_, contents) = VectorStore::open(&config(dir.path(), 100), 3).unwrap();
+        assert_eq!(contents.embeddings.len(), 2);
+        assert_eq!(
+            std::fs::metadata(&wal_path).unwrap().len() as usize,
+            valid_len
+        );
+
+        // A flipped bit in a complete record is reported.
+        let mut wal = std::fs::read(&wal_path).unwrap();
+        let last = wal.len() - 1;
+        wal[last] ^= 0xff;
+        std::fs::write(&wal_path, &wal).unwrap();
+        assert!(matches!(
+            VectorStore::open(&config(dir.path(), 100), 3),
+            Err(EmbeddingsError::CorruptedStore { .. })
+        ));
+        std::fs::remove_file(&wal_path).unwrap();
+
+        let snapshot_path = dir.path().join(SNAPSHOT_FILE);
+        let mut snapshot = std::fs::read(&snapshot_path).unwrap();
+        snapshot[24] ^= 0xff;
+        std::fs::write(&snapshot_path, &snapshot).unwrap();
+        assert!(matches!(
+            VectorStore::open(&config(dir.path(), 100), 3),
+            Err(EmbeddingsError::CorruptedStore { .. })
+        ));
+    }
+
+    #[test]
+    fn test_store_detects_corrupted_length() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let (mut store, _) = VectorStore::open(&config(dir.path(), 100), 3).unwrap();
+            for i in 0..3 {
+                store
+                    .append(&WalEntry::Insert(id(i), embedding(i as f32)))
+                    .unwrap();
+            }
+        }
+        let wal_path = dir.path().join(WAL_FILE);
+        let wal = std::fs::read(&wal_path).unwrap();
+        let record_len = wal.len() / 3;
+
+        // The last record, cut short by a crash, is discarded.
+        std::fs::write(&wal_path, &wal[..wal.len() - 5]).unwrap();
+        let (_, contents) = VectorStore::open(&config(dir.path(), 100), 3).unwrap();
+        assert_eq!(contents.embeddings.len(), 2);
+
+        // A middle record whose length runs past the end of the log is reported, rather than
+        // taken for a torn tail, dropping the records after it.
+        let mut wal = wal;
+        let len = u32::from_le_bytes(wal[record_len..record_len + 4].try_into().unwrap());
+        wal[record_len..record_len + 4].copy_from_slice(&(len + 1000).to_le_bytes());
+        std::fs::write(&wal_path, &wal).unwrap();
+        assert!(matches!(
+            VectorStore::open(&config(dir.path(), 100), 3),
+            Err(EmbeddingsError::CorruptedStore { .. })
+        ));
+    }
+
+    #[test]
+    fn test_store_rejects_other_dimension() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let (mut store, _) = VectorStore::open(&config(dir.path(), 100), 3).unwrap();
+            store
+                .snapshot(&[(id(0), embedding(0.0))], &HashMap::new(), &HashMap::new())
+                .unwrap();
+        }
+        assert!(matches!(
+            VectorStore::open(&config(dir.path(), 100), 4),
+            Err(EmbeddingsError::DimensionMismatch {
+                expected: 4,
+                found: 3
+            })
+        ));
+    }
+
+    #[test]
+    fn test_store_records_model() {
+        let dir = tempfile::tempdir().unwrap();
+        let model = ModelTag::new("hashing-bow-3", "1");
+        {
+            let (mut store, contents) = VectorStore::open(&config(dir.path(), 100), 3).unwrap();
+            assert_eq!(contents.model, None);
+            store.set_model(model.clone()).unwrap();
+            store.append(&WalEntry::Reset).unwrap();
+            store
+                .append(&WalEntry::Insert(id(0), embedding(0.0)))
+                .unwrap();
+        }
+
+        // The model is kept across a reset, and written into snapshots.
+        let (mut store, contents) = VectorStore::open(&config(dir.path(), 100), 3).unwrap();
+        assert_eq!(contents.model.as_ref(), Some(&model));
+        assert_eq!(store.model(), Some(&model));
+        store
+            .snapshot(&contents.embeddings, &HashMap::new(), &HashMap::new())
+            .unwrap();
+        drop(store);
+        let (mut store, contents) = VectorStore::open(&config(dir.path(), 100), 3).unwrap();
+        assert_eq!(contents.model.as_ref(), Some(&model));
+
+        // Replacing the contents switches the dimension and the model at once.
+        let other = ModelTag::new("hashing-bow-2", "1");
+        let data = [(id(1), Embedding::new(vec![1.0, 2.0], 2).unwrap())];
+        store
+            .replace(
+                2,
+                Some(other.clone()),
+                &data,
+                &HashMap::new(),
+                &HashMap::new(),
+            )
+            .unwrap();
+        drop(store);
+        assert!(matches!(
+            VectorStore::open(&config(dir.path(), 100), 3),
+            Err(EmbeddingsError::DimensionMismatch { .. })
+        ));
+        let (_, contents) = VectorStore::open(&config(dir.path(), 100), 2).unwrap();
+        assert_eq!(contents.model, Some(other));
+        assert_eq!(contents.embeddings, data.to_vec());
+    }
+
+    #[test]
+    fn test_store_reads_integer_ids() {
+        let dir = tempfile::tempdir().unwrap();
+        // A version 3 snapshot, holding chunk 0 and its text.
+        let mut snapshot = SNAPSHOT_MAGIC.to_vec();
+        snapshot.extend_from_slice(&3u32.to_le_bytes());
+        snapshot.extend_from_slice(&3u32.to_le_bytes());
+        snapshot.extend_from_slice(&1u64.to_le_bytes());
+        snapshot.extend_from_slice(&0u32.to_le_bytes());
+        encode_vector(&mut snapshot, embedding(0.0).as_slice());
+        snapshot.extend_from_slice(&0u64.to_le_bytes());
+        snapshot.extend_from_slice(&1u64.to_le_bytes());
+        snapshot.extend_from_slice(&0u32.to_le_bytes());
+        snapshot.extend_from_slice(&5u32.to_le_bytes());
+        snapshot.extend_from_slice(b"Hello");
+        let checksum = crc32fast::hash(&snapshot);
+        snapshot.extend_from_slice(&checksum.to_le_bytes());
+        std::fs::write(dir.path().join(SNAPSHOT_FILE), snapshot).unwrap();
+        // And a log record inserting chunk 1.
+        let mut payload = vec![LEGACY_INSERT_TAG];
+        payload.extend_from_slice(&1u32.to_le_bytes());
+        encode_vector(&mut payload, embedding(1.0).as_slice());
+        let mut wal = (payload.len() as u32).to_le_bytes().to_vec();
+        wal.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        wal.extend_from_slice(&payload);
+        std::fs::write(dir.path().join(WAL_FILE), wal).unwrap();
+
+        let (mut store, contents) = VectorStore::open(&config(dir.path(), 100), 3).unwrap();
+        assert_eq!(
+            contents.embeddings,
+            vec![(id(0), embedding(0.0)), (id(1), embedding(1.0))]
+        );
+        assert_eq!(
+            contents.texts,
+            HashMap::from([(id(0), "Hello".to_string())])
+        );
+
+        // New entries are logged by chunk id, after the replayed ones.
+        let chunk_id = ChunkId::new();
+        store.append(&WalEntry::Delete(id(0))).unwrap();
+        store
+            .append(&WalEntry::Insert(chunk_id, embedding(2.0)))
+            .unwrap();
+        drop(store);
+        let (_, contents) = VectorStore::open(&config(dir.path(), 100), 3).unwrap();
+        assert_eq!(
+            contents.embeddings,
+            vec![(id(1), embedding(1.0)), (chunk_id, embedding(2.0))]
+        );
+    }
+}
@@ -0,0 +1,294 @@
+use std::{
+    borrow::Cow,
+    fs::{File, OpenOptions},
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::{embeddings::Embedding, error::EmbeddingsError, store::storage_error};
+
+/// Full precision vectors of an index, by position, either in memory or in a file. Both lay
+/// the vectors out one after the other, so that scans read them in order.
+pub enum FullVectors {
+    Memory { dimension: usize, values: Vec<f32> },
+    File(VectorFile),
+}
+
+impl FullVectors {
+    pub fn new(dimension: usize) -> Self {
+        Self::Memory {
+            dimension,
+            values: vec![],
+        }
+    }
+
+    pub fn from_embeddings(dimension: usize, embeddings: Vec<Embedding>) -> Self {
+        let mut values = Vec::with_capacity(dimension * embeddings.len());
+        embeddings
+            .iter()
+            .for_each(|embedding| values.extend_from_slice(embedding.as_slice()));
+        Self::Memory { dimension, values }
+    }
+
+    pub fn dimension(&self) -> usize {
+        match self {
+            Self::Memory { dimension, .. } => *dimension,
+            Self::File(file) => file.dimension,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Memory { dimension, values } => values.len() / (*dimension).max(1),
+            Self::File(file) => file.len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, position: usize) -> Result<Cow<'_, [f32]>, EmbeddingsError> {
+        match self {
+            Self::Memory { dimension, values } => Ok(Cow::Borrowed(
+                &values[position * dimension..(position + 1) * dimension],
+            )),
+            Self::File(file) => file.read(position).map(Cow::Owned),
+        }
+    }
+
+    /// Sets the vector at `position`, which is either taken, or the next free one.
+    pub fn set(&mut self, position: usize, embedding: Embedding) -> Result<(), EmbeddingsError> {
+        match self {
+            Self::Memory { dimension, values } if position * *dimension == values.len() => {
+                values.extend_from_slice(embedding.as_slice())
+            }
+            Self::Memory { dimension, values } => values
+                [position * *dimension..(position + 1) * *dimension]
+                .copy_from_slice(embedding.as_slice()),
+            Self::File(file) => {
+                file.write(position, embedding.as_slice())?;
+                file.len = file.len.max(position + 1);
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the vector at `position`, moving the last one in its place.
+    pub fn swap_remove(&mut self, position: usize) -> Result<(), EmbeddingsError> {
+        let last = self.len() - 1;
+        match self {
+            Self::Memory { dimension, values } => {
+                values.copy_within(last * *dimension.., position * *dimension);
+                values.truncate(last * *dimension);
+            }
+            Self::File(file) => {
+                if position != last {
+                    let vector = file.read(last)?;
+                    file.write(position, &vector)?;
+                }
+                file.truncate(last)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes every vector, along with their file, if any.
+    pub fn clear(&mut self) -> Result<(), EmbeddingsError> {
+        if let Self::File(file) = std::mem::replace(self, Self::new(self.dimension())) {
+            std::fs::remove_file(&file.path).map_err(|e| storage_error(&file.path, e))?;
+        }
+        Ok(())
+    }
+
+    /// Every vector, in position order.
+    pub fn to_vec(&self) -> Result<Vec<Embedding>, EmbeddingsError> {
+        (0..self.len())
+            .map(|position| Embedding::new(self.get(position)?.into_owned(), self.dimension()))
+            .collect()
+    }
+
+    /// Bytes held in memory by the vectors.
+    pub fn memory_bytes(&self) -> usize {
+        match self {
+            Self::Memory { values, .. } => 4 * values.len(),
+            Self::File(_) => 0,
+        }
+    }
+
+    /// Bytes of the vectors kept in a file.
+    pub fn file_bytes(&self) -> usize {
+        match self {
+            Self::Memory { .. } => 0,
+            Self::File(file) => 4 * file.dimension * file.len,
+        }
+    }
+}
+
+/// Vectors laid out one after the other in a file, as little endian floats.
+///
+/// The file is a copy of vectors persisted by the store, so it is never synced, and is
+/// written anew whenever the store is opened.
+pub struct VectorFile {
+    path: PathBuf,
+    file: File,
+    dimension: usize,
+    len: usize,
+}
+
+impl VectorFile {
+    /// Creates the file at `path`, replacing any previous one, holding `values`: vectors of
+    /// `dimension` one after the other.
+    pub fn create(path: &Path, dimension: usize, values: &[f32]) -> Result<Self, EmbeddingsError> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .map_err(|e| storage_error(path, e))?;
+        let mut vector_file = Self {
+            path: path.to_path_buf(),
+            file,
+            dimension,
+            len: values.len() / dimension.max(1),
+        };
+        let bytes: Vec<u8> = values.iter().flat_map(|x| x.to_le_bytes()).collect();
+        write_all_at(&vector_file.file, &bytes, 0).map_err(|e| storage_error(path, e))?;
+        vector_file.truncate(vector_file.len)?;
+        Ok(vector_file)
+    }
+
+    fn read(&self, position: usize) -> Result<Vec<f32>, EmbeddingsError> {
+        let mut bytes = vec![0; 4 * self.dimension];
+        read_exact_at(&self.file, &mut bytes, self.offset(position))
+            .map_err(|e| storage_error(&self.path, e))?;
+        Ok(bytes
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect())
+    }
+
+    fn write(&self, position: usize, vector: &[f32]) -> Result<(), EmbeddingsError> {
+        let bytes: Vec<u8> = vector.iter().flat_map(|x| x.to_le_bytes()).collect();
+        write_all_at(&self.file, &bytes, self.offset(position))
+            .map_err(|e| storage_error(&self.path, e))
+    }
+
+    fn truncate(&mut self, len: usize) -> Result<(), EmbeddingsError> {
+        self.file
+            .set_len(self.offset(len))
+            .map_err(|e| storage_error(&self.path, e))?;
+        self.len = len;
+        Ok(())
+    }
+
+    fn offset(&self, position: usize) -> u64 {
+        (4 * self.dimension * position) as u64
+    }
+}
+
+/// Reads exactly `buf.len()` bytes of `file` from `offset`, without moving a cursor shared
+/// with concurrent reads.
+#[cfg(unix)]
+pub(crate) fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+/// Writes all of `buf` to `file` from `offset`, without moving a cursor shared with
+/// concurrent reads.
+#[cfg(unix)]
+pub(crate) fn write_all_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+pub(crate) fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+pub(crate) fn write_all_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_write(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => {
+                buf = &buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Pseudo-random vectors, with components in `[-0.5, 0.5)`, for tests needing vectors without
+/// the ties of near identical sentences.
+#[cfg(test)]
+pub(crate) fn random_vectors(n: usize, dimension: usize, seed: u64) -> Vec<Vec<f32>> {
+    let mut state = seed;
+    (0..n)
+        .map(|_| {
+            (0..dimension)
+                .map(|_| {
+                    state = state
+                        .wrapping_mul(6364136223846793005)
+                        .wrapping_add(1442695040888963407);
+                    ((state >> 33) as f32 / (1u64 << 31) as f32) - 0.5
+                })
+                .collect()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn embedding(x: f32) -> Embedding {
+        Embedding::new(vec![x, -x, 2.0 * x], 3).unwrap()
+    }
+
+    #[test]
+    fn test_vector_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vectors.bin");
+        let memory = FullVectors::from_embeddings(3, vec![embedding(1.0), embedding(2.0)]);
+        let FullVectors::Memory { values, .. } = &memory else {
+            unreachable!()
+        };
+        let file = FullVectors::File(VectorFile::create(&path, 3, values).unwrap());
+        for mut vectors in [memory, file] {
+            assert_eq!(vectors.len(), 2);
+            assert_eq!(vectors.get(1).unwrap().as_ref(), embedding(2.0).as_slice());
+
+            vectors.set(2, embedding(3.0)).unwrap();
+            vectors.set(0, embedding(4.0)).unwrap();
+            vectors.swap_remove(1).unwrap();
+            assert_eq!(
+                vectors.to_vec().unwrap(),
+                vec![embedding(4.0), embedding(3.0)]
+            );
+            assert_eq!(vectors.memory_bytes() + vectors.file_bytes(), 2 * 12);
+
+            vectors.swap_remove(1).unwrap();
+            vectors.swap_remove(0).unwrap();
+            assert!(vectors.is_empty());
+        }
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+    }
+}
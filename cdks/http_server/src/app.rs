@@ -1,61 +1,73 @@
-use std::sync::{atomic::AtomicU32, mpsc, Arc};
+use std::sync::Arc;
 
 use axum::{
     extract::FromRef,
     routing::{get, post},
     Router,
 };
-use embeddings::embeddings::DEFAULT_MODEL_EMBEDDING_SIZE;
-use log::info;
-use serde_json::Value;
-use tokio::sync::{
-    mpsc::{Receiver, Sender},
-    Mutex,
+use embeddings::{
+    chunker::{ChunkerConfig, LlmTokenCounter},
+    client::EmbeddingsClient,
+    dedup::DedupConfig,
 };
+use log::info;
+use neo4j::neo4j_service::Neo4jRequest;
+use tokio::sync::mpsc::Sender;
 
 use crate::{
     client::OpenAiClient,
     handlers::{
-        enhanced_llm_response_handler, process_chunk_handler, related_knowledge_handler,
-        retrieve_knowledge_handler,
+        delete_document_handler, documents_mentioning_handler, enhanced_llm_response_handler,
+        get_chunk_handler, get_document_handler, get_record_handler, process_chunk_handler,
+        process_document_handler, related_knowledge_handler, retrieve_knowledge_handler,
     },
 };
 
 #[derive(Clone, FromRef)]
 pub struct AppState {
-    pub(crate) request_id: Arc<AtomicU32>,
-    pub(crate) tx_neo4j: Sender<Value>,
-    pub(crate) rx_neo4j_relations: Arc<Mutex<Receiver<Value>>>,
+    pub(crate) tx_neo4j: Sender<Neo4jRequest>,
     pub(crate) client: Arc<OpenAiClient>,
-    pub(crate) embeddings_receiver: Arc<Mutex<mpsc::Receiver<[f32; DEFAULT_MODEL_EMBEDDING_SIZE]>>>,
-    pub(crate) embeddings_text_sender: Arc<Mutex<std::sync::mpsc::Sender<String>>>,
-    pub(crate) embeddings_indices_receiver: Arc<Mutex<std::sync::mpsc::Receiver<u32>>>,
+    pub(crate) embeddings: EmbeddingsClient,
+    pub(crate) dedup: Option<DedupConfig>,
+    pub(crate) chunker: ChunkerConfig,
+    /// Seconds after which a pending extraction is claimed again.
+    pub(crate) pending_timeout: u64,
+    pub(crate) llm_tokens: Arc<LlmTokenCounter>,
 }
 
 pub fn routes(
-    tx_neo4j: Sender<Value>,
-    rx_neo4j_relations: Receiver<Value>,
+    tx_neo4j: Sender<Neo4jRequest>,
     client: OpenAiClient,
-    embeddings_receiver: mpsc::Receiver<[f32; DEFAULT_MODEL_EMBEDDING_SIZE]>,
-    embeddings_text_sender: mpsc::Sender<String>,
-    embeddings_indices_receiver: std::sync::mpsc::Receiver<u32>,
+    embeddings: EmbeddingsClient,
+    dedup: Option<DedupConfig>,
+    chunker: ChunkerConfig,
+    pending_timeout: u64,
+    llm_tokens: Arc<LlmTokenCounter>,
 ) -> Router {
     let app_state = AppState {
-        request_id: Arc::new(AtomicU32::new(0)),
         tx_neo4j,
-        rx_neo4j_relations: Arc::new(Mutex::new(rx_neo4j_relations)),
         client: Arc::new(client),
-        embeddings_receiver: Arc::new(Mutex::new(embeddings_receiver)),
-        embeddings_text_sender: Arc::new(Mutex::new(embeddings_text_sender)),
-        embeddings_indices_receiver: Arc::new(Mutex::new(embeddings_indices_receiver)),
+        embeddings,
+        dedup,
+        chunker,
+        pending_timeout,
+        llm_tokens,
     };
 
     info!("Routing..");
 
     Router::new()
         .route("/", post(process_chunk_handler))
+        .route("/document", post(process_document_handler))
+        .route(
+            "/document/:id",
+            get(get_document_handler).delete(delete_document_handler),
+        )
+        .route("/documents_mentioning", get(documents_mentioning_handler))
         .route("/retrieve_knowledge", get(retrieve_knowledge_handler))
         .route("/related_knowledge", get(related_knowledge_handler))
         .route("/enhanced_knowledge", get(enhanced_llm_response_handler))
+        .route("/chunk/:id", get(get_chunk_handler))
+        .route("/record/:hash", get(get_record_handler))
         .with_state(app_state)
 }
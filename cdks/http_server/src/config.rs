@@ -1,13 +1,45 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, sync::Arc};
+
+use embeddings::{
+    chunker::{approximate_llm_tokens, ChunkerConfig, LlmTokenCounter},
+    dedup::DedupConfig,
+};
 
 pub struct Config {
     pub(crate) socket_address: SocketAddr,
+    /// Detection of ingested chunks duplicating stored ones. If not set, every chunk is stored.
+    pub(crate) dedup: Option<DedupConfig>,
+    /// Token budgets of the chunks documents are split into. Chunks posted on their own may not
+    /// exceed its LLM budget either.
+    pub(crate) chunker: ChunkerConfig,
+    /// Seconds after which a chunk still pending extraction, e.g. because the server stopped
+    /// mid-extraction, is extracted again when posted.
+    pub(crate) pending_timeout: u64,
+    /// Counts the LLM tokens of posted chunks, against the LLM budget of `chunker`.
+    pub(crate) llm_tokens: Arc<LlmTokenCounter>,
+}
+
+impl Config {
+    /// Counts the LLM tokens of posted chunks with `llm_tokens`, e.g. the tokenizer of the LLM,
+    /// rather than [approximating](approximate_llm_tokens) them. The embeddings service
+    /// splitting documents should count them the same way.
+    pub fn with_llm_tokens<L>(mut self, llm_tokens: L) -> Self
+    where
+        L: Fn(&str) -> usize + Send + Sync + 'static,
+    {
+        self.llm_tokens = Arc::new(llm_tokens);
+        self
+    }
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             socket_address: "127.0.0.1:3000".parse().unwrap(),
+            dedup: Some(DedupConfig::default()),
+            chunker: ChunkerConfig::default(),
+            pending_timeout: 600,
+            llm_tokens: Arc::new(approximate_llm_tokens),
         }
     }
 }
@@ -14,6 +14,7 @@ pub enum Error {
     // -- Server errors.
     FailedToStartService,
     InternalError,
+    ServiceTimeout,
     // -- Model errors.
     FailedToStoreIntent,
     OpenAIError,
@@ -52,6 +53,7 @@ impl Error {
                 StatusCode::INTERNAL_SERVER_ERROR,
                 ClientError::INTERNAL_SERVER_ERROR,
             ),
+            Self::ServiceTimeout => (StatusCode::GATEWAY_TIMEOUT, ClientError::SERVICE_ERROR),
             // -- Model
             Self::FailedToStoreIntent => (
                 StatusCode::INTERNAL_SERVER_ERROR,
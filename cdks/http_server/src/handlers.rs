@@ -1,18 +1,35 @@
-use axum::{extract::State, Json};
-use neo4j::neo4j_builder::Neo4jQuery;
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use embeddings::{
+    chunker::DocumentSplit,
+    dedup::{ContentHash, DuplicatePolicy},
+    error::EmbeddingsError,
+    id::ChunkId,
+    index::{IngestionClaim, StoredChunk},
+    metadata::{ChunkMetadata, ChunkPosition, Ingestion, IngestionStatus},
+    search::{SearchParams, SearchResult},
+};
+use neo4j::{
+    neo4j_builder::{ChunkNode, DocumentPosition, Neo4jQuery},
+    neo4j_service::Neo4jRequest,
+};
 use regex::Regex;
-use serde_json::json;
-use tokio::join;
+use serde_json::{json, Value};
 
 use crate::{
     app::AppState,
     error::{Error, Result},
     types::{
-        EnhancedLlmRequest, EnhancedLlmResponse, OpenAiRequest, ProcessChunkRequest,
-        ProcessChunkResponse, RelatedKnowledgeRequest, RelatedKnowledgeResponse,
-        RetrieveKnowledgeRequest, RetrieveKnowledgeResponse,
+        ChunkResponse, DeleteDocumentResponse, DocumentChunkResponse, DocumentResponse,
+        DocumentsMentioningRequest, DocumentsMentioningResponse, EnhancedLlmRequest,
+        EnhancedLlmResponse, OpenAiModelParams, OpenAiRequest, ProcessChunkRequest,
+        ProcessChunkResponse, ProcessDocumentRequest, ProcessDocumentResponse,
+        RelatedKnowledgeRequest, RelatedKnowledgeResponse, RetrieveKnowledgeRequest,
+        RetrieveKnowledgeResponse,
     },
-    utils::{generate_answer, kg_to_query_json, retrieve_prompt},
+    utils::{generate_answer, kg_to_query_json, relation_triplets, retrieve_prompt},
 };
 use log::{error, info};
 
@@ -20,29 +37,209 @@ pub async fn process_chunk_handler(
     State(state): State<AppState>,
     Json(request): Json<ProcessChunkRequest>,
 ) -> Result<Json<ProcessChunkResponse>> {
-    let ProcessChunkRequest { chunk, params } = request;
+    let ProcessChunkRequest {
+        chunk,
+        metadata,
+        params,
+    } = request;
+    process_chunk(state, chunk, metadata.unwrap_or_default(), None, params)
+        .await
+        .map(Json)
+}
+
+pub async fn process_document_handler(
+    State(state): State<AppState>,
+    Json(request): Json<ProcessDocumentRequest>,
+) -> Result<Json<ProcessDocumentResponse>> {
+    let ProcessDocumentRequest {
+        document,
+        metadata,
+        chunker,
+        params,
+    } = request;
+    let mut metadata = metadata.unwrap_or_default();
+    let document_id = metadata
+        .document_id
+        .get_or_insert_with(|| ContentHash::of(&document).to_string())
+        .clone();
+    let DocumentSplit {
+        sections,
+        chunks: document_chunks,
+    } = state
+        .embeddings
+        .split_document(document, chunker.unwrap_or(state.chunker))
+        .await
+        .map_err(embeddings_error)?;
+    info!(
+        "Split document {document_id} into {} sections and {} chunks",
+        sections.len(),
+        document_chunks.len()
+    );
+
+    let mut chunks = Vec::with_capacity(document_chunks.len());
+    for document_chunk in document_chunks {
+        let metadata = ChunkMetadata {
+            position: Some(ChunkPosition {
+                section: document_chunk.section as u32,
+                index: document_chunk.index as u32,
+                start: document_chunk.start as u64,
+                end: document_chunk.end as u64,
+            }),
+            ..metadata.clone()
+        };
+        let response = process_chunk(
+            state.clone(),
+            document_chunk.text,
+            metadata,
+            sections[document_chunk.section].title.clone(),
+            params.clone(),
+        )
+        .await
+        .unwrap_or_else(|e| ProcessChunkResponse {
+            is_success: false,
+            chunk_id: None,
+            hash: None,
+            status: None,
+            error_message: Some(e.to_string()),
+            duplicate_of: None,
+        });
+        chunks.push(DocumentChunkResponse {
+            index: document_chunk.index,
+            section: document_chunk.section,
+            start: document_chunk.start,
+            end: document_chunk.end,
+            tokens: document_chunk.tokens,
+            response,
+        });
+    }
+
+    let failed = chunks
+        .iter()
+        .filter(|chunk| !chunk.response.is_success)
+        .count();
+    Ok(Json(ProcessDocumentResponse {
+        document_id,
+        sections,
+        is_success: failed == 0,
+        error_message: (failed > 0)
+            .then(|| format!("Failed to process {failed} of {} chunks", chunks.len())),
+        chunks,
+    }))
+}
+
+/// Stores `chunk` and extracts its knowledge graph, unless it duplicates a stored chunk. The
+/// graph is linked to the chunk, and to its document and section if it was split from one.
+///
+/// Posting a chunk again returns the record of its first ingestion, unless its extraction
+/// failed, in which case it is retried under the same id.
+async fn process_chunk(
+    state: AppState,
+    chunk: String,
+    metadata: ChunkMetadata,
+    section_title: Option<String>,
+    params: OpenAiModelParams,
+) -> Result<ProcessChunkResponse> {
+    let llm_tokens = (state.llm_tokens)(&chunk);
+    if llm_tokens > state.chunker.max_llm_tokens {
+        return Ok(ProcessChunkResponse {
+            is_success: false,
+            chunk_id: None,
+            hash: None,
+            status: None,
+            error_message: Some(format!(
+                "Chunk has {llm_tokens} LLM tokens, over the {} tokens budget, post it to /document to have it split",
+                state.chunker.max_llm_tokens
+            )),
+            duplicate_of: None,
+        });
+    }
+
     let prompt = retrieve_prompt(&chunk);
+    let document =
+        metadata
+            .document_id
+            .clone()
+            .zip(metadata.position)
+            .map(|(document_id, position)| DocumentPosition {
+                document_id,
+                section: position.section,
+                section_title,
+                index: position.index,
+                start: position.start,
+                end: position.end,
+            });
 
-    // send text chunk to the embeddings service to be processed.
-    let request_id = state.request_id.clone();
-    let embeddings_join_handle = tokio::spawn(async move {
-        let send_string = format!(r#"{{"chunk_text":[{:?},"{}"]}}"#, request_id, chunk);
-        state
-            .embeddings_text_sender
-            .lock()
-            .await
-            .send(send_string)
-            .map_err(|e| {
-                error!("Failed to send chunk to embeddings service, with error: {e}");
-                Error::InternalError
-            })?;
-        Ok::<(), Error>(())
-    });
+    // send text chunk to the embeddings service to be processed, and skip the extraction of
+    // chunks already ingested. The chunk keeps its id in the knowledge graph. Looking the chunk
+    // up and storing it in a single request, concurrent posts of a chunk store it only once.
+    let chunk_id = ChunkId::new();
+    let claim = state
+        .embeddings
+        .claim_ingestion(
+            chunk_id,
+            chunk.clone(),
+            metadata,
+            state.dedup,
+            state.pending_timeout,
+        )
+        .await
+        .map_err(embeddings_error)?;
+    let chunk_id = match claim {
+        IngestionClaim::Existing(chunk) => {
+            let StoredChunk { id, ingestion, .. } = *chunk;
+            info!("Chunk was already ingested under id {id}");
+            let ingestion = ingestion.unwrap_or_default();
+            return Ok(ProcessChunkResponse {
+                is_success: true,
+                chunk_id: Some(id),
+                hash: ingestion.hash,
+                status: Some(ingestion.status),
+                error_message: None,
+                duplicate_of: None,
+            });
+        }
+        IngestionClaim::Retry(chunk_id) => {
+            info!("Retrying the extraction of chunk {chunk_id}");
+            chunk_id
+        }
+        IngestionClaim::Stored(None) => chunk_id,
+        IngestionClaim::Stored(Some(duplicate)) => {
+            info!(
+                "Chunk duplicates stored chunk {}, skipping it",
+                duplicate.id
+            );
+            // Linked duplicates are stored, under their own id.
+            let linked = state
+                .dedup
+                .is_some_and(|dedup| dedup.policy == DuplicatePolicy::Link);
+            let status = linked.then_some(IngestionStatus::Duplicate);
+            if let Some(status) = status {
+                let ingestion = Ingestion {
+                    status,
+                    ..Default::default()
+                };
+                state
+                    .embeddings
+                    .set_ingestion(chunk_id, ingestion)
+                    .await
+                    .map_err(embeddings_error)?;
+            }
+            return Ok(ProcessChunkResponse {
+                is_success: true,
+                chunk_id: linked.then_some(chunk_id),
+                hash: None,
+                status,
+                error_message: None,
+                duplicate_of: Some(duplicate),
+            });
+        }
+    };
 
     info!("Making OpenAI call with prompt: {prompt}");
 
-    let request_id = state.request_id.clone();
+    let task_state = state.clone();
     let openai_join_handle = tokio::spawn(async move {
+        let state = task_state;
         let openai_request = OpenAiRequest { prompt, params };
         match state.client.call(openai_request).await {
             Ok(response) => {
@@ -58,17 +255,13 @@ pub async fn process_chunk_handler(
 
                 info!("Obtained knowledge graph: {:?}", knowledge_graph);
 
-                if let Some(kg) = knowledge_graph {
-                    match kg_to_query_json(
-                        &kg,
-                        request_id.load(std::sync::atomic::Ordering::SeqCst),
-                    ) {
-                        Ok(query) => {
-                            if let Err(e) = state.tx_neo4j.send(query).await {
-                                error!("Failed to send query to Neo4J service, with error: {e}");
-                                return Err(Error::InternalError);
-                            };
-                        }
+                if let Some(kg) = &knowledge_graph {
+                    let chunk = ChunkNode {
+                        chunk_id: chunk_id.to_string(),
+                        document,
+                    };
+                    match kg_to_query_json(kg, chunk) {
+                        Ok(query) => query_neo4j(&state, query).await.map(|_| ())?,
                         Err(e) => {
                             error!(
                             "Failed to generate neo4j query from knowledge graph, with error: {e}"
@@ -77,45 +270,55 @@ pub async fn process_chunk_handler(
                         }
                     }
                 }
+                Ok(knowledge_graph)
             }
             Err(e) => {
                 error!("Failed to get OpenAI response, with error {e}");
-                return Err(Error::InternalError);
+                Err(Error::InternalError)
             }
         }
-        Ok::<(), Error>(())
     });
 
-    let (embedding_result, openai_result) = join!(embeddings_join_handle, openai_join_handle);
+    let openai_result = openai_join_handle
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|result| result.map_err(|e| e.to_string()));
 
-    match (embedding_result, openai_result) {
-        (Ok(_), Ok(_)) => {
-            state
-                .request_id
-                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-            Ok(Json(ProcessChunkResponse {
-                is_success: true,
-                hash: [0u8; 32],
-                error_message: None,
-            }))
-        }
-        (Err(e), Ok(_)) => {
-            // Task 1 failed
-            error!("Task 1 failed, with error: {}", e);
-            Err(Error::InternalError)
-        }
-        (Ok(_), Err(e)) => {
-            // Task 2 failed
-            error!("Task 2 failed, with error: {}", e);
-            Err(Error::InternalError)
-        }
-        (Err(e1), Err(e2)) => {
-            // Both tasks failed
-            error!("Task 1 failed: with error {}", e1);
-            error!("Task 2 failed: with error {}", e2);
-            Err(Error::InternalError)
+    let ingestion = match openai_result {
+        Ok(knowledge_graph) => Ingestion {
+            status: IngestionStatus::Extracted,
+            hash: Some(ContentHash::of_extraction(
+                &chunk,
+                knowledge_graph.as_deref(),
+            )),
+            knowledge_graph,
+            ..Default::default()
+        },
+        Err(e) => {
+            error!("Knowledge graph extraction failed, with error: {}", e);
+            Ingestion {
+                status: IngestionStatus::Failed,
+                ..Default::default()
+            }
         }
+    };
+    let (status, hash) = (ingestion.status, ingestion.hash);
+    state
+        .embeddings
+        .set_ingestion(chunk_id, ingestion)
+        .await
+        .map_err(embeddings_error)?;
+    if status == IngestionStatus::Failed {
+        return Err(Error::InternalError);
     }
+    Ok(ProcessChunkResponse {
+        is_success: true,
+        chunk_id: Some(chunk_id),
+        hash,
+        status: Some(status),
+        error_message: None,
+        duplicate_of: None,
+    })
 }
 
 pub async fn retrieve_knowledge_handler(
@@ -123,32 +326,27 @@ pub async fn retrieve_knowledge_handler(
     Json(request): Json<RetrieveKnowledgeRequest>,
 ) -> Result<Json<RetrieveKnowledgeResponse>> {
     let RetrieveKnowledgeRequest {
-        node_indices,
+        chunk_ids,
+        include_text,
         params: _params,
     } = request;
-    let query = serde_json::to_value(Neo4jQuery::Retrieve(node_indices)).map_err(|e| {
-        error!("Failed to build JSON from node indices, with error: {e}");
-        Error::InternalError
-    })?;
-    state.tx_neo4j.send(query).await.map_err(|e| {
-        error!("Failed to build JSON from node indices, with error: {e}");
+    let chunks = match include_text {
+        Some(true) => Some(fetch_chunks(&state, chunk_ids.clone()).await?),
+        _ => None,
+    };
+    let query = serde_json::to_value(Neo4jQuery::RetrieveChunks(
+        chunk_ids.iter().map(ChunkId::to_string).collect(),
+    ))
+    .map_err(|e| {
+        error!("Failed to build JSON from chunk ids, with error: {e}");
         Error::InternalError
     })?;
+    let knowledge_graph_data = query_neo4j(&state, query).await?;
+    info!("Received new token: {knowledge_graph_data}");
 
-    let knowledge_graph_data =
-        if let Some(data) = state.rx_neo4j_relations.lock().await.recv().await {
-            info!("Received new token: {data}");
-            data
-        } else {
-            error!("Failed to receive a response from Neo4j service");
-            return Err(Error::InternalError);
-        };
-
-    state
-        .request_id
-        .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
     Ok(Json(RetrieveKnowledgeResponse {
         knowledge_graph_data: Some(knowledge_graph_data),
+        chunks,
         is_success: true,
         error_message: None,
     }))
@@ -158,48 +356,168 @@ pub async fn related_knowledge_handler(
     State(state): State<AppState>,
     Json(request): Json<RelatedKnowledgeRequest>,
 ) -> Result<Json<RelatedKnowledgeResponse>> {
-    let RelatedKnowledgeRequest { chunk, num_queries } = request;
+    let RelatedKnowledgeRequest {
+        chunk,
+        num_queries,
+        metric,
+        min_score,
+        filter,
+        mode,
+        hybrid,
+        mmr_lambda,
+        include_text,
+        group_by_document,
+    } = request;
 
-    let num_queries = num_queries.unwrap_or(1);
+    let params = SearchParams {
+        num_queries: num_queries.unwrap_or(1),
+        metric: metric.unwrap_or_default(),
+        min_score,
+        filter,
+        mode: mode.unwrap_or_default(),
+        hybrid: hybrid.unwrap_or_default(),
+        mmr_lambda,
+    };
+    let (results, documents) = match group_by_document {
+        Some(true) => {
+            let documents = state
+                .embeddings
+                .search_documents(chunk, params)
+                .await
+                .map_err(embeddings_error)?;
+            let mut results: Vec<SearchResult> = documents
+                .iter()
+                .flat_map(|document| document.results.iter().copied())
+                .collect();
+            results.sort_by(|r1, r2| r2.score.total_cmp(&r1.score));
+            (results, Some(documents))
+        }
+        _ => (search_embeddings(&state, chunk, params).await?, None),
+    };
+    let knowledge_graph_chunks: Vec<ChunkId> = results.iter().map(|r| r.id).collect();
+    let scores: Vec<f32> = results.iter().map(|r| r.score).collect();
+    let chunks = match include_text {
+        Some(true) => Some(fetch_chunks(&state, knowledge_graph_chunks.clone()).await?),
+        _ => None,
+    };
 
-    let send_string = format!(r#"{{"get_chunk_id":["{}",{}]}}"#, chunk, num_queries);
-    state
-        .embeddings_text_sender
-        .lock()
+    Ok(Json(RelatedKnowledgeResponse {
+        knowledge_graph_data: Some(json!({
+            "knowledge_graph_chunks": knowledge_graph_chunks,
+            "scores": scores,
+        })),
+        chunks,
+        documents,
+        is_success: true,
+        error_message: None,
+    }))
+}
+
+pub async fn get_document_handler(
+    State(state): State<AppState>,
+    Path(document_id): Path<String>,
+) -> Result<Json<DocumentResponse>> {
+    let chunks = state
+        .embeddings
+        .document(document_id.clone())
         .await
-        .send(send_string)
-        .map_err(|e| {
-            error!("Failed to send chunk to embeddings service, with error: {e}");
+        .map_err(embeddings_error)?;
+    let error_message = chunks
+        .is_empty()
+        .then(|| format!("No chunk stored for document {document_id}"));
+    Ok(Json(DocumentResponse {
+        document_id,
+        chunks,
+        is_success: error_message.is_none(),
+        error_message,
+    }))
+}
+
+pub async fn delete_document_handler(
+    State(state): State<AppState>,
+    Path(document_id): Path<String>,
+) -> Result<Json<DeleteDocumentResponse>> {
+    let deleted = state
+        .embeddings
+        .delete_document(document_id.clone())
+        .await
+        .map_err(embeddings_error)?;
+    let query =
+        serde_json::to_value(Neo4jQuery::DeleteDocument(document_id.clone())).map_err(|e| {
+            error!("Failed to build JSON from document id, with error: {e}");
             Error::InternalError
         })?;
+    query_neo4j(&state, query).await?;
 
-    // TODO: for now we follow a simple approach of waiting for `num_queries` tokens, notice that the database might have less than `num_queries chunks`
-    let mut received_tokens = 0;
-    let mut knowledge_graph_chunks = vec![];
-    while let Ok(knowledge_chunk) = state
-        .embeddings_indices_receiver
-        .lock()
-        .await
-        .recv()
-        .map_err(|e| {
-            error!("Failed to received knowledge chunk from embeddings service, with error: {e}");
+    info!("Deleted {} chunks of document {document_id}", deleted.len());
+    Ok(Json(DeleteDocumentResponse {
+        document_id,
+        deleted,
+        is_success: true,
+        error_message: None,
+    }))
+}
+
+pub async fn documents_mentioning_handler(
+    State(state): State<AppState>,
+    Json(request): Json<DocumentsMentioningRequest>,
+) -> Result<Json<DocumentsMentioningResponse>> {
+    let query =
+        serde_json::to_value(Neo4jQuery::DocumentsMentioning(request.entity)).map_err(|e| {
+            error!("Failed to build JSON from entity, with error: {e}");
             Error::InternalError
-        })
-    {
-        received_tokens += 1;
-        knowledge_graph_chunks.push(knowledge_chunk);
-        if received_tokens >= num_queries {
-            break;
-        }
-    }
+        })?;
+    let data = query_neo4j(&state, query).await?;
+    let documents = serde_json::from_value(data["documents"].clone()).map_err(|e| {
+        error!("Invalid documents from Neo4j service, with error: {e}");
+        Error::InternalError
+    })?;
 
-    Ok(Json(RelatedKnowledgeResponse {
-        knowledge_graph_data: Some(json!({ "knowledge_graph_chunks": knowledge_graph_chunks })),
+    Ok(Json(DocumentsMentioningResponse {
+        documents,
         is_success: true,
         error_message: None,
     }))
 }
 
+pub async fn get_chunk_handler(
+    State(state): State<AppState>,
+    Path(id): Path<ChunkId>,
+) -> Result<Json<ChunkResponse>> {
+    match state.embeddings.chunk(id).await {
+        Ok(chunk) => Ok(Json(ChunkResponse {
+            chunk: Some(chunk),
+            is_success: true,
+            error_message: None,
+        })),
+        Err(e @ EmbeddingsError::NotFound(_)) => Ok(Json(ChunkResponse {
+            chunk: None,
+            is_success: false,
+            error_message: Some(e.to_string()),
+        })),
+        Err(e) => Err(embeddings_error(e)),
+    }
+}
+
+pub async fn get_record_handler(
+    State(state): State<AppState>,
+    Path(hash): Path<ContentHash>,
+) -> Result<Json<ChunkResponse>> {
+    match state.embeddings.chunk_by_hash(hash).await {
+        Ok(chunk) => Ok(Json(ChunkResponse {
+            chunk: Some(chunk),
+            is_success: true,
+            error_message: None,
+        })),
+        Err(e @ EmbeddingsError::HashNotFound(_)) => Ok(Json(ChunkResponse {
+            chunk: None,
+            is_success: false,
+            error_message: Some(e.to_string()),
+        })),
+        Err(e) => Err(embeddings_error(e)),
+    }
+}
+
 pub async fn enhanced_llm_response_handler(
     State(state): State<AppState>,
     Json(request): Json<EnhancedLlmRequest>,
@@ -207,74 +525,43 @@ pub async fn enhanced_llm_response_handler(
     let EnhancedLlmRequest {
         prompt,
         num_queries,
+        metric,
+        min_score,
+        filter,
+        mode,
+        hybrid,
+        mmr_lambda,
         params,
     } = request;
     let num_queries = num_queries.unwrap_or(1);
 
-    let send_string = format!(r#"{{"get_chunk_id":["{}",{}]}}"#, prompt, num_queries);
-    state
-        .embeddings_text_sender
-        .lock()
-        .await
-        .send(send_string)
-        .map_err(|e| {
-            error!("Failed to send chunk to embeddings service, with error: {e}");
-            Error::InternalError
-        })?;
-
-    let mut retrievals = 0;
-    let mut knowledge_chunks = vec![];
-
-    let lock = state.embeddings_indices_receiver.lock().await;
+    let search_params = SearchParams {
+        num_queries,
+        metric: metric.unwrap_or_default(),
+        min_score,
+        filter,
+        mode: mode.unwrap_or_default(),
+        hybrid: hybrid.unwrap_or_default(),
+        mmr_lambda,
+    };
+    let knowledge_chunks: Vec<ChunkId> = search_embeddings(&state, prompt.clone(), search_params)
+        .await?
+        .into_iter()
+        .map(|r| r.id)
+        .collect();
+    info!("Received knowledge chunks: {:?}", knowledge_chunks);
 
-    while let Ok(knowledge_chunk) = lock.recv().map_err(|e| {
-        error!("Failed to received knowledge chunk from embeddings service, with error: {e}");
+    let query = serde_json::to_value(Neo4jQuery::RetrieveChunks(
+        knowledge_chunks.iter().map(ChunkId::to_string).collect(),
+    ))
+    .map_err(|e| {
+        error!("Failed to build JSON from chunk ids, with error: {e}");
         Error::InternalError
-    }) {
-        info!("Received new knowledge_chunk: {}", knowledge_chunk);
-        knowledge_chunks.push(knowledge_chunk);
-        retrievals += 1;
-        if retrievals >= num_queries {
-            break;
-        }
-    }
-
-    state
-        .tx_neo4j
-        .send(
-            serde_json::from_str::<serde_json::Value>(&format!(
-                r#"{{"retrieve":{:?}}}"#,
-                knowledge_chunks
-            ))
-            .map_err(|e| {
-                error!("Failed to deserialize value, with error: {e}");
-                Error::InternalError
-            })?,
-        )
-        .await
-        .map_err(|e| {
-            error!("Failed to send query to Neo4J database, with error: {}", e);
-            Error::InternalError
-        })?;
-
-    let mut retrievals = 0;
-    let mut knowledge_graph_triplets = vec![];
-
-    while let Some(value) = state.rx_neo4j_relations.lock().await.recv().await {
-        let head = value["head"].to_string();
-        let tail = value["tail"].to_string();
-        let relation = value["relation"].to_string();
-        info!(
-            "Got new head = {}, tail = {}, relation = {}",
-            head, tail, relation
-        );
-        let triplet = format!("{} | {} | {}", head, relation, tail);
-        knowledge_graph_triplets.push(triplet);
-        retrievals += 1;
-        if retrievals >= num_queries {
-            break;
-        }
-    }
+    })?;
+    // The chunks' knowledge graph comes back whole, as `{"entities": [..], "relations": [..]}`.
+    let knowledge_graph = query_neo4j(&state, query).await?;
+    let knowledge_graph_triplets = relation_triplets(&knowledge_graph);
+    info!("Got knowledge graph triplets: {knowledge_graph_triplets:?}");
 
     let output_prompt = generate_answer(&prompt, knowledge_graph_triplets);
     let open_ai_request = OpenAiRequest {
@@ -295,3 +582,48 @@ pub async fn enhanced_llm_response_handler(
         error_message: None,
     }))
 }
+
+/// Sends `query` to the Neo4j service, and waits for its reply, failing if the query did.
+async fn query_neo4j(state: &AppState, query: Value) -> Result<Value> {
+    let (request, reply) = Neo4jRequest::with_reply(query);
+    state.tx_neo4j.send(request).await.map_err(|e| {
+        error!("Failed to send query to Neo4J service, with error: {e}");
+        Error::InternalError
+    })?;
+    reply
+        .await
+        .map_err(|_| {
+            error!("Failed to receive a response from Neo4j service");
+            Error::InternalError
+        })?
+        .map_err(|e| {
+            error!("Neo4j query failed, with error: {e}");
+            Error::InternalError
+        })
+}
+
+/// Searches the embeddings service for the chunks closest to `text`.
+async fn search_embeddings(
+    state: &AppState,
+    text: String,
+    params: SearchParams,
+) -> Result<Vec<SearchResult>> {
+    state
+        .embeddings
+        .search(text, params)
+        .await
+        .map_err(embeddings_error)
+}
+
+/// Fetches the text of the chunks stored under `ids`, skipping those stored without it.
+async fn fetch_chunks(state: &AppState, ids: Vec<ChunkId>) -> Result<Vec<StoredChunk>> {
+    state.embeddings.chunks(ids).await.map_err(embeddings_error)
+}
+
+fn embeddings_error(e: EmbeddingsError) -> Error {
+    error!("Embeddings service request failed, with error: {e}");
+    match e {
+        EmbeddingsError::Timeout(_) => Error::ServiceTimeout,
+        _ => Error::InternalError,
+    }
+}
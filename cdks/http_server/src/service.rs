@@ -1,19 +1,15 @@
 use axum::Server;
-use embeddings::embeddings::DEFAULT_MODEL_EMBEDDING_SIZE;
-use serde_json::Value;
-use std::sync::mpsc;
-use tokio::sync::mpsc::{Receiver, Sender};
+use embeddings::client::EmbeddingsClient;
+use neo4j::neo4j_service::Neo4jRequest;
+use tokio::sync::mpsc::Sender;
 
 use crate::{app::routes, client::OpenAiClient, config::Config, error::Error};
 use log::{error, info};
 
-pub async fn run_service<'a>(
-    tx_neo4j: Sender<Value>,
-    rx_neo4j_relations: Receiver<Value>,
+pub async fn run_service(
+    tx_neo4j: Sender<Neo4jRequest>,
     client: OpenAiClient,
-    embeddings_receiver: mpsc::Receiver<[f32; DEFAULT_MODEL_EMBEDDING_SIZE]>,
-    embeddings_text_sender: mpsc::Sender<String>,
-    embeddings_index_receiver: mpsc::Receiver<u32>,
+    embeddings: EmbeddingsClient,
     config: Config,
 ) -> Result<(), anyhow::Error> {
     let mut bind = true;
@@ -28,11 +24,12 @@ pub async fn run_service<'a>(
     let server = server.serve(
         routes(
             tx_neo4j,
-            rx_neo4j_relations,
             client,
-            embeddings_receiver,
-            embeddings_text_sender,
-            embeddings_index_receiver,
+            embeddings,
+            config.dedup,
+            config.chunker,
+            config.pending_timeout,
+            config.llm_tokens,
         )
         .into_make_service(),
     );
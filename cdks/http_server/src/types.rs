@@ -1,8 +1,17 @@
+use embeddings::{
+    chunker::{ChunkerConfig, Section},
+    dedup::{ContentHash, DuplicateMatch},
+    id::ChunkId,
+    index::StoredChunk,
+    metadata::{ChunkMetadata, IngestionStatus, MetadataFilter},
+    search::{DocumentResults, HybridParams, Metric, SearchMode},
+};
 use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct ProcessChunkRequest {
     pub(crate) chunk: String,
+    pub(crate) metadata: Option<ChunkMetadata>,
     #[serde(flatten)]
     pub(crate) params: OpenAiModelParams,
 }
@@ -10,13 +19,56 @@ pub struct ProcessChunkRequest {
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct ProcessChunkResponse {
     pub(crate) is_success: bool,
-    pub(crate) hash: [u8; 32],
+    /// Id the chunk was stored under, in the embeddings service and in Neo4j.
+    pub(crate) chunk_id: Option<ChunkId>,
+    /// Digest of the chunk and of its knowledge graph, once extracted. Posting the chunk again
+    /// returns the same one, and `/record/:hash` looks the chunk up by it.
+    pub(crate) hash: Option<ContentHash>,
+    pub(crate) status: Option<IngestionStatus>,
+    pub(crate) error_message: Option<String>,
+    /// Stored chunk that the chunk duplicates, in which case no knowledge graph was extracted.
+    pub(crate) duplicate_of: Option<DuplicateMatch>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ProcessDocumentRequest {
+    pub(crate) document: String,
+    /// Metadata of every chunk of the document. Its `document_id` defaults to the content hash
+    /// of the document.
+    pub(crate) metadata: Option<ChunkMetadata>,
+    /// Token budgets of the chunks, the server ones if not set.
+    pub(crate) chunker: Option<ChunkerConfig>,
+    #[serde(flatten)]
+    pub(crate) params: OpenAiModelParams,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DocumentChunkResponse {
+    pub(crate) index: usize,
+    pub(crate) section: usize,
+    /// Byte offsets of the chunk in the document.
+    pub(crate) start: usize,
+    pub(crate) end: usize,
+    pub(crate) tokens: usize,
+    #[serde(flatten)]
+    pub(crate) response: ProcessChunkResponse,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ProcessDocumentResponse {
+    pub(crate) document_id: String,
+    pub(crate) sections: Vec<Section>,
+    pub(crate) chunks: Vec<DocumentChunkResponse>,
+    pub(crate) is_success: bool,
     pub(crate) error_message: Option<String>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct RetrieveKnowledgeRequest {
-    pub(crate) node_indices: Vec<usize>,
+    #[serde(alias = "node_indices")]
+    pub(crate) chunk_ids: Vec<ChunkId>,
+    /// Whether to return the text of the chunks as well.
+    pub(crate) include_text: Option<bool>,
     #[serde(flatten)]
     pub(crate) params: OpenAiModelParams,
 }
@@ -24,6 +76,7 @@ pub struct RetrieveKnowledgeRequest {
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct RetrieveKnowledgeResponse {
     pub(crate) knowledge_graph_data: Option<serde_json::Value>,
+    pub(crate) chunks: Option<Vec<StoredChunk>>,
     pub(crate) is_success: bool,
     pub(crate) error_message: Option<String>,
 }
@@ -32,11 +85,60 @@ pub struct RetrieveKnowledgeResponse {
 pub struct RelatedKnowledgeRequest {
     pub(crate) chunk: String,
     pub(crate) num_queries: Option<u32>,
+    pub(crate) metric: Option<Metric>,
+    pub(crate) min_score: Option<f32>,
+    pub(crate) filter: Option<MetadataFilter>,
+    pub(crate) mode: Option<SearchMode>,
+    pub(crate) hybrid: Option<HybridParams>,
+    pub(crate) mmr_lambda: Option<f32>,
+    /// Whether to return the text of the related chunks as well.
+    pub(crate) include_text: Option<bool>,
+    /// Whether to return the related chunks grouped by document as well.
+    pub(crate) group_by_document: Option<bool>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct RelatedKnowledgeResponse {
     pub(crate) knowledge_graph_data: Option<serde_json::Value>,
+    pub(crate) chunks: Option<Vec<StoredChunk>>,
+    pub(crate) documents: Option<Vec<DocumentResults>>,
+    pub(crate) is_success: bool,
+    pub(crate) error_message: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DocumentResponse {
+    pub(crate) document_id: String,
+    /// Chunks of the document, in document order.
+    pub(crate) chunks: Vec<StoredChunk>,
+    pub(crate) is_success: bool,
+    pub(crate) error_message: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DeleteDocumentResponse {
+    pub(crate) document_id: String,
+    /// Ids of the deleted chunks.
+    pub(crate) deleted: Vec<ChunkId>,
+    pub(crate) is_success: bool,
+    pub(crate) error_message: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DocumentsMentioningRequest {
+    pub(crate) entity: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DocumentsMentioningResponse {
+    pub(crate) documents: Vec<String>,
+    pub(crate) is_success: bool,
+    pub(crate) error_message: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ChunkResponse {
+    pub(crate) chunk: Option<StoredChunk>,
     pub(crate) is_success: bool,
     pub(crate) error_message: Option<String>,
 }
@@ -45,6 +147,12 @@ pub struct RelatedKnowledgeResponse {
 pub struct EnhancedLlmRequest {
     pub(crate) prompt: String,
     pub(crate) num_queries: Option<u32>,
+    pub(crate) metric: Option<Metric>,
+    pub(crate) min_score: Option<f32>,
+    pub(crate) filter: Option<MetadataFilter>,
+    pub(crate) mode: Option<SearchMode>,
+    pub(crate) hybrid: Option<HybridParams>,
+    pub(crate) mmr_lambda: Option<f32>,
     #[serde(flatten)]
     pub(crate) params: OpenAiModelParams,
 }
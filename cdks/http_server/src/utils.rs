@@ -1,7 +1,7 @@
 use anyhow::anyhow;
 use log::{error, info};
 use neo4j::graph::KnowledgeGraph;
-use neo4j::neo4j_builder::Neo4jQuery;
+use neo4j::neo4j_builder::{ChunkNode, Neo4jQuery};
 use serde_json::Value;
 
 pub(crate) fn retrieve_prompt(chunk: &str) -> String {
@@ -29,7 +29,30 @@ pub(crate) fn generate_answer(question: &str, knowledge_graph: Vec<String>) -> S
     prompt
 }
 
-pub(crate) fn kg_to_query_json(kg: &str, id: u32) -> anyhow::Result<Value> {
+/// `head | relation | tail` triplets of the relations of a retrieved knowledge graph.
+pub(crate) fn relation_triplets(knowledge_graph: &Value) -> Vec<String> {
+    let field =
+        |relation: &Value, name: &str| relation[name].as_str().unwrap_or_default().to_string();
+    knowledge_graph["relations"]
+        .as_array()
+        .map(|relations| {
+            relations
+                .iter()
+                .map(|relation| {
+                    format!(
+                        "{} | {} | {}",
+                        field(relation, "head"),
+                        field(relation, "relation"),
+                        field(relation, "tail")
+                    )
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Builds the query storing the knowledge graph `kg`, extracted from `chunk`.
+pub(crate) fn kg_to_query_json(kg: &str, chunk: ChunkNode) -> anyhow::Result<Value> {
     let kg_str = unescape_json(kg);
     info!("KNOWLEDGE GRAPH: {}", kg);
     let graph = serde_json::from_str::<KnowledgeGraph>(&kg_str).map_err(|e| {
@@ -45,7 +68,9 @@ pub(crate) fn kg_to_query_json(kg: &str, id: u32) -> anyhow::Result<Value> {
 
     info!("Retrieved Knowledge Graph: {:?}", graph);
 
-    let query_builder = graph.to_cypher_query_builder(&[("query_id", format!("{}", id).as_str())]);
+    let query_builder = graph
+        .to_cypher_query_builder(&[("query_id", chunk.chunk_id.as_str())])
+        .chunk(chunk);
     serde_json::to_value(Neo4jQuery::Builder(query_builder))
         .map_err(|e| anyhow!("Failed to convert to query builder, with error: {e}"))
 }
@@ -60,9 +85,47 @@ fn unescape_json(s: &str) -> String {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
 
     #[test]
     fn test_kg_to_query_json() {
-        todo!()
+        let kg = r#"{{\"entities\":[\"alice\",\"madrid\"],\"relations\":[{{\"head\":\"alice\",\"tail\":\"madrid\",\"relation\":\"livesIn\"}}]}}"#;
+        let chunk = ChunkNode {
+            chunk_id: "7".to_string(),
+            document: None,
+        };
+        let query = kg_to_query_json(kg, chunk.clone()).unwrap();
+        let Neo4jQuery::Builder(query_builder) = serde_json::from_value(query).unwrap() else {
+            panic!("Expected a builder query");
+        };
+        let (query, params) = query_builder.build().unwrap();
+        assert!(query.contains("CREATE (n0:alice { query_id:$param_0 })"));
+        assert!(query.contains("CREATE (n0)-[:livesIn]->(n1)"));
+        assert!(query.contains("MERGE (c:Chunk { chunk_id:$param_2 })"));
+        assert_eq!(
+            params
+                .iter()
+                .map(|(_, value)| value.as_str())
+                .collect::<Vec<_>>(),
+            vec!["7", "7", "7"]
+        );
+
+        assert!(kg_to_query_json("not a graph", chunk).is_err());
+    }
+
+    #[test]
+    fn test_relation_triplets() {
+        let knowledge_graph = serde_json::json!({
+            "entities": ["alice", "madrid", "spain"],
+            "relations": [
+                { "head": "alice", "tail": "madrid", "relation": "livesIn" },
+                { "head": "madrid", "tail": "spain", "relation": "capitalOf" },
+            ]
+        });
+        assert_eq!(
+            relation_triplets(&knowledge_graph),
+            vec!["alice | livesIn | madrid", "madrid | capitalOf | spain"]
+        );
+        assert!(relation_triplets(&serde_json::json!({})).is_empty());
     }
 }
@@ -97,11 +97,17 @@ impl<'a, 'b> KnowledgeGraph<'a, 'b> {
         for entity in &self.entities {
             query_builder = query_builder.create_node(entity.0, properties);
         }
+        let position = |entity: &Entity| {
+            self.entities
+                .iter()
+                .position(|e| e == entity)
+                .expect("Relations integrity have been verified already")
+        };
         for relation in &self.relations {
             query_builder = query_builder
                 .add_edge(
-                    &relation.head.0.to_string(),
-                    &relation.tail.0.to_string(),
+                    position(&relation.head),
+                    position(&relation.tail),
                     relation.relation,
                 )
                 .expect("Relations integrity have been verified already");
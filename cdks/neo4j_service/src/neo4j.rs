@@ -1,6 +1,6 @@
 use anyhow::anyhow;
 use log::{error, info};
-use neo4rs::{query, Config, Graph, Node, Relation};
+use neo4rs::{query, Config, Graph, Node, Query, Relation};
 use serde_json::{json, Value};
 use std::sync::Arc;
 
@@ -11,12 +11,11 @@ pub struct Neo4jConnection {
 impl Neo4jConnection {
     pub async fn new(config: Config) -> Result<Self, anyhow::Error> {
         Ok(Self {
-            graph: Arc::new(Graph::connect(config).await.map_err(|e| {
-                anyhow!(
-                    "Failed to start database connection, with error: {}",
-                    e.to_string()
-                )
-            })?),
+            graph: Arc::new(
+                Graph::connect(config).await.map_err(|e| {
+                    anyhow!("Failed to start database connection, with error: {}", e)
+                })?,
+            ),
         })
     }
 
@@ -27,10 +26,7 @@ impl Neo4jConnection {
     ) -> Result<(), anyhow::Error> {
         let tx = self.graph.start_txn().await.map_err(|e| {
             error!("Failed to start a new transaction, with error: {}", e);
-            anyhow!(
-                "Failed to start a new transaction, with error: {}",
-                e.to_string()
-            )
+            anyhow!("Failed to start a new transaction, with error: {}", e)
         })?;
 
         info!("Running query...");
@@ -55,17 +51,37 @@ impl Neo4jConnection {
             node_ids
         );
 
+        self.retrieve_relations(&cypher_query, query(&cypher_query))
+            .await
+    }
+
+    /// Retrieves the relations of the entities extracted from the chunks stored under
+    /// `chunk_ids`, which the entities hold as their `query_id` property.
+    pub async fn retrieve_chunks(&self, chunk_ids: Vec<String>) -> Result<Value, anyhow::Error> {
+        let cypher_query = "MATCH (n) WHERE n.query_id IN $chunk_ids \
+                MATCH (n) -[r] -> (m) \
+                RETURN n, r, m";
+
+        self.retrieve_relations(
+            cypher_query,
+            query(cypher_query).param("chunk_ids", chunk_ids),
+        )
+        .await
+    }
+
+    async fn retrieve_relations(
+        &self,
+        cypher_query: &str,
+        q: Query,
+    ) -> Result<Value, anyhow::Error> {
         let tx = self.graph.start_txn().await.map_err(|e| {
             error!("Failed to start a new transaction, with error: {}", e);
-            anyhow!(
-                "Failed to start a new transaction, with error: {}",
-                e.to_string()
-            )
+            anyhow!("Failed to start a new transaction, with error: {}", e)
         })?;
 
         info!("Running query...");
 
-        let mut row_stream = tx.execute(query(&cypher_query)).await.map_err(|e| {
+        let mut row_stream = tx.execute(q).await.map_err(|e| {
             error!("Failed to execute query {cypher_query}, with error: {e}");
             anyhow!("Failed to execute query {cypher_query}, with error: {e}")
         })?;
@@ -96,4 +112,45 @@ impl Neo4jConnection {
 
         Ok(json!({"entities": entities, "relations": relations}))
     }
+
+    pub async fn delete_document(&self, document_id: String) -> Result<(), anyhow::Error> {
+        // Entities are created for every chunk, so that they go along with it.
+        self.execute(
+            "MATCH (d:Document { document_id:$document_id }) \
+                OPTIONAL MATCH (d)-[:HAS_SECTION]->(s:Section) \
+                OPTIONAL MATCH (s)-[:HAS_CHUNK]->(c:Chunk) \
+                OPTIONAL MATCH (c)-[:MENTIONS]->(e) \
+                DETACH DELETE d, s, c, e",
+            vec![("document_id".to_string(), document_id)],
+        )
+        .await
+    }
+
+    pub async fn documents_mentioning(&self, entity: String) -> Result<Value, anyhow::Error> {
+        let cypher_query = "MATCH (d:Document)-[:HAS_SECTION]->(:Section)-[:HAS_CHUNK]->(:Chunk)-[:MENTIONS]->(e) \
+                WHERE $entity IN labels(e) \
+                RETURN DISTINCT d.document_id AS document_id";
+
+        let tx = self.graph.start_txn().await.map_err(|e| {
+            error!("Failed to start a new transaction, with error: {}", e);
+            anyhow!("Failed to start a new transaction, with error: {}", e)
+        })?;
+
+        info!("Running query...");
+
+        let mut row_stream = tx
+            .execute(query(cypher_query).param("entity", entity))
+            .await
+            .map_err(|e| {
+                error!("Failed to execute query {cypher_query}, with error: {e}");
+                anyhow!("Failed to execute query {cypher_query}, with error: {e}")
+            })?;
+
+        let mut documents = vec![];
+        while let Some(row) = row_stream.next().await? {
+            documents.extend(row.get::<String>("document_id"));
+        }
+
+        Ok(json!({ "documents": documents }))
+    }
 }
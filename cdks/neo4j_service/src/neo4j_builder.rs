@@ -8,6 +8,13 @@ pub type Labels = Vec<usize>;
 pub enum Neo4jQuery {
     Builder(Neo4jQueryBuilder),
     Retrieve(Labels),
+    /// Retrieves the relations of the entities extracted from chunks, by chunk id.
+    RetrieveChunks(Vec<String>),
+    /// Deletes a document, along with its sections, chunks and the entities they mention,
+    /// replying with the document id once deleted.
+    DeleteDocument(String),
+    /// Retrieves the ids of the documents with a chunk mentioning an entity.
+    DocumentsMentioning(String),
 }
 
 #[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
@@ -18,17 +25,42 @@ pub struct Node {
 
 #[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct Edge {
-    source: String,
-    target: String,
+    /// Position of the source node among the created nodes.
+    source: usize,
+    /// Position of the target node among the created nodes.
+    target: usize,
     edge_relation: String,
 }
 
+/// Chunk the nodes of a query were extracted from, linked to every one of them.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ChunkNode {
+    pub chunk_id: String,
+    /// Document the chunk was split from, if any.
+    pub document: Option<DocumentPosition>,
+}
+
+/// Position of a chunk in its document, which parents its section, which parents the chunk.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct DocumentPosition {
+    pub document_id: String,
+    pub section: u32,
+    pub section_title: Option<String>,
+    /// Position of the chunk among the chunks of the document.
+    pub index: u32,
+    /// Byte offsets of the chunk in the document.
+    pub start: u64,
+    pub end: u64,
+}
+
 #[derive(Debug, Default, Clone, Deserialize, Serialize)]
 pub struct Neo4jQueryBuilder {
     nodes: Vec<Node>,
     edges: Vec<Edge>,
     return_fields: Vec<String>,
     limit: Option<usize>,
+    #[serde(default)]
+    chunk: Option<ChunkNode>,
 }
 
 impl Neo4jQueryBuilder {
@@ -38,6 +70,7 @@ impl Neo4jQueryBuilder {
             edges: vec![],
             return_fields: vec![],
             limit: None,
+            chunk: None,
         }
     }
 
@@ -53,26 +86,27 @@ impl Neo4jQueryBuilder {
         self
     }
 
+    /// Links the `source`-th and the `target`-th created nodes, so that nodes sharing a label
+    /// are told apart.
     pub fn add_edge(
         mut self,
-        source: &String,
-        target: &String,
+        source: usize,
+        target: usize,
         relation: &str,
     ) -> Result<Self, anyhow::Error> {
-        let labels = self.nodes.iter().map(|s| &s.label).collect::<Vec<_>>();
-        if !labels.contains(&source) {
+        if source >= self.nodes.len() {
             return Err(anyhow!(
                 "Edge source is not stored as a Node, please add it first."
             ));
         }
-        if !labels.contains(&target) {
+        if target >= self.nodes.len() {
             return Err(anyhow!(
-                "Edge source is not stored as a Node, please add it first."
+                "Edge target is not stored as a Node, please add it first."
             ));
         }
         let edge = Edge {
-            source: source.clone(),
-            target: target.clone(),
+            source,
+            target,
             edge_relation: relation.to_string(),
         };
         self.edges.push(edge);
@@ -88,10 +122,19 @@ impl Neo4jQueryBuilder {
         self.limit = Some(limit);
         self
     }
+
+    /// Links the created nodes to the `Chunk` node they were extracted from, which is merged
+    /// into its `Document` and `Section` nodes.
+    pub fn chunk(mut self, chunk: ChunkNode) -> Self {
+        self.chunk = Some(chunk);
+        self
+    }
 }
 
 impl Neo4jQueryBuilder {
-    pub fn build(&self) -> (String, Vec<(String, String)>) {
+    /// Builds the Cypher query and its parameters, failing on an edge between nodes which
+    /// are not created, as a deserialized builder may hold.
+    pub fn build(&self) -> Result<(String, Vec<(String, String)>), anyhow::Error> {
         let mut query = String::new();
         let mut params = vec![];
 
@@ -117,38 +160,59 @@ impl Neo4jQueryBuilder {
         }
 
         if !self.edges.is_empty() {
-            let mut with_clause = "WITH ".to_string();
-            (0..self.nodes.len() - 1).for_each(|i| with_clause.push_str(&format!("n{}, ", i)));
-            with_clause.push_str(&format!("n{} ", self.nodes.len() - 1));
             for edge in &self.edges {
-                let source_index = self
-                    .nodes
-                    .iter()
-                    .enumerate()
-                    .find(|(_, x)| x.label == edge.source)
-                    .unwrap()
-                    .0;
-                let target_index = self
-                    .nodes
-                    .iter()
-                    .enumerate()
-                    .find(|(_, x)| x.label == edge.target)
-                    .unwrap()
-                    .0;
-                query.push_str(&with_clause);
+                if edge.source >= self.nodes.len() || edge.target >= self.nodes.len() {
+                    return Err(anyhow!(
+                        "Edge {} links nodes {} and {}, of only {} created",
+                        edge.edge_relation,
+                        edge.source,
+                        edge.target,
+                        self.nodes.len()
+                    ));
+                }
+                // Created nodes are still bound, and matching them by label would link every
+                // node of the graph sharing it.
                 query.push_str(&format!(
-                    "MATCH (n{}:{}), (n{}:{}) CREATE (n{})-[:{}]->(n{})\n",
-                    source_index,
-                    edge.source,
-                    target_index,
-                    edge.target,
-                    source_index,
-                    edge.edge_relation,
-                    target_index
+                    "CREATE (n{})-[:{}]->(n{})\n",
+                    edge.source, edge.edge_relation, edge.target
                 ));
             }
         }
 
+        if let Some(chunk) = &self.chunk {
+            let mut param = |value: String| {
+                let param_name = format!("param_{}", params.len());
+                params.push((param_name.clone(), value));
+                format!("${param_name}")
+            };
+            query.push_str(&format!(
+                "MERGE (c:Chunk {{ chunk_id:{} }})\n",
+                param(chunk.chunk_id.clone())
+            ));
+            if let Some(document) = &chunk.document {
+                let document_id = param(document.document_id.clone());
+                query.push_str(&format!(
+                    "SET c.document_id = {document_id}, c.index = {}, c.start = {}, c.end = {}\n",
+                    param(document.index.to_string()),
+                    param(document.start.to_string()),
+                    param(document.end.to_string())
+                ));
+                query.push_str(&format!(
+                    "MERGE (d:Document {{ document_id:{document_id} }})\n"
+                ));
+                query.push_str(&format!(
+                    "MERGE (s:Section {{ document_id:{document_id}, index:{} }})\n",
+                    param(document.section.to_string())
+                ));
+                if let Some(title) = &document.section_title {
+                    query.push_str(&format!("SET s.title = {}\n", param(title.clone())));
+                }
+                query.push_str("MERGE (d)-[:HAS_SECTION]->(s)\nMERGE (s)-[:HAS_CHUNK]->(c)\n");
+            }
+            (0..self.nodes.len())
+                .for_each(|i| query.push_str(&format!("CREATE (c)-[:MENTIONS]->(n{i})\n")));
+        }
+
         if !self.return_fields.is_empty() {
             let fields: Vec<String> = self
                 .return_fields
@@ -162,7 +226,7 @@ impl Neo4jQueryBuilder {
             query.push_str(&format!(" LIMIT {}\n", limit));
         }
 
-        (query, params)
+        Ok((query, params))
     }
 }
 
@@ -175,11 +239,11 @@ mod tests {
         let query_builder = Neo4jQueryBuilder::new()
             .create_node("Person", &[("name", "Alice"), ("age", "30")])
             .create_node("House", &[("city", "Madrid"), ("type", "apartment")])
-            .add_edge(&"Person".to_string(), &"House".to_string(), "OWNS")
+            .add_edge(0, 1, "OWNS")
             .expect("Failed to add edge");
 
-        let (query, params) = query_builder.build();
-        assert_eq!(query, "CREATE (n0:Person { name:$param_0, age:$param_1 })\nCREATE (n1:House { city:$param_2, type:$param_3 })\nMATCH (a:Person), (b:House) CREATE (a)-[:OWNS]->(b)\n");
+        let (query, params) = query_builder.build().unwrap();
+        assert_eq!(query, "CREATE (n0:Person { name:$param_0, age:$param_1 })\nCREATE (n1:House { city:$param_2, type:$param_3 })\nCREATE (n0)-[:OWNS]->(n1)\n");
         assert_eq!(
             params,
             vec![
@@ -196,13 +260,13 @@ mod tests {
         let query_builder = Neo4jQueryBuilder::new()
             .create_node("Person", &[("name", "Alice"), ("age", "30")])
             .create_node("Person", &[("name", "Bob"), ("age", "25")])
-            .add_edge(&"Person".to_string(), &"Person".to_string(), "KNOWS")
+            .add_edge(0, 1, "KNOWS")
             .expect("Failed to add edge")
             .return_fields(&["a.name", "b.name"])
             .limit(10);
 
-        let (query, params) = query_builder.build();
-        assert_eq!(query, "CREATE (n0:Person { name:$param_0, age:$param_1 })\nCREATE (n1:Person { name:$param_2, age:$param_3 })\nMATCH (a:Person), (b:Person) CREATE (a)-[:KNOWS]->(b)\n RETURN n.a.name, n.b.name\n LIMIT 10\n");
+        let (query, params) = query_builder.build().unwrap();
+        assert_eq!(query, "CREATE (n0:Person { name:$param_0, age:$param_1 })\nCREATE (n1:Person { name:$param_2, age:$param_3 })\nCREATE (n0)-[:KNOWS]->(n1)\n RETURN n.a.name, n.b.name\n LIMIT 10\n");
         assert_eq!(
             params,
             vec![
@@ -214,6 +278,56 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_edge_to_unknown_node() {
+        let query_builder = Neo4jQueryBuilder::new().create_node("Person", &[("name", "Alice")]);
+        assert!(query_builder.clone().add_edge(0, 1, "KNOWS").is_err());
+
+        let query_builder: Neo4jQueryBuilder = serde_json::from_value(serde_json::json!({
+            "nodes": [{ "label": "Person", "properties": [] }],
+            "edges": [{ "source": 0, "target": 1, "edge_relation": "KNOWS" }],
+            "return_fields": [],
+            "limit": null,
+        }))
+        .unwrap();
+        assert!(query_builder.build().is_err());
+    }
+
+    #[test]
+    fn test_build_query_in_chunk() {
+        let query_builder = Neo4jQueryBuilder::new()
+            .create_node("Alice", &[])
+            .create_node("Madrid", &[])
+            .add_edge(0, 1, "livesIn")
+            .expect("Failed to add edge")
+            .chunk(ChunkNode {
+                chunk_id: "7".to_string(),
+                document: Some(DocumentPosition {
+                    document_id: "doc-1".to_string(),
+                    section: 1,
+                    section_title: Some("Home".to_string()),
+                    index: 3,
+                    start: 120,
+                    end: 180,
+                }),
+            });
+
+        let (query, params) = query_builder.build().unwrap();
+        assert_eq!(
+            query,
+            "CREATE (n0:Alice)\nCREATE (n1:Madrid)\nCREATE (n0)-[:livesIn]->(n1)\n\
+            MERGE (c:Chunk { chunk_id:$param_0 })\n\
+            SET c.document_id = $param_1, c.index = $param_2, c.start = $param_3, c.end = $param_4\n\
+            MERGE (d:Document { document_id:$param_1 })\n\
+            MERGE (s:Section { document_id:$param_1, index:$param_5 })\n\
+            SET s.title = $param_6\n\
+            MERGE (d)-[:HAS_SECTION]->(s)\nMERGE (s)-[:HAS_CHUNK]->(c)\n\
+            CREATE (c)-[:MENTIONS]->(n0)\nCREATE (c)-[:MENTIONS]->(n1)\n"
+        );
+        let values: Vec<_> = params.iter().map(|(_, value)| value.as_str()).collect();
+        assert_eq!(values, vec!["7", "doc-1", "3", "120", "180", "1", "Home"]);
+    }
+
     #[test]
     fn test_deserialize() {
         let query_builder = Neo4jQueryBuilder::new()
@@ -233,5 +347,10 @@ mod tests {
             serde_json::to_string(&neo4j_query).expect("Failed to deserialize"),
             r#"{"retrieve":[0,1,2]}"#
         );
+        let neo4j_query = Neo4jQuery::RetrieveChunks(vec!["chunk-0".to_string()]);
+        assert_eq!(
+            serde_json::to_string(&neo4j_query).expect("Failed to deserialize"),
+            r#"{"retrieve_chunks":["chunk-0"]}"#
+        );
     }
 }
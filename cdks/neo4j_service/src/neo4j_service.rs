@@ -1,35 +1,69 @@
 use std::sync::Arc;
 
 use anyhow::anyhow;
-use log::{error, info};
+use log::{error, info, warn};
 use serde_json::Value;
 use tokio::{
-    sync::{
-        mpsc::{Receiver, Sender},
-        RwLock,
-    },
+    sync::{mpsc::Receiver, oneshot, RwLock},
     task::JoinHandle,
 };
 
 use crate::{neo4j::Neo4jConnection, neo4j_builder::Neo4jQuery};
 
+/// Reply to a [`Neo4jRequest`]: the data its query returned, or the error it failed with.
+pub type Neo4jReply = Result<Value, anyhow::Error>;
+
+/// A JSON encoded [`Neo4jQuery`], along with the sender its reply goes to, for queries which
+/// have one.
+#[derive(Debug)]
+pub struct Neo4jRequest {
+    pub query: Value,
+    pub reply: Option<oneshot::Sender<Neo4jReply>>,
+}
+
+impl Neo4jRequest {
+    /// A request whose query has no reply, e.g. one storing a knowledge graph.
+    pub fn new(query: Value) -> Self {
+        Self { query, reply: None }
+    }
+
+    /// A request whose query replies with data, or its error, along with the receiver of the
+    /// reply.
+    pub fn with_reply(query: Value) -> (Self, oneshot::Receiver<Neo4jReply>) {
+        let (reply, reply_receiver) = oneshot::channel();
+        (
+            Self {
+                query,
+                reply: Some(reply),
+            },
+            reply_receiver,
+        )
+    }
+
+    /// Sends `reply` to the requester, unless it stopped waiting for it.
+    fn reply(self, reply: Neo4jReply) {
+        if let Some(sender) = self.reply {
+            if sender.send(reply).is_err() {
+                warn!("Dropping a reply whose requester is gone");
+            }
+        }
+    }
+}
+
 pub struct Neo4jService {
-    rx_query: Receiver<Value>,
-    tx_relations: Sender<Value>,
+    rx_query: Receiver<Neo4jRequest>,
     connection: Arc<RwLock<Neo4jConnection>>,
 }
 
 impl Neo4jService {
     pub async fn spawn(
-        rx_query: Receiver<Value>,
-        tx_relations: Sender<Value>,
+        rx_query: Receiver<Neo4jRequest>,
         connection: Arc<RwLock<Neo4jConnection>>,
     ) -> JoinHandle<Result<(), anyhow::Error>> {
         tokio::spawn(async move {
             info!("Starting Neo4jService...");
             Self {
                 rx_query,
-                tx_relations,
                 connection,
             }
             .run()
@@ -37,42 +71,77 @@ impl Neo4jService {
         })
     }
 
+    /// Serves queries until every sender is dropped. A failing query is logged and answered
+    /// with its error, without ending the service.
     pub async fn run(&mut self) -> Result<(), anyhow::Error> {
-        while let Some(query_value) = self.rx_query.recv().await {
-            info!("Received a new query: {query_value}");
-
-            let query = serde_json::from_value::<Neo4jQuery>(query_value)
-                .map_err(|e| anyhow!("Failed to deserialized received value, with error: {e}"))?;
-
-            match query {
-                Neo4jQuery::Builder(query_builder) => {
-                    let (query, params) = query_builder.build();
-
-                    info!("Executing query...");
-
-                    self.connection
-                        .write()
-                        .await
-                        .execute(&query, params)
-                        .await?;
-                }
-                Neo4jQuery::Retrieve(node_ids) => {
-                    info!("Executing query...");
-
-                    let output_kg = self
-                        .connection
-                        .write()
-                        .await
-                        .retrieve_on_match(node_ids)
-                        .await?;
-
-                    self.tx_relations.send(output_kg).await.map_err(|e| {
-                        error!("Failed to send new JSON relation, with error: {e}");
-                        anyhow!("Failed to send new JSON relation, with error: {e}")
-                    })?;
-                }
+        while let Some(request) = self.rx_query.recv().await {
+            info!("Received a new query: {}", request.query);
+
+            let reply = self.handle(request.query.clone()).await;
+            if let Err(e) = &reply {
+                error!("Failed to handle Neo4j query, with error: {e}");
             }
+            request.reply(reply);
         }
         Ok(())
     }
+
+    async fn handle(&self, query_value: Value) -> Result<Value, anyhow::Error> {
+        let query = serde_json::from_value::<Neo4jQuery>(query_value)
+            .map_err(|e| anyhow!("Failed to deserialized received value, with error: {e}"))?;
+
+        match query {
+            Neo4jQuery::Builder(query_builder) => {
+                let (query, params) = query_builder.build()?;
+
+                info!("Executing query...");
+
+                self.connection
+                    .write()
+                    .await
+                    .execute(&query, params)
+                    .await?;
+
+                Ok(Value::Null)
+            }
+            Neo4jQuery::DeleteDocument(document_id) => {
+                info!("Deleting document {document_id}...");
+
+                self.connection
+                    .write()
+                    .await
+                    .delete_document(document_id.clone())
+                    .await?;
+
+                Ok(Value::String(document_id))
+            }
+            Neo4jQuery::DocumentsMentioning(entity) => {
+                info!("Executing query...");
+
+                self.connection
+                    .write()
+                    .await
+                    .documents_mentioning(entity)
+                    .await
+            }
+            Neo4jQuery::RetrieveChunks(chunk_ids) => {
+                info!("Executing query...");
+
+                self.connection
+                    .write()
+                    .await
+                    .retrieve_chunks(chunk_ids)
+                    .await
+            }
+            Neo4jQuery::Retrieve(node_ids) => {
+                info!("Executing query...");
+
+                self.connection
+                    .write()
+                    .await
+                    .retrieve_on_match(node_ids)
+                    .await
+            }
+        }
+    }
 }